@@ -0,0 +1,149 @@
+#[derive(PartialEq, Clone, Copy)]
+pub enum Indentation {
+    None,
+    Spaces,
+    Tabs,
+    Mixed,
+}
+
+pub struct BufferStats {
+    pub lines: usize,
+    pub longest_line: Option<(usize, usize)>,
+    pub indentation: Indentation,
+    pub dominant_indentation: Indentation,
+    pub anomalies: Vec<String>,
+}
+
+pub fn compute(lines: &[String]) -> BufferStats {
+    let mut longest_line: Option<(usize, usize)> = None;
+    let mut space_lines: usize = 0;
+    let mut tab_lines: usize = 0;
+    let mut mixed_lines: usize = 0;
+    let mut control_lines = Vec::new();
+    let mut replacement_lines = Vec::new();
+    let mut carriage_returns: usize = 0;
+    for (index, line) in lines.iter().enumerate() {
+        let len = line.chars().count();
+        if longest_line.map_or(true, |(_, longest)| len > longest) {
+            longest_line = Some((index, len));
+        }
+        match classify(line) {
+            Indentation::Mixed => mixed_lines = mixed_lines.saturating_add(1),
+            Indentation::Spaces => space_lines = space_lines.saturating_add(1),
+            Indentation::Tabs => tab_lines = tab_lines.saturating_add(1),
+            Indentation::None => (),
+        }
+        if line.contains('\r') {
+            carriage_returns = carriage_returns.saturating_add(1);
+        }
+        if line.contains('\u{fffd}') {
+            replacement_lines.push(index);
+        }
+        if line
+            .chars()
+            .any(|c| c.is_control() && c != '\t' && c != '\r')
+        {
+            control_lines.push(index);
+        }
+    }
+    let indentation = if mixed_lines > 0 || (space_lines > 0 && tab_lines > 0) {
+        Indentation::Mixed
+    } else if tab_lines > 0 {
+        Indentation::Tabs
+    } else if space_lines > 0 {
+        Indentation::Spaces
+    } else {
+        Indentation::None
+    };
+    let mut anomalies = Vec::new();
+    if lines
+        .first()
+        .map_or(false, |line| line.starts_with('\u{feff}'))
+    {
+        anomalies.push("byte order mark".to_string());
+    }
+    if carriage_returns > 0 {
+        anomalies.push(format!(
+            "stray carriage returns on {} lines",
+            carriage_returns
+        ));
+    }
+    if let Some(first) = replacement_lines.first() {
+        anomalies.push(format!(
+            "replacement characters on {} lines (first: {})",
+            replacement_lines.len(),
+            first.saturating_add(1)
+        ));
+    }
+    if let Some(first) = control_lines.first() {
+        anomalies.push(format!(
+            "control characters on {} lines (first: {})",
+            control_lines.len(),
+            first.saturating_add(1)
+        ));
+    }
+    BufferStats {
+        lines: lines.len(),
+        longest_line,
+        indentation,
+        dominant_indentation: if tab_lines > space_lines {
+            Indentation::Tabs
+        } else {
+            Indentation::Spaces
+        },
+        anomalies,
+    }
+}
+
+pub fn classify(line: &str) -> Indentation {
+    let mut spaces = false;
+    let mut tabs = false;
+    for c in line.chars() {
+        match c {
+            ' ' => spaces = true,
+            '\t' => tabs = true,
+            _ => break,
+        }
+    }
+    match (spaces, tabs) {
+        (true, true) => Indentation::Mixed,
+        (true, false) => Indentation::Spaces,
+        (false, true) => Indentation::Tabs,
+        (false, false) => Indentation::None,
+    }
+}
+
+impl BufferStats {
+    pub fn is_inconsistent(&self, line: &str) -> bool {
+        if self.indentation != Indentation::Mixed {
+            return false;
+        }
+        match classify(line) {
+            Indentation::None => false,
+            Indentation::Mixed => true,
+            kind => kind != self.dominant_indentation,
+        }
+    }
+
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.indentation == Indentation::Mixed {
+            warnings.push("mixed tabs and spaces".to_string());
+        }
+        warnings.extend(self.anomalies.iter().cloned());
+        warnings
+    }
+
+    pub fn summary(&self) -> String {
+        let longest = self.longest_line.map_or(String::new(), |(line, len)| {
+            format!(", longest line {} ({} chars)", line.saturating_add(1), len)
+        });
+        let indentation = match self.indentation {
+            Indentation::None => "no indentation",
+            Indentation::Spaces => "spaces",
+            Indentation::Tabs => "tabs",
+            Indentation::Mixed => "mixed indentation",
+        };
+        format!("{} lines{}, {}", self.lines, longest, indentation)
+    }
+}
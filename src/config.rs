@@ -0,0 +1,429 @@
+use crate::keymap::{self, Mapping};
+use crate::privacy;
+use crate::secrets::{self, SecretPattern};
+use crate::selection::Selection;
+use crate::theme::{Theme, DEFAULT_THEME};
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use termion::event::Key;
+
+pub const OPTION_NAMES: [&str; 23] = [
+    "autopairs",
+    "autoread",
+    "autosave",
+    "backup",
+    "backupdir",
+    "breaksymlinks",
+    "clipboard",
+    "colorscheme",
+    "dimunfocused",
+    "expandtab",
+    "journal",
+    "langmap",
+    "leader",
+    "list",
+    "lsorder",
+    "number",
+    "progress",
+    "regexsearch",
+    "secretscan",
+    "shiftwidth",
+    "tabstop",
+    "textwidth",
+    "wrapcomments",
+];
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum BackupPolicy {
+    None,
+    Simple,
+    Numbered,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum ProgressStyle {
+    Percent,
+    Lines,
+    Bar,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum BufferOrder {
+    Mru,
+    Path,
+    Modified,
+}
+
+pub struct Config {
+    pub break_symlinks: bool,
+    pub auto_pairs: bool,
+    pub autoread: bool,
+    pub autosave: bool,
+    pub journal: bool,
+    pub dim_unfocused: bool,
+    pub number: bool,
+    pub list: bool,
+    pub expand_tab: bool,
+    pub tabstop: usize,
+    pub shiftwidth: usize,
+    pub textwidth: usize,
+    pub wrap_comments: bool,
+    pub regex_search: bool,
+    pub secret_scan: bool,
+    pub secret_patterns: Vec<SecretPattern>,
+    pub private_patterns: Vec<String>,
+    pub backup: BackupPolicy,
+    pub backup_dir: Option<PathBuf>,
+    pub progress: ProgressStyle,
+    pub buffer_order: BufferOrder,
+    pub clipboard: Option<Selection>,
+    pub colorscheme: String,
+    pub commands: BTreeMap<String, String>,
+    pub mappings: Vec<Mapping>,
+    pub leader: Key,
+    pub langmap: HashMap<char, char>,
+    pub langmap_spec: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            break_symlinks: false,
+            auto_pairs: true,
+            autoread: false,
+            autosave: false,
+            journal: false,
+            dim_unfocused: false,
+            number: true,
+            list: false,
+            expand_tab: true,
+            tabstop: 4,
+            shiftwidth: 4,
+            textwidth: 0,
+            wrap_comments: false,
+            regex_search: false,
+            secret_scan: false,
+            secret_patterns: secrets::builtin(),
+            private_patterns: privacy::DEFAULT_PATTERNS
+                .iter()
+                .map(|pattern| (*pattern).to_string())
+                .collect(),
+            backup: BackupPolicy::None,
+            backup_dir: None,
+            progress: ProgressStyle::Percent,
+            buffer_order: BufferOrder::Mru,
+            clipboard: None,
+            colorscheme: DEFAULT_THEME.to_string(),
+            commands: BTreeMap::new(),
+            mappings: Vec::new(),
+            leader: Key::Char('\\'),
+            langmap: HashMap::new(),
+            langmap_spec: String::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> (Self, Vec<String>) {
+        let mut config = Self::default();
+        let mut errors = Vec::new();
+        if let Some(path) = Self::path() {
+            if let Ok(contents) = fs::read_to_string(&path) {
+                for (index, line) in contents.lines().enumerate() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') || line.starts_with('"') {
+                        continue;
+                    }
+                    let result = if let Some(definition) = line.strip_prefix("command!") {
+                        config.define_command(definition, true)
+                    } else if let Some(definition) = line.strip_prefix("command ") {
+                        config.define_command(definition, false)
+                    } else if let Some(definition) = line.strip_prefix("map ") {
+                        config.add_mapping(definition, None)
+                    } else if let Some(definition) = line.strip_prefix("ftmap ") {
+                        definition
+                            .trim()
+                            .split_once(char::is_whitespace)
+                            .ok_or_else(|| anyhow!("Usage: ftmap filetype keys expansion"))
+                            .and_then(|(file_type, definition)| {
+                                config.add_mapping(definition, Some(file_type))
+                            })
+                    } else if let Some(pattern) = line.strip_prefix("secret ") {
+                        config.add_secret_pattern(pattern)
+                    } else if let Some(pattern) = line.strip_prefix("private ") {
+                        config.private_patterns.push(pattern.trim().to_string());
+                        Ok(())
+                    } else {
+                        config.set(line.strip_prefix("set ").unwrap_or(line).trim())
+                    };
+                    if let Err(error) = result {
+                        errors.push(format!("{}:{}: {}", path.display(), index + 1, error));
+                    }
+                }
+            }
+        }
+        (config, errors)
+    }
+
+    pub fn path() -> Option<PathBuf> {
+        let config_home = env::var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .ok()?;
+        Some(config_home.join("hecto").join("config"))
+    }
+
+    pub fn set(&mut self, setting: &str) -> Result<()> {
+        let (name, value) = match setting.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (setting.trim(), None),
+        };
+        if let Some(flag) = self.bool_option(name) {
+            *flag = match value {
+                None | Some("true") | Some("on") | Some("1") => true,
+                Some("false") | Some("off") | Some("0") => false,
+                Some(value) => bail!("Invalid value for {}: {}", name, value),
+            };
+            return Ok(());
+        }
+        if let Some(flag) = name
+            .strip_prefix("no")
+            .and_then(|name| self.bool_option(name))
+        {
+            *flag = false;
+            return Ok(());
+        }
+        let value = || value.ok_or_else(|| anyhow!("Option {} requires a value", name));
+        match name {
+            "backup" => {
+                self.backup = match value()? {
+                    "none" => BackupPolicy::None,
+                    "simple" => BackupPolicy::Simple,
+                    "numbered" => BackupPolicy::Numbered,
+                    other => bail!("Invalid value for backup: {}", other),
+                }
+            }
+            "backupdir" => {
+                let dir = value()?;
+                self.backup_dir = if dir.is_empty() {
+                    None
+                } else {
+                    Some(PathBuf::from(dir))
+                };
+            }
+            "tabstop" | "ts" => self.tabstop = parse_width(name, value()?)?,
+            "shiftwidth" | "sw" => self.shiftwidth = parse_width(name, value()?)?,
+            "textwidth" | "tw" => {
+                let width = value()?;
+                self.textwidth = width
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid value for {}: {}", name, width))?;
+            }
+            "progress" => {
+                self.progress = match value()? {
+                    "percent" => ProgressStyle::Percent,
+                    "lines" => ProgressStyle::Lines,
+                    "bar" => ProgressStyle::Bar,
+                    other => bail!("Invalid value for progress: {}", other),
+                }
+            }
+            "lsorder" => {
+                self.buffer_order = match value()? {
+                    "mru" => BufferOrder::Mru,
+                    "path" => BufferOrder::Path,
+                    "modified" => BufferOrder::Modified,
+                    other => bail!("Invalid value for lsorder: {}", other),
+                }
+            }
+            "clipboard" => {
+                self.clipboard = match value()? {
+                    "" => None,
+                    "unnamed" => Some(Selection::Primary),
+                    "unnamedplus" => Some(Selection::Clipboard),
+                    other => bail!("Invalid value for clipboard: {}", other),
+                }
+            }
+            "langmap" => {
+                let spec = value()?;
+                self.langmap = keymap::parse_langmap(spec)?;
+                self.langmap_spec = spec.to_string();
+            }
+            "leader" => {
+                let notation = value()?;
+                self.leader = match keymap::parse_keys(notation, self.leader)?[..] {
+                    [key] => key,
+                    _ => bail!("Invalid value for leader: {}", notation),
+                };
+            }
+            "colorscheme" => {
+                let name = value()?;
+                Theme::load(name)?;
+                self.colorscheme = name.to_string();
+            }
+            _ => bail!("Unknown option: {}", name),
+        }
+        Ok(())
+    }
+
+    pub fn get(&mut self, name: &str) -> Result<String> {
+        if let Some(flag) = self.bool_option(name) {
+            return Ok(if *flag {
+                name.to_string()
+            } else {
+                format!("no{}", name)
+            });
+        }
+        let value = match name {
+            "backup" => match self.backup {
+                BackupPolicy::None => "none".to_string(),
+                BackupPolicy::Simple => "simple".to_string(),
+                BackupPolicy::Numbered => "numbered".to_string(),
+            },
+            "backupdir" => self
+                .backup_dir
+                .as_ref()
+                .map_or(String::new(), |dir| dir.display().to_string()),
+            "tabstop" | "ts" => self.tabstop.to_string(),
+            "shiftwidth" | "sw" => self.shiftwidth.to_string(),
+            "textwidth" | "tw" => self.textwidth.to_string(),
+            "progress" => match self.progress {
+                ProgressStyle::Percent => "percent".to_string(),
+                ProgressStyle::Lines => "lines".to_string(),
+                ProgressStyle::Bar => "bar".to_string(),
+            },
+            "lsorder" => match self.buffer_order {
+                BufferOrder::Mru => "mru".to_string(),
+                BufferOrder::Path => "path".to_string(),
+                BufferOrder::Modified => "modified".to_string(),
+            },
+            "clipboard" => match self.clipboard {
+                None => String::new(),
+                Some(Selection::Primary) => "unnamed".to_string(),
+                Some(Selection::Clipboard) => "unnamedplus".to_string(),
+            },
+            "langmap" => self.langmap_spec.clone(),
+            "leader" => keymap::format_keys(&[self.leader]),
+            "colorscheme" => self.colorscheme.clone(),
+            _ => bail!("Unknown option: {}", name),
+        };
+        Ok(format!("{}={}", name, value))
+    }
+
+    pub fn define_command(&mut self, definition: &str, overwrite: bool) -> Result<()> {
+        let definition = definition.trim();
+        let (name, expansion) = definition
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("Usage: command Name expansion"))?;
+        if !name
+            .chars()
+            .next()
+            .map_or(false, |c| c.is_ascii_uppercase())
+            || !name.chars().all(|c| c.is_ascii_alphabetic())
+        {
+            bail!("User command names must be letters starting with an uppercase letter");
+        }
+        if !overwrite && self.commands.contains_key(name) {
+            bail!("Command already exists: add ! to replace it");
+        }
+        self.commands
+            .insert(name.to_string(), expansion.trim().to_string());
+        Ok(())
+    }
+
+    pub fn add_mapping(&mut self, definition: &str, file_type: Option<&str>) -> Result<()> {
+        let mapping = Mapping::parse(definition, file_type, self.leader)?;
+        self.mappings.retain(|existing| {
+            existing.lhs != mapping.lhs || existing.file_type != mapping.file_type
+        });
+        self.mappings.push(mapping);
+        Ok(())
+    }
+
+    pub fn add_secret_pattern(&mut self, pattern: &str) -> Result<()> {
+        let pattern = pattern.trim();
+        self.secret_patterns
+            .push(SecretPattern::new(pattern, pattern)?);
+        Ok(())
+    }
+
+    pub fn persist(name: &str, value: &str) -> Result<()> {
+        let path = Self::path().ok_or_else(|| anyhow!("No config directory"))?;
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error).with_context(|| format!("{}", path.display())),
+        };
+        let setting = format!("set {}={}", name, value);
+        let negated = format!("no{}", name);
+        let mut replaced = false;
+        let mut lines: Vec<String> = contents
+            .lines()
+            .filter_map(|line| {
+                let trimmed = line.trim();
+                let option = trimmed.strip_prefix("set ").unwrap_or(trimmed).trim();
+                let option_name = option.split('=').next().unwrap_or_default().trim();
+                if option_name != name && option_name != negated {
+                    Some(line.to_string())
+                } else if replaced {
+                    None
+                } else {
+                    replaced = true;
+                    Some(setting.clone())
+                }
+            })
+            .collect();
+        if !replaced {
+            lines.push(setting);
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut contents = lines.join("\n");
+        contents.push('\n');
+        // Replace the file a symlinked config points at, not the link.
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        fs::write(&temp_path, contents).with_context(|| format!("{}", temp_path.display()))?;
+        fs::rename(&temp_path, &path).with_context(|| format!("{}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn toggle(&mut self, name: &str) -> Result<()> {
+        let flag = self
+            .bool_option(name)
+            .ok_or_else(|| anyhow!("Option {} is not a toggle", name))?;
+        *flag = !*flag;
+        Ok(())
+    }
+
+    fn bool_option(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "breaksymlinks" => Some(&mut self.break_symlinks),
+            "autopairs" => Some(&mut self.auto_pairs),
+            "autoread" => Some(&mut self.autoread),
+            "autosave" => Some(&mut self.autosave),
+            "journal" => Some(&mut self.journal),
+            "dimunfocused" => Some(&mut self.dim_unfocused),
+            "number" | "nu" => Some(&mut self.number),
+            "list" => Some(&mut self.list),
+            "expandtab" | "et" => Some(&mut self.expand_tab),
+            "regexsearch" => Some(&mut self.regex_search),
+            "secretscan" => Some(&mut self.secret_scan),
+            "wrapcomments" => Some(&mut self.wrap_comments),
+            _ => None,
+        }
+    }
+}
+
+fn parse_width(name: &str, value: &str) -> Result<usize> {
+    match value.parse::<usize>() {
+        Ok(width) if width > 0 => Ok(width),
+        _ => bail!("Invalid value for {}: {}", name, value),
+    }
+}
@@ -0,0 +1,279 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+const GPG_EXTENSIONS: [&str; 3] = ["gpg", "pgp", "asc"];
+const GPG_ARMOR_HEADER: &[u8] = b"-----BEGIN PGP MESSAGE-----";
+const AGE_HEADER: &[u8] = b"age-encryption.org/v1";
+const AGE_ARMOR_HEADER: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+const AGE_SCRYPT_STANZA: &[u8] = b"-> scrypt";
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Cipher {
+    Gpg,
+    Age,
+}
+
+#[derive(Clone)]
+pub enum Encryption {
+    Gpg {
+        recipients: Vec<String>,
+        passphrase: Option<String>,
+        armor: bool,
+    },
+    Age {
+        identity: PathBuf,
+        armor: bool,
+    },
+}
+
+impl Encryption {
+    pub fn needs_passphrase(&self) -> bool {
+        matches!(self, Self::Gpg { recipients, passphrase: None, .. } if recipients.is_empty())
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Gpg {
+                recipients,
+                passphrase,
+                armor,
+            } => {
+                let mut command = Command::new("gpg");
+                command.args(["--batch", "--yes", "--quiet", "--output", "-"]);
+                if *armor {
+                    command.arg("--armor");
+                }
+                let mut input = Vec::new();
+                if recipients.is_empty() {
+                    let passphrase = passphrase
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("No passphrase to encrypt with"))?;
+                    command.args(["--pinentry-mode", "loopback", "--passphrase-fd", "0"]);
+                    command.arg("--symmetric");
+                    input.extend_from_slice(passphrase.as_bytes());
+                    input.push(b'\n');
+                } else {
+                    command.args(["--trust-model", "always", "--encrypt"]);
+                    for recipient in recipients {
+                        command.arg("--recipient").arg(recipient);
+                    }
+                }
+                input.extend_from_slice(plaintext);
+                run(&mut command, &input).context("gpg failed")
+            }
+            Self::Age { identity, armor } => {
+                let mut command = Command::new("age");
+                command.arg("--encrypt").arg("--identity").arg(identity);
+                if *armor {
+                    command.arg("--armor");
+                }
+                run(&mut command, plaintext).context("age failed")
+            }
+        }
+    }
+}
+
+pub fn detect(path: &str) -> Option<Cipher> {
+    let extension = Path::new(path)
+        .extension()?
+        .to_string_lossy()
+        .to_lowercase();
+    let header = read_header(path).unwrap_or_default();
+    if extension == "age" || header.starts_with(AGE_HEADER) || header.starts_with(AGE_ARMOR_HEADER)
+    {
+        Some(Cipher::Age)
+    } else if GPG_EXTENSIONS.contains(&extension.as_str())
+        && (extension != "asc" || header.starts_with(GPG_ARMOR_HEADER))
+    {
+        Some(Cipher::Gpg)
+    } else {
+        None
+    }
+}
+
+pub fn plaintext_name(path: &str) -> &str {
+    ["gpg", "pgp", "asc", "age"]
+        .iter()
+        .find_map(|extension| {
+            path.strip_suffix(extension)
+                .and_then(|rest| rest.strip_suffix('.'))
+        })
+        .unwrap_or(path)
+}
+
+pub fn decrypt_gpg(path: &str, passphrase: Option<&str>) -> Result<(String, Encryption)> {
+    let mut command = Command::new("gpg");
+    command.args([
+        "--batch",
+        "--quiet",
+        "--status-fd",
+        "2",
+        "--pinentry-mode",
+        "loopback",
+        "--passphrase-fd",
+        "0",
+        "--decrypt",
+    ]);
+    command.arg(path);
+    let mut input = passphrase.unwrap_or_default().as_bytes().to_vec();
+    input.push(b'\n');
+    let (plaintext, status) = run_with_stderr(&mut command, &input).context("gpg failed")?;
+    let recipients = status
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] ENC_TO "))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .map(str::to_string)
+        .collect();
+    let armor = read_header(path)
+        .unwrap_or_default()
+        .starts_with(GPG_ARMOR_HEADER);
+    Ok((
+        String::from_utf8(plaintext).context("Decrypted file is not valid UTF-8")?,
+        Encryption::Gpg {
+            recipients,
+            passphrase: passphrase.map(str::to_string),
+            armor,
+        },
+    ))
+}
+
+pub fn decrypt_age(path: &str, identity: &Path) -> Result<(String, Encryption)> {
+    let header = read_header(path)?;
+    if header
+        .windows(AGE_SCRYPT_STANZA.len())
+        .any(|window| window == AGE_SCRYPT_STANZA)
+    {
+        bail!("Passphrase-encrypted age files are not supported");
+    }
+    let mut command = Command::new("age");
+    command
+        .arg("--decrypt")
+        .arg("--identity")
+        .arg(identity)
+        .arg(path);
+    let plaintext = run(&mut command, &[]).context("age failed")?;
+    Ok((
+        String::from_utf8(plaintext).context("Decrypted file is not valid UTF-8")?,
+        Encryption::Age {
+            identity: identity.to_path_buf(),
+            armor: header.starts_with(AGE_ARMOR_HEADER),
+        },
+    ))
+}
+
+fn read_header(path: &str) -> Result<Vec<u8>> {
+    let mut header = Vec::new();
+    fs::File::open(path)?.take(256).read_to_end(&mut header)?;
+    Ok(header)
+}
+
+fn run(command: &mut Command, input: &[u8]) -> Result<Vec<u8>> {
+    run_with_stderr(command, input).map(|(stdout, _)| stdout)
+}
+
+fn run_with_stderr(command: &mut Command, input: &[u8]) -> Result<(Vec<u8>, String)> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let writer = child.stdin.take().map(|mut stdin| {
+        let input = input.to_vec();
+        thread::spawn(move || stdin.write_all(&input))
+    });
+    let output = child.wait_with_output()?;
+    let written = writer.map_or(Ok(Ok(())), thread::JoinHandle::join);
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    if !output.status.success() {
+        let message = stderr
+            .lines()
+            .filter(|line| !line.starts_with("[GNUPG:]"))
+            .last()
+            .unwrap_or_default()
+            .to_string();
+        bail!("{} ({})", message, output.status);
+    }
+    written.map_err(|_| anyhow!("Could not write to {:?}", command.get_program()))??;
+    Ok((output.stdout, stderr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn with_file(name: &str, contents: &[u8], check: impl FnOnce(&str)) {
+        let path = env::temp_dir().join(format!("hecto-crypt-{}-{}", process::id(), name));
+        let path = path.to_string_lossy().to_string();
+        assert!(fs::write(&path, contents).is_ok());
+        check(&path);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn extensions_pick_the_cipher() {
+        assert!(detect("/no/such/notes.age") == Some(Cipher::Age));
+        assert!(detect("/no/such/notes.gpg") == Some(Cipher::Gpg));
+        assert!(detect("/no/such/notes.PGP") == Some(Cipher::Gpg));
+        assert!(detect("/no/such/notes.txt").is_none());
+    }
+
+    #[test]
+    fn asc_files_need_a_message_header() {
+        with_file("message.asc", b"-----BEGIN PGP MESSAGE-----\n", |path| {
+            assert!(detect(path) == Some(Cipher::Gpg));
+        });
+        with_file(
+            "key.asc",
+            b"-----BEGIN PGP PUBLIC KEY BLOCK-----\n",
+            |path| {
+                assert!(detect(path).is_none());
+            },
+        );
+    }
+
+    #[test]
+    fn age_headers_are_recognised_under_any_extension() {
+        with_file(
+            "binary.txt",
+            b"age-encryption.org/v1\n-> X25519 abc\n",
+            |path| {
+                assert!(detect(path) == Some(Cipher::Age));
+            },
+        );
+        with_file(
+            "armored.txt",
+            b"-----BEGIN AGE ENCRYPTED FILE-----\n",
+            |path| assert!(detect(path) == Some(Cipher::Age)),
+        );
+    }
+
+    #[test]
+    fn plaintext_names_drop_the_cipher_extension() {
+        assert_eq!(plaintext_name("notes.md.gpg"), "notes.md");
+        assert_eq!(plaintext_name("notes.rs.age"), "notes.rs");
+        assert_eq!(plaintext_name("notes.md"), "notes.md");
+        assert_eq!(plaintext_name("gpg"), "gpg");
+    }
+
+    #[test]
+    fn only_symmetric_gpg_without_a_passphrase_asks_for_one() {
+        let symmetric = Encryption::Gpg {
+            recipients: Vec::new(),
+            passphrase: None,
+            armor: false,
+        };
+        let public_key = Encryption::Gpg {
+            recipients: vec![String::from("me@example.com")],
+            passphrase: None,
+            armor: false,
+        };
+        assert!(symmetric.needs_passphrase());
+        assert!(!public_key.needs_passphrase());
+    }
+}
@@ -0,0 +1,79 @@
+use similar::{Algorithm, ChangeTag, TextDiff};
+
+pub fn unified(old: &[String], new: &[String], context: usize) -> Vec<String> {
+    let old: Vec<&str> = old.iter().map(String::as_str).collect();
+    let new: Vec<&str> = new.iter().map(String::as_str).collect();
+    let diff = TextDiff::configure()
+        .algorithm(Algorithm::Myers)
+        .diff_slices(&old, &new);
+    let mut lines = Vec::new();
+    for hunk in diff.unified_diff().context_radius(context).iter_hunks() {
+        lines.push(hunk.header().to_string());
+        for change in hunk.iter_changes() {
+            let sign = match change.tag() {
+                ChangeTag::Equal => ' ',
+                ChangeTag::Delete => '-',
+                ChangeTag::Insert => '+',
+            };
+            lines.push(format!("{}{}", sign, change.value()));
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.split(' ').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn identical_input_has_no_hunks() {
+        assert!(unified(&lines("a b c"), &lines("a b c"), 3).is_empty());
+    }
+
+    #[test]
+    fn insert_hunk() {
+        assert_eq!(
+            unified(&lines("a b c d"), &lines("a b x c d"), 1),
+            ["@@ -2,2 +2,3 @@", " b", "+x", " c"]
+        );
+    }
+
+    #[test]
+    fn delete_hunk() {
+        assert_eq!(
+            unified(&lines("a b c d"), &lines("a c d"), 1),
+            ["@@ -1,3 +1,2 @@", " a", "-b", " c"]
+        );
+    }
+
+    #[test]
+    fn replace_hunk() {
+        assert_eq!(
+            unified(&lines("a b c"), &lines("a x c"), 0),
+            ["@@ -2 +2 @@", "-b", "+x"]
+        );
+    }
+
+    #[test]
+    fn distant_changes_get_separate_hunks() {
+        let old = lines("a b c d e f g h i j");
+        let new = lines("A b c d e f g h i J");
+        assert_eq!(
+            unified(&old, &new, 1),
+            [
+                "@@ -1,2 +1,2 @@",
+                "-a",
+                "+A",
+                " b",
+                "@@ -9,2 +9,2 @@",
+                " i",
+                "-j",
+                "+J"
+            ]
+        );
+    }
+}
@@ -1,11 +1,74 @@
+use crate::crypt::{self, Encryption};
+use crate::encoding;
+use crate::highlighting;
+use crate::reflow;
+use crate::BackupPolicy;
+use crate::CaseChange;
+use crate::Config;
+use crate::Encoding;
 use crate::FileType;
+use crate::HighlightingOptions;
+use crate::JumpList;
 use crate::Position;
 use crate::Row;
 use crate::SearchDirection;
-use anyhow::Result;
+use crate::SearchPattern;
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Generations are unique across documents and undo states, so two snapshots
+// with the same generation hold the same contents.
+static NEXT_GENERATION: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(PartialEq, Clone, Copy, Default)]
+pub enum LineEnding {
+    #[default]
+    Unix,
+    Dos,
+}
+
+impl LineEnding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unix" => Some(Self::Unix),
+            "dos" => Some(Self::Dos),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Unix => "unix",
+            Self::Dos => "dos",
+        }
+    }
+
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            Self::Unix => b"\n",
+            Self::Dos => b"\r\n",
+        }
+    }
+
+    // The more common ending wins; the second value says whether the other
+    // one turned up too.
+    fn detect(contents: &str) -> (Self, bool) {
+        let crlf = contents.matches("\r\n").count();
+        let lf = contents.matches('\n').count().saturating_sub(crlf);
+        let line_ending = if crlf > lf { Self::Dos } else { Self::Unix };
+        (line_ending, crlf > 0 && lf > 0)
+    }
+}
 
 #[derive(Default)]
 pub struct Document {
@@ -13,6 +76,18 @@ pub struct Document {
     pub file_name: Option<String>,
     dirty: bool,
     file_type: FileType,
+    marks: HashMap<char, Position>,
+    jumps: JumpList,
+    folds: Vec<(usize, usize)>,
+    disk_mtime: Option<SystemTime>,
+    generation: usize,
+    highlight_generation: usize,
+    exact_rows: usize,
+    encryption: Option<Encryption>,
+    line_ending: LineEnding,
+    mixed_line_endings: bool,
+    no_eol: bool,
+    encoding: Encoding,
 }
 
 impl Clone for Document {
@@ -22,24 +97,128 @@ impl Clone for Document {
             file_name: self.file_name.clone(),
             dirty: false,
             file_type: self.file_type.clone(),
+            marks: self.marks.clone(),
+            jumps: self.jumps.clone(),
+            folds: self.folds.clone(),
+            disk_mtime: self.disk_mtime,
+            generation: self.generation,
+            highlight_generation: self.highlight_generation,
+            exact_rows: self.exact_rows,
+            encryption: self.encryption.clone(),
+            line_ending: self.line_ending,
+            mixed_line_endings: self.mixed_line_endings,
+            no_eol: self.no_eol,
+            encoding: self.encoding,
         }
     }
 }
 
 impl Document {
     pub fn open(filename: &str) -> Result<Self> {
-        let contents = fs::read_to_string(filename)?;
-        let file_type = FileType::from(filename);
+        let bytes = fs::read(filename)?;
+        let encoding = Encoding::detect(&bytes);
+        let mut document = Self::from_contents(filename, &encoding.decode(&bytes)?, None);
+        document.encoding = encoding;
+        Ok(document)
+    }
+
+    pub fn open_encrypted(filename: &str, contents: &str, encryption: Encryption) -> Self {
+        Self::from_contents(filename, contents, Some(encryption))
+    }
+
+    fn from_contents(filename: &str, contents: &str, encryption: Option<Encryption>) -> Self {
+        let file_type = FileType::from(crypt::plaintext_name(filename));
         let mut rows = Vec::new();
         for value in contents.lines() {
             rows.push(Row::from(value));
         }
-        Ok(Self {
+        let (line_ending, mixed_line_endings) = LineEnding::detect(contents);
+        Self {
             rows,
             file_name: Some(filename.to_string()),
             dirty: false,
             file_type,
-        })
+            marks: HashMap::new(),
+            jumps: JumpList::default(),
+            folds: Vec::new(),
+            disk_mtime: modified_time(filename),
+            generation: next_generation(),
+            highlight_generation: 0,
+            exact_rows: 0,
+            encryption,
+            line_ending,
+            mixed_line_endings,
+            no_eol: !contents.is_empty() && !contents.ends_with('\n'),
+            encoding: Encoding::default(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    pub fn set_file_name(&mut self, file_name: &str) {
+        self.file_name = Some(file_name.to_string());
+        self.file_type = FileType::from(crypt::plaintext_name(file_name));
+        self.unhighlight_all();
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.rows.iter().map(Row::contents).collect()
+    }
+
+    pub fn with_lines(&self, lines: &[String]) -> Self {
+        let mut document = self.clone();
+        document.rows = lines.iter().map(|line| Row::from(&line[..])).collect();
+        document.unhighlight_all();
+        document
+    }
+
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    pub fn has_mixed_line_endings(&self) -> bool {
+        self.mixed_line_endings
+    }
+
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.dirty = true;
+        self.generation = next_generation();
+        self.line_ending = line_ending;
+        self.mixed_line_endings = false;
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.dirty = true;
+        for row in &mut self.rows {
+            let contents = row.contents();
+            if let Cow::Owned(converted) = encoding::convert(&contents, self.encoding, encoding) {
+                *row = Row::from(&converted[..]);
+            }
+        }
+        self.generation = next_generation();
+        self.unhighlight_all();
+        self.encoding = encoding;
+    }
+
+    pub fn eol(&self) -> bool {
+        !self.no_eol
+    }
+
+    pub fn set_eol(&mut self, eol: bool) {
+        self.dirty = true;
+        self.generation = next_generation();
+        self.no_eol = !eol;
+    }
+
+    pub fn set_file_type(&mut self, file_type: FileType) {
+        self.file_type = file_type;
+        self.unhighlight_all();
     }
 
     pub fn file_type(&self) -> String {
@@ -73,7 +252,7 @@ impl Document {
             .unwrap_or(0)
     }
 
-    fn insert_newline(&mut self, at: &Position) {
+    pub fn insert_newline(&mut self, at: &Position, tabstop: usize, shiftwidth: usize) {
         if at.y > self.rows.len() {
             return;
         }
@@ -84,9 +263,11 @@ impl Document {
         self.dirty = true;
         #[allow(clippy::indexing_slicing)]
         let current_row = &mut self.rows[at.y];
-        let new_row = current_row.split(at.x);
+        let new_row = current_row.split(at.x, tabstop, shiftwidth);
         #[allow(clippy::integer_arithmetic)]
         self.rows.insert(at.y + 1, new_row);
+        self.shift_marks_down(at.y.saturating_add(1), 1);
+        self.unhighlight_rows(at.y);
     }
 
     pub fn insert(&mut self, at: &Position, c: char) {
@@ -95,7 +276,8 @@ impl Document {
         }
         self.dirty = true;
         if c == '\n' {
-            self.insert_newline(at);
+            // A shiftwidth of one keeps the indentation as it is.
+            self.insert_newline(at, 1, 1);
         } else if at.y == self.rows.len() {
             let mut row = Row::default();
             row.insert(0, c);
@@ -114,6 +296,7 @@ impl Document {
         }
         self.dirty = true;
         self.rows.splice(y..y, vec![line.into()]);
+        self.shift_marks_down(y, 1);
         self.unhighlight_rows(y);
     }
 
@@ -122,9 +305,276 @@ impl Document {
         self.insert(at, c);
     }
 
+    pub fn mark(&self, name: char) -> Option<Position> {
+        self.marks.get(&name).copied()
+    }
+
+    pub fn marks(&self) -> Vec<(char, Position)> {
+        let mut marks: Vec<(char, Position)> = self
+            .marks
+            .iter()
+            .map(|(name, position)| (*name, *position))
+            .collect();
+        marks.sort_by_key(|(name, _)| *name);
+        marks
+    }
+
+    // Lines that open a definition: a keyword of the filetype such as fn or
+    // class, after any modifiers, followed by a name.
+    pub fn symbols(&self) -> Vec<(usize, String)> {
+        const DEFINITIONS: &[&str] = &[
+            "class",
+            "def",
+            "enum",
+            "fn",
+            "func",
+            "function",
+            "impl",
+            "interface",
+            "mod",
+            "module",
+            "struct",
+            "trait",
+            "type",
+        ];
+        const MODIFIERS: &[&str] = &[
+            "abstract",
+            "async",
+            "const",
+            "export",
+            "extern",
+            "final",
+            "private",
+            "protected",
+            "pub",
+            "public",
+            "static",
+            "unsafe",
+        ];
+        let opts = self.file_type.highlighting_options();
+        let is_keyword = |word: &str| {
+            DEFINITIONS.contains(&word)
+                && (opts
+                    .primary_keywords()
+                    .iter()
+                    .any(|keyword| keyword == word)
+                    || opts
+                        .secondary_keywords()
+                        .iter()
+                        .any(|keyword| keyword == word))
+        };
+        self.rows
+            .iter()
+            .enumerate()
+            .filter_map(|(y, row)| {
+                let contents = row.contents();
+                let mut words = contents
+                    .split_whitespace()
+                    .skip_while(|word| MODIFIERS.contains(&word.split('(').next().unwrap_or(word)));
+                if !is_keyword(words.next()?) {
+                    return None;
+                }
+                let name = words.next()?;
+                name.starts_with(|c: char| c.is_alphanumeric() || c == '_')
+                    .then(|| (y, contents.trim().to_string()))
+            })
+            .collect()
+    }
+
+    pub fn set_mark(&mut self, name: char, position: Position) {
+        self.marks.insert(name, position);
+    }
+
+    pub fn inherit_state(&mut self, other: &Self) {
+        self.marks = other.marks.clone();
+        self.jumps = other.jumps.clone();
+        let len = self.rows.len();
+        self.folds = other
+            .folds
+            .iter()
+            .copied()
+            .filter(|(_, end)| *end < len)
+            .collect();
+        self.disk_mtime = other.disk_mtime;
+        if self.file_type.name() != other.file_type.name() {
+            self.set_file_type(other.file_type.clone());
+        }
+    }
+
+    pub fn changed_on_disk(&self) -> bool {
+        match &self.file_name {
+            Some(file_name) => modified_time(file_name) != self.disk_mtime,
+            None => false,
+        }
+    }
+
+    pub fn refresh_disk_mtime(&mut self) {
+        self.disk_mtime = self.file_name.as_deref().and_then(modified_time);
+    }
+
+    pub fn jumps_mut(&mut self) -> &mut JumpList {
+        &mut self.jumps
+    }
+
+    fn shift_marks_down(&mut self, start: usize, count: usize) {
+        self.jumps.shift_down(start, count);
+        self.folds
+            .retain(|(fold_start, fold_end)| start <= *fold_start || start > *fold_end);
+        for (fold_start, fold_end) in &mut self.folds {
+            if *fold_start >= start {
+                *fold_start = fold_start.saturating_add(count);
+                *fold_end = fold_end.saturating_add(count);
+            }
+        }
+        for mark in self.marks.values_mut() {
+            if mark.y >= start {
+                mark.y = mark.y.saturating_add(count);
+            }
+        }
+    }
+
+    fn remove_marked_lines(&mut self, start: usize, count: usize) {
+        self.jumps.remove_lines(start, count);
+        let end = start.saturating_add(count);
+        self.folds
+            .retain(|(fold_start, fold_end)| *fold_end < start || *fold_start >= end);
+        for (fold_start, fold_end) in &mut self.folds {
+            if *fold_start >= end {
+                *fold_start = fold_start.saturating_sub(count);
+                *fold_end = fold_end.saturating_sub(count);
+            }
+        }
+        self.marks.retain(|_, mark| mark.y < start || mark.y >= end);
+        for mark in self.marks.values_mut() {
+            if mark.y >= end {
+                mark.y = mark.y.saturating_sub(count);
+            }
+        }
+    }
+
+    pub fn closed_fold(&self, y: usize) -> Option<(usize, usize)> {
+        self.folds
+            .iter()
+            .copied()
+            .find(|(start, end)| (*start..=*end).contains(&y))
+    }
+
+    pub fn fold_count(&self) -> usize {
+        self.folds.len()
+    }
+
+    pub fn indent_fold(&self, y: usize) -> Option<(usize, usize)> {
+        let end = self.block_end(y);
+        if end > y {
+            return Some((y, end));
+        }
+        let is_blank = |index: usize| self.rows.get(index).map_or(true, Row::is_blank);
+        let indent_of = |index: usize| self.rows.get(index).map_or(0, Row::indent_len);
+        let indent = (y..self.rows.len())
+            .find(|index| !is_blank(*index))
+            .map_or(0, indent_of);
+        let header = (0..y)
+            .rev()
+            .find(|index| !is_blank(*index) && indent_of(*index) < indent)?;
+        let end = self.block_end(header);
+        (end >= y).then_some((header, end))
+    }
+
+    fn block_end(&self, header: usize) -> usize {
+        let Some(base) = self
+            .rows
+            .get(header)
+            .filter(|row| !row.is_blank())
+            .map(Row::indent_len)
+        else {
+            return header;
+        };
+        let mut end = header;
+        for (y, row) in self.rows.iter().enumerate().skip(header.saturating_add(1)) {
+            if row.is_blank() {
+                continue;
+            }
+            if row.indent_len() <= base {
+                break;
+            }
+            end = y;
+        }
+        end
+    }
+
+    pub fn close_fold(&mut self, start: usize, end: usize) {
+        self.folds
+            .retain(|(fold_start, fold_end)| *fold_end < start || *fold_start > end);
+        let index = self
+            .folds
+            .iter()
+            .position(|(fold_start, _)| *fold_start > start)
+            .unwrap_or(self.folds.len());
+        self.folds.insert(index, (start, end));
+    }
+
+    pub fn open_fold(&mut self, y: usize) -> bool {
+        let before = self.folds.len();
+        self.folds
+            .retain(|(start, end)| !(*start..=*end).contains(&y));
+        self.folds.len() != before
+    }
+
+    pub fn open_all_folds(&mut self) {
+        self.folds.clear();
+    }
+
+    pub fn close_all_folds(&mut self) {
+        self.folds.clear();
+        let mut y = 0;
+        while y < self.rows.len() {
+            let end = self.block_end(y);
+            if end > y {
+                self.folds.push((y, end));
+            }
+            y = end.saturating_add(1);
+        }
+    }
+
+    pub fn display_line(&self, y: usize) -> usize {
+        let hidden: usize = self
+            .folds
+            .iter()
+            .filter(|(start, _)| *start < y)
+            .map(|(start, end)| cmp::min(*end, y).saturating_sub(*start))
+            .sum();
+        y.saturating_sub(hidden)
+    }
+
+    pub fn line_at_display(&self, display: usize) -> usize {
+        let mut y = display;
+        for (start, end) in &self.folds {
+            if *start >= y {
+                break;
+            }
+            y = y.saturating_add(end.saturating_sub(*start));
+        }
+        y
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
     fn unhighlight_rows(&mut self, start: usize) {
+        self.generation = next_generation();
+        self.highlight_generation = next_generation();
         let start = start.saturating_sub(1);
-        for row in self.rows.iter_mut().skip(start) {
+        self.exact_rows = cmp::min(self.exact_rows, start);
+        for row in self.rows.iter_mut().skip(start).take(2) {
+            row.is_highlighted = false;
+        }
+    }
+
+    fn unhighlight_all(&mut self) {
+        self.highlight_generation = next_generation();
+        self.exact_rows = 0;
+        for row in &mut self.rows {
             row.is_highlighted = false;
         }
     }
@@ -140,7 +590,17 @@ impl Document {
         if at.x == self.rows[at.y].len() && at.y + 1 < len {
             let next_row = self.rows.remove(at.y + 1);
             let row = &mut self.rows[at.y];
+            let joined_at = row.len();
             row.append(&next_row);
+            for mark in self.marks.values_mut() {
+                if mark.y == at.y + 1 {
+                    *mark = Position {
+                        x: mark.x.saturating_add(joined_at),
+                        y: at.y,
+                    };
+                }
+            }
+            self.remove_marked_lines(at.y + 1, 1);
         } else {
             let row = &mut self.rows[at.y];
             deleted = row.delete(at.x);
@@ -155,34 +615,627 @@ impl Document {
         }
         self.dirty = true;
         self.rows.splice(y..=y, vec![]);
+        self.remove_marked_lines(y, 1);
+        self.unhighlight_rows(y);
+    }
+
+    pub fn change_case(&mut self, start: &Position, end: &Position, case: CaseChange) {
+        let mut changed = false;
+        for (y, row) in self
+            .rows
+            .iter_mut()
+            .enumerate()
+            .take(end.y.saturating_add(1))
+            .skip(start.y)
+        {
+            let from = if y == start.y { start.x } else { 0 };
+            let to = if y == end.y { end.x } else { row.len() };
+            changed |= row.change_case(from, to, case);
+        }
+        if changed {
+            self.dirty = true;
+            self.unhighlight_rows(start.y);
+        }
+    }
+
+    pub fn add_to_number(&mut self, at: &Position, delta: i64) -> Option<usize> {
+        let end = self.rows.get_mut(at.y)?.add_to_number(at.x, delta)?;
+        self.dirty = true;
+        self.unhighlight_rows(at.y);
+        Some(end)
+    }
+
+    pub fn text_range(&self, start: &Position, end: &Position) -> String {
+        let mut lines = Vec::new();
+        for (y, row) in self
+            .rows
+            .iter()
+            .enumerate()
+            .skip(start.y)
+            .take(end.y.saturating_sub(start.y).saturating_add(1))
+        {
+            let from = if y == start.y { start.x } else { 0 };
+            let to = if y == end.y { end.x } else { row.len() };
+            lines.push(row.substring(from, to));
+        }
+        lines.join("\n")
+    }
+
+    #[allow(clippy::indexing_slicing)]
+    pub fn delete_range(&mut self, start: &Position, end: &Position) {
+        if start.y >= self.rows.len() || end.y < start.y {
+            return;
+        }
+        self.dirty = true;
+        if start.y == end.y {
+            self.rows[start.y].delete_range(start.x, end.x);
+        } else {
+            let end_y = cmp::min(end.y, self.rows.len().saturating_sub(1));
+            let last_row = &self.rows[end_y];
+            let tail = last_row.substring(end.x, last_row.len());
+            let row = &mut self.rows[start.y];
+            row.delete_range(start.x, row.len());
+            row.append(&Row::from(&tail[..]));
+            self.rows.drain(start.y.saturating_add(1)..=end_y);
+            self.remove_marked_lines(start.y.saturating_add(1), end_y.saturating_sub(start.y));
+        }
+        self.unhighlight_rows(start.y);
+    }
+
+    #[allow(clippy::indexing_slicing)]
+    pub fn insert_text(&mut self, at: &Position, text: &str) -> Position {
+        if at.y > self.rows.len() {
+            return *at;
+        }
+        if at.y == self.rows.len() {
+            self.rows.push(Row::default());
+        }
+        self.dirty = true;
+        let row = &self.rows[at.y];
+        let head = row.substring(0, at.x);
+        let tail = row.substring(at.x, row.len());
+        let mut lines: Vec<String> = text.split('\n').map(String::from).collect();
+        let mut end = *at;
+        if let Some(first) = lines.first_mut() {
+            first.insert_str(0, &head);
+        }
+        if let Some(last) = lines.last_mut() {
+            end.x = last.graphemes(true).count();
+            last.push_str(&tail);
+        }
+        end.y = at.y.saturating_add(lines.len().saturating_sub(1));
+        self.shift_marks_down(at.y.saturating_add(1), lines.len().saturating_sub(1));
+        let rows = lines.iter().map(|line| {
+            let mut row = Row::from(&line[..]);
+            row.mark_dirty();
+            row
+        });
+        self.rows.splice(at.y..=at.y, rows);
+        self.unhighlight_rows(at.y);
+        end
+    }
+
+    #[allow(clippy::indexing_slicing)]
+    pub fn substitute(
+        &mut self,
+        regex: &Regex,
+        replacement: &str,
+        start: usize,
+        end: usize,
+        global: bool,
+    ) -> (usize, usize) {
+        let mut substitutions: usize = 0;
+        let mut lines: usize = 0;
+        let end = cmp::min(end, self.rows.len().saturating_sub(1));
+        for y in (start..=end).rev() {
+            let Some(row) = self.rows.get(y) else {
+                continue;
+            };
+            let contents = row.contents();
+            let matches = if global {
+                regex.find_iter(&contents).count()
+            } else {
+                usize::from(regex.is_match(&contents))
+            };
+            if matches == 0 {
+                continue;
+            }
+            let replaced = if global {
+                regex.replace_all(&contents, replacement)
+            } else {
+                regex.replace(&contents, replacement)
+            };
+            self.replace_line(y, &replaced);
+            substitutions = substitutions.saturating_add(matches);
+            lines = lines.saturating_add(1);
+        }
+        (substitutions, lines)
+    }
+
+    pub fn join_line(&mut self, y: usize) -> Option<usize> {
+        let first = self.rows.get(y)?.contents();
+        let second = self.rows.get(y.saturating_add(1))?.contents();
+        let first = first.trim_end();
+        let second = second.trim_start();
+        let x = first.graphemes(true).count();
+        let joined = if first.is_empty() || second.is_empty() || second.starts_with(')') {
+            format!("{}{}", first, second)
+        } else {
+            format!("{} {}", first, second)
+        };
+        self.delete_line(y.saturating_add(1));
+        self.replace_line(y, &joined);
+        Some(x)
+    }
+
+    pub fn is_indentation_normalized(&self, tabstop: usize, use_tabs: bool) -> bool {
+        self.rows
+            .iter()
+            .all(|row| reindent(&row.contents(), None, tabstop, use_tabs).is_none())
+    }
+
+    pub fn normalize_indentation(&mut self, tabstop: usize, use_tabs: bool) -> usize {
+        let mut fixed_lines: usize = 0;
+        for y in 0..self.rows.len() {
+            let Some(contents) = self.rows.get(y).map(Row::contents) else {
+                continue;
+            };
+            if let Some(fixed) = reindent(&contents, None, tabstop, use_tabs) {
+                self.replace_line(y, &fixed);
+                fixed_lines = fixed_lines.saturating_add(1);
+            }
+        }
+        fixed_lines
+    }
+
+    pub fn shift_lines(
+        &mut self,
+        start: usize,
+        end: usize,
+        width: usize,
+        right: bool,
+        tabstop: usize,
+        use_tabs: bool,
+    ) -> usize {
+        let mut shifted_lines: usize = 0;
+        let end = cmp::min(end, self.rows.len().saturating_sub(1));
+        for y in start..=end {
+            let Some(contents) = self.rows.get(y).map(Row::contents) else {
+                continue;
+            };
+            if right && contents.is_empty() {
+                continue;
+            }
+            let (indent, _) = split_indent(&contents);
+            let columns = indent_columns(indent, tabstop);
+            let columns = if right {
+                columns.saturating_add(width)
+            } else {
+                columns.saturating_sub(width)
+            };
+            if let Some(shifted) = reindent(&contents, Some(columns), tabstop, use_tabs) {
+                self.replace_line(y, &shifted);
+                shifted_lines = shifted_lines.saturating_add(1);
+            }
+        }
+        shifted_lines
+    }
+
+    pub fn opens_block(&self, y: usize, x: usize) -> bool {
+        let Some(row) = self.rows.get(y) else {
+            return false;
+        };
+        let indent_after = self.file_type.highlighting_options().indent_after();
+        row.code_chars()
+            .filter(|(index, c)| *index < x && !c.is_whitespace())
+            .last()
+            .map_or(false, |(_, c)| indent_after.contains(&c))
+    }
+
+    pub fn dedents_on(&self, c: char) -> bool {
+        self.file_type
+            .highlighting_options()
+            .dedent_on()
+            .contains(&c)
+    }
+
+    pub fn closing_pair(&self, c: char) -> Option<char> {
+        self.file_type.highlighting_options().closing_pair(c)
+    }
+
+    pub fn indent_width(&self, y: usize, tabstop: usize) -> usize {
+        self.rows.get(y).map_or(0, |row| {
+            let contents = row.contents();
+            indent_columns(split_indent(&contents).0, tabstop)
+        })
+    }
+
+    pub fn set_indent(&mut self, y: usize, columns: usize, tabstop: usize, use_tabs: bool) {
+        let Some(contents) = self.rows.get(y).map(Row::contents) else {
+            return;
+        };
+        if let Some(reindented) = reindent(&contents, Some(columns), tabstop, use_tabs) {
+            self.replace_line(y, &reindented);
+        }
+    }
+
+    pub fn toggle_comments(&mut self, start: usize, end: usize) -> bool {
+        let opts = self.file_type.highlighting_options();
+        let (open, close): (String, String) = if opts.line_comment().is_empty() {
+            (
+                opts.block_comment_start().iter().collect(),
+                opts.block_comment_end().iter().collect(),
+            )
+        } else {
+            (opts.line_comment().iter().collect(), String::new())
+        };
+        if open.is_empty() {
+            return false;
+        }
+        let end = cmp::min(end, self.rows.len().saturating_sub(1));
+        let lines: Vec<String> = self
+            .rows
+            .get(start..=end)
+            .map_or(Vec::new(), |rows| rows.iter().map(Row::contents).collect());
+        let is_commented = |line: &str| {
+            let code = line.trim();
+            code.starts_with(&open) && code.ends_with(&close)
+        };
+        let uncomment = lines
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .all(|line| is_commented(line));
+        let indent = lines
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| split_indent(line).0.len())
+            .min()
+            .unwrap_or(0);
+        for (y, line) in (start..).zip(&lines) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let toggled = if uncomment {
+                let (leading, rest) = split_indent(line);
+                let rest = rest.strip_prefix(&open).unwrap_or(rest);
+                let rest = rest.strip_prefix(' ').unwrap_or(rest);
+                let rest = rest.trim_end().strip_suffix(&close).unwrap_or(rest);
+                let rest = if close.is_empty() {
+                    rest
+                } else {
+                    rest.strip_suffix(' ').unwrap_or(rest)
+                };
+                format!("{}{}", leading, rest)
+            } else {
+                let (leading, rest) = line.split_at(indent);
+                if close.is_empty() {
+                    format!("{}{} {}", leading, open, rest)
+                } else {
+                    format!("{}{} {} {}", leading, open, rest, close)
+                }
+            };
+            self.replace_line(y, &toggled);
+        }
+        true
+    }
+
+    pub fn reflow_lines(&mut self, start: usize, end: usize, width: usize) -> usize {
+        let end = cmp::min(end, self.rows.len().saturating_sub(1));
+        let lines: Vec<String> = self
+            .rows
+            .get(start..=end)
+            .map_or(Vec::new(), |rows| rows.iter().map(Row::contents).collect());
+        if lines.is_empty() {
+            return 0;
+        }
+        let reflowed = reflow::reflow(&lines, width, self.file_type.highlighting_options());
+        if reflowed != lines {
+            self.replace_lines(start, end, &reflowed);
+        }
+        reflowed.len()
+    }
+
+    pub fn replace_lines(&mut self, start: usize, end: usize, lines: &[String]) {
+        if start >= self.rows.len() {
+            for (y, line) in (self.rows.len()..).zip(lines) {
+                self.insert_line(y, line);
+            }
+            return;
+        }
+        let end = cmp::min(end, self.rows.len().saturating_sub(1));
+        for _ in start..end {
+            self.delete_line(start.saturating_add(1));
+        }
+        if lines.is_empty() {
+            self.delete_line(start);
+        } else {
+            self.replace_line(start, &lines.join("\n"));
+        }
+    }
+
+    pub fn splice_lines(&mut self, start: usize, removed: usize, lines: &[String]) {
+        let start = cmp::min(start, self.rows.len());
+        let removed = cmp::min(removed, self.rows.len().saturating_sub(start));
+        for (y, line) in (start..).zip(lines.iter().take(removed)) {
+            self.replace_line(y, line);
+        }
+        for (y, line) in (start.saturating_add(removed)..).zip(lines.iter().skip(removed)) {
+            self.insert_line(y, line);
+            if let Some(row) = self.rows.get_mut(y) {
+                row.mark_dirty();
+            }
+        }
+        for _ in lines.len()..removed {
+            self.delete_line(start.saturating_add(lines.len()));
+        }
+    }
+
+    pub fn wrap_line(
+        &mut self,
+        y: usize,
+        width: usize,
+        tabstop: usize,
+        comments_only: bool,
+    ) -> Option<(usize, usize)> {
+        let line = self.rows.get(y)?.contents();
+        let opts = self.file_type.highlighting_options();
+        let is_code = (opts.comments() || opts.multiline_comments()) && !opts.code_fences();
+        if comments_only && is_code && !reflow::is_comment(&line, opts) {
+            return None;
+        }
+        let (head, prefix, rest_start) = reflow::hard_wrap(&line, width, tabstop, opts)?;
+        let rest = self.rows.get(y)?.substring(rest_start, usize::MAX);
+        self.replace_line(y, &format!("{}\n{}{}", head, prefix, rest));
+        Some((rest_start, prefix.graphemes(true).count()))
+    }
+
+    pub fn replace_line(&mut self, y: usize, text: &str) {
+        let rows: Vec<Row> = text
+            .split('\n')
+            .map(|line| {
+                let mut row = Row::from(line);
+                row.mark_dirty();
+                row
+            })
+            .collect();
+        let added = rows.len().saturating_sub(1);
+        self.rows.splice(y..=y, rows);
+        self.shift_marks_down(y.saturating_add(1), added);
+        self.dirty = true;
         self.unhighlight_rows(y);
     }
 
-    pub fn save(&mut self) -> Result<usize> {
+    pub fn word_range(&self, at: &Position, around: bool) -> Option<(Position, Position)> {
+        let (start, end) = self.row(at.y)?.word_bounds(at.x, around)?;
+        Some((Position { x: start, y: at.y }, Position { x: end, y: at.y }))
+    }
+
+    pub fn quote_range(
+        &self,
+        at: &Position,
+        quote: char,
+        around: bool,
+    ) -> Option<(Position, Position)> {
+        let (start, end) = self.row(at.y)?.quote_bounds(at.x, quote, around)?;
+        Some((Position { x: start, y: at.y }, Position { x: end, y: at.y }))
+    }
+
+    pub fn enclosing_pair(
+        &self,
+        at: &Position,
+        open: char,
+        close: char,
+    ) -> Option<(Position, Position)> {
+        let open_position = self.find_unmatched(at, open, close, SearchDirection::Backward)?;
+        let close_position = self.find_unmatched(
+            &Position {
+                x: open_position.x.saturating_add(1),
+                y: open_position.y,
+            },
+            close,
+            open,
+            SearchDirection::Forward,
+        )?;
+        Some((open_position, close_position))
+    }
+
+    fn find_unmatched(
+        &self,
+        at: &Position,
+        target: char,
+        nested: char,
+        direction: SearchDirection,
+    ) -> Option<Position> {
+        let mut depth: usize = 0;
+        let mut y = at.y;
+        loop {
+            let row = self.row(y)?;
+            let contents = row.contents();
+            let graphemes: Vec<&str> = contents.graphemes(true).collect();
+            for x in Self::scan_order(row.len(), y == at.y, at.x, direction) {
+                let c = graphemes.get(x).and_then(|g| g.chars().next());
+                if c == Some(nested)
+                    && !(direction == SearchDirection::Backward && y == at.y && x == at.x)
+                {
+                    depth = depth.saturating_add(1);
+                } else if c == Some(target) {
+                    if depth == 0 {
+                        return Some(Position { x, y });
+                    }
+                    depth = depth.saturating_sub(1);
+                }
+            }
+            if direction == SearchDirection::Forward {
+                y = y.saturating_add(1);
+            } else if y == 0 {
+                return None;
+            } else {
+                y = y.saturating_sub(1);
+            }
+        }
+    }
+
+    fn scan_order(
+        len: usize,
+        is_start_row: bool,
+        start: usize,
+        direction: SearchDirection,
+    ) -> Box<dyn Iterator<Item = usize>> {
+        match (direction, is_start_row) {
+            (SearchDirection::Forward, true) => Box::new(start..len),
+            (SearchDirection::Forward, false) => Box::new(0..len),
+            (SearchDirection::Backward, true) => Box::new((0..=cmp::min(start, len)).rev()),
+            (SearchDirection::Backward, false) => Box::new((0..len).rev()),
+        }
+    }
+
+    pub fn paragraph_range(&self, y: usize, around: bool) -> Option<(usize, usize)> {
+        let is_blank = |index: usize| {
+            self.rows
+                .get(index)
+                .map(|row| row.contents().trim().is_empty())
+        };
+        let blank = is_blank(y)?;
+        let mut start = y;
+        while start > 0 && is_blank(start.saturating_sub(1)) == Some(blank) {
+            start = start.saturating_sub(1);
+        }
+        let mut end = y;
+        while is_blank(end.saturating_add(1)) == Some(blank) {
+            end = end.saturating_add(1);
+        }
+        if around {
+            while is_blank(end.saturating_add(1)) == Some(!blank) {
+                end = end.saturating_add(1);
+            }
+        }
+        Some((start, end))
+    }
+
+    pub fn save(&mut self, config: &Config, backup: bool) -> Result<usize> {
+        let Some(job) = self.prepare_save(config, backup)? else {
+            return Ok(0);
+        };
+        let generation = job.generation;
+        let bytes_written = job.run()?;
+        self.finish_save(generation);
+        Ok(bytes_written)
+    }
+
+    // The contents are encoded up front so the write itself can happen on
+    // another thread while editing goes on.
+    pub fn prepare_save(&self, config: &Config, backup: bool) -> Result<Option<SaveJob>> {
+        let Some(file_name) = self.file_name.clone() else {
+            return Ok(None);
+        };
+        let file_name = if config.break_symlinks {
+            file_name
+        } else {
+            Self::resolve_symlink(&file_name)
+        };
+        let mut bytes = Vec::new();
+        self.write_contents(&mut bytes)?;
+        Ok(Some(SaveJob {
+            file_name,
+            bytes,
+            backup: if backup {
+                config.backup
+            } else {
+                BackupPolicy::None
+            },
+            backup_dir: config.backup_dir.clone(),
+            generation: self.generation,
+        }))
+    }
+
+    // Returns false when the buffer changed while it was being written, in
+    // which case it stays modified.
+    pub fn finish_save(&mut self, generation: usize) -> bool {
+        self.refresh_disk_mtime();
+        if generation != self.generation {
+            return false;
+        }
+        self.mark_saved();
+        true
+    }
+
+    fn mark_saved(&mut self) {
+        for row in &mut self.rows {
+            row.save();
+        }
+        self.dirty = false;
+        self.refresh_disk_mtime();
+    }
+
+    fn resolve_symlink(file_name: &str) -> String {
+        let path = Path::new(file_name);
+        let is_symlink =
+            fs::symlink_metadata(path).map_or(false, |metadata| metadata.file_type().is_symlink());
+        if !is_symlink {
+            return file_name.to_string();
+        }
+        let target = fs::canonicalize(path).or_else(|_| {
+            fs::read_link(path).map(|target| match path.parent() {
+                Some(parent) => parent.join(target),
+                None => target,
+            })
+        });
+        match target {
+            Ok(target) => target.to_string_lossy().to_string(),
+            Err(_) => file_name.to_string(),
+        }
+    }
+
+    pub fn save_privileged(&mut self) -> Result<usize> {
+        let file_name = self
+            .file_name
+            .clone()
+            .ok_or_else(|| anyhow!("Buffer has no file name"))?;
+        let mut child = Command::new("sudo")
+            .arg("tee")
+            .arg(&file_name)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .context("Could not run sudo")?;
+        let bytes_written = match child.stdin.take() {
+            Some(mut stdin) => self.write_contents(&mut stdin)?,
+            None => 0,
+        };
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("sudo tee {} failed: {}", file_name, status);
+        }
+        self.mark_saved();
+        Ok(bytes_written)
+    }
+
+    fn write_contents<W: Write>(&self, writer: &mut W) -> Result<usize> {
+        if self.encryption.is_none() && self.encoding == Encoding::Utf8 {
+            return self.write_rows(writer);
+        }
+        let mut text = Vec::new();
+        self.write_rows(&mut text)?;
+        let mut bytes = self.encoding.encode(std::str::from_utf8(&text)?)?;
+        if let Some(encryption) = &self.encryption {
+            bytes = encryption.encrypt(&bytes)?;
+        }
+        writer.write_all(&bytes)?;
+        Ok(bytes.len())
+    }
+
+    fn write_rows<W: Write>(&self, writer: &mut W) -> Result<usize> {
         let mut bytes_written = 0;
-        if let Some(file_name) = &self.file_name {
-            let file_exists = Path::new(file_name).exists();
-            let mut new_file_name = file_name.clone();
-            if file_exists {
-                new_file_name.push_str(".new")
-            }
-            let mut file = fs::File::create(&new_file_name)?;
-            self.file_type = FileType::from(file_name);
-            for row in &mut self.rows {
-                let row_bytes = row.as_bytes();
-                file.write_all(row_bytes)?;
-                file.write_all(b"\n")?;
-                bytes_written += row_bytes.len() + 1;
-                row.save();
-            }
-            if file_exists {
-                let mut old_file_name = file_name.clone();
-                old_file_name.push_str(".old");
-                fs::rename(file_name, &old_file_name)?;
-                fs::rename(&new_file_name, file_name)?;
-                fs::remove_file(&old_file_name)?;
-            }
-            self.dirty = false;
+        let line_ending = self.line_ending.as_bytes();
+        let last = self.rows.len().saturating_sub(1);
+        for (y, row) in self.rows.iter().enumerate() {
+            let row_bytes = row.as_bytes();
+            writer.write_all(row_bytes)?;
+            bytes_written += row_bytes.len();
+            if y < last || !self.no_eol {
+                writer.write_all(line_ending)?;
+                bytes_written += line_ending.len();
+            }
         }
         Ok(bytes_written)
     }
@@ -192,7 +1245,12 @@ impl Document {
     }
 
     #[allow(clippy::indexing_slicing)]
-    pub fn find(&self, query: &str, at: &Position, direction: SearchDirection) -> Option<Position> {
+    pub fn find(
+        &self,
+        query: &SearchPattern,
+        at: &Position,
+        direction: SearchDirection,
+    ) -> Option<Position> {
         if at.y >= self.rows.len() {
             return None;
         }
@@ -220,24 +1278,391 @@ impl Document {
         None
     }
 
-    pub fn highlight(&mut self, word: &Option<String>, until: Option<usize>) {
-        let mut start_with_comment = false;
-        let until = if let Some(until) = until {
-            if until.saturating_add(1) < self.rows.len() {
-                until.saturating_add(1)
-            } else {
-                self.rows.len()
+    pub fn highlight_range(&mut self, y: usize, start: usize, end: usize) {
+        if let Some(row) = self.rows.get_mut(y) {
+            row.highlight_range(start, end, highlighting::Type::CurrentMatch);
+        }
+    }
+
+    pub fn match_at(&self, pattern: &SearchPattern, at: &Position) -> Option<(usize, usize)> {
+        self.rows.get(at.y)?.match_at(pattern, at.x)
+    }
+
+    pub fn match_positions(&self, pattern: &SearchPattern) -> Vec<Position> {
+        self.rows
+            .iter()
+            .enumerate()
+            .flat_map(|(y, row)| {
+                row.match_ranges(pattern)
+                    .into_iter()
+                    .map(move |(x, _)| Position { x, y })
+            })
+            .collect()
+    }
+
+    pub fn match_count(&self, pattern: &SearchPattern, at: &Position) -> (usize, usize) {
+        let mut current: usize = 0;
+        let mut total: usize = 0;
+        for (y, row) in self.rows.iter().enumerate() {
+            for (start, _) in row.match_ranges(pattern) {
+                total = total.saturating_add(1);
+                if (y, start) <= (at.y, at.x) {
+                    current = total;
+                }
+            }
+        }
+        (current, total)
+    }
+
+    pub fn clear_highlighting(&mut self) {
+        self.unhighlight_all();
+    }
+
+    pub fn check_pairs(&mut self) -> Option<(Position, String)> {
+        self.unhighlight_all();
+        self.highlight(&None, None);
+        let mut open: Vec<(char, Position)> = Vec::new();
+        let mut literal: Option<(Position, &str)> = None;
+        let mut state = highlighting::State::Normal;
+        for (y, row) in self.rows.iter().enumerate() {
+            for (x, c) in row.code_chars() {
+                match c {
+                    '(' | '[' | '{' => open.push((c, Position { x, y })),
+                    ')' | ']' | '}' => {
+                        let expected = match c {
+                            ')' => '(',
+                            ']' => '[',
+                            _ => '{',
+                        };
+                        match open.pop() {
+                            Some((opening, _)) if opening == expected => (),
+                            Some((opening, position)) => {
+                                return Some((
+                                    Position { x, y },
+                                    format!(
+                                        "'{}' does not match '{}' on line {}",
+                                        c,
+                                        opening,
+                                        position.y.saturating_add(1)
+                                    ),
+                                ));
+                            }
+                            None => return Some((Position { x, y }, format!("Unmatched '{}'", c))),
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            if let Some(x) = row.unterminated_string() {
+                return Some((Position { x, y }, "Unterminated string".to_string()));
+            }
+            let end_state = row.end_state().inner();
+            if state == highlighting::State::Normal && *end_state != highlighting::State::Normal {
+                let x = row
+                    .code_chars()
+                    .last()
+                    .map_or(0, |(x, _)| x.saturating_add(1));
+                let kind = if *end_state == highlighting::State::MultilineComment {
+                    "comment"
+                } else {
+                    "string"
+                };
+                literal = Some((Position { x, y }, kind));
+            }
+            state = end_state.clone();
+        }
+        if state != highlighting::State::Normal {
+            if let Some((position, kind)) = literal {
+                return Some((position, format!("Unterminated {}", kind)));
             }
+        }
+        open.first()
+            .map(|(c, position)| (*position, format!("Unclosed '{}'", c)))
+    }
+
+    pub fn highlight(&mut self, word: &Option<SearchPattern>, until: Option<usize>) {
+        let until = if let Some(until) = until {
+            cmp::min(until.saturating_add(1), self.rows.len())
         } else {
             self.rows.len()
         };
         #[allow(clippy::indexing_slicing)]
-        for row in &mut self.rows[..until] {
-            start_with_comment = row.highlight(
-                self.file_type.highlighting_options(),
+        highlight_rows(
+            &mut self.rows[..until],
+            &self.file_type,
+            word,
+            highlighting::State::Normal,
+        );
+        self.exact_rows = cmp::max(self.exact_rows, until);
+    }
+
+    // Only the rows from start to until are highlighted here. They carry on
+    // from the state the row above ended in, which is a guess until the rows
+    // above the window have been highlighted; false is returned in that case.
+    pub fn highlight_window(
+        &mut self,
+        word: &Option<SearchPattern>,
+        start: usize,
+        until: usize,
+    ) -> bool {
+        let until = cmp::min(until.saturating_add(1), self.rows.len());
+        let start = cmp::min(start, until);
+        let state = start
+            .checked_sub(1)
+            .and_then(|y| self.rows.get(y))
+            .filter(|row| row.is_highlighted)
+            .map_or(highlighting::State::Normal, |row| row.end_state().clone());
+        #[allow(clippy::indexing_slicing)]
+        highlight_rows(&mut self.rows[start..until], &self.file_type, word, state);
+        if start > self.exact_rows {
+            return false;
+        }
+        self.exact_rows = cmp::max(self.exact_rows, until);
+        true
+    }
+
+    pub fn highlight_job(&self, until: usize) -> Option<HighlightJob> {
+        let until = cmp::min(until, self.rows.len());
+        let start = self.exact_rows;
+        let rows = self
+            .rows
+            .get(start..until)
+            .filter(|rows| !rows.is_empty())?;
+        Some(HighlightJob {
+            rows: rows.iter().map(Row::text_copy).collect(),
+            start,
+            state: start
+                .checked_sub(1)
+                .and_then(|y| self.rows.get(y))
+                .map_or(highlighting::State::Normal, |row| row.end_state().clone()),
+            file_type: self.file_type.clone(),
+            generation: self.highlight_generation,
+        })
+    }
+
+    pub fn apply_highlighting(&mut self, job: HighlightJob) {
+        if job.generation != self.highlight_generation || job.start > self.exact_rows {
+            return;
+        }
+        let until = job.start.saturating_add(job.rows.len());
+        for (row, highlighted) in self.rows.iter_mut().skip(job.start).zip(job.rows) {
+            row.take_highlighting(highlighted);
+        }
+        self.exact_rows = cmp::max(self.exact_rows, until);
+    }
+}
+
+pub struct HighlightJob {
+    rows: Vec<Row>,
+    start: usize,
+    state: highlighting::State,
+    file_type: FileType,
+    generation: usize,
+}
+
+impl HighlightJob {
+    pub fn run(mut self) -> Self {
+        highlight_rows(&mut self.rows, &self.file_type, &None, self.state.clone());
+        self
+    }
+}
+
+pub struct SaveJob {
+    file_name: String,
+    bytes: Vec<u8>,
+    backup: BackupPolicy,
+    backup_dir: Option<PathBuf>,
+    pub generation: usize,
+}
+
+impl SaveJob {
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn run(self) -> Result<usize> {
+        let file_exists = Path::new(&self.file_name).exists();
+        if file_exists {
+            self.write_backup()
+                .with_context(|| format!("Could not back up {}", self.file_name))?;
+        }
+        let temp_file_name = temp_file_name(&self.file_name);
+        match self.write_replacement(&temp_file_name, file_exists) {
+            Ok(()) => Ok(self.bytes.len()),
+            Err(error) => {
+                let _ = fs::remove_file(&temp_file_name);
+                Err(error)
+            }
+        }
+    }
+
+    fn write_replacement(&self, temp_file_name: &Path, file_exists: bool) -> Result<()> {
+        let file_name = &self.file_name;
+        let mut file = fs::File::create(temp_file_name)
+            .with_context(|| format!("Could not create {}", temp_file_name.display()))?;
+        if file_exists {
+            let permissions = fs::metadata(file_name)
+                .with_context(|| format!("Could not read permissions of {}", file_name))?
+                .permissions();
+            fs::set_permissions(temp_file_name, permissions).with_context(|| {
+                format!("Could not set permissions of {}", temp_file_name.display())
+            })?;
+        }
+        file.write_all(&self.bytes)
+            .with_context(|| format!("Could not write {}", temp_file_name.display()))?;
+        file.sync_all()
+            .with_context(|| format!("Could not write {}", temp_file_name.display()))?;
+        fs::rename(temp_file_name, file_name)
+            .with_context(|| format!("Could not replace {}", file_name))?;
+        Ok(())
+    }
+
+    fn write_backup(&self) -> Result<()> {
+        if self.backup == BackupPolicy::None {
+            return Ok(());
+        }
+        let path = Path::new(&self.file_name);
+        let base_name = path
+            .file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().to_string());
+        let dir = match &self.backup_dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                dir.clone()
+            }
+            None => path.parent().map_or_else(PathBuf::new, Path::to_path_buf),
+        };
+        let backup_name = if self.backup == BackupPolicy::Numbered {
+            let prefix = format!("{}.~", base_name);
+            let latest = fs::read_dir(if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                &dir
+            })?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.strip_prefix(&prefix)?
+                    .strip_suffix('~')?
+                    .parse::<usize>()
+                    .ok()
+            })
+            .max()
+            .unwrap_or(0);
+            format!("{}{}~", prefix, latest.saturating_add(1))
+        } else {
+            format!("{}~", base_name)
+        };
+        fs::copy(path, dir.join(backup_name))?;
+        Ok(())
+    }
+}
+
+fn next_generation() -> usize {
+    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+fn temp_file_name(file_name: &str) -> PathBuf {
+    let path = Path::new(file_name);
+    let base_name = path
+        .file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().to_string());
+    path.with_file_name(format!(".{}.hecto-tmp", base_name))
+}
+
+pub fn indent_string(columns: usize, tabstop: usize, use_tabs: bool) -> String {
+    if use_tabs {
+        let tabstop = cmp::max(tabstop, 1);
+        #[allow(clippy::integer_arithmetic, clippy::integer_division)]
+        let (tabs, spaces) = (columns / tabstop, columns % tabstop);
+        format!("{}{}", "\t".repeat(tabs), " ".repeat(spaces))
+    } else {
+        " ".repeat(columns)
+    }
+}
+
+fn indent_columns(indent: &str, tabstop: usize) -> usize {
+    let tabstop = cmp::max(tabstop, 1);
+    indent.chars().fold(0, |column: usize, c| {
+        if c == '\t' {
+            column.saturating_add(tabstop.saturating_sub(column % tabstop))
+        } else {
+            column.saturating_add(1)
+        }
+    })
+}
+
+fn split_indent(contents: &str) -> (&str, &str) {
+    let indent_len = contents
+        .find(|c: char| c != ' ' && c != '\t')
+        .unwrap_or(contents.len());
+    contents.split_at(indent_len)
+}
+
+fn reindent(
+    contents: &str,
+    columns: Option<usize>,
+    tabstop: usize,
+    use_tabs: bool,
+) -> Option<String> {
+    let (indent, rest) = split_indent(contents);
+    let columns = columns.unwrap_or_else(|| indent_columns(indent, tabstop));
+    let indent_new = indent_string(columns, tabstop, use_tabs);
+    if indent_new == indent {
+        return None;
+    }
+    Some(format!("{}{}", indent_new, rest))
+}
+
+fn modified_time(file_name: &str) -> Option<SystemTime> {
+    fs::metadata(file_name)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn highlight_rows(
+    rows: &mut [Row],
+    file_type: &FileType,
+    word: &Option<SearchPattern>,
+    mut state: highlighting::State,
+) {
+    let opts = file_type.highlighting_options();
+    let mut embedded: HashMap<String, FileType> = HashMap::new();
+    for row in rows {
+        state = if opts.code_fences() {
+            highlight_fenced(row, opts, word, state, &mut embedded)
+        } else {
+            row.highlight(opts, word, state)
+        };
+    }
+}
+
+fn highlight_fenced(
+    row: &mut Row,
+    opts: &HighlightingOptions,
+    word: &Option<SearchPattern>,
+    state: highlighting::State,
+    embedded: &mut HashMap<String, FileType>,
+) -> highlighting::State {
+    let fence = row.fence_language().map(str::to_string);
+    match (state, fence) {
+        (highlighting::State::Fence(_, _), Some(language)) if language.is_empty() => {
+            row.highlight(opts, word, highlighting::State::Normal)
+        }
+        (highlighting::State::Normal, Some(language)) => {
+            row.highlight(opts, word, highlighting::State::Normal);
+            highlighting::State::Fence(language, Box::new(highlighting::State::Normal))
+        }
+        (highlighting::State::Fence(language, inner), _) => {
+            let file_type = embedded
+                .entry(language.clone())
+                .or_insert_with(|| FileType::from_name(&language).unwrap_or_default());
+            row.highlight(
+                file_type.highlighting_options(),
                 word,
-                start_with_comment,
-            );
+                highlighting::State::Fence(language, inner),
+            )
         }
+        (state, _) => row.highlight(opts, word, state),
     }
 }
@@ -1,23 +1,69 @@
+use crate::bufstats::{self, BufferStats};
+use crate::config::{BufferOrder, OPTION_NAMES};
+use crate::crypt::{self, Cipher};
+use crate::diff;
+use crate::excommand::{self, Address, Builtin, ExCommand, LineRange, LineSpec};
+use crate::grammar::{self, Action, InsertAction, KeyState, Motion, NormalCommand, Operator, Step};
+use crate::health;
+use crate::history::History;
+use crate::journal::{self, Journal, Splice};
+use crate::keymap::{self, Lookup};
+use crate::man;
+use crate::output::{OutputPane, TextStyle};
+use crate::picker::{self, Candidate, Picker};
+use crate::preview::Preview;
+use crate::privacy;
+use crate::profile::{self, Phase, Profiler};
+use crate::quickfix::{self, QuickfixEntry, QuickfixList};
+use crate::replace::{self, FileReplacement};
+use crate::secrets;
+use crate::selection::{self, Selection};
+use crate::stat;
+use crate::tasks::{CancelToken, TaskResult, Tasks};
+use crate::terminal::TerminalEvent;
+use crate::theme::Theme;
+use crate::trash;
+use crate::undofile::{self, UndoRecord};
+use crate::CaseChange;
+use crate::Config;
 use crate::Document;
+use crate::Encoding;
+use crate::FileType;
+use crate::LineEnding;
+use crate::ProgressStyle;
 use crate::Row;
+use crate::SearchPattern;
 use crate::Terminal;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono;
 use chrono::DateTime;
 use chrono::Local;
+use regex::Regex;
+use std::cmp;
+use std::collections::VecDeque;
 use std::env;
-use std::path::Path;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::thread;
-use std::time::{self, Duration, Instant};
-use termion::color;
-use termion::event::Event::{Key as KeyEvent, Mouse};
-use termion::event::{Key, MouseButton, MouseEvent};
+use std::time::{Duration, Instant};
+use termion::event::Event::{Key as KeyEvent, Mouse, Unsupported};
+use termion::event::{Event, Key, MouseButton, MouseEvent};
 use unicode_segmentation::UnicodeSegmentation;
 
-const STATUS_FG_COLOR: color::Rgb = color::Rgb(63, 63, 63);
-const STATUS_BG_COLOR: color::Rgb = color::Rgb(239, 239, 239);
+const MIN_NUMBER_WIDTH: usize = 3;
+const MAX_COMMAND_DEPTH: usize = 10;
+const DEFAULT_TEXTWIDTH: usize = 79;
+const PROGRESS_BAR_WIDTH: usize = 8;
 const VERSION: &str = env!("CARGO_PKG_VERSION");
-const REFRESH_INTERVAL: u64 = 1000 / 120;
+const FRAME_INTERVAL: Duration = Duration::from_millis(1000 / 60);
+const MESSAGE_DURATION: Duration = Duration::from_secs(5);
+const MAX_MESSAGES: usize = 200;
+const WHEEL_LINES: usize = 3;
+const FILTER_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 #[derive(PartialEq, Copy, Clone)]
 pub enum SearchDirection {
@@ -25,6 +71,13 @@ pub enum SearchDirection {
     Backward,
 }
 
+#[derive(Copy, Clone)]
+enum ViewAnchor {
+    Top,
+    Center,
+    Bottom,
+}
+
 #[derive(PartialEq, Copy, Clone)]
 enum Mode {
     Insert,
@@ -40,7 +93,7 @@ struct CursorPosition {
     max_x: usize,
 }
 
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Copy, Clone, PartialEq)]
 pub struct Position {
     pub x: usize,
     pub y: usize,
@@ -51,7 +104,7 @@ impl From<Position> for CursorPosition {
         Self {
             x: item.x,
             y: item.y,
-            max_x: 0,
+            max_x: item.x,
         }
     }
 }
@@ -70,11 +123,86 @@ struct StatusMessage {
     text: String,
 }
 
+struct Buffer {
+    id: usize,
+    document: Document,
+    versions: Vec<Version>,
+    version_index: usize,
+    saved_version: Option<usize>,
+    cursor_position: CursorPosition,
+    offset: Position,
+    private: bool,
+    journal: Option<Journal>,
+    last_used: usize,
+}
+
+impl Buffer {
+    fn is_modified(&self) -> bool {
+        self.versions.len() != 1 && self.saved_version != Some(self.version_index)
+    }
+
+    fn name(&self) -> String {
+        self.document
+            .file_name
+            .clone()
+            .unwrap_or_else(|| "[No Name]".to_string())
+    }
+}
+
+#[derive(Clone)]
+struct Clipboard {
+    text: String,
+    linewise: bool,
+}
+
+enum Span {
+    Chars(Position, Position),
+    Lines(usize, usize),
+}
+
+// What . repeats: the command as parsed, the keys it read for itself (such as
+// the ex command after :), and what was typed in the insert session it began.
+#[derive(Clone)]
+struct Change {
+    command: NormalCommand,
+    keys: Vec<Key>,
+    inserted: Vec<InsertAction>,
+}
+
+// The lines are what the write puts on disk, which the journal has to start
+// from if the buffer changes before it finishes.
+struct PendingSave {
+    task: usize,
+    buffer_id: usize,
+    generation: usize,
+    lines: Vec<String>,
+}
+
+struct Filter {
+    task: usize,
+    buffer_id: usize,
+    generation: usize,
+    start: usize,
+    end: usize,
+    command: String,
+}
+
+struct Rename {
+    points: Vec<Position>,
+    primary: usize,
+    word: String,
+    typed: String,
+    text: String,
+    row_len: usize,
+}
+
 #[derive(Default, Clone)]
 struct Version {
     document: Document,
     position: CursorPosition,
     timestamp: DateTime<Local>,
+    parent: Option<usize>,
+    redo_child: Option<usize>,
 }
 
 impl StatusMessage {
@@ -94,112 +222,762 @@ pub struct Editor {
     offset: Position,
     document: Document,
     status_message: StatusMessage,
-    highlighted_word: Option<String>,
-    clipboard: Option<String>,
+    highlighted_word: Option<SearchPattern>,
+    clipboard: Option<Clipboard>,
+    register: Option<Selection>,
     mode: Mode,
     versions: Vec<Version>,
     version_index: usize,
-    has_saved: bool,
+    saved_version: Option<usize>,
     query: Option<String>,
+    pending_keys: VecDeque<Key>,
+    replay_keys: VecDeque<Key>,
+    change: Option<Change>,
+    change_made: bool,
+    last_change: Option<Change>,
+    config: Config,
+    theme: Theme,
+    profiler: Option<Profiler>,
+    command_depth: usize,
+    focused: bool,
+    substitute_match: Option<(usize, usize, usize)>,
+    search_count: Option<((Position, usize, usize), usize, usize)>,
+    search_history: History,
+    private: bool,
+    buffers: Vec<Buffer>,
+    buffer_id: usize,
+    next_buffer_id: usize,
+    buffer_tick: usize,
+    visual_count: usize,
+    key_state: KeyState,
+    output: Option<OutputPane>,
+    preview: Option<Preview>,
+    messages: Vec<String>,
+    logged_message: Option<Instant>,
+    tasks: Tasks,
+    stats_task: Option<usize>,
+    // Task, buffer and document generation of a write in progress.
+    save_task: Option<PendingSave>,
+    highlight_task: Option<(usize, usize)>,
+    filter: Option<Filter>,
+    buffer_stats: Option<BufferStats>,
+    snapshot_pending: bool,
+    highlight_pending: bool,
+    offset_pinned: bool,
+    quickfix: QuickfixList,
+    pending_replace: Vec<FileReplacement>,
+    timeline: Vec<usize>,
+    rename: Option<Rename>,
+    journal: Option<Journal>,
 }
 
 impl Editor {
     pub fn run(&mut self) -> Result<()> {
+        let mut redraw = true;
+        let mut last_frame: Option<Instant> = None;
+        let mut clock = 0;
         loop {
-            self.refresh_screen()?;
+            let since_frame = last_frame.map_or(FRAME_INTERVAL, |frame| frame.elapsed());
+            if redraw && (since_frame >= FRAME_INTERVAL || self.should_quit) {
+                self.refresh_screen()?;
+                last_frame = Some(Instant::now());
+                clock = chrono::offset::Local::now().timestamp();
+                redraw = false;
+            }
             if self.should_quit {
                 break;
             }
-            if let Some(res) = self.term_read_event() {
-                res?;
+            if self.highlight_pending {
+                self.highlight_pending = false;
+                redraw = true;
                 continue;
             }
-            thread::sleep(time::Duration::from_millis(REFRESH_INTERVAL));
+            // While a frame is owed, only wait out the rest of the frame so that
+            // bursts of input are coalesced into one redraw.
+            let timeout = if redraw {
+                FRAME_INTERVAL.saturating_sub(since_frame)
+            } else {
+                self.idle_timeout()
+            };
+            match self.terminal.read_event(Some(timeout)) {
+                Some(TerminalEvent::Input(event)) => {
+                    let input_started = Instant::now();
+                    self.handle_event(event)?;
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.record(Phase::Input, input_started.elapsed());
+                    }
+                    self.log_message();
+                    self.write_journal();
+                    redraw = true;
+                }
+                Some(TerminalEvent::Resize) => redraw = true,
+                Some(TerminalEvent::Wake) => redraw |= self.poll_tasks(),
+                None => {
+                    if self.journal.as_ref().map_or(false, Journal::is_due) {
+                        self.flush_journal();
+                    }
+                    let expiry = self.message_expiry();
+                    redraw |= chrono::offset::Local::now().timestamp() != clock
+                        || last_frame
+                            .map_or(false, |frame| frame < expiry && expiry <= Instant::now());
+                }
+            }
         }
         Ok(())
     }
 
+    fn idle_timeout(&self) -> Duration {
+        // Nothing but the status bar clock and message expiry can change the
+        // screen without an event, so sleep until whichever comes first.
+        let millis = u64::from(chrono::offset::Local::now().timestamp_subsec_millis());
+        let timeout = Duration::from_millis(1000_u64.saturating_sub(millis));
+        self.message_expiry()
+            .checked_duration_since(Instant::now())
+            .map_or(timeout, |remaining| cmp::min(timeout, remaining))
+    }
+
+    fn message_expiry(&self) -> Instant {
+        self.status_message.time + MESSAGE_DURATION
+    }
+
+    fn poll_tasks(&mut self) -> bool {
+        let results = self.tasks.poll();
+        let redraw = !results.is_empty();
+        for (id, result) in results {
+            match result {
+                TaskResult::BufferStats(stats) => {
+                    if self.stats_task == Some(id) {
+                        self.stats_task = None;
+                        self.apply_buffer_stats(stats);
+                    }
+                }
+                TaskResult::Grep {
+                    pattern,
+                    entries,
+                    buffer_id,
+                } => self.show_grep_results(&pattern, entries, buffer_id),
+                TaskResult::Save(result) => self.finish_background_save(id, result),
+                TaskResult::Filter(output) => self.finish_filter(id, output),
+                TaskResult::Highlight(job) => {
+                    if self.highlight_task == Some((id, self.buffer_id)) {
+                        self.document.apply_highlighting(job);
+                    }
+                }
+            }
+        }
+        redraw
+    }
+
+    fn spawn_buffer_stats(&mut self) {
+        self.buffer_stats = None;
+        let lines = self.document.lines();
+        self.stats_task = Some(self.tasks.spawn("buffer stats", move |_| {
+            Some(TaskResult::BufferStats(bufstats::compute(&lines)))
+        }));
+    }
+
+    // Rows above the screen are highlighted on the pool so that jumping deep
+    // into a large file does not wait for everything before it.
+    fn spawn_highlighting(&mut self) {
+        if let Some((id, _)) = self.highlight_task {
+            if self.tasks.is_running(id) {
+                return;
+            }
+        }
+        let Some(job) = self.document.highlight_job(self.offset.y) else {
+            return;
+        };
+        let id = self
+            .tasks
+            .spawn("highlight", move |_| Some(TaskResult::Highlight(job.run())));
+        self.highlight_task = Some((id, self.buffer_id));
+    }
+
+    fn apply_buffer_stats(&mut self, stats: BufferStats) {
+        let warnings = stats.warnings();
+        if !warnings.is_empty() {
+            self.status_message = StatusMessage::from(format!("WARNING: {}", warnings.join("; ")));
+            self.log_message();
+        }
+        self.buffer_stats = Some(stats);
+    }
+
+    fn check_pairs(&mut self) {
+        match self.document.check_pairs() {
+            Some((position, problem)) => {
+                self.record_jump();
+                self.cursor_position = position.into();
+                self.readjust_cursor();
+                self.scroll();
+                self.status_message = StatusMessage::from(format!(
+                    "ERR: {} on line {}",
+                    problem,
+                    position.y.saturating_add(1)
+                ));
+            }
+            None => {
+                self.status_message = StatusMessage::from("All pairs are balanced".to_string());
+            }
+        }
+    }
+
+    fn fix_indentation(&mut self) {
+        let mut fixed: usize = 0;
+        let (tabstop, use_tabs) = (self.config.tabstop, !self.config.expand_tab);
+        if !self.document.is_indentation_normalized(tabstop, use_tabs) {
+            self.doc_edit(|editor| {
+                fixed = editor.document.normalize_indentation(tabstop, use_tabs);
+            });
+            self.readjust_cursor();
+        }
+        self.spawn_buffer_stats();
+        self.status_message = StatusMessage::from(format!(
+            "Fixed indentation on {} line{}",
+            fixed,
+            if fixed == 1 { "" } else { "s" }
+        ));
+    }
+
+    fn show_tasks(&mut self) {
+        let tasks = self.tasks.status();
+        if tasks.is_empty() {
+            self.status_message = StatusMessage::from("No running tasks".to_string());
+            return;
+        }
+        let lines = tasks
+            .iter()
+            .map(|task| {
+                let state = match task.running {
+                    _ if task.cancelled => "cancelling".to_string(),
+                    Some(elapsed) => format!("running {:.1}s", elapsed.as_secs_f64()),
+                    None => format!("queued {:.1}s", task.waiting.as_secs_f64()),
+                };
+                format!("{:>4}  {:<16} {}", task.id, state, task.name)
+            })
+            .collect();
+        self.show_output("tasks", lines);
+    }
+
+    fn show_clipboard(&mut self) {
+        let mut lines: Vec<String> = [Selection::Clipboard, Selection::Primary]
+            .iter()
+            .map(|selection| {
+                format!(
+                    "{:<18} copy: {:<13} paste: {}",
+                    selection.name(),
+                    selection::active(*selection, false),
+                    selection::active(*selection, true)
+                )
+            })
+            .collect();
+        lines.push(String::new());
+        lines.extend(selection::providers().iter().map(|provider| {
+            format!(
+                "{:<13} {}",
+                provider.name(),
+                if provider.is_available() {
+                    "available"
+                } else {
+                    "unavailable"
+                }
+            )
+        }));
+        self.show_output("clipboard", lines);
+    }
+
+    fn cancel_tasks(&mut self, id: Option<&str>) {
+        self.status_message = match id {
+            None => StatusMessage::from(format!("Cancelled {} tasks", self.tasks.cancel_all())),
+            Some(id) => match id.parse() {
+                Ok(id) => match self.tasks.cancel(id) {
+                    Ok(()) => StatusMessage::from(format!("Cancelled task {}", id)),
+                    Err(error) => StatusMessage::from(format!("ERR: {}", error)),
+                },
+                Err(_) => StatusMessage::from(format!("ERR: Invalid task id: {}", id)),
+            },
+        };
+    }
+
+    fn show_buffer_stats(&mut self) {
+        self.status_message = match &self.buffer_stats {
+            Some(stats) => {
+                let mut message = stats.summary();
+                for warning in stats.warnings() {
+                    message.push_str("; ");
+                    message.push_str(&warning);
+                }
+                StatusMessage::from(message)
+            }
+            None => StatusMessage::from("Statistics are not available yet".to_string()),
+        };
+    }
+
+    fn log_message(&mut self) {
+        let message = &self.status_message;
+        if message.text.is_empty() || self.logged_message == Some(message.time) {
+            return;
+        }
+        self.logged_message = Some(message.time);
+        self.messages.push(message.text.clone());
+        if self.messages.len() > MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+    }
+
+    fn focus_changed(&mut self, focused: bool) {
+        self.focused = focused;
+        if focused {
+            self.check_disk_changes();
+        } else if self.config.autosave
+            && self.contains_changes()
+            && self.document.file_name.is_some()
+        {
+            self.save(false);
+        }
+    }
+
+    fn check_disk_changes(&mut self) {
+        if !self.document.changed_on_disk() {
+            return;
+        }
+        let file_name = match &self.document.file_name {
+            Some(file_name) => file_name.clone(),
+            None => return,
+        };
+        if self.config.autoread && !self.contains_changes() {
+            let position = self.cursor_position;
+            self.open_target(&file_name);
+            self.cursor_position = position;
+            self.readjust_cursor();
+            self.status_message =
+                StatusMessage::from(format!("\"{}\" reloaded from disk", file_name));
+        } else {
+            self.document.refresh_disk_mtime();
+            self.status_message = StatusMessage::from(format!(
+                "WARNING: \"{}\" changed on disk (:e! to reload)",
+                file_name
+            ));
+        }
+    }
+
     fn quit(&mut self) {
+        let _ = self.persist_history();
+        self.abandon();
+    }
+
+    // Unsaved changes are thrown away, so their history is not kept either.
+    fn abandon(&mut self) {
+        self.discard_journals();
         self.should_quit = true;
     }
 
     pub fn default() -> Self {
         let args: Vec<String> = env::args().collect();
-        let mut initial_status = String::from(": for commands");
-        let (document, versions) = if let Some(file_name) = args.get(1) {
-            if let Some((doc, versions)) = Self::open_document(&file_name) {
-                (doc, versions)
-            } else {
-                initial_status = format!("ERR: Could not open file: {}", file_name);
-                (Document::default(), vec![Version::default()])
-            }
-        } else {
-            (Document::default(), vec![Version::default()])
-        };
-
-        Self {
+        let (config, mut config_errors) = Config::load();
+        config_errors.extend(FileType::load_definitions());
+        let theme = Theme::load(&config.colorscheme).unwrap_or_default();
+        let mut editor = Self {
             should_quit: false,
             terminal: Terminal::default().expect("Failed to initialize terminal"),
             cursor_position: CursorPosition::default(),
             selection_start: Position::default(),
             offset: Position::default(),
-            document,
-            status_message: StatusMessage::from(initial_status),
+            document: Document::default(),
+            status_message: StatusMessage::from(String::from(": for commands")),
             highlighted_word: None,
             clipboard: None,
+            register: None,
             mode: Mode::Normal,
-            versions,
+            versions: vec![Version::default()],
             version_index: 0,
-            has_saved: false,
+            saved_version: Some(0),
             query: None,
+            pending_keys: VecDeque::new(),
+            replay_keys: VecDeque::new(),
+            change: None,
+            change_made: false,
+            last_change: None,
+            config,
+            theme,
+            profiler: None,
+            command_depth: 0,
+            focused: true,
+            substitute_match: None,
+            search_count: None,
+            search_history: History::load("search"),
+            private: false,
+            buffers: Vec::new(),
+            buffer_id: 1,
+            next_buffer_id: 2,
+            buffer_tick: 0,
+            visual_count: 0,
+            key_state: KeyState::default(),
+            output: None,
+            preview: None,
+            messages: Vec::new(),
+            logged_message: None,
+            tasks: Tasks::default(),
+            stats_task: None,
+            save_task: None,
+            highlight_task: None,
+            filter: None,
+            buffer_stats: None,
+            snapshot_pending: false,
+            highlight_pending: false,
+            offset_pinned: false,
+            quickfix: QuickfixList::default(),
+            pending_replace: Vec::new(),
+            timeline: Vec::new(),
+            rename: None,
+            journal: None,
+        };
+        if let Some(error) = config_errors.first() {
+            editor.status_message = StatusMessage::from(format!("ERR: {}", error));
         }
+        editor.tasks.set_waker(editor.terminal.waker());
+        if let Some(file_name) = args.get(1) {
+            editor.open_target(file_name);
+            editor.highlight_pending = true;
+            editor.scroll();
+        }
+        editor
     }
 
-    fn open_document(file_name: &str) -> Option<(Document, Vec<Version>)> {
+    fn open_document(&mut self, file_name: &str) -> Option<(Document, Vec<Version>, usize)> {
         let doc = Document::open(&file_name);
         if let Ok(doc) = doc {
+            match Self::load_history(&doc) {
+                Ok(Some((versions, index))) => return Some((doc, versions, index)),
+                Ok(None) => (),
+                Err(error) => {
+                    self.status_message =
+                        StatusMessage::from(format!("WARNING! Ignoring undo history: {}", error));
+                }
+            }
             let version = Version {
-                document: doc.clone(),
-                position: CursorPosition::default(),
                 timestamp: chrono::offset::Local::now(),
+                ..Version::default()
             };
-            Some((doc, vec![version]))
+            Some((doc, vec![version], 0))
         } else {
             None
         }
     }
 
+    fn open_encrypted_document(
+        &mut self,
+        file_name: &str,
+        cipher: Cipher,
+    ) -> Result<Option<(Document, Vec<Version>, usize)>> {
+        let decrypted = match cipher {
+            Cipher::Gpg => match crypt::decrypt_gpg(file_name, None) {
+                Ok(decrypted) if !decrypted.1.needs_passphrase() => decrypted,
+                _ => {
+                    let Some(passphrase) = self.prompt_secret("Passphrase: ")? else {
+                        return Ok(None);
+                    };
+                    crypt::decrypt_gpg(file_name, Some(&passphrase))?
+                }
+            },
+            Cipher::Age => {
+                let Some(identity) = self.prompt("Age identity file: ", |_, _, _| {})? else {
+                    return Ok(None);
+                };
+                crypt::decrypt_age(file_name, Path::new(&identity))?
+            }
+        };
+        let (contents, encryption) = decrypted;
+        let doc = Document::open_encrypted(file_name, &contents, encryption);
+        let version = Version {
+            timestamp: chrono::offset::Local::now(),
+            ..Version::default()
+        };
+        Ok(Some((doc, vec![version], 0)))
+    }
+
+    fn load_history(doc: &Document) -> Result<Option<(Vec<Version>, usize)>> {
+        let Some(file_name) = doc.file_name.as_ref() else {
+            return Ok(None);
+        };
+        let Some((current, records)) = undofile::read(file_name)? else {
+            return Ok(None);
+        };
+        let lines = doc.lines();
+        let index = if records.get(current).map(|record| &record.lines) == Some(&lines) {
+            current
+        } else {
+            records
+                .iter()
+                .rposition(|record| record.lines == lines)
+                .ok_or_else(|| anyhow!("{} was changed outside the editor", file_name))?
+        };
+        let versions = records
+            .iter()
+            .map(|record| Version {
+                document: doc.with_lines(&record.lines),
+                position: CursorPosition {
+                    x: record.cursor.0,
+                    y: record.cursor.1,
+                    max_x: record.cursor.2,
+                },
+                timestamp: record.timestamp,
+                parent: record.parent,
+                redo_child: record.redo_child,
+            })
+            .collect();
+        Ok(Some((versions, index)))
+    }
+
+    fn persist_history(&self) -> Result<()> {
+        let file_name = match &self.document.file_name {
+            Some(file_name) if self.versions.len() > 1 && !self.is_private() => file_name,
+            _ => return Ok(()),
+        };
+        let records: Vec<UndoRecord> = self
+            .versions
+            .iter()
+            .map(|version| UndoRecord {
+                parent: version.parent,
+                redo_child: version.redo_child,
+                timestamp: version.timestamp,
+                cursor: (
+                    version.position.x,
+                    version.position.y,
+                    version.position.max_x,
+                ),
+                lines: version.document.lines(),
+            })
+            .collect();
+        undofile::write(file_name, self.version_index, &records)
+    }
+
+    fn is_private(&self) -> bool {
+        self.private || self.document.is_encrypted()
+    }
+
+    fn toggle_private(&mut self) {
+        if self.document.is_encrypted() {
+            self.private = true;
+            self.status_message =
+                StatusMessage::from("ERR: Encrypted buffers are always private".to_string());
+            return;
+        }
+        self.private = !self.private;
+        if self.private {
+            if let Some(journal) = self.journal.take() {
+                journal.discard();
+            }
+            if let Some(file_name) = &self.document.file_name {
+                undofile::remove(file_name);
+            }
+        }
+        self.status_message = StatusMessage::from(format!(
+            "Privacy mode {}",
+            if self.private { "on" } else { "off" }
+        ));
+    }
+
+    fn journaled_file(&self) -> Option<String> {
+        match &self.document.file_name {
+            Some(file_name) if self.config.journal && !self.is_private() => Some(file_name.clone()),
+            _ => None,
+        }
+    }
+
+    fn start_journal(&self) -> Result<Option<Journal>> {
+        match self.journaled_file() {
+            Some(file_name) => {
+                Journal::start(&file_name, &self.document, self.version_index).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn open_journal(&mut self) {
+        self.journal = None;
+        let Some(file_name) = self.journaled_file() else {
+            return;
+        };
+        let mut splices = Vec::new();
+        match journal::read(&file_name) {
+            Ok(Some(recovery)) if !recovery.splices.is_empty() => {
+                if recovery.base == journal::base_checksum(&self.document.lines()) {
+                    splices = recovery.splices;
+                } else {
+                    self.status_message = StatusMessage::from(format!(
+                        "WARNING! Ignoring journal: {} changed since it was written",
+                        file_name
+                    ));
+                }
+            }
+            Ok(_) => (),
+            Err(error) => {
+                self.status_message =
+                    StatusMessage::from(format!("WARNING! Ignoring journal: {}", error));
+            }
+        }
+        match Journal::start(&file_name, &self.document, self.version_index) {
+            Ok(journal) => self.journal = Some(journal),
+            Err(error) => {
+                self.status_message =
+                    StatusMessage::from(format!("ERR: Could not start journal: {}", error));
+            }
+        }
+        if !splices.is_empty() {
+            self.recover_journal(&file_name, &splices);
+        }
+    }
+
+    fn recover_journal(&mut self, file_name: &str, splices: &[Splice]) {
+        let answer = self
+            .prompt(
+                &format!(
+                    "Recover {} unsaved edits to {} from the journal? (y/n) ",
+                    splices.len(),
+                    file_name
+                ),
+                |_, _, _| {},
+            )
+            .unwrap_or(None);
+        if answer.as_deref() != Some("y") {
+            return;
+        }
+        self.doc_edit(|editor| {
+            for splice in splices {
+                editor
+                    .document
+                    .splice_lines(splice.start, splice.removed, &splice.lines);
+            }
+        });
+        self.readjust_cursor();
+        self.status_message = StatusMessage::from(format!(
+            "Recovered {} edits from the journal",
+            splices.len()
+        ));
+        self.write_journal();
+    }
+
+    fn flush_journal(&mut self) {
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+        if let Err(error) = journal.flush(&self.document) {
+            self.journal = None;
+            self.status_message =
+                StatusMessage::from(format!("ERR: Could not write journal: {}", error));
+        }
+    }
+
+    fn write_journal(&mut self) {
+        if self.journaled_file().is_none() {
+            if let Some(journal) = self.journal.take() {
+                journal.discard();
+            }
+            return;
+        }
+        if self.journal.is_none() && !self.contains_changes() {
+            self.journal = self.start_journal().ok().flatten();
+        }
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+        if let Err(error) = journal.record(&self.document, self.version_index) {
+            self.journal = None;
+            self.status_message =
+                StatusMessage::from(format!("ERR: Could not write journal: {}", error));
+        }
+    }
+
+    fn discard_journals(&mut self) {
+        let journals = self
+            .buffers
+            .iter_mut()
+            .filter_map(|buffer| buffer.journal.take());
+        for journal in self.journal.take().into_iter().chain(journals) {
+            journal.discard();
+        }
+    }
+
     fn refresh_screen(&mut self) -> Result<()> {
         self.terminal.update_size()?;
+        Terminal::begin_synchronized_update();
         Terminal::cursor_hide();
-        Terminal::cursor_position(&Position::default(), false);
+        Terminal::cursor_position(&Position::default(), 0);
+        if self.output.is_none() {
+            self.preview = None;
+        }
         if self.should_quit {
             Terminal::clear_screen();
         } else {
-            self.document.highlight(
-                &self.highlighted_word,
-                Some(
-                    self.offset
-                        .y
+            let highlight_started = Instant::now();
+            if !self.highlight_pending {
+                // Closed folds hide rows, so the bottom of the screen is found
+                // through the fold map rather than by counting document rows.
+                let last_visible = self.document.line_at_display(
+                    self.document
+                        .display_line(self.offset.y)
                         .saturating_add(self.terminal.size().height as usize),
-                ),
-            );
+                );
+                let exact = self.document.highlight_window(
+                    &self.highlighted_word,
+                    self.offset.y,
+                    last_visible,
+                );
+                if !exact {
+                    self.spawn_highlighting();
+                }
+            }
+            let current_match = self.substitute_match.or_else(|| {
+                let pattern = self.highlighted_word.as_ref()?;
+                let (start, end) = self
+                    .document
+                    .match_at(pattern, &self.cursor_position.into())?;
+                Some((self.cursor_position.y, start, end))
+            });
+            if let Some((y, start, end)) = current_match {
+                self.document.highlight_range(y, start, end);
+            }
+            let render_started = Instant::now();
+            if let Some(profiler) = &mut self.profiler {
+                profiler.frame();
+                profiler.record(
+                    Phase::Highlight,
+                    render_started.duration_since(highlight_started),
+                );
+            }
+            self.update_search_count();
             self.draw_rows();
             self.draw_status_bar();
             self.draw_message_bar();
+            self.terminal
+                .present(!self.focused && self.config.dim_unfocused);
+            let x = if self.document.closed_fold(self.cursor_position.y).is_some() {
+                0
+            } else {
+                self.cursor_column().saturating_sub(self.offset.x)
+            };
             Terminal::cursor_position(
                 &Position {
-                    x: self.cursor_position.x.saturating_sub(self.offset.x),
-                    y: self.cursor_position.y.saturating_sub(self.offset.y),
+                    x,
+                    y: self.screen_line(self.cursor_position.y),
+                },
+                if self.document.row(self.cursor_position.y).is_some() {
+                    self.gutter_width()
+                } else {
+                    0
                 },
-                !self.document.row(self.cursor_position.y).is_none(),
             );
+            if let Some(profiler) = &mut self.profiler {
+                profiler.record(Phase::Render, render_started.elapsed());
+            }
         }
-        if self.cursor_position.y >= self.offset.y
-            && self.cursor_position.y.abs_diff(self.offset.y) < self.terminal.size().height.into()
+        if self.output.is_none()
+            && self.cursor_position.y >= self.offset.y
+            && self.screen_line(self.cursor_position.y) < self.terminal.size().height.into()
         {
             Terminal::cursor_show();
         }
+        Terminal::end_synchronized_update();
         self.terminal.flush()
     }
 
@@ -234,6 +1012,21 @@ impl Editor {
         format!("{} {} {}", lines_added.abs(), magnitude, add_type)
     }
 
+    fn version_time_message(timestamp: &DateTime<Local>) -> String {
+        let diff = chrono::offset::Local::now() - timestamp;
+        if diff.num_minutes() < 10 {
+            Self::get_time_message(diff)
+        } else {
+            timestamp.format("%H:%M:%S").to_string()
+        }
+    }
+
+    fn version_lines_message(document: &Document, old_len: usize, old_changes: usize) -> String {
+        let lines_added = document.len() as i64 - old_len as i64;
+        let lines_changed = (document.lines_changed() as i64 - old_changes as i64).abs();
+        Self::get_lines_message(lines_added, lines_changed)
+    }
+
     fn version_status_message(
         &self,
         old_len: usize,
@@ -241,89 +1034,253 @@ impl Editor {
         index: usize,
         timestamp: &DateTime<Local>,
     ) -> String {
-        let current_time = chrono::offset::Local::now();
-        let diff = current_time - timestamp;
-        let time_msg = if diff.num_minutes() < 10 {
-            Self::get_time_message(diff)
-        } else {
-            timestamp.format("%H:%M:%S").to_string()
-        };
-        let lines_added = self.document.len() as i64 - old_len as i64;
-        let lines_changed = (self.document.lines_changed() as i64 - old_changes as i64).abs();
-        let lines_msg = Self::get_lines_message(lines_added, lines_changed);
-        format!("{}; before #{}  {}", lines_msg, index, time_msg)
+        format!(
+            "{}; before #{}  {}",
+            Self::version_lines_message(&self.document, old_len, old_changes),
+            index,
+            Self::version_time_message(timestamp)
+        )
     }
 
     fn undo(&mut self) -> Result<()> {
-        if self.version_index == 0 {
-            self.status_message = StatusMessage::from("Already at oldest change".to_string());
-            return Ok(());
+        match self.versions[self.version_index].parent {
+            Some(parent) => self.restore_version(parent, parent, self.version_index),
+            None => {
+                self.status_message = StatusMessage::from("Already at oldest change".to_string());
+                Ok(())
+            }
         }
-        let prev_doc = &self.versions[self.version_index].document;
-        let prev_len = prev_doc.len();
-        let prev_changes = prev_doc.lines_changed();
-        self.version_index = self.version_index.saturating_sub(1);
-        let version = &self.versions[self.version_index];
-        self.document = version.document.clone();
-        self.cursor_position = version.position;
-        let msg = self.version_status_message(
-            prev_len,
-            prev_changes,
-            self.version_index.saturating_add(1),
-            &version.timestamp,
-        );
-        self.status_message = StatusMessage::from(msg);
-        self.readjust_cursor();
-        self.refresh_screen()?;
-        Ok(())
     }
 
     fn redo(&mut self) -> Result<()> {
-        if self.version_index == self.versions.len() - 1 {
-            self.status_message = StatusMessage::from("Already at newest change".to_string());
+        match self.versions[self.version_index].redo_child {
+            Some(child) => self.restore_version(child, self.version_index, child),
+            None => {
+                self.status_message = StatusMessage::from("Already at newest change".to_string());
+                Ok(())
+            }
+        }
+    }
+
+    fn step_chronologically(&mut self, direction: SearchDirection) -> Result<()> {
+        let target = match direction {
+            SearchDirection::Backward => self.version_index.saturating_sub(1),
+            SearchDirection::Forward => cmp::min(
+                self.version_index.saturating_add(1),
+                self.versions.len() - 1,
+            ),
+        };
+        self.jump_to_version(target, direction)
+    }
+
+    fn travel_in_time(&mut self, commands: &Vec<&str>, direction: SearchDirection) -> Result<()> {
+        let amount = commands.get(1).copied().unwrap_or("1");
+        let target = match parse_time_offset(amount) {
+            Some(TimeOffset::Steps(steps)) => match direction {
+                SearchDirection::Backward => self.version_index.saturating_sub(steps),
+                SearchDirection::Forward => cmp::min(
+                    self.version_index.saturating_add(steps),
+                    self.versions.len() - 1,
+                ),
+            },
+            Some(TimeOffset::Duration(duration)) => {
+                let reference = self.versions[self.version_index].timestamp;
+                let time = match direction {
+                    SearchDirection::Backward => reference.checked_sub_signed(duration),
+                    SearchDirection::Forward => reference.checked_add_signed(duration),
+                };
+                let Some(time) = time else {
+                    self.status_message =
+                        StatusMessage::from(format!("ERR: Time offset out of range: {}", amount));
+                    return Ok(());
+                };
+                match direction {
+                    SearchDirection::Backward => self.versions[..self.version_index]
+                        .iter()
+                        .rposition(|version| version.timestamp <= time)
+                        .unwrap_or(0),
+                    SearchDirection::Forward => self
+                        .versions
+                        .iter()
+                        .enumerate()
+                        .skip(self.version_index.saturating_add(1))
+                        .find(|(_, version)| version.timestamp >= time)
+                        .map_or(self.versions.len() - 1, |(index, _)| index),
+                }
+            }
+            None => {
+                self.status_message =
+                    StatusMessage::from(format!("Invalid time offset: {}", amount));
+                return Ok(());
+            }
+        };
+        self.jump_to_version(target, direction)
+    }
+
+    fn jump_to_version(&mut self, target: usize, direction: SearchDirection) -> Result<()> {
+        if target == self.version_index {
+            self.status_message = StatusMessage::from(format!(
+                "Already at {} change",
+                if direction == SearchDirection::Forward {
+                    "newest"
+                } else {
+                    "oldest"
+                }
+            ));
             return Ok(());
         }
+        let anchor = cmp::min(target, self.version_index);
+        let label = cmp::max(target, self.version_index);
+        self.restore_version(target, anchor, label)
+    }
+
+    fn restore_version(&mut self, target: usize, anchor: usize, label: usize) -> Result<()> {
+        let prev_doc = &self.versions[self.version_index].document;
+        let prev_len = prev_doc.len();
+        let prev_changes = prev_doc.lines_changed();
+        if let Some(parent) = self.versions[target].parent {
+            self.versions[parent].redo_child = Some(target);
+        }
+        self.version_index = target;
+        let mut document = self.versions[target].document.clone();
+        document.inherit_state(&self.document);
+        self.document = document;
         let Version {
-            document: prev_doc,
             position,
             timestamp,
-        } = &self.versions[self.version_index];
-        let prev_len = prev_doc.len();
-        let prev_changes = prev_doc.lines_changed();
-        self.version_index = self.version_index.saturating_add(1);
-        let version = &self.versions[self.version_index];
-        self.document = version.document.clone();
-        self.cursor_position = *position;
-        let msg =
-            self.version_status_message(prev_len, prev_changes, self.version_index, &timestamp);
+            ..
+        } = &self.versions[anchor];
+        let position = *position;
+        let msg = self.version_status_message(prev_len, prev_changes, label, timestamp);
         self.status_message = StatusMessage::from(msg);
-        self.readjust_cursor();
+        self.restore_cursor(position);
         self.refresh_screen()?;
         Ok(())
     }
 
-    fn add_version(&mut self) {
-        if self.version_index != self.versions.len() - 1 {
-            self.versions.drain((self.version_index + 1)..);
+    fn undo_list(&mut self) {
+        let leaves: Vec<String> = self
+            .versions
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| {
+                !self
+                    .versions
+                    .iter()
+                    .any(|version| version.parent == Some(*index))
+            })
+            .map(|(index, version)| {
+                let marker = if index == self.version_index { "*" } else { "" };
+                format!(
+                    "#{}{} {}",
+                    index,
+                    marker,
+                    version.timestamp.format("%H:%M:%S")
+                )
+            })
+            .collect();
+        self.status_message = StatusMessage::from(format!("Undo leaves: {}", leaves.join(" | ")));
+    }
+
+    fn show_timeline(&mut self) {
+        self.timeline = (0..self.versions.len()).rev().collect();
+        let lines = self
+            .timeline
+            .iter()
+            .map(|index| {
+                let version = &self.versions[*index];
+                let summary = match version.parent.and_then(|parent| self.versions.get(parent)) {
+                    Some(parent) => Self::version_lines_message(
+                        &version.document,
+                        parent.document.len(),
+                        parent.document.lines_changed(),
+                    ),
+                    None => "original".to_string(),
+                };
+                format!(
+                    "#{:<4}{} {:<16} {}",
+                    index,
+                    if *index == self.version_index {
+                        "*"
+                    } else {
+                        " "
+                    },
+                    Self::version_time_message(&version.timestamp),
+                    summary
+                )
+            })
+            .collect();
+        let mut pane = OutputPane::selectable("timeline", lines);
+        pane.set_hint("Enter to jump, d to diff against current, q to close");
+        self.output = Some(pane);
+        let view_height = self.output_view_height();
+        let current = self
+            .versions
+            .len()
+            .saturating_sub(self.version_index.saturating_add(1));
+        if let Some(output) = &mut self.output {
+            output.move_selection(current, true, view_height);
         }
+    }
+
+    fn jump_to_timeline_entry(&mut self, index: usize) {
+        let Some(target) = self.timeline.get(index).copied() else {
+            return;
+        };
+        self.output = None;
+        self.timeline.clear();
+        let direction = if target < self.version_index {
+            SearchDirection::Backward
+        } else {
+            SearchDirection::Forward
+        };
+        if let Err(error) = self.jump_to_version(target, direction) {
+            self.status_message = StatusMessage::from(format!("ERR: {}", error));
+        }
+    }
+
+    fn show_timeline_diff(&mut self, index: usize) {
+        let Some(version) = self
+            .timeline
+            .get(index)
+            .and_then(|target| self.versions.get(*target))
+        else {
+            return;
+        };
+        let lines = diff::unified(&version.document.lines(), &self.document.lines(), 3);
+        if lines.is_empty() {
+            self.status_message = StatusMessage::from(format!(
+                "#{} matches the current state",
+                self.timeline[index]
+            ));
+            return;
+        }
+        let title = format!("diff #{} against current", self.timeline[index]);
+        self.show_output(&title, lines);
+    }
+
+    fn add_version(&mut self) {
         let Version {
-            document: _,
             position,
             timestamp,
+            ..
         } = self.versions[self.version_index];
         let version = Version {
             document: self.document.clone(),
             position,
             timestamp,
+            parent: Some(self.version_index),
+            redo_child: None,
         };
         self.document = self.document.clone();
         self.versions.push(version);
-        self.version_index = self.versions.len() - 1;
-        self.has_saved = false;
+        let new_index = self.versions.len() - 1;
+        self.versions[self.version_index].redo_child = Some(new_index);
+        self.version_index = new_index;
     }
 
     fn contains_changes(&self) -> bool {
-        self.versions.len() != 1 && self.version_index != 0 && !self.has_saved
+        self.versions.len() != 1 && self.saved_version != Some(self.version_index)
     }
 
     fn save_prev_cursor_position(&mut self) {
@@ -332,46 +1289,254 @@ impl Editor {
         prev_version.timestamp = chrono::offset::Local::now();
     }
 
-    fn save(&mut self) {
+    fn save(&mut self, force: bool) -> bool {
         if !self.contains_changes() {
             self.status_message = StatusMessage::from("No changes to write.".to_string());
+            return true;
+        }
+        if !self.ready_to_save(force) {
+            return false;
+        }
+        let save_result = self.document.save(&self.config, !self.private);
+        self.report_save(save_result)
+    }
+
+    fn save_in_background(&mut self, force: bool) {
+        if !self.contains_changes() {
+            self.status_message = StatusMessage::from("No changes to write.".to_string());
+            return;
+        }
+        if !self.ready_to_save(force) {
             return;
         }
+        let job = match self.document.prepare_save(&self.config, !self.private) {
+            Ok(Some(job)) => job,
+            Ok(None) => return,
+            Err(error) => {
+                self.report_save(Err(error));
+                return;
+            }
+        };
+        let file_name = job.file_name().to_string();
+        let generation = job.generation;
+        let id = self
+            .tasks
+            .spawn_uncancellable(&format!("write {}", file_name), move || {
+                TaskResult::Save(job.run())
+            });
+        self.save_task = Some(PendingSave {
+            task: id,
+            buffer_id: self.buffer_id,
+            generation,
+            lines: self.document.lines(),
+        });
+        self.status_message = StatusMessage::from(format!("Writing {}...", file_name));
+    }
+
+    fn ready_to_save(&mut self, force: bool) -> bool {
+        if let Some(save) = &self.save_task {
+            if self.tasks.is_running(save.task) {
+                self.status_message =
+                    StatusMessage::from("ERR: A write is still in progress".to_string());
+                return false;
+            }
+        }
+
+        if !force && self.warn_about_secrets() {
+            return false;
+        }
 
         if self.document.file_name.is_none() {
             let new_name = self.prompt("Save as: ", |_, _, _| {}).unwrap_or(None);
-            if new_name.is_none() {
-                self.status_message = StatusMessage::from("Save aborted.".to_string());
+            match new_name {
+                Some(new_name) => self.document.set_file_name(&new_name),
+                None => {
+                    self.status_message = StatusMessage::from("Save aborted.".to_string());
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn finish_background_save(&mut self, id: usize, result: Result<usize>) {
+        if self.save_task.as_ref().map_or(true, |save| save.task != id) {
+            return;
+        }
+        let Some(save) = self.save_task.take() else {
+            return;
+        };
+        let bytes_written = match result {
+            Ok(bytes_written) => bytes_written,
+            Err(error) => {
+                self.report_save(Err(error));
+                return;
+            }
+        };
+        if save.buffer_id == self.buffer_id {
+            if self.document.finish_save(save.generation) {
+                self.report_save(Ok(bytes_written));
+                return;
+            }
+            self.saved_version = None;
+            if let Err(error) = self.persist_history() {
+                self.status_message =
+                    StatusMessage::from(format!("ERR: Could not write undo history: {}", error));
+                return;
+            }
+            if let Some(file_name) = self.journaled_file() {
+                let journal =
+                    Journal::start_from(&file_name, save.lines, &self.document, self.version_index);
+                match journal {
+                    Ok(journal) => self.journal = Some(journal),
+                    Err(error) => {
+                        self.journal = None;
+                        self.status_message =
+                            StatusMessage::from(format!("ERR: Could not start journal: {}", error));
+                        return;
+                    }
+                }
+            }
+        } else if let Some(buffer) = self
+            .buffers
+            .iter_mut()
+            .find(|buffer| buffer.id == save.buffer_id)
+        {
+            let saved = buffer.document.finish_save(save.generation);
+            buffer.saved_version = saved.then_some(buffer.version_index);
+            if let (Some(_), Some(file_name)) = (buffer.journal.take(), &buffer.document.file_name)
+            {
+                buffer.journal = Journal::start_from(
+                    file_name,
+                    save.lines,
+                    &buffer.document,
+                    buffer.version_index,
+                )
+                .ok();
+            }
+            if saved {
+                self.status_message = StatusMessage::from(format!(
+                    "File saved successfully: {} bytes written.",
+                    bytes_written
+                ));
                 return;
             }
-            self.document.file_name = new_name;
         }
+        self.status_message = StatusMessage::from(format!(
+            "File written: {} bytes; buffer changed since.",
+            bytes_written
+        ));
+    }
+
+    fn save_as(&mut self, commands: &Vec<&str>, force: bool) {
+        match commands.get(1) {
+            Some(path) => {
+                if !force && self.warn_about_secrets() {
+                    return;
+                }
+                self.document.set_file_name(path);
+                let save_result = self.document.save(&self.config, !self.private);
+                self.report_save(save_result);
+            }
+            None => self.status_message = StatusMessage::from(format!("ERR: No path entered")),
+        }
+    }
+
+    fn warn_about_secrets(&mut self) -> bool {
+        if !self.config.secret_scan {
+            return false;
+        }
+        match secrets::scan(&self.document.lines(), &self.config.secret_patterns) {
+            Some(secret) => {
+                self.status_message = StatusMessage::from(format!(
+                    "WARNING! Line {} looks like a secret ({}): add ! to write anyway.",
+                    secret.line, secret.name
+                ));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn save_privileged(&mut self) -> Result<()> {
+        if self.document.file_name.is_none() {
+            self.status_message = StatusMessage::from("ERR: Buffer has no file name".to_string());
+            return Ok(());
+        }
+        Terminal::clear_screen();
+        Terminal::cursor_position(&Position::default(), 0);
+        self.terminal.flush()?;
+        self.terminal.suspend_raw_mode()?;
+        let save_result = self.document.save_privileged();
+        self.terminal.activate_raw_mode()?;
+        self.terminal.invalidate();
+        self.report_save(save_result);
+        Ok(())
+    }
 
-        let save_result = self.document.save();
+    fn report_save(&mut self, save_result: Result<usize>) -> bool {
         match save_result {
             Ok(bytes_written) => {
                 self.status_message = StatusMessage::from(format!(
                     "File saved successfully: {} bytes written.",
                     bytes_written
                 ));
-                self.has_saved = true;
+                self.saved_version = Some(self.version_index);
+                if let Err(error) = self.persist_history() {
+                    self.status_message = StatusMessage::from(format!(
+                        "ERR: Could not write undo history: {}",
+                        error
+                    ));
+                }
+                match self.start_journal() {
+                    Ok(journal) => self.journal = journal,
+                    Err(error) => {
+                        self.journal = None;
+                        self.status_message =
+                            StatusMessage::from(format!("ERR: Could not start journal: {}", error));
+                    }
+                }
+                true
             }
             Err(error) => {
-                self.status_message = StatusMessage::from(format!("Error writing file! {}", error));
+                self.status_message = StatusMessage::from(Self::save_error_message(&error));
+                false
             }
         }
     }
 
+    fn save_error_message(error: &anyhow::Error) -> String {
+        let kind = error.downcast_ref::<io::Error>().map(io::Error::kind);
+        let hint = match kind {
+            Some(ErrorKind::PermissionDenied) => {
+                "permission denied; try :w!! to write with sudo or :saveas <path>"
+            }
+            Some(ErrorKind::ReadOnlyFilesystem) => "read-only filesystem; try :saveas <path>",
+            Some(ErrorKind::StorageFull) => "disk full; free up space or :saveas <path>",
+            Some(ErrorKind::NotFound) => "directory does not exist; try :saveas <path>",
+            Some(ErrorKind::IsADirectory) => "path is a directory; try :saveas <path>",
+            _ => "try :saveas <path>",
+        };
+        format!("Error writing file! {:#} ({})", error, hint)
+    }
+
     fn move_to_search_term(&mut self, direction: SearchDirection) {
         if let Some(query) = &self.query.clone() {
+            let pattern = match SearchPattern::new(query, self.config.regex_search) {
+                Ok(pattern) => pattern,
+                Err(error) => {
+                    self.status_message = StatusMessage::from(format!("ERR: {}", error));
+                    return;
+                }
+            };
+            self.highlighted_word = Some(pattern.clone());
+            let origin = self.cursor_position;
             let mut found = false;
             if direction == SearchDirection::Forward {
                 self.move_cursor(Key::Right);
             }
-            if let Some(position) =
-                self.document
-                    .find(query, &self.cursor_position.into(), direction)
-            {
+            if let Some(position) = self.find_unfolded(&pattern, direction) {
+                self.record_jump_from(origin.into());
                 self.cursor_position = position.into();
                 self.scroll();
                 found = true;
@@ -382,39 +1547,98 @@ impl Editor {
         }
     }
 
+    fn find_unfolded(
+        &self,
+        pattern: &SearchPattern,
+        direction: SearchDirection,
+    ) -> Option<Position> {
+        let mut at: Position = self.cursor_position.into();
+        for _ in 0..=self.document.fold_count() {
+            let position = self.document.find(pattern, &at, direction)?;
+            let Some((start, end)) = self.document.closed_fold(position.y) else {
+                return Some(position);
+            };
+            let len = self.document.len();
+            at = if direction == SearchDirection::Forward {
+                Position {
+                    x: 0,
+                    y: end.saturating_add(1).checked_rem(len).unwrap_or(0),
+                }
+            } else {
+                let y = start.checked_sub(1).unwrap_or(len.saturating_sub(1));
+                Position {
+                    x: self.document.row(y).map_or(0, Row::len),
+                    y,
+                }
+            };
+        }
+        None
+    }
+
     fn search(&mut self) {
         let old_position = self.cursor_position;
         let mut direction = SearchDirection::Forward;
+        let history = self.search_history.entries().to_vec();
         self.query = self
-            .prompt("/", |editor, key, query| {
+            .prompt_with_history("/", &history, &[], |editor, key, query| {
                 let mut moved = false;
                 match key {
-                    Key::Right | Key::Down => {
+                    Key::Right => {
                         direction = SearchDirection::Forward;
                         editor.move_cursor(Key::Right);
                         moved = true;
                     }
-                    Key::Left | Key::Up => direction = SearchDirection::Backward,
+                    Key::Left => direction = SearchDirection::Backward,
                     _ => direction = SearchDirection::Forward,
                 }
-                if let Some(position) =
+                let pattern = SearchPattern::new(query, editor.config.regex_search).ok();
+                if let Some(position) = pattern.as_ref().and_then(|pattern| {
                     editor
                         .document
-                        .find(query, &editor.cursor_position.into(), direction)
-                {
+                        .find(pattern, &editor.cursor_position.into(), direction)
+                }) {
                     editor.cursor_position = position.into();
                     editor.scroll();
                 } else if moved {
                     editor.move_cursor(Key::Left);
                 }
-                editor.highlighted_word = Some(query.to_string());
+                editor.set_search_highlight(pattern);
             })
             .unwrap_or(None);
         if self.query.is_none() {
             self.cursor_position = old_position;
             self.scroll();
+            self.set_search_highlight(None);
+        } else {
+            self.record_jump_from(old_position.into());
+        }
+        if let Some(query) = &self.query {
+            if self.is_private() {
+                self.search_history.push_private(query);
+            } else {
+                let _ = self.search_history.push(query);
+            }
         }
-        self.highlighted_word = None;
+    }
+
+    fn set_search_highlight(&mut self, pattern: Option<SearchPattern>) {
+        self.highlighted_word = pattern;
+        self.search_count = None;
+        self.document.clear_highlighting();
+    }
+
+    fn update_search_count(&mut self) {
+        let Some(pattern) = &self.highlighted_word else {
+            self.search_count = None;
+            return;
+        };
+        let position: Position = self.cursor_position.into();
+        let key = (position, self.version_index, self.document.generation());
+        if matches!(self.search_count, Some((counted, _, _)) if counted == key) {
+            return;
+        }
+        let (current, total) = self.document.match_count(pattern, &position);
+        self.search_count = Some((key, current, total));
     }
 
     fn show_cwd(&mut self) {
@@ -426,30 +1650,72 @@ impl Editor {
     }
 
     fn term_read_key_blocking(&mut self) -> Result<Key> {
+        if let Some(key) = self.replay_keys.pop_front() {
+            return Ok(key);
+        }
+        if let Some(key) = self.pending_keys.pop_front() {
+            self.record_key(key);
+            return Ok(key);
+        }
+        let mut redraw = true;
         loop {
-            self.refresh_screen()?;
-            match self.terminal.read_event() {
-                Some(event) => match event? {
-                    KeyEvent(key) => return Ok(key),
-                    _ => (),
-                },
-                None => (),
+            if redraw {
+                self.refresh_screen()?;
             }
-            thread::sleep(time::Duration::from_millis(REFRESH_INTERVAL));
+            redraw = match self.terminal.read_event(None) {
+                Some(TerminalEvent::Input(event)) => match event? {
+                    KeyEvent(key) => {
+                        self.record_key(key);
+                        return Ok(key);
+                    }
+                    _ => true,
+                },
+                Some(TerminalEvent::Resize) => true,
+                Some(TerminalEvent::Wake) => self.poll_tasks(),
+                None => return Err(anyhow!("Terminal input closed")),
+            };
         }
     }
 
-    fn term_read_event(&mut self) -> Option<Result<()>> {
-        match self.terminal.read_event() {
-            Some(event) => match event {
-                Ok(event) => match event {
-                    KeyEvent(key) => Some(self.process_keypress(key)),
-                    Mouse(me) => Some(self.process_mouse_event(me)),
-                    _ => None,
-                },
-                Err(error) => Some(Err(error)),
-            },
-            None => None,
+    fn read_command_key(&mut self) -> Result<Key> {
+        if !self.pending_keys.is_empty() || !self.replay_keys.is_empty() {
+            return self.term_read_key_blocking();
+        }
+        let key = self.term_read_key_blocking()?;
+        let key = self.translate_key(key);
+        if let Some(last) = self
+            .change
+            .as_mut()
+            .and_then(|change| change.keys.last_mut())
+        {
+            *last = key;
+        }
+        Ok(key)
+    }
+
+    fn record_key(&mut self, key: Key) {
+        if let Some(change) = &mut self.change {
+            change.keys.push(key);
+        }
+    }
+
+    fn translate_key(&self, key: Key) -> Key {
+        match key {
+            Key::Char(c) => Key::Char(self.config.langmap.get(&c).copied().unwrap_or(c)),
+            _ => key,
+        }
+    }
+
+    fn handle_event(&mut self, event: Result<Event>) -> Result<()> {
+        match event? {
+            KeyEvent(key) => self.process_keypress(key),
+            Mouse(me) => self.process_mouse_event(me),
+            Unsupported(bytes) => {
+                if let Some(focused) = Terminal::focus_change(&bytes) {
+                    self.focus_changed(focused);
+                }
+                Ok(())
+            }
         }
     }
 
@@ -457,9 +1723,12 @@ impl Editor {
     where
         C: FnMut(&mut Self),
     {
+        self.take_snapshot();
+        self.change_made = true;
         if self.mode != Mode::Insert {
             self.save_prev_cursor_position();
         }
+        self.document.open_fold(self.cursor_position.y);
         callback(self);
         if self.mode != Mode::Insert {
             self.add_version();
@@ -478,16 +1747,124 @@ impl Editor {
         })
     }
 
-    fn doc_paste_clipboard(&mut self) {
-        if let Some(content) = &self.clipboard.clone() {
+    fn set_clipboard(&mut self, clipboard: Clipboard) {
+        if let Some(selection) = self.register.or(self.config.clipboard) {
+            let mut text = clipboard.text.clone();
+            if clipboard.linewise {
+                text.push('\n');
+            }
+            if let Err(error) = selection::copy(selection, &text) {
+                self.status_message = StatusMessage::from(format!("ERR: {}", error));
+            }
+        }
+        self.clipboard = Some(clipboard);
+    }
+
+    fn read_clipboard(&mut self) -> Option<Clipboard> {
+        match self.register.or(self.config.clipboard) {
+            Some(selection) => match selection::paste(selection) {
+                Ok(None) => self.clipboard.clone(),
+                Ok(Some(text)) => Some(match text.strip_suffix('\n') {
+                    Some(lines) => Clipboard {
+                        text: lines.to_string(),
+                        linewise: true,
+                    },
+                    None => Clipboard {
+                        text,
+                        linewise: false,
+                    },
+                }),
+                Err(error) => {
+                    self.status_message = StatusMessage::from(format!("ERR: {}", error));
+                    None
+                }
+            },
+            None => self.clipboard.clone(),
+        }
+    }
+
+    fn doc_paste_clipboard(&mut self, count: usize, after: bool) {
+        if let Some(clipboard) = &self.read_clipboard() {
             self.doc_edit(|editor| {
-                editor
-                    .document
-                    .insert_line(editor.cursor_position.y, &content);
+                if clipboard.linewise {
+                    let mut y = editor.cursor_position.y;
+                    if after && !editor.document.is_empty() {
+                        y = y.saturating_add(1);
+                    }
+                    let lines: Vec<&str> = clipboard.text.split('\n').collect();
+                    for (index, line) in lines.iter().cycle().take(lines.len() * count).enumerate()
+                    {
+                        editor.document.insert_line(y.saturating_add(index), line);
+                    }
+                    editor.cursor_position = CursorPosition::default();
+                    editor.cursor_position.y = y;
+                    editor.move_cursor(Key::Char('I'));
+                } else {
+                    let mut at: Position = editor.cursor_position.into();
+                    if after
+                        && editor
+                            .document
+                            .row(at.y)
+                            .map_or(false, |row| !row.is_empty())
+                    {
+                        at.x = at.x.saturating_add(1);
+                    }
+                    let end = editor
+                        .document
+                        .insert_text(&at, &clipboard.text.repeat(count));
+                    editor.cursor_position = Position {
+                        x: end.x.saturating_sub(1),
+                        y: end.y,
+                    }
+                    .into();
+                }
             });
         }
     }
 
+    fn doc_replace_lines_with_clipboard(&mut self, start: usize, end: usize, count: usize) {
+        let Some(clipboard) = self.read_clipboard() else {
+            return;
+        };
+        let replaced: Vec<String> = (start..=end)
+            .filter_map(|y| self.document.row(y).map(Row::contents))
+            .collect();
+        let pasted: Vec<&str> = clipboard.text.split('\n').collect();
+        let lines: Vec<String> = pasted
+            .iter()
+            .cycle()
+            .take(pasted.len().saturating_mul(count))
+            .map(|line| (*line).to_string())
+            .collect();
+        self.doc_edit(|editor| {
+            editor.document.replace_lines(start, end, &lines);
+            editor.cursor_position = CursorPosition::default();
+            editor.cursor_position.y = start;
+            editor.move_cursor(Key::Char('I'));
+        });
+        self.set_clipboard(Clipboard {
+            text: replaced.join("\n"),
+            linewise: true,
+        });
+    }
+
+    fn doc_join_lines(&mut self, count: usize) {
+        let y = self.cursor_position.y;
+        let mut joined_at = None;
+        self.doc_edit(|editor| {
+            for _ in 1..count {
+                match editor.document.join_line(y) {
+                    Some(x) => joined_at = Some(x),
+                    None => break,
+                }
+            }
+        });
+        if let Some(x) = joined_at {
+            self.cursor_position.x = x;
+            self.cursor_position.max_x = x;
+        }
+    }
+
     fn doc_delete(&mut self) -> usize {
         let mut deleted = 0;
         self.doc_edit(|editor| {
@@ -496,9 +1873,17 @@ impl Editor {
         deleted
     }
 
-    fn doc_delete_line(&mut self) {
+    fn doc_delete_range(&mut self, start: &Position, end: &Position) {
+        self.doc_edit(|editor| {
+            editor.document.delete_range(start, end);
+        });
+    }
+
+    fn doc_delete_lines(&mut self, start: usize, end: usize) {
         self.doc_edit(|editor| {
-            editor.document.delete_line(editor.cursor_position.y);
+            for _ in start..=end {
+                editor.document.delete_line(start);
+            }
         });
     }
 
@@ -508,7 +1893,53 @@ impl Editor {
         });
     }
 
+    fn record_jump(&mut self) {
+        self.record_jump_from(self.cursor_position.into());
+    }
+
+    fn record_jump_from(&mut self, position: Position) {
+        self.document.set_mark('\'', position);
+        self.document.jumps_mut().push(position);
+    }
+
+    fn jump_back(&mut self) {
+        let current = self.cursor_position.into();
+        if let Some(position) = self.document.jumps_mut().back(current) {
+            self.cursor_position = position.into();
+            self.readjust_cursor();
+        }
+    }
+
+    fn jump_forward(&mut self) {
+        if let Some(position) = self.document.jumps_mut().forward() {
+            self.cursor_position = position.into();
+            self.readjust_cursor();
+        }
+    }
+
+    fn set_mark(&mut self, name: char) {
+        if name.is_ascii_alphabetic() {
+            self.document.set_mark(name, self.cursor_position.into());
+        }
+    }
+
+    fn jump_to_mark(&mut self, name: char, linewise: bool) {
+        let name = if name == '`' { '\'' } else { name };
+        match self.document.mark(name) {
+            Some(position) => {
+                self.record_jump();
+                self.cursor_position = position.into();
+                if linewise {
+                    self.cursor_position.x = self.document.left_space(position.y);
+                }
+                self.readjust_cursor();
+            }
+            None => self.status_message = StatusMessage::from(format!("Mark not set: {}", name)),
+        }
+    }
+
     fn jump_to_line(&mut self, line: usize) {
+        self.record_jump();
         self.cursor_position = CursorPosition {
             x: 0,
             y: line.saturating_sub(1),
@@ -517,23 +1948,6 @@ impl Editor {
         self.readjust_cursor();
     }
 
-    fn jump_to_offset(&mut self, offset: &str) {
-        if let Ok(line_num) = offset.parse::<i64>() {
-            let mut y = self.cursor_position.y as i64 + line_num;
-            if y < 0 {
-                y = 0;
-            }
-            self.cursor_position = CursorPosition {
-                x: 0,
-                y: y as usize,
-                max_x: 0,
-            };
-            self.readjust_cursor();
-        } else {
-            self.status_message = StatusMessage::from(format!("Invalid offset: {}", offset));
-        }
-    }
-
     fn cwd_command(&mut self, commands: &Vec<&str>) {
         let input_path = match commands.get(1) {
             Some(path) => path,
@@ -559,273 +1973,1841 @@ impl Editor {
                 return;
             }
         };
-        if let Some((doc, versions)) = Self::open_document(input_path) {
+        self.open_target(input_path);
+    }
+
+    fn open_target(&mut self, target: &str) {
+        let (path, position) = parse_file_target(target);
+        let canonical = fs::canonicalize(path).ok();
+        if let Some(index) = self.buffers.iter().position(|buffer| {
+            canonical.is_some()
+                && buffer
+                    .document
+                    .file_name
+                    .as_ref()
+                    .and_then(|file_name| fs::canonicalize(file_name).ok())
+                    == canonical
+        }) {
+            self.switch_to_buffer(index);
+            if let Some(position) = position {
+                self.cursor_position = position.into();
+                self.readjust_cursor();
+            }
+            return;
+        }
+        let opened = match crypt::detect(path) {
+            Some(cipher) => match self.open_encrypted_document(path, cipher) {
+                Ok(opened) => opened,
+                Err(error) => {
+                    self.status_message = StatusMessage::from(format!(
+                        "ERR: Could not decrypt {}: {:#}",
+                        path, error
+                    ));
+                    return;
+                }
+            },
+            None => self.open_document(path),
+        };
+        if let Some((doc, versions, version_index)) = opened {
+            self.take_snapshot();
+            let _ = self.persist_history();
+            if !self.is_current_file(path) && self.stash_buffer() {
+                self.buffer_id = self.next_buffer_id;
+                self.next_buffer_id = self.next_buffer_id.saturating_add(1);
+            }
+            self.private =
+                doc.is_encrypted() || privacy::is_private(path, &self.config.private_patterns);
             self.document = doc;
+            self.snapshot_pending = versions.len() == 1;
             self.versions = versions;
-            self.version_index = 0;
-            self.cursor_position = CursorPosition::default();
+            self.version_index = version_index;
+            self.saved_version = Some(version_index);
+            self.cursor_position = position.unwrap_or_default().into();
             self.readjust_cursor();
+            if self.document.encoding() == Encoding::Utf8Lossy {
+                self.status_message = StatusMessage::from(format!(
+                    "WARNING: \"{}\" is not valid UTF-8; invalid bytes shown as \u{fffd} are kept on save",
+                    path
+                ));
+            } else if self.document.has_mixed_line_endings() {
+                self.status_message = StatusMessage::from(format!(
+                    "WARNING: Mixed line endings; all are written as {} (:set ff to change)",
+                    self.document.line_ending().name()
+                ));
+            }
+            self.spawn_buffer_stats();
+            self.open_journal();
         } else {
             self.status_message =
-                StatusMessage::from(format!("ERR: Could not open file: {}", input_path));
+                StatusMessage::from(format!("ERR: Could not open file: {}", path));
         };
     }
 
-    fn perform_command_safely<C>(&mut self, mut callback: C)
-    where
-        C: FnMut(&mut Self),
-    {
-        if self.contains_changes() {
-            self.status_message = StatusMessage::from(
-                "WARNING! File has unsaved changes: add ! to override.".to_string(),
-            );
-        } else {
-            callback(self);
+    fn take_snapshot(&mut self) {
+        if mem::take(&mut self.snapshot_pending) {
+            self.versions[self.version_index].document = self.document.clone();
         }
     }
 
-    fn parse_command(&mut self, input: &str) {
-        let commands: Vec<&str> = input.split_whitespace().collect();
-        match commands.get(0) {
-            Some(command) => match *command {
-                "w" => self.save(),
-                "p" => self.show_cwd(),
-                "wq" | "x" => {
-                    self.save();
-                    self.quit();
-                }
-                "q" => self.perform_command_safely(|editor| editor.quit()),
-                "q!" => self.quit(),
-                "e" => self.perform_command_safely(|editor| editor.open_file(&commands)),
-                "e!" => self.open_file(&commands),
-                "c" => self.cwd_command(&commands),
-                _ => {
-                    self.status_message =
-                        StatusMessage::from(format!("Command not found: {}", command));
-                }
-            },
-            None => (),
+    fn stash_buffer(&mut self) -> bool {
+        if let Some(rename) = self.rename.take() {
+            let _ = self.finish_rename(rename);
         }
+        self.take_snapshot();
+        if self.document.file_name.is_none() && !self.contains_changes() {
+            return false;
+        }
+        self.flush_journal();
+        self.buffer_tick = self.buffer_tick.saturating_add(1);
+        self.buffers.push(Buffer {
+            id: self.buffer_id,
+            document: mem::take(&mut self.document),
+            versions: mem::replace(&mut self.versions, vec![Version::default()]),
+            version_index: self.version_index,
+            saved_version: self.saved_version,
+            cursor_position: self.cursor_position,
+            offset: self.offset,
+            private: self.private,
+            journal: self.journal.take(),
+            last_used: self.buffer_tick,
+        });
+        true
     }
 
-    fn execute_command(&mut self) -> Result<()> {
-        let input = self.prompt(":", |_, _, _| {})?;
-        if input.is_none() {
-            return Ok(());
+    fn switch_to_buffer(&mut self, index: usize) {
+        if index >= self.buffers.len() {
+            return;
         }
-        let input = input.unwrap();
-        let input = input.trim();
-        let first_char = input.chars().nth(0).unwrap_or(' ');
-        if first_char == '+' || first_char == '-' {
-            self.jump_to_offset(input);
-        } else if let Ok(line) = input.parse::<usize>() {
-            self.jump_to_line(line);
-        } else {
-            self.parse_command(input);
+        let _ = self.persist_history();
+        let buffer = self.buffers.remove(index);
+        self.stash_buffer();
+        self.buffer_id = buffer.id;
+        self.document = buffer.document;
+        self.versions = buffer.versions;
+        self.version_index = buffer.version_index;
+        self.saved_version = buffer.saved_version;
+        self.offset = buffer.offset;
+        self.private = buffer.private;
+        self.journal = buffer.journal;
+        self.search_count = None;
+        self.restore_cursor(buffer.cursor_position);
+        self.spawn_buffer_stats();
+        self.status_message = StatusMessage::from(format!(
+            "\"{}\" {} lines",
+            self.document.file_name.clone().unwrap_or_default(),
+            self.document.len()
+        ));
+    }
+
+    fn alternate_index(&self) -> Option<usize> {
+        self.buffers
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, buffer)| buffer.last_used)
+            .map(|(index, _)| index)
+    }
+
+    fn alternate_buffer(&mut self) {
+        match self.alternate_index() {
+            Some(index) => self.switch_to_buffer(index),
+            None => {
+                self.status_message = StatusMessage::from("ERR: No alternate file".to_string());
+            }
         }
-        Ok(())
     }
 
-    fn repeat_keypress(&mut self, n: u32) -> Result<()> {
-        if n == 0 {
-            return Ok(());
+    fn buffer_command(&mut self, id: &str) {
+        match id
+            .parse::<usize>()
+            .ok()
+            .and_then(|id| self.buffers.iter().position(|buffer| buffer.id == id))
+        {
+            Some(index) => self.switch_to_buffer(index),
+            None if id.parse::<usize>().ok() == Some(self.buffer_id) => (),
+            None => {
+                self.status_message = StatusMessage::from(format!("ERR: No such buffer: {}", id));
+            }
         }
-        let mut number_message = n.to_string();
-        self.status_message = StatusMessage::from(number_message.clone());
-        self.refresh_screen()?;
-        while let Key::Char(c) = self.term_read_key_blocking()? {
-            if c.is_numeric() {
-                number_message.push(c);
-                self.status_message = StatusMessage::from(number_message.clone());
-                self.refresh_screen()?;
-            } else if c.is_alphabetic() {
-                if c == 'r' || c == 's' {
-                    break;
-                }
-                if let Ok(repeats) = number_message.parse() {
-                    for _ in 0..repeats {
-                        self.normal_mode(c)?;
-                    }
-                }
-                break;
-            } else {
-                break;
+    }
+
+    fn list_buffers(&mut self) {
+        let alternate = self
+            .buffers
+            .iter()
+            .max_by_key(|buffer| buffer.last_used)
+            .map(|buffer| buffer.id);
+        let mut entries: Vec<(usize, String, bool, usize, usize)> = self
+            .buffers
+            .iter()
+            .map(|buffer| {
+                (
+                    buffer.id,
+                    buffer.name(),
+                    buffer.is_modified(),
+                    buffer.cursor_position.y,
+                    buffer.last_used,
+                )
+            })
+            .collect();
+        entries.push((
+            self.buffer_id,
+            self.document
+                .file_name
+                .clone()
+                .unwrap_or_else(|| "[No Name]".to_string()),
+            self.contains_changes(),
+            self.cursor_position.y,
+            usize::MAX,
+        ));
+        match self.config.buffer_order {
+            BufferOrder::Mru => entries.sort_by(|a, b| b.4.cmp(&a.4)),
+            BufferOrder::Path => entries.sort_by(|a, b| a.1.cmp(&b.1)),
+            BufferOrder::Modified => entries.sort_by(|a, b| b.2.cmp(&a.2).then(b.4.cmp(&a.4))),
+        }
+        let lines = entries
+            .into_iter()
+            .map(|(id, name, modified, line, _)| {
+                let flag = if id == self.buffer_id {
+                    '%'
+                } else if Some(id) == alternate {
+                    '#'
+                } else {
+                    ' '
+                };
+                format!(
+                    "{:>3} {}{} \"{}\" line {}",
+                    id,
+                    flag,
+                    if modified { '+' } else { ' ' },
+                    name,
+                    line.saturating_add(1)
+                )
+            })
+            .collect();
+        self.show_output("buffers", lines);
+    }
+
+    fn modified_hidden_buffer(&self) -> Option<String> {
+        self.buffers
+            .iter()
+            .find(|buffer| buffer.is_modified())
+            .map(Buffer::name)
+    }
+
+    fn quit_safely(&mut self) {
+        match self.modified_hidden_buffer() {
+            Some(name) => {
+                self.status_message = StatusMessage::from(format!(
+                    "WARNING! Buffer {} has unsaved changes: add ! to override.",
+                    name
+                ));
             }
+            None => self.perform_command_safely(|editor| editor.quit()),
         }
-        self.status_message = StatusMessage::from(String::new());
-        self.refresh_screen()?;
-        Ok(())
     }
 
-    fn normal_mode(&mut self, c: char) -> Result<()> {
-        match c {
-            'h' => self.move_cursor(Key::Left),
-            'j' => self.move_cursor(Key::Down),
-            'k' => self.move_cursor(Key::Up),
-            'l' => self.move_cursor(Key::Right),
-            '0' => self.move_cursor(Key::Home),
-            '$' => self.move_cursor(Key::End),
-            'g' | 'G' => {
-                self.cursor_position.y = if c == 'g' { 0 } else { self.document.len() };
-                self.cursor_position.x = 0;
-                self.cursor_position.max_x = 0;
+    fn open_file_under_cursor(&mut self) {
+        let target = self
+            .document
+            .row(self.cursor_position.y)
+            .and_then(|row| row.path_at(self.cursor_position.x));
+        match target {
+            Some(target) => self.perform_command_safely(|editor| editor.open_target(&target)),
+            None => {
+                self.status_message =
+                    StatusMessage::from("ERR: No file name under cursor".to_string())
             }
-            'a' | 'A' | 'i' | 'I' => {
-                if c == 'a' {
-                    self.move_cursor(Key::Right);
-                } else if c == 'A' {
-                    self.move_cursor(Key::End);
-                } else if c == 'I' {
-                    self.move_cursor(Key::Char(c));
-                }
-                self.switch_mode(Mode::Insert);
+        }
+    }
+
+    fn delete_file(&mut self) {
+        let file_name = match &self.document.file_name {
+            Some(file_name) => file_name.clone(),
+            None => {
+                self.status_message = StatusMessage::from("ERR: Buffer has no file".to_string());
+                return;
             }
-            'o' | 'O' => {
-                self.switch_mode(Mode::Insert);
-                if c == 'o' {
-                    self.move_cursor(Key::Down);
+        };
+        let answer = self
+            .prompt(&format!("Delete {}? (y/n) ", file_name), |_, _, _| {})
+            .unwrap_or(None);
+        if answer.as_deref() != Some("y") {
+            self.status_message = StatusMessage::from("Delete aborted.".to_string());
+            return;
+        }
+        // The undo file is found through the file's path, so it goes first.
+        undofile::remove(&file_name);
+        match trash::remove(Path::new(&file_name)) {
+            Ok(destination) => {
+                if let Some(journal) = self.journal.take() {
+                    journal.discard();
                 }
-                self.doc_insert_line("");
-                if c == 'O' {
-                    self.move_cursor(Key::Down);
+                self.document = Document::default();
+                self.versions = vec![Version::default()];
+                self.version_index = 0;
+                self.saved_version = Some(0);
+                self.cursor_position = CursorPosition::default();
+                self.offset = Position::default();
+                if let Some(index) = self.alternate_index() {
+                    self.switch_to_buffer(index);
                 }
+                self.status_message = StatusMessage::from(format!(
+                    "Moved {} to {}",
+                    file_name,
+                    destination.display()
+                ));
             }
-            'r' => {
-                let key = self.term_read_key_blocking()?;
-                match key {
-                    Key::Char(key) => {
-                        self.doc_replace(key);
-                    }
-                    _ => return Ok(()),
-                }
+            Err(error) => {
+                let _ = self.persist_history();
+                self.status_message =
+                    StatusMessage::from(format!("ERR: Could not delete {}: {}", file_name, error));
             }
-            'x' | 's' => {
-                if let Some(row) = self.document.row(self.cursor_position.y) {
-                    let row_len = row.len();
-                    if row_len > 0 {
-                        self.doc_delete();
-                    }
-                    if c == 'x' && self.cursor_position.x == row_len.saturating_sub(1) {
-                        self.move_cursor(Key::Left);
-                    } else if c == 's' {
-                        self.switch_mode(Mode::Insert);
-                    }
-                }
+        }
+    }
+
+    fn rename_file(&mut self, commands: &Vec<&str>) {
+        let new_name = match commands.get(1) {
+            Some(path) => path,
+            None => {
+                self.status_message = StatusMessage::from(format!("ERR: No path entered"));
+                return;
             }
-            'd' | 'y' => {
-                if let Some(row) = self.document.row(self.cursor_position.y) {
-                    self.clipboard = Some(row.contents().trim().to_string());
-                    if c == 'd' {
-                        self.doc_delete_line();
-                        self.move_cursor(Key::Home);
+        };
+        if Path::new(new_name).exists() {
+            self.status_message =
+                StatusMessage::from(format!("ERR: File already exists: {}", new_name));
+            return;
+        }
+        if let Some(old_name) = &self.document.file_name {
+            let result = Path::new(new_name)
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .map_or(Ok(()), fs::create_dir_all)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| {
+                    if Path::new(old_name).exists() {
+                        // The undo file is keyed by the old path, which stops
+                        // resolving once the file has moved.
+                        undofile::remove(old_name);
+                        trash::move_file(Path::new(old_name), Path::new(new_name))
+                    } else {
+                        Ok(())
                     }
-                }
+                });
+            if let Err(error) = result {
+                let _ = self.persist_history();
+                self.status_message =
+                    StatusMessage::from(format!("ERR: Could not rename file: {}", error));
+                return;
             }
-            'p' => {
-                if self.clipboard.is_some() {
-                    self.doc_paste_clipboard();
-                    self.move_cursor(Key::Down);
-                }
+        }
+        self.document.set_file_name(new_name);
+        for version in &mut self.versions {
+            version.document.set_file_name(new_name);
+        }
+        self.status_message = StatusMessage::from(format!("Renamed to {}", new_name));
+        if let Err(error) = self.persist_history() {
+            self.status_message =
+                StatusMessage::from(format!("ERR: Could not write undo history: {}", error));
+        }
+        if let Some(journal) = &mut self.journal {
+            if let Err(error) = journal.rename(new_name) {
+                self.journal = None;
+                self.status_message =
+                    StatusMessage::from(format!("ERR: Could not move journal: {}", error));
             }
-            'R' => self.switch_mode(Mode::Replace),
-            'v' => self.switch_mode(Mode::Visual),
-            '/' => self.search(),
-            ':' => self.execute_command()?,
-            'u' => self.undo()?,
-            'n' => self.move_to_search_term(SearchDirection::Forward),
-            'N' => self.move_to_search_term(SearchDirection::Backward),
-            c if c.is_numeric() => {
-                if let Some(n) = c.to_digit(10) {
-                    self.repeat_keypress(n)?
+        }
+    }
+
+    fn set_options(&mut self, commands: &[&str]) {
+        if commands.len() < 2 || commands.get(1) == Some(&"all") {
+            let mut lines = vec![
+                format!("filetype={}", self.document.file_type()),
+                format!("fileformat={}", self.document.line_ending().name()),
+                format!("fileencoding={}", self.document.encoding().name()),
+                eol_setting(self.document.eol()).to_string(),
+            ];
+            for name in OPTION_NAMES {
+                if let Ok(value) = self.config.get(name) {
+                    lines.push(value);
                 }
             }
-            _ => (),
+            self.show_output("options", lines);
+            return;
         }
-        if self.mode != Mode::Insert && self.document.is_dirty() {
-            self.add_version();
+        for setting in commands.iter().skip(1) {
+            if let Err(error) = self.set_option(setting) {
+                self.status_message = StatusMessage::from(format!("ERR: {}", error));
+                return;
+            }
         }
-        Ok(())
     }
 
-    fn insert_mode(&mut self, c: char) {
-        match c {
-            '\t' => {
-                let spaces = 4 - self.cursor_position.x % 4;
-                for _ in 0..spaces {
-                    self.doc_insert(' ');
-                    self.move_cursor(Key::Right);
-                }
+    fn set_option(&mut self, setting: &str) -> Result<()> {
+        let (name, value) = match setting.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (setting, None),
+        };
+        match (name, value) {
+            ("filetype" | "ft", Some(value)) => {
+                let file_type = FileType::from_name(value)
+                    .ok_or_else(|| anyhow!("Unknown filetype: {}", value))?;
+                self.document.set_file_type(file_type);
+                self.status_message =
+                    StatusMessage::from(format!("filetype={}", self.document.file_type()));
             }
-            '\n' => {
-                self.doc_insert(c);
-                self.move_cursor(Key::Right);
-                let mut spaces = 0;
-                if let Some(row) = self.document.row(self.cursor_position.y) {
-                    spaces = row.indentation();
+            ("filetype" | "ft" | "filetype?" | "ft?", None) => {
+                self.status_message =
+                    StatusMessage::from(format!("filetype={}", self.document.file_type()));
+            }
+            ("fileformat" | "ff", Some(value)) => {
+                let line_ending = LineEnding::from_name(value)
+                    .ok_or_else(|| anyhow!("Unknown fileformat: {}", value))?;
+                if line_ending != self.document.line_ending() {
+                    self.doc_edit(|editor| editor.document.set_line_ending(line_ending));
                 }
-                for _ in 0..spaces.saturating_sub(self.cursor_position.x) {
-                    self.move_cursor(Key::Right);
+                self.status_message =
+                    StatusMessage::from(format!("fileformat={}", line_ending.name()));
+            }
+            ("fileencoding" | "fenc", Some(value)) => {
+                let encoding = Encoding::from_name(value)
+                    .ok_or_else(|| anyhow!("Unknown fileencoding: {}", value))?;
+                if encoding != self.document.encoding() {
+                    self.doc_edit(|editor| editor.document.set_encoding(encoding));
                 }
+                self.status_message =
+                    StatusMessage::from(format!("fileencoding={}", encoding.name()));
             }
-            '(' | '[' | '{' | '\'' | '"' => {
-                self.doc_insert(c);
-                self.move_cursor(Key::Right);
-                let closing = if c == '(' {
-                    ')'
-                } else if c == '[' {
-                    ']'
-                } else if c == '{' {
-                    '}'
-                } else if c == '"' || self.document.file_type() != "Rust" {
-                    c
-                } else {
-                    return;
-                };
-                self.doc_insert(closing);
+            ("fileencoding" | "fenc" | "fileencoding?" | "fenc?", None) => {
+                self.status_message = StatusMessage::from(format!(
+                    "fileencoding={}",
+                    self.document.encoding().name()
+                ));
+            }
+            ("eol" | "endofline", None) => self.set_eol(true),
+            ("noeol" | "noendofline", None) => self.set_eol(false),
+            ("eol!" | "inveol" | "endofline!" | "invendofline", None) => {
+                self.set_eol(!self.document.eol());
+            }
+            ("eol?" | "endofline?", None) => {
+                self.status_message =
+                    StatusMessage::from(eol_setting(self.document.eol()).to_string());
+            }
+            ("fileformat" | "ff" | "fileformat?" | "ff?", None) => {
+                self.status_message = StatusMessage::from(format!(
+                    "fileformat={}",
+                    self.document.line_ending().name()
+                ));
+            }
+            (_, None) if name.ends_with('?') => {
+                let value = self.config.get(name.trim_end_matches('?'))?;
+                self.status_message = StatusMessage::from(value);
+            }
+            (_, None) if name.ends_with('!') || name.starts_with("inv") => {
+                let name = name.trim_end_matches('!');
+                let name = name.strip_prefix("inv").unwrap_or(name);
+                self.config.toggle(name)?;
+                self.status_message = StatusMessage::from(self.config.get(name)?);
             }
             _ => {
-                self.doc_insert(c);
-                self.move_cursor(Key::Right);
+                self.config.set(setting)?;
+                if self.theme.name != self.config.colorscheme {
+                    self.theme = Theme::load(&self.config.colorscheme).unwrap_or_default();
+                }
             }
         }
+        Ok(())
     }
 
-    fn replace_mode(&mut self, c: char) {
-        self.doc_replace(c);
-        self.move_cursor(Key::Right);
+    fn set_eol(&mut self, eol: bool) {
+        if eol != self.document.eol() {
+            self.doc_edit(|editor| editor.document.set_eol(eol));
+        }
+        self.status_message = StatusMessage::from(eol_setting(eol).to_string());
     }
 
-    // TODO: arrow keys select and highlight portions of document
-    // from starting position, enter sets clipboard content to
-    // selection and returns to normal mode
-    // Store start and end selection cursor position.
-    // If row.y == start.y, highlight all characters >= start.x
-    // else if row.y == end.y, highlight all characters <= end.x
-    // else highlight entire row
-    fn visual_mode(&mut self, c: char) -> Result<()> {
-        self.selection_start = self.cursor_position.into();
-        self.switch_mode(Mode::Normal);
-        match c {
-            'h' | 'j' | 'k' | 'l' | 'c' | 'd' | 'y' => self.normal_mode(c)?,
-            _ => (),
+    fn show_stat(&mut self) {
+        let message = match &self.document.file_name {
+            Some(file_name) => match stat::describe(Path::new(file_name)) {
+                Ok(description) => description,
+                Err(error) => format!("ERR: Could not stat {}: {}", file_name, error),
+            },
+            None => "ERR: Buffer has no file".to_string(),
+        };
+        self.status_message = StatusMessage::from(message);
+    }
+
+    fn perform_command_safely<C>(&mut self, mut callback: C)
+    where
+        C: FnMut(&mut Self),
+    {
+        if self.contains_changes() {
+            self.status_message = StatusMessage::from(
+                "WARNING! File has unsaved changes: add ! to override.".to_string(),
+            );
+        } else {
+            callback(self);
         }
-        self.switch_mode(Mode::Visual);
-        Ok(())
     }
 
-    fn switch_mode(&mut self, mode: Mode) {
-        use Mode::*;
-        match mode {
-            Normal => {
-                let prev_mode = self.mode;
-                if prev_mode != Mode::Normal {
-                    self.mode = mode;
-                    self.move_cursor(Key::Left);
-                }
-                if prev_mode == Mode::Insert && self.document.is_dirty() {
-                    self.add_version();
+    fn run_builtin(&mut self, builtin: Builtin, bangs: usize, command: &ExCommand) -> Result<()> {
+        let arguments = command.args.trim_start();
+        let mut commands: Vec<&str> = vec![command.name];
+        commands.extend(arguments.split_whitespace());
+        let force = bangs > 0;
+        match builtin {
+            Builtin::Write => match bangs {
+                0 => self.save_in_background(false),
+                1 => self.save_in_background(true),
+                _ => self.save_privileged()?,
+            },
+            Builtin::SaveAs => self.save_as(&commands, force),
+            Builtin::ShowCwd => self.show_cwd(),
+            Builtin::WriteQuit => {
+                if self.save(force) {
+                    if force {
+                        self.quit();
+                    } else {
+                        self.quit_safely();
+                    }
                 }
             }
+            Builtin::Quit => {
+                if force {
+                    self.abandon();
+                } else {
+                    self.quit_safely();
+                }
+            }
+            Builtin::Edit => {
+                if force {
+                    self.open_file(&commands);
+                } else {
+                    self.perform_command_safely(|editor| editor.open_file(&commands));
+                }
+            }
+            Builtin::Cwd => self.cwd_command(&commands),
+            Builtin::DeleteFile => self.delete_file(),
+            Builtin::RenameFile => self.rename_file(&commands),
+            Builtin::Private => self.toggle_private(),
+            Builtin::Buffers => self.list_buffers(),
+            Builtin::Buffer => match commands.get(1) {
+                Some(id) => self.buffer_command(id),
+                None => self.pick_buffer()?,
+            },
+            Builtin::Stat => self.show_stat(),
+            Builtin::Stats => self.show_buffer_stats(),
+            Builtin::FixIndent => self.fix_indentation(),
+            Builtin::CheckPairs => self.check_pairs(),
+            Builtin::UndoList => self.undo_list(),
+            Builtin::Timeline => self.show_timeline(),
+            Builtin::Earlier => self.travel_in_time(&commands, SearchDirection::Backward)?,
+            Builtin::Later => self.travel_in_time(&commands, SearchDirection::Forward)?,
+            Builtin::Set => self.set_options(&commands),
+            Builtin::NoHighlight => self.set_search_highlight(None),
+            Builtin::Grep => self.grep(&commands[1..].join(" ")),
+            Builtin::Copen => self.open_quickfix_list(),
+            Builtin::Cnext => self.quickfix_step(SearchDirection::Forward),
+            Builtin::Cprevious => self.quickfix_step(SearchDirection::Backward),
+            Builtin::Command => self.user_command(arguments, force)?,
+            Builtin::DelCommand => match commands.get(1) {
+                Some(name) => {
+                    if self.config.commands.remove(*name).is_none() {
+                        self.status_message =
+                            StatusMessage::from(format!("ERR: No such user command: {}", name));
+                    }
+                }
+                None => {
+                    self.status_message =
+                        StatusMessage::from("ERR: No command name entered".to_string());
+                }
+            },
+            Builtin::Files => self.pick_file()?,
+            Builtin::Colorscheme => match commands.get(1) {
+                Some(name) => self.set_option(&format!("colorscheme={}", name))?,
+                None => self.pick_colorscheme()?,
+            },
+            Builtin::Marks => self.pick_mark()?,
+            Builtin::Registers => self.pick_register()?,
+            Builtin::Symbols => self.pick_symbol()?,
+            Builtin::Profile => {
+                self.profile_command(commands.get(1).copied(), commands.get(2).copied())
+            }
+            Builtin::Man => self.show_man_page(&commands[1..].join(" ")),
+            Builtin::Messages => {
+                let messages = self.messages.clone();
+                self.show_output("messages", messages);
+            }
+            Builtin::Tasks => self.show_tasks(),
+            Builtin::Clipboard => self.show_clipboard(),
+            Builtin::Cancel => self.cancel_tasks(commands.get(1).copied()),
+            Builtin::CheckHealth => {
+                let size = self.terminal.size();
+                let report = health::report(size.width, size.height);
+                self.show_output("checkhealth", report);
+            }
+            Builtin::Substitute => {
+                if let Err(error) = self.substitute(command) {
+                    self.status_message = StatusMessage::from(format!("ERR: {}", error));
+                }
+            }
+            Builtin::Cdo | Builtin::Cfdo => {
+                if let Err(error) = self.quickfix_do(command.args, builtin == Builtin::Cfdo) {
+                    self.status_message = StatusMessage::from(format!("ERR: {}", error));
+                }
+            }
+            Builtin::Greplace => {
+                if let Err(error) = self.preview_project_replace(command.args) {
+                    self.status_message = StatusMessage::from(format!("ERR: {}", error));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn execute_command(&mut self) -> Result<()> {
+        let completions = self.command_names();
+        let input = self.prompt_with_history(":", &[], &completions, |_, _, _| {})?;
+        if let Some(input) = input {
+            match excommand::parse(&input) {
+                Ok(command) => self.run_ex_command(&command)?,
+                Err(error) => self.status_message = StatusMessage::from(format!("ERR: {}", error)),
+            }
+        }
+        Ok(())
+    }
+
+    fn command_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = excommand::builtin_names()
+            .map(str::to_string)
+            .chain(self.config.commands.keys().cloned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn user_command(&mut self, definition: &str, overwrite: bool) -> Result<()> {
+        if definition.trim().is_empty() {
+            let lines: Vec<String> = self
+                .config
+                .commands
+                .iter()
+                .map(|(name, expansion)| format!("{:<12} {}", name, expansion))
+                .collect();
+            if lines.is_empty() {
+                self.status_message = StatusMessage::from("No user commands defined".to_string());
+            } else {
+                self.show_output("commands", lines);
+            }
+            return Ok(());
+        }
+        self.config.define_command(definition, overwrite)?;
+        Ok(())
+    }
+
+    fn run_user_command(&mut self, command: &ExCommand) -> Result<()> {
+        let Some(expansion) = self.config.commands.get(command.name).cloned() else {
+            return Err(anyhow!("Command not found: {}", command.name));
+        };
+        if self.command_depth >= MAX_COMMAND_DEPTH {
+            return Err(anyhow!("User commands nested too deeply"));
+        }
+        let expanded = expansion.replace("<args>", command.args);
+        self.command_depth = self.command_depth.saturating_add(1);
+        let result = excommand::parse(&expanded)
+            .and_then(|expanded_command| self.run_ex_command(&expanded_command));
+        self.command_depth = self.command_depth.saturating_sub(1);
+        result
+    }
+
+    fn run_ex_command(&mut self, command: &ExCommand) -> Result<()> {
+        if command.name.is_empty() && command.args.is_empty() {
+            if command.range.is_some() {
+                match self.resolve_range(command.range) {
+                    Ok((_, end)) => self.jump_to_line(end.saturating_add(1)),
+                    Err(error) => {
+                        self.status_message = StatusMessage::from(format!("ERR: {}", error))
+                    }
+                }
+            }
+            return Ok(());
+        }
+        if command.name.is_empty() {
+            if let Some(shell_command) = command.args.strip_prefix('!') {
+                if command.range.is_none() {
+                    self.run_shell_command(shell_command);
+                } else if let Err(error) = self.filter_lines(command.range, shell_command) {
+                    self.status_message = StatusMessage::from(format!("ERR: {:#}", error));
+                }
+                return Ok(());
+            }
+        }
+        if command
+            .name
+            .chars()
+            .next()
+            .map_or(false, |c| c.is_ascii_uppercase())
+        {
+            if command.range.is_some() {
+                self.status_message =
+                    StatusMessage::from(format!("ERR: No range allowed: {}", command.name));
+            } else if let Err(error) = self.run_user_command(command) {
+                self.status_message = StatusMessage::from(format!("ERR: {}", error));
+            }
+            return Ok(());
+        }
+        let Some((builtin, bangs)) = excommand::builtin(command.name) else {
+            self.status_message =
+                StatusMessage::from(format!("Command not found: {}", command.name));
+            return Ok(());
+        };
+        // Dropping a range would run the command on the whole buffer or the
+        // current file instead.
+        if command.range.is_some() && !builtin.range {
+            self.status_message =
+                StatusMessage::from(format!("ERR: No range allowed: {}", command.name));
+            return Ok(());
+        }
+        self.run_builtin(builtin.builtin, bangs, command)
+    }
+
+    fn resolve_line(&self, spec: &LineSpec) -> Result<usize> {
+        let last = self.document.len().saturating_sub(1);
+        let line = match spec.address {
+            Address::Current => self.cursor_position.y,
+            Address::Last => last,
+            Address::Line(line) => line.saturating_sub(1),
+            Address::Mark(name) => {
+                self.document
+                    .mark(name)
+                    .ok_or_else(|| anyhow!("Mark not set: {}", name))?
+                    .y
+            }
+        };
+        let line = i64::try_from(line)?.saturating_add(spec.offset);
+        Ok(cmp::min(usize::try_from(line).unwrap_or(0), last))
+    }
+
+    fn resolve_range(&self, range: Option<LineRange>) -> Result<(usize, usize)> {
+        let current = LineSpec {
+            address: Address::Current,
+            offset: 0,
+        };
+        match range.unwrap_or(LineRange::Single(current)) {
+            LineRange::Whole => Ok((0, self.document.len().saturating_sub(1))),
+            LineRange::Single(spec) => {
+                let line = self.resolve_line(&spec)?;
+                Ok((line, line))
+            }
+            LineRange::Span(start, end) => {
+                let start = self.resolve_line(&start)?;
+                let end = self.resolve_line(&end)?;
+                Ok((cmp::min(start, end), cmp::max(start, end)))
+            }
+        }
+    }
+
+    fn substitute(&mut self, command: &ExCommand) -> Result<()> {
+        let substitution = excommand::parse_substitution(command.args)?;
+        let (start, end) = self.resolve_range(command.range)?;
+        let pattern = if substitution.flags.contains('i') {
+            format!("(?i){}", substitution.pattern)
+        } else {
+            substitution.pattern.clone()
+        };
+        let regex = Regex::new(&pattern)?;
+        let global = substitution.flags.contains('g');
+        let found = (start..=end)
+            .filter_map(|y| self.document.row(y))
+            .any(|row| regex.is_match(&row.contents()));
+        if !found {
+            self.status_message =
+                StatusMessage::from(format!("Pattern not found: {}", substitution.pattern));
+            return Ok(());
+        }
+        let (substitutions, lines) = if substitution.flags.contains('c') {
+            self.substitute_interactively(&regex, &substitution.replacement, start, end, global)?
+        } else {
+            let mut result = (0, 0);
+            self.doc_edit(|editor| {
+                result = editor.document.substitute(
+                    &regex,
+                    &substitution.replacement,
+                    start,
+                    end,
+                    global,
+                );
+            });
+            result
+        };
+        self.readjust_cursor();
+        self.status_message = StatusMessage::from(format!(
+            "{} substitution{} on {} line{}",
+            substitutions,
+            if substitutions == 1 { "" } else { "s" },
+            lines,
+            if lines == 1 { "" } else { "s" }
+        ));
+        Ok(())
+    }
+
+    fn substitute_interactively(
+        &mut self,
+        regex: &Regex,
+        replacement: &str,
+        start: usize,
+        mut end: usize,
+        global: bool,
+    ) -> Result<(usize, usize)> {
+        let origin = self.cursor_position;
+        let mut substitutions: usize = 0;
+        let mut lines: usize = 0;
+        let mut last_changed = None;
+        let mut replace_all = false;
+        let mut y = start;
+        let mut from = 0;
+        while y <= end {
+            let Some(contents) = self.document.row(y).map(Row::contents) else {
+                break;
+            };
+            let Some(captures) = regex.captures_at(&contents, from) else {
+                y = y.saturating_add(1);
+                from = 0;
+                continue;
+            };
+            let Some(found) = captures.get(0) else {
+                break;
+            };
+            let (mut replace, mut stop) = (true, false);
+            if !replace_all {
+                self.cursor_position.y = y;
+                self.cursor_position.x = contents[..found.start()].graphemes(true).count();
+                self.cursor_position.max_x = self.cursor_position.x;
+                self.scroll();
+                self.substitute_match = Some((
+                    y,
+                    contents[..found.start()].graphemes(true).count(),
+                    contents[..found.end()].graphemes(true).count(),
+                ));
+                self.status_message = StatusMessage::from("replace? (y/n/a/q/l)".to_string());
+                let key = self.term_read_key_blocking();
+                self.substitute_match = None;
+                match key? {
+                    Key::Char('y') => (),
+                    Key::Char('n') => replace = false,
+                    Key::Char('a') => replace_all = true,
+                    Key::Char('l') => stop = true,
+                    Key::Char('q') | Key::Esc | Key::Ctrl('c') => break,
+                    _ => continue,
+                }
+            }
+            let mut next = found.end();
+            if replace {
+                let mut text = contents[..found.start()].to_string();
+                captures.expand(replacement, &mut text);
+                let added = text.matches('\n').count();
+                next = text.rfind('\n').map_or(text.len(), |index| {
+                    text.len().saturating_sub(index.saturating_add(1))
+                });
+                text.push_str(&contents[found.end()..]);
+                self.take_snapshot();
+                self.document.replace_line(y, &text);
+                substitutions = substitutions.saturating_add(1);
+                if last_changed != Some(y) {
+                    lines = lines.saturating_add(1);
+                }
+                y = y.saturating_add(added);
+                end = end.saturating_add(added);
+                last_changed = Some(y);
+            }
+            if stop {
+                break;
+            }
+            if !global {
+                y = y.saturating_add(1);
+                from = 0;
+            } else if found.start() == found.end() {
+                match contents[found.end()..].chars().next() {
+                    Some(c) => from = next.saturating_add(c.len_utf8()),
+                    None => {
+                        y = y.saturating_add(1);
+                        from = 0;
+                    }
+                }
+            } else {
+                from = next;
+            }
+        }
+        self.status_message = StatusMessage::from(String::new());
+        if substitutions > 0 {
+            let position = self.cursor_position;
+            self.cursor_position = origin;
+            self.doc_edit(|editor| editor.cursor_position = position);
+        } else {
+            self.cursor_position = origin;
+        }
+        Ok((substitutions, lines))
+    }
+
+    fn run_normal_command(&mut self, command: NormalCommand) -> Result<()> {
+        let register = match command.register {
+            None | Some('"') => None,
+            Some(c) => match Selection::from_register(c) {
+                Some(selection) => Some(selection),
+                None => {
+                    self.status_message =
+                        StatusMessage::from(format!("ERR: Unknown register \"{}", c));
+                    return Ok(());
+                }
+            },
+        };
+        self.register = register;
+        let count = command.count;
+        let result = match command.action {
+            Action::Key(Key::Ctrl(c @ ('a' | 'x'))) => {
+                self.add_to_number(count.unwrap_or(1), c == 'a');
+                Ok(())
+            }
+            Action::Key(Key::Char('G')) | Action::GCommand('g') if count.is_some() => {
+                let line = count.unwrap_or(1);
+                self.jump_to_line(cmp::min(line, self.document.len()));
+                Ok(())
+            }
+            Action::Key(Key::Char('.')) => self.repeat_last_change(count),
+            Action::Key(Key::Char(c)) => match count {
+                Some(count) if c.is_alphabetic() || c == '~' => self.counted_command(c, count),
+                _ => self.normal_mode(c),
+            },
+            Action::Key(key) => self.special_key(key),
+            Action::Operator(operator, motion) => {
+                self.apply_operator(operator, motion, count.unwrap_or(1))
+            }
+            Action::Replace(c) => {
+                self.replace_chars(c, count.unwrap_or(1));
+                Ok(())
+            }
+            Action::SetMark(name) => {
+                self.set_mark(name);
+                Ok(())
+            }
+            Action::JumpToMark { mark, linewise } => {
+                self.jump_to_mark(mark, linewise);
+                Ok(())
+            }
+            Action::GCommand(c) => self.g_command(c),
+            Action::ZCommand(c) => {
+                self.z_command(c);
+                Ok(())
+            }
+            Action::AddSurrounding(motion, target) => self.add_surrounding(motion, target),
+            Action::DeleteSurrounding(target) => self.change_surrounding(target, None),
+            Action::ChangeSurrounding(target, replacement) => {
+                self.change_surrounding(target, Some(replacement))
+            }
+        };
+        self.register = None;
+        if self.mode != Mode::Insert && self.document.is_dirty() {
+            self.add_version();
+        }
+        result
+    }
+
+    fn normal_mode(&mut self, c: char) -> Result<()> {
+        match c {
+            'h' => self.move_cursor(Key::Left),
+            'j' => self.move_cursor(Key::Down),
+            'k' => self.move_cursor(Key::Up),
+            'l' => self.move_cursor(Key::Right),
+            '0' => self.move_cursor(Key::Home),
+            '$' => self.move_cursor(Key::End),
+            'G' => {
+                self.record_jump();
+                self.cursor_position = CursorPosition::default();
+                self.cursor_position.y = self.document.len();
+            }
+            'a' | 'A' | 'i' | 'I' => {
+                if c == 'a' {
+                    self.move_cursor(Key::Right);
+                } else if c == 'A' {
+                    self.move_cursor(Key::End);
+                } else if c == 'I' {
+                    self.move_cursor(Key::Char(c));
+                }
+                self.switch_mode(Mode::Insert);
+            }
+            'o' | 'O' => {
+                self.switch_mode(Mode::Insert);
+                if c == 'o' {
+                    self.move_cursor(Key::Down);
+                }
+                self.doc_insert_line("");
+                if c == 'O' {
+                    self.move_cursor(Key::Down);
+                }
+            }
+            'x' | 's' => {
+                if let Some(row) = self.document.row(self.cursor_position.y) {
+                    let row_len = row.len();
+                    if row_len > 0 {
+                        self.doc_delete();
+                    }
+                    if c == 'x' && self.cursor_position.x == row_len.saturating_sub(1) {
+                        self.move_cursor(Key::Left);
+                    } else if c == 's' {
+                        self.switch_mode(Mode::Insert);
+                    }
+                }
+            }
+            'p' | 'P' | 'J' => self.counted_command(c, 1)?,
+            '~' => {
+                let start: Position = self.cursor_position.into();
+                let end = Position {
+                    x: start.x.saturating_add(1),
+                    y: start.y,
+                };
+                self.doc_edit(|editor| {
+                    editor
+                        .document
+                        .change_case(&start, &end, CaseChange::Toggle);
+                });
+                if self
+                    .document
+                    .row(start.y)
+                    .map_or(false, |row| end.x < row.len())
+                {
+                    self.move_cursor(Key::Right);
+                }
+            }
+            'R' => self.switch_mode(Mode::Replace),
+            'v' => self.switch_mode(Mode::Visual),
+            '/' => self.search(),
+            ':' => self.execute_command()?,
+            'u' => self.undo()?,
+            '\t' => self.jump_forward(),
+            'n' => self.move_to_search_term(SearchDirection::Forward),
+            'N' => self.move_to_search_term(SearchDirection::Backward),
+            _ => (),
+        }
+        if self.mode != Mode::Insert && self.document.is_dirty() {
+            self.add_version();
+        }
+        Ok(())
+    }
+
+    fn g_command(&mut self, c: char) -> Result<()> {
+        match c {
+            'g' => {
+                self.record_jump();
+                self.cursor_position = CursorPosition::default();
+            }
+            'f' => self.open_file_under_cursor(),
+            'n' => self.select_next_match(),
+            'v' => self.reselect(),
+            'r' => self.start_rename(),
+            '-' => self.step_chronologically(SearchDirection::Backward)?,
+            '+' => self.step_chronologically(SearchDirection::Forward)?,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::integer_division)]
+    fn z_command(&mut self, c: char) {
+        match c {
+            'h' => self.scroll_horizontally(1, false),
+            'l' => self.scroll_horizontally(1, true),
+            'H' => self.scroll_horizontally(self.text_width() / 2, false),
+            'L' => self.scroll_horizontally(self.text_width() / 2, true),
+            'a' => {
+                if !self.document.open_fold(self.cursor_position.y) {
+                    self.close_fold();
+                }
+            }
+            'o' => {
+                self.document.open_fold(self.cursor_position.y);
+            }
+            'c' => self.close_fold(),
+            'z' => self.align_view(ViewAnchor::Center),
+            't' => self.align_view(ViewAnchor::Top),
+            'b' => self.align_view(ViewAnchor::Bottom),
+            'R' => self.document.open_all_folds(),
+            'M' => {
+                self.document.close_all_folds();
+                self.snap_to_fold();
+            }
+            _ => (),
+        }
+    }
+
+    fn replace_chars(&mut self, c: char, count: usize) {
+        let at: Position = self.cursor_position.into();
+        let end = at.x.saturating_add(count);
+        if self.document.row(at.y).map_or(true, |row| end > row.len()) {
+            return;
+        }
+        self.doc_edit(|editor| {
+            for x in at.x..end {
+                editor.document.replace(&Position { x, y: at.y }, c);
+            }
+        });
+        let x = end.saturating_sub(1);
+        self.cursor_position = CursorPosition {
+            x,
+            y: at.y,
+            max_x: x,
+        };
+    }
+
+    fn close_fold(&mut self) {
+        match self.document.indent_fold(self.cursor_position.y) {
+            Some((start, end)) => {
+                self.document.close_fold(start, end);
+                self.snap_to_fold();
+            }
+            None => self.status_message = StatusMessage::from("ERR: No fold found".to_string()),
+        }
+    }
+
+    fn snap_to_fold(&mut self) {
+        if let Some((start, _)) = self
+            .document
+            .closed_fold(self.cursor_position.y)
+            .filter(|(start, _)| *start != self.cursor_position.y)
+        {
+            self.cursor_position.y = start;
+            self.cursor_position.x = self.document.left_space(start);
+            self.cursor_position.max_x = self.cursor_position.x;
+        }
+    }
+
+    fn add_to_number(&mut self, count: usize, increment: bool) {
+        let at: Position = self.cursor_position.into();
+        if !self
+            .document
+            .row(at.y)
+            .map_or(false, |row| row.has_number_after(at.x))
+        {
+            return;
+        }
+        let magnitude = i64::try_from(count).unwrap_or(i64::MAX);
+        let delta = if increment {
+            magnitude
+        } else {
+            magnitude.saturating_neg()
+        };
+        self.doc_edit(|editor| {
+            if let Some(x) = editor.document.add_to_number(&at, delta) {
+                editor.cursor_position = CursorPosition {
+                    x,
+                    y: at.y,
+                    max_x: x,
+                };
+            }
+        });
+    }
+
+    fn read_register(&mut self) -> Result<Option<Option<Selection>>> {
+        Ok(match self.read_command_key()? {
+            Key::Char('"') => Some(None),
+            Key::Char(c) => match Selection::from_register(c) {
+                Some(selection) => Some(Some(selection)),
+                None => {
+                    self.status_message =
+                        StatusMessage::from(format!("ERR: Unknown register \"{}", c));
+                    None
+                }
+            },
+            _ => None,
+        })
+    }
+
+    fn counted_command(&mut self, c: char, count: usize) -> Result<()> {
+        match c {
+            'p' | 'P' => self.doc_paste_clipboard(count, c == 'p'),
+            'J' => self.doc_join_lines(cmp::max(count, 2)),
+            _ => {
+                for _ in 0..count {
+                    self.normal_mode(c)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_operator(&mut self, operator: Operator, motion: Motion, count: usize) -> Result<()> {
+        let Some(span) = self.motion_span(motion, count) else {
+            return Ok(());
+        };
+        let line_end = |y: usize| self.document.row(y).map_or(0, Row::len);
+        let (start_y, end_y) = match span {
+            Span::Lines(start, end) => (start, end),
+            Span::Chars(start, end) => (start.y, end.y),
+        };
+        let (start, end) = match span {
+            Span::Lines(start, end) => (
+                Position { x: 0, y: start },
+                Position {
+                    x: line_end(end),
+                    y: end,
+                },
+            ),
+            Span::Chars(start, end) => (start, end),
+        };
+        match operator {
+            Operator::Delete | Operator::Yank | Operator::Change => {
+                let c = match operator {
+                    Operator::Delete => 'd',
+                    Operator::Yank => 'y',
+                    _ => 'c',
+                };
+                match span {
+                    Span::Lines(start, end) => self.lines_operator(c, start, end),
+                    Span::Chars(start, end) => self.range_operator(c, start, end),
+                }
+            }
+            Operator::ShiftRight | Operator::ShiftLeft => {
+                self.shift_lines(start_y, end_y, 1, operator == Operator::ShiftRight);
+                self.cursor_position.y = start_y;
+                self.move_cursor(Key::Char('I'));
+            }
+            Operator::Format => self.reflow_lines(start_y, end_y),
+            Operator::Comment => self.toggle_comments(start_y, end_y),
+            Operator::Lowercase | Operator::Uppercase | Operator::ToggleCase => {
+                let case = match operator {
+                    Operator::Lowercase => CaseChange::Lower,
+                    Operator::Uppercase => CaseChange::Upper,
+                    _ => CaseChange::Toggle,
+                };
+                self.doc_edit(|editor| editor.document.change_case(&start, &end, case));
+                self.cursor_position = start.into();
+                self.readjust_cursor();
+            }
+        }
+        Ok(())
+    }
+
+    fn motion_span(&mut self, motion: Motion, count: usize) -> Option<Span> {
+        let at: Position = self.cursor_position.into();
+        self.document.row(at.y)?;
+        let last = self.document.len().saturating_sub(1);
+        let line_end = |y: usize| self.document.row(y).map_or(0, Row::len);
+        Some(match motion {
+            Motion::Line => Span::Lines(
+                at.y,
+                cmp::min(at.y.saturating_add(count.saturating_sub(1)), last),
+            ),
+            Motion::Down => Span::Lines(at.y, cmp::min(at.y.saturating_add(count), last)),
+            Motion::Up => Span::Lines(at.y.saturating_sub(count), at.y),
+            Motion::FirstLine => Span::Lines(0, at.y),
+            Motion::LastLine => Span::Lines(at.y, last),
+            Motion::ParagraphEnd => {
+                let (_, end) = self.document.paragraph_range(at.y, false)?;
+                Span::Lines(at.y, end)
+            }
+            Motion::WordEnd => {
+                let mut end = at;
+                for index in 0..count {
+                    let around = index.saturating_add(1) < count;
+                    match self.document.word_range(&end, around) {
+                        Some((_, word_end)) => end = word_end,
+                        None if index > 0 => break,
+                        None => return None,
+                    }
+                }
+                Span::Chars(at, end)
+            }
+            Motion::LineEnd => Span::Chars(
+                at,
+                Position {
+                    x: line_end(at.y),
+                    y: at.y,
+                },
+            ),
+            Motion::LineStart => Span::Chars(Position { x: 0, y: at.y }, at),
+            Motion::NextMatch => {
+                let (start, end) = self.next_match_range()?;
+                Span::Chars(start, end)
+            }
+            Motion::Object {
+                object: 'p',
+                around,
+            } => {
+                let (start, end) = self.document.paragraph_range(at.y, around)?;
+                Span::Lines(start, end)
+            }
+            Motion::Object { object, around } => {
+                let (start, end) = self.text_object_range(object, around)?;
+                Span::Chars(start, end)
+            }
+        })
+    }
+
+    fn add_surrounding(&mut self, motion: Motion, target: char) -> Result<()> {
+        let at: Position = self.cursor_position.into();
+        let range = match motion {
+            Motion::Line => self.document.row(at.y).map(|row| {
+                (
+                    Position {
+                        x: row.indent_len(),
+                        y: at.y,
+                    },
+                    Position {
+                        x: row.len(),
+                        y: at.y,
+                    },
+                )
+            }),
+            _ => match self.motion_span(motion, 1) {
+                Some(Span::Chars(start, end)) => Some((start, end)),
+                Some(Span::Lines(start, end)) => Some((
+                    Position { x: 0, y: start },
+                    Position {
+                        x: self.document.row(end).map_or(0, Row::len),
+                        y: end,
+                    },
+                )),
+                None => None,
+            },
+        };
+        if let Some((start, end)) = range {
+            self.surround_range(start, end, target)?;
+        }
+        Ok(())
+    }
+
+    fn surround_range(&mut self, start: Position, end: Position, target: char) -> Result<()> {
+        let Some((opening, closing)) = self.surround_pair(target)? else {
+            return Ok(());
+        };
+        self.doc_edit(|editor| {
+            editor.document.insert_text(&end, &closing);
+            editor.document.insert_text(&start, &opening);
+            editor.cursor_position = start.into();
+        });
+        Ok(())
+    }
+
+    fn change_surrounding(&mut self, target: char, replacement: Option<char>) -> Result<()> {
+        let Some((open, close)) = self.surrounding(target) else {
+            self.status_message =
+                StatusMessage::from(format!("ERR: No surrounding {} found", target));
+            return Ok(());
+        };
+        let (opening, closing) = match replacement {
+            Some(replacement) => match self.surround_pair(replacement)? {
+                Some(pair) => pair,
+                None => return Ok(()),
+            },
+            None => (String::new(), String::new()),
+        };
+        self.doc_edit(|editor| {
+            editor.document.delete_range(&close.0, &close.1);
+            editor.document.insert_text(&close.0, &closing);
+            editor.document.delete_range(&open.0, &open.1);
+            editor.document.insert_text(&open.0, &opening);
+            editor.cursor_position = open.0.into();
+        });
+        self.readjust_cursor();
+        Ok(())
+    }
+
+    fn surrounding(&self, target: char) -> Option<((Position, Position), (Position, Position))> {
+        let at: Position = self.cursor_position.into();
+        if target == 't' {
+            let ((open_start, open_end), (close_start, close_end)) =
+                self.document.row(at.y)?.tag_bounds(at.x)?;
+            let position = |x: usize| Position { x, y: at.y };
+            return Some((
+                (position(open_start), position(open_end)),
+                (position(close_start), position(close_end)),
+            ));
+        }
+        if target == 'w' {
+            return None;
+        }
+        let (start, end) = self.text_object_range(target, false)?;
+        Some((
+            (
+                Position {
+                    x: start.x.saturating_sub(1),
+                    y: start.y,
+                },
+                start,
+            ),
+            (
+                end,
+                Position {
+                    x: end.x.saturating_add(1),
+                    y: end.y,
+                },
+            ),
+        ))
+    }
+
+    fn surround_pair(&mut self, target: char) -> Result<Option<(String, String)>> {
+        let pair = |opening: &str, closing: &str| Some((opening.to_string(), closing.to_string()));
+        Ok(match target {
+            '(' => pair("( ", " )"),
+            '[' => pair("[ ", " ]"),
+            '{' => pair("{ ", " }"),
+            ')' | 'b' => pair("(", ")"),
+            ']' => pair("[", "]"),
+            '}' | 'B' => pair("{", "}"),
+            '>' => pair("<", ">"),
+            't' | '<' => {
+                let tag = self.prompt("Tag: ", |_, _, _| {})?.unwrap_or_default();
+                let tag = tag.trim().trim_start_matches('<').trim_end_matches('>');
+                tag.split_whitespace()
+                    .next()
+                    .map(|name| (format!("<{}>", tag), format!("</{}>", name)))
+            }
+            c if c.is_alphanumeric() || c.is_whitespace() => None,
+            c => Some((c.to_string(), c.to_string())),
+        })
+    }
+
+    fn lines_operator(&mut self, operator: char, start: usize, end: usize) {
+        let lines: Vec<String> = (start..=end)
+            .filter_map(|y| self.document.row(y).map(Row::contents))
+            .collect();
+        self.set_clipboard(Clipboard {
+            text: lines.join("\n"),
+            linewise: true,
+        });
+        if operator == 'd' {
+            self.doc_delete_lines(start, end);
+            self.cursor_position.y = start;
+            self.move_cursor(Key::Home);
+        } else if operator == 'c' {
+            let len = self.document.row(end).map_or(0, Row::len);
+            self.doc_delete_range(&Position { x: 0, y: start }, &Position { x: len, y: end });
+            self.cursor_position = CursorPosition::default();
+            self.cursor_position.y = start;
+            self.switch_mode(Mode::Insert);
+        } else {
+            self.cursor_position.y = start;
+            self.readjust_cursor();
+        }
+    }
+
+    fn reflow_lines(&mut self, start: usize, end: usize) {
+        let width = match self.config.textwidth {
+            0 => DEFAULT_TEXTWIDTH,
+            width => width,
+        };
+        let mut lines = 0;
+        self.doc_edit(|editor| {
+            lines = editor.document.reflow_lines(start, end, width);
+        });
+        self.cursor_position = CursorPosition::from(Position {
+            x: 0,
+            y: start.saturating_add(lines.saturating_sub(1)),
+        });
+        self.readjust_cursor();
+    }
+
+    fn toggle_comments(&mut self, start: usize, end: usize) {
+        let mut toggled = false;
+        self.doc_edit(|editor| {
+            toggled = editor.document.toggle_comments(start, end);
+        });
+        if !toggled {
+            self.status_message = StatusMessage::from(format!(
+                "ERR: No comment syntax for {}",
+                self.document.file_type()
+            ));
+        }
+        self.readjust_cursor();
+    }
+
+    fn text_object_range(&self, object: char, around: bool) -> Option<(Position, Position)> {
+        let at: Position = self.cursor_position.into();
+        let pair = match object {
+            'w' => return self.document.word_range(&at, around),
+            '"' | '\'' | '`' => return self.document.quote_range(&at, object, around),
+            '(' | ')' | 'b' => ('(', ')'),
+            '{' | '}' | 'B' => ('{', '}'),
+            '[' | ']' => ('[', ']'),
+            '<' | '>' => ('<', '>'),
+            _ => return None,
+        };
+        let (open, close) = self.document.enclosing_pair(&at, pair.0, pair.1)?;
+        if around {
+            Some((
+                open,
+                Position {
+                    x: close.x.saturating_add(1),
+                    y: close.y,
+                },
+            ))
+        } else {
+            Some((
+                Position {
+                    x: open.x.saturating_add(1),
+                    y: open.y,
+                },
+                close,
+            ))
+        }
+    }
+
+    fn range_operator(&mut self, operator: char, start: Position, end: Position) {
+        self.set_clipboard(Clipboard {
+            text: self.document.text_range(&start, &end),
+            linewise: false,
+        });
+        if operator != 'y' {
+            self.doc_delete_range(&start, &end);
+        }
+        self.cursor_position = start.into();
+        if operator == 'c' {
+            self.switch_mode(Mode::Insert);
+        } else {
+            self.readjust_cursor();
+        }
+    }
+
+    fn next_match_range(&mut self) -> Option<(Position, Position)> {
+        let Some(query) = self.query.clone() else {
+            self.status_message = StatusMessage::from("ERR: No previous search".to_string());
+            return None;
+        };
+        let pattern = match SearchPattern::new(&query, self.config.regex_search) {
+            Ok(pattern) => pattern,
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("ERR: {}", error));
+                return None;
+            }
+        };
+        let at: Position = self.cursor_position.into();
+        let found = if self.document.match_at(&pattern, &at).is_some() {
+            Some(at)
+        } else {
+            self.document.find(&pattern, &at, SearchDirection::Forward)
+        };
+        let Some((y, (start, end))) =
+            found.and_then(|found| Some((found.y, self.document.match_at(&pattern, &found)?)))
+        else {
+            self.status_message = StatusMessage::from(format!("Pattern not found: {}", query));
+            return None;
+        };
+        self.highlighted_word = Some(pattern);
+        Some((Position { x: start, y }, Position { x: end, y }))
+    }
+
+    fn select_next_match(&mut self) {
+        if let Some((start, end)) = self.next_match_range() {
+            self.switch_mode(Mode::Visual);
+            self.selection_start = start;
+            self.cursor_position = CursorPosition::from(Position {
+                x: end.x.saturating_sub(1),
+                y: end.y,
+            });
+            self.scroll();
+        }
+    }
+
+    fn insert_mode(&mut self, c: char) {
+        match c {
+            '\t' if self.config.expand_tab => {
+                let width = self.config.shiftwidth;
+                let spaces = width.saturating_sub(self.cursor_column() % width);
+                for _ in 0..spaces {
+                    self.doc_insert(' ');
+                    self.move_cursor(Key::Right);
+                }
+            }
+            '\n' if self.rename.is_some() => self.switch_mode(Mode::Normal),
+            '\n' => {
+                let opens_block = self
+                    .document
+                    .opens_block(self.cursor_position.y, self.cursor_position.x);
+                let (tabstop, shiftwidth) = (self.config.tabstop, self.config.shiftwidth);
+                self.doc_edit(|editor| {
+                    editor.document.insert_newline(
+                        &editor.cursor_position.into(),
+                        tabstop,
+                        shiftwidth,
+                    );
+                });
+                self.move_cursor(Key::Right);
+                let mut spaces = 0;
+                if let Some(row) = self.document.row(self.cursor_position.y) {
+                    spaces = row.indent_len();
+                }
+                for _ in 0..spaces.saturating_sub(self.cursor_position.x) {
+                    self.move_cursor(Key::Right);
+                }
+                if opens_block {
+                    self.indent_new_block();
+                }
+            }
+            c if self.config.auto_pairs && self.document.closing_pair(c).is_some() => {
+                self.doc_insert(c);
+                self.move_cursor(Key::Right);
+                if let Some(closing) = self.document.closing_pair(c) {
+                    self.doc_insert(closing);
+                }
+            }
+            _ => {
+                if self.document.dedents_on(c) {
+                    self.dedent_line();
+                }
+                self.doc_insert(c);
+                self.move_cursor(Key::Right);
+                if !c.is_whitespace() {
+                    self.auto_wrap();
+                }
+            }
+        }
+    }
+
+    fn indent_new_block(&mut self) {
+        let y = self.cursor_position.y;
+        let (tabstop, use_tabs) = (self.config.tabstop, !self.config.expand_tab);
+        let base = self.document.indent_width(y, tabstop);
+        let closes = self
+            .document
+            .row(y)
+            .and_then(|row| row.contents().trim_start().chars().next())
+            .map_or(false, |c| self.document.dedents_on(c));
+        let width = self.config.shiftwidth;
+        self.doc_edit(|editor| {
+            if closes {
+                editor.document.insert(&editor.cursor_position.into(), '\n');
+                editor
+                    .document
+                    .set_indent(y.saturating_add(1), base, tabstop, use_tabs);
+            }
+            editor
+                .document
+                .set_indent(y, base.saturating_add(width), tabstop, use_tabs);
+        });
+        self.cursor_position.x = self.document.row(y).map_or(0, Row::indent_len);
+        self.cursor_position.max_x = self.cursor_position.x;
+    }
+
+    fn dedent_line(&mut self) {
+        let y = self.cursor_position.y;
+        let blank_before = self.document.row(y).map_or(false, |row| {
+            row.substring(0, self.cursor_position.x).trim().is_empty()
+        });
+        if !blank_before || self.cursor_position.x == 0 {
+            return;
+        }
+        let (tabstop, use_tabs) = (self.config.tabstop, !self.config.expand_tab);
+        let columns = self
+            .document
+            .indent_width(y, tabstop)
+            .saturating_sub(self.config.shiftwidth);
+        self.doc_edit(|editor| editor.document.set_indent(y, columns, tabstop, use_tabs));
+        self.cursor_position.x = self.document.row(y).map_or(0, Row::indent_len);
+        self.cursor_position.max_x = self.cursor_position.x;
+    }
+
+    fn auto_wrap(&mut self) {
+        if self.config.textwidth == 0 {
+            return;
+        }
+        let (width, tabstop) = (self.config.textwidth, self.config.tabstop);
+        let comments_only = self.config.wrap_comments;
+        let y = self.cursor_position.y;
+        let mut wrapped = None;
+        self.doc_edit(|editor| {
+            wrapped = editor.document.wrap_line(y, width, tabstop, comments_only);
+        });
+        if let Some((rest_start, prefix_len)) = wrapped {
+            if self.cursor_position.x >= rest_start {
+                self.cursor_position.x = self
+                    .cursor_position
+                    .x
+                    .saturating_sub(rest_start)
+                    .saturating_add(prefix_len);
+                self.cursor_position.y = y.saturating_add(1);
+            }
+        }
+    }
+
+    fn replace_mode(&mut self, c: char) {
+        self.doc_replace(c);
+        self.move_cursor(Key::Right);
+    }
+
+    // TODO: arrow keys select and highlight portions of document
+    // from starting position, enter sets clipboard content to
+    // selection and returns to normal mode
+    // Store start and end selection cursor position.
+    // If row.y == start.y, highlight all characters >= start.x
+    // else if row.y == end.y, highlight all characters <= end.x
+    // else highlight entire row
+    fn visual_mode(&mut self, c: char) -> Result<()> {
+        if let Some(digit) = c.to_digit(10) {
+            if digit > 0 || self.visual_count > 0 {
+                self.visual_count = self
+                    .visual_count
+                    .saturating_mul(10)
+                    .saturating_add(digit as usize);
+                return Ok(());
+            }
+        }
+        let count = cmp::max(self.visual_count, 1);
+        self.visual_count = 0;
+        self.mode = Mode::Normal;
+        match c {
+            'h' | 'j' | 'k' | 'l' => {
+                for _ in 0..count {
+                    self.normal_mode(c)?;
+                }
+            }
+            'd' | 'y' => {
+                let start = cmp::min(self.selection_start.y, self.cursor_position.y);
+                let end = cmp::max(self.selection_start.y, self.cursor_position.y);
+                self.mode = Mode::Visual;
+                self.switch_mode(Mode::Normal);
+                self.lines_operator(c, start, end);
+                self.readjust_cursor();
+                return Ok(());
+            }
+            '>' | '<' => self.shift_selection(count, c == '>'),
+            'p' | 'P' => {
+                let start = cmp::min(self.selection_start.y, self.cursor_position.y);
+                let end = cmp::max(self.selection_start.y, self.cursor_position.y);
+                self.mode = Mode::Visual;
+                self.switch_mode(Mode::Normal);
+                self.doc_replace_lines_with_clipboard(start, end, count);
+                return Ok(());
+            }
+            'S' => {
+                let start = cmp::min(self.selection_start.y, self.cursor_position.y);
+                let end = cmp::max(self.selection_start.y, self.cursor_position.y);
+                self.mode = Mode::Visual;
+                self.switch_mode(Mode::Normal);
+                if let Key::Char(target) = self.read_command_key()? {
+                    let indentation = self.document.row(start).map_or(0, Row::indent_len);
+                    let line_end = self.document.row(end).map_or(0, Row::len);
+                    self.surround_range(
+                        Position {
+                            x: indentation,
+                            y: start,
+                        },
+                        Position {
+                            x: line_end,
+                            y: end,
+                        },
+                        target,
+                    )?;
+                }
+                return Ok(());
+            }
+            '"' => {
+                if let Some(register) = self.read_register()? {
+                    if let Key::Char(c) = self.read_command_key()? {
+                        self.mode = Mode::Visual;
+                        self.register = register;
+                        let result = self.visual_mode(c);
+                        self.register = None;
+                        return result;
+                    }
+                }
+            }
+            'g' => {
+                let key = self.read_command_key()?;
+                if key == Key::Char('q') || key == Key::Char('c') {
+                    let start = cmp::min(self.selection_start.y, self.cursor_position.y);
+                    let end = cmp::max(self.selection_start.y, self.cursor_position.y);
+                    self.mode = Mode::Visual;
+                    self.switch_mode(Mode::Normal);
+                    if key == Key::Char('q') {
+                        self.reflow_lines(start, end);
+                    } else {
+                        self.toggle_comments(start, end);
+                    }
+                    return Ok(());
+                }
+            }
+            _ => (),
+        }
+        self.mode = Mode::Visual;
+        Ok(())
+    }
+
+    fn shift_selection(&mut self, count: usize, right: bool) {
+        let start = cmp::min(self.selection_start.y, self.cursor_position.y);
+        let end = cmp::max(self.selection_start.y, self.cursor_position.y);
+        self.shift_lines(start, end, count, right);
+    }
+
+    fn shift_lines(&mut self, start: usize, end: usize, count: usize, right: bool) {
+        let width = self.config.shiftwidth.saturating_mul(count);
+        let (tabstop, use_tabs) = (self.config.tabstop, !self.config.expand_tab);
+        let mut shifted = 0;
+        self.doc_edit(|editor| {
+            shifted = editor
+                .document
+                .shift_lines(start, end, width, right, tabstop, use_tabs);
+        });
+        self.readjust_cursor();
+        self.status_message = StatusMessage::from(format!(
+            "{} line{} {}ed {} time{}",
+            shifted,
+            if shifted == 1 { "" } else { "s" },
+            if right { '>' } else { '<' },
+            count,
+            if count == 1 { "" } else { "s" }
+        ));
+    }
+
+    fn reselect(&mut self) {
+        match (self.document.mark('<'), self.document.mark('>')) {
+            (Some(start), Some(end)) => {
+                self.switch_mode(Mode::Visual);
+                self.selection_start = start;
+                self.cursor_position = end.into();
+                self.readjust_cursor();
+            }
+            _ => {
+                self.status_message = StatusMessage::from("ERR: No previous selection".to_string())
+            }
+        }
+    }
+
+    fn set_visual_marks(&mut self) {
+        let start = self.selection_start;
+        let end: Position = self.cursor_position.into();
+        let (first, last) = if (start.y, start.x) <= (end.y, end.x) {
+            (start, end)
+        } else {
+            (end, start)
+        };
+        self.document.set_mark('<', first);
+        self.document.set_mark('>', last);
+    }
+
+    fn switch_mode(&mut self, mode: Mode) {
+        use Mode::*;
+        match mode {
+            Normal => {
+                let prev_mode = self.mode;
+                if prev_mode == Mode::Visual {
+                    self.set_visual_marks();
+                }
+                // A cancelled rename leaves the buffer as it was, which should
+                // not cost an undo step.
+                let cancelled = prev_mode == Mode::Insert
+                    && self.rename.take().is_some_and(|rename| {
+                        !self.finish_rename(rename)
+                            && self.document.lines()
+                                == self.versions[self.version_index].document.lines()
+                    });
+                if prev_mode != Mode::Normal {
+                    self.mode = mode;
+                    self.move_cursor(Key::Left);
+                }
+                if prev_mode == Mode::Insert && self.document.is_dirty() && !cancelled {
+                    self.add_version();
+                }
+            }
+            Visual => self.selection_start = self.cursor_position.into(),
             Insert => {
                 let prev_version = &mut self.versions[self.version_index];
                 prev_version.position = self.cursor_position;
@@ -833,48 +3815,1180 @@ impl Editor {
             }
             _ => (),
         }
-        self.mode = mode;
+        self.mode = mode;
+    }
+
+    // A count given to . replaces the one the change was made with.
+    fn repeat_last_change(&mut self, count: Option<usize>) -> Result<()> {
+        let Some(change) = self.last_change.clone() else {
+            return Ok(());
+        };
+        let mut command = change.command;
+        if count.is_some() {
+            command.count = count;
+        }
+        self.replay_keys = change.keys.into();
+        let result = self.run_normal_command(command);
+        self.replay_keys.clear();
+        result?;
+        for action in change.inserted {
+            self.run_insert_action(action);
+        }
+        if self.mode == Mode::Insert || self.mode == Mode::Replace {
+            self.switch_mode(Mode::Normal);
+        }
+        Ok(())
+    }
+
+    fn record_change(&mut self, prev_mode: Mode) {
+        let in_session = |mode: Mode| mode == Mode::Insert || mode == Mode::Replace;
+        if in_session(self.mode) {
+            return;
+        }
+        if (self.change_made && prev_mode == Mode::Normal) || in_session(prev_mode) {
+            if let Some(change) = self.change.take() {
+                self.last_change = Some(change);
+            }
+        }
+        self.change = None;
+        self.change_made = false;
+    }
+
+    fn process_keypress(&mut self, pressed_key: Key) -> Result<()> {
+        let pressed_key = if (self.mode == Mode::Normal || self.mode == Mode::Visual)
+            && self.output.is_none()
+            && !self.key_state.awaits_literal()
+        {
+            self.translate_key(pressed_key)
+        } else {
+            pressed_key
+        };
+        if self.mode != Mode::Normal
+            || self.output.is_some()
+            || self.config.mappings.is_empty()
+            || !self.key_state.is_idle()
+        {
+            return self.handle_keypress(pressed_key);
+        }
+        let file_type = self.document.file_type();
+        let mut keys = vec![pressed_key];
+        let mut matched: Option<(Vec<Key>, usize)> = None;
+        loop {
+            match keymap::lookup(&self.config.mappings, &file_type, &keys) {
+                Lookup::Exact(mapping, longer) => {
+                    matched = Some((mapping.rhs.clone(), keys.len()));
+                    if !longer {
+                        break;
+                    }
+                }
+                Lookup::Prefix => (),
+                Lookup::None => break,
+            }
+            let leader = keys.first() == Some(&self.config.leader);
+            if leader {
+                self.show_leader_mappings(&file_type, &keys);
+            }
+            let key = self.read_command_key();
+            self.output = None;
+            let key = key?;
+            if leader && key == Key::Esc {
+                return Ok(());
+            }
+            keys.push(key);
+        }
+        if let Some((rhs, len)) = matched {
+            keys = rhs.into_iter().chain(keys.into_iter().skip(len)).collect();
+        }
+        self.pending_keys.extend(keys);
+        while let Some(key) = self.pending_keys.pop_front() {
+            self.handle_keypress(key)?;
+        }
+        Ok(())
+    }
+
+    fn show_leader_mappings(&mut self, file_type: &str, keys: &[Key]) {
+        let lines: Vec<String> = keymap::continuations(&self.config.mappings, file_type, keys)
+            .iter()
+            .map(|mapping| {
+                format!(
+                    "{:<12} {}",
+                    keymap::format_keys(mapping.lhs.get(keys.len()..).unwrap_or_default()),
+                    keymap::format_keys(&mapping.rhs)
+                )
+            })
+            .collect();
+        let title = format!(
+            "<leader>{}",
+            keymap::format_keys(keys.get(1..).unwrap_or_default())
+        );
+        let mut pane = OutputPane::new(&title, lines);
+        pane.set_hint("press a key, Esc to cancel");
+        self.output = Some(pane);
+    }
+
+    fn handle_keypress(&mut self, pressed_key: Key) -> Result<()> {
+        if self.output.is_some() {
+            self.output_keypress(pressed_key);
+            return Ok(());
+        }
+        let prev_mode = self.mode;
+        let idle = self.key_state.is_idle();
+        if prev_mode != Mode::Insert && prev_mode != Mode::Replace && idle {
+            self.change_made = false;
+        }
+        match self.mode {
+            Mode::Normal => {
+                let step = self.key_state.feed(pressed_key);
+                if !idle || step == Step::Pending {
+                    self.status_message = StatusMessage::from(self.key_state.pending_text());
+                }
+                match step {
+                    Step::Pending | Step::Cancelled => return Ok(()),
+                    Step::Complete(command) => {
+                        if command.action != Action::Key(Key::Char('.')) {
+                            self.change = Some(Change {
+                                command,
+                                keys: Vec::new(),
+                                inserted: Vec::new(),
+                            });
+                        }
+                        self.run_normal_command(command)?;
+                    }
+                }
+            }
+            Mode::Insert | Mode::Replace => {
+                if let Some(action) = grammar::insert_action(pressed_key) {
+                    if let Some(change) = &mut self.change {
+                        change.inserted.push(action);
+                    }
+                    self.run_insert_action(action);
+                }
+            }
+            Mode::Visual => match pressed_key {
+                Key::Char(c) => self.visual_mode(c)?,
+                key => self.special_key(key)?,
+            },
+        }
+        self.sync_rename();
+        self.record_change(prev_mode);
+        self.scroll();
+        Ok(())
+    }
+
+    fn run_insert_action(&mut self, action: InsertAction) {
+        match action {
+            InsertAction::Insert(c) => {
+                if self.mode == Mode::Replace {
+                    self.replace_mode(c);
+                } else {
+                    self.insert_mode(c);
+                }
+            }
+            InsertAction::Exit => self.switch_mode(Mode::Normal),
+            InsertAction::Delete => {
+                self.doc_delete();
+            }
+            InsertAction::Backspace => {
+                if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
+                    self.move_cursor(Key::Left);
+                    if self.mode == Mode::Insert {
+                        let deleted = self.doc_delete();
+                        self.cursor_position.x = self.cursor_position.x.saturating_sub(deleted);
+                        self.cursor_position.max_x = self.cursor_position.x;
+                    }
+                }
+            }
+            InsertAction::Move(key) => self.move_cursor(key),
+        }
+    }
+
+    fn special_key(&mut self, key: Key) -> Result<()> {
+        match key {
+            Key::Esc => self.switch_mode(Mode::Normal),
+            Key::Delete => {
+                self.doc_delete();
+            }
+            Key::Backspace => {
+                if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
+                    self.move_cursor(Key::Left);
+                }
+            }
+            Key::Up
+            | Key::Down
+            | Key::Left
+            | Key::Right
+            | Key::PageUp
+            | Key::PageDown
+            | Key::End
+            | Key::Home => self.move_cursor(key),
+            Key::Ctrl('r') => {
+                if self.mode == Mode::Normal {
+                    self.redo()?
+                }
+            }
+            Key::Ctrl('o') => {
+                if self.mode == Mode::Normal {
+                    self.jump_back();
+                }
+            }
+            Key::Ctrl(c @ ('d' | 'u' | 'f' | 'b' | 'e' | 'y')) => {
+                if self.mode == Mode::Normal || self.mode == Mode::Visual {
+                    let height = self.terminal.size().height as usize;
+                    let half = cmp::max(height / 2, 1);
+                    let page = cmp::max(height.saturating_sub(2), 1);
+                    match c {
+                        'd' => self.scroll_view(half, true, half),
+                        'u' => self.scroll_view(half, false, half),
+                        'f' => self.scroll_view(page, true, 0),
+                        'b' => self.scroll_view(page, false, 0),
+                        'e' => self.scroll_view(1, true, 0),
+                        _ => self.scroll_view(1, false, 0),
+                    }
+                }
+            }
+            Key::Ctrl('6') => {
+                if self.mode == Mode::Normal {
+                    self.alternate_buffer();
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn start_rename(&mut self) {
+        let at: Position = self.cursor_position.into();
+        let word = self
+            .document
+            .word_range(&at, false)
+            .and_then(|(start, end)| {
+                let row = self.document.row(at.y)?;
+                Some((start, row.substring(start.x, end.x)))
+            })
+            .filter(|(_, word)| {
+                !word.is_empty() && word.chars().all(|c| c.is_alphanumeric() || c == '_')
+            });
+        let Some((start, word)) = word else {
+            self.status_message = StatusMessage::from("ERR: No word under cursor".to_string());
+            return;
+        };
+        let pattern = match SearchPattern::new(&format!(r"\b{}\b", regex::escape(&word)), true) {
+            Ok(pattern) => pattern,
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("ERR: {}", error));
+                return;
+            }
+        };
+        let occurrences = self.document.match_positions(&pattern);
+        let Some(primary) = occurrences.iter().position(|position| *position == start) else {
+            return;
+        };
+        let len = word.graphemes(true).count();
+        let points: Vec<Position> = occurrences
+            .iter()
+            .enumerate()
+            .map(|(index, position)| {
+                let before = occurrences
+                    .iter()
+                    .take(index)
+                    .filter(|earlier| earlier.y == position.y)
+                    .count();
+                Position {
+                    x: position.x.saturating_sub(before.saturating_mul(len)),
+                    y: position.y,
+                }
+            })
+            .collect();
+        self.switch_mode(Mode::Insert);
+        self.doc_edit(|editor| {
+            editor.document.delete_range(
+                &start,
+                &Position {
+                    x: start.x.saturating_add(len),
+                    y: start.y,
+                },
+            );
+        });
+        self.cursor_position = start.into();
+        self.status_message =
+            StatusMessage::from(format!("Renaming {} occurrences of {}", points.len(), word));
+        self.rename = Some(Rename {
+            row_len: self.document.row(start.y).map_or(0, Row::len),
+            points,
+            primary,
+            typed: String::new(),
+            text: word.clone(),
+            word,
+        });
+    }
+
+    // Until something is typed the other occurrences keep the original word,
+    // so leaving an empty rename puts the word back where it was deleted.
+    fn finish_rename(&mut self, rename: Rename) -> bool {
+        if !rename.typed.is_empty() {
+            self.status_message = StatusMessage::from(format!(
+                "Renamed {} occurrences of {} to {}",
+                rename.points.len(),
+                rename.word,
+                rename.typed
+            ));
+            return true;
+        }
+        let text_len = rename.text.graphemes(true).count();
+        let Some(y) = rename.points.get(rename.primary).map(|point| point.y) else {
+            return false;
+        };
+        let start = Position {
+            x: point_column(&rename.points, rename.primary, |_| text_len),
+            y,
+        };
+        self.doc_edit(|editor| {
+            editor.document.insert_text(&start, &rename.word);
+        });
+        self.cursor_position = start.into();
+        self.status_message = StatusMessage::from("Rename cancelled".to_string());
+        false
+    }
+
+    fn sync_rename(&mut self) {
+        let Some(mut rename) = self.rename.take() else {
+            return;
+        };
+        let Some(base) = rename.points.get(rename.primary).copied() else {
+            return;
+        };
+        if self.mode != Mode::Insert {
+            return;
+        }
+        let old_len = rename.text.graphemes(true).count();
+        let typed_len = rename.typed.graphemes(true).count();
+        let row_len = self.document.row(base.y).map_or(0, Row::len);
+        let primary_x = point_column(&rename.points, rename.primary, |_| old_len);
+        let Some(new_len) = typed_len
+            .saturating_add(row_len)
+            .checked_sub(rename.row_len)
+        else {
+            let _ = self.finish_rename(rename);
+            return;
+        };
+        let cursor = self.cursor_position;
+        if cursor.y != base.y
+            || cursor.x < primary_x
+            || cursor.x > primary_x.saturating_add(new_len)
+        {
+            let _ = self.finish_rename(rename);
+            return;
+        }
+        let typed = self.document.row(base.y).map_or(String::new(), |row| {
+            row.substring(primary_x, primary_x.saturating_add(new_len))
+        });
+        let text = if typed.is_empty() {
+            rename.word.clone()
+        } else {
+            typed.clone()
+        };
+        if text != rename.text {
+            let primary = rename.primary;
+            let len = |index: usize| if index == primary { new_len } else { old_len };
+            let points = &rename.points;
+            self.doc_edit(|editor| {
+                for (index, point) in points.iter().enumerate().rev() {
+                    if index == primary {
+                        continue;
+                    }
+                    let start = Position {
+                        x: point_column(points, index, len),
+                        y: point.y,
+                    };
+                    let end = Position {
+                        x: start.x.saturating_add(old_len),
+                        y: point.y,
+                    };
+                    editor.document.delete_range(&start, &end);
+                    editor.document.insert_text(&start, &text);
+                }
+            });
+            let text_len = text.graphemes(true).count();
+            let x = cursor
+                .x
+                .saturating_sub(primary_x)
+                .saturating_add(point_column(points, primary, |_| text_len));
+            self.cursor_position = CursorPosition {
+                x,
+                y: cursor.y,
+                max_x: x,
+            };
+            rename.text = text;
+        }
+        rename.typed = typed;
+        rename.row_len = self.document.row(base.y).map_or(0, Row::len);
+        self.rename = Some(rename);
+    }
+
+    fn output_keypress(&mut self, key: Key) {
+        let view_height = self.output_view_height();
+        let Some(output) = &mut self.output else {
+            return;
+        };
+        match key {
+            Key::Char('q') | Key::Esc => {
+                self.output = None;
+                self.timeline.clear();
+                if !self.pending_replace.is_empty() {
+                    self.pending_replace.clear();
+                    self.status_message = StatusMessage::from("Replace cancelled".to_string());
+                }
+            }
+            Key::Char('y') if !self.pending_replace.is_empty() => {
+                self.output = None;
+                self.apply_project_replace();
+            }
+            Key::Char('\n') => match output.selected() {
+                Some(index) if !self.timeline.is_empty() => self.jump_to_timeline_entry(index),
+                Some(index) => {
+                    self.output = None;
+                    self.jump_to_quickfix(index);
+                }
+                None => output.move_selection(1, true, view_height),
+            },
+            Key::Char('d') if !self.timeline.is_empty() => {
+                if let Some(index) = output.selected() {
+                    self.show_timeline_diff(index);
+                }
+            }
+            Key::Char('j') | Key::Down => output.move_selection(1, true, view_height),
+            Key::Char('k') | Key::Up => output.move_selection(1, false, view_height),
+            Key::Char(' ') | Key::PageDown | Key::Ctrl('d') => {
+                output.move_selection(view_height, true, view_height);
+            }
+            Key::PageUp | Key::Ctrl('u') => output.move_selection(view_height, false, view_height),
+            _ => (),
+        }
+        if self.timeline.is_empty() {
+            if let Some(index) = self.output.as_ref().and_then(OutputPane::selected) {
+                self.preview_quickfix(index);
+            }
+        }
+    }
+
+    fn preview_quickfix(&mut self, index: usize) {
+        if let Some(entry) = self.quickfix.get(index).cloned() {
+            self.show_preview(&entry.path, entry.line.saturating_sub(1));
+        }
+    }
+
+    // A file that cannot be previewed leaves the pane out rather than
+    // reporting an error for every candidate passed over.
+    fn show_preview(&mut self, path: &str, line: usize) {
+        match &mut self.preview {
+            Some(preview) if preview.path() == path => preview.set_line(line),
+            _ => self.preview = Preview::open(path, line).ok(),
+        }
+    }
+
+    fn grep(&mut self, pattern: &str) {
+        if pattern.is_empty() {
+            self.status_message = StatusMessage::from("ERR: No pattern entered".to_string());
+            return;
+        }
+        let search_pattern = match SearchPattern::new(pattern, true) {
+            Ok(search_pattern) => search_pattern,
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("ERR: {}", error));
+                return;
+            }
+        };
+        let root = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+        let pattern = pattern.to_string();
+        let name = format!("grep {}", pattern);
+        let buffer_id = self.buffer_id;
+        self.tasks.spawn(&name, move |cancel| {
+            let entries = quickfix::grep(&root, &search_pattern, cancel);
+            Some(TaskResult::Grep {
+                pattern,
+                entries,
+                buffer_id,
+            })
+        });
+        self.status_message = StatusMessage::from(format!("Searching for {}...", name));
+    }
+
+    fn show_grep_results(&mut self, pattern: &str, entries: Vec<QuickfixEntry>, buffer_id: usize) {
+        if entries.is_empty() {
+            self.status_message = StatusMessage::from(format!("No matches for {}", pattern));
+            return;
+        }
+        self.quickfix = QuickfixList::new(entries);
+        self.status_message =
+            StatusMessage::from(format!("{} matches for {}", self.quickfix.len(), pattern));
+        // Someone who has moved on to another buffer keeps their place; the
+        // list is still there for :copen or :cnext.
+        if buffer_id == self.buffer_id {
+            self.jump_to_quickfix(0);
+            self.open_quickfix_list();
+        }
+    }
+
+    fn preview_project_replace(&mut self, args: &str) -> Result<()> {
+        let substitution = excommand::parse_substitution(args)?;
+        let pattern = if substitution.flags.contains('i') {
+            format!("(?i){}", substitution.pattern)
+        } else {
+            substitution.pattern.clone()
+        };
+        let regex = Regex::new(&pattern)?;
+        let root = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+        let files = replace::plan(
+            &root,
+            &regex,
+            &substitution.replacement,
+            substitution.flags.contains('g'),
+        );
+        if files.is_empty() {
+            self.status_message =
+                StatusMessage::from(format!("Pattern not found: {}", substitution.pattern));
+            return Ok(());
+        }
+        if self.contains_changes() && self.current_file_in(&files) {
+            return Err(anyhow!("Save the current buffer before replacing in it"));
+        }
+        if let Some(buffer) = self
+            .buffers
+            .iter()
+            .find(|buffer| buffer.is_modified() && buffer_in(buffer, &files))
+        {
+            return Err(anyhow!(
+                "Save buffer {} ({}) before replacing in it",
+                buffer.id,
+                buffer.name()
+            ));
+        }
+        let replacements: usize = files.iter().map(|file| file.replacements).sum();
+        self.show_output("replace preview", replace::preview(&files));
+        self.status_message = StatusMessage::from(format!(
+            "{} replacement{} in {} file{}, press y to write or q to cancel",
+            replacements,
+            if replacements == 1 { "" } else { "s" },
+            files.len(),
+            if files.len() == 1 { "" } else { "s" }
+        ));
+        self.pending_replace = files;
+        Ok(())
+    }
+
+    fn apply_project_replace(&mut self) {
+        let files = std::mem::take(&mut self.pending_replace);
+        match replace::apply(&files) {
+            Ok(count) => {
+                if self.current_file_in(&files) {
+                    if let Some(file_name) = self.document.file_name.clone() {
+                        let position = self.cursor_position;
+                        self.open_target(&file_name);
+                        self.cursor_position = position;
+                        self.readjust_cursor();
+                    }
+                }
+                self.reload_hidden_buffers(&files);
+                self.status_message = StatusMessage::from(format!(
+                    "Wrote {} file{}",
+                    count,
+                    if count == 1 { "" } else { "s" }
+                ));
+            }
+            Err(error) => self.status_message = StatusMessage::from(format!("ERR: {}", error)),
+        }
+    }
+
+    fn current_file_in(&self, files: &[FileReplacement]) -> bool {
+        self.document
+            .file_name
+            .as_ref()
+            .map_or(false, |file_name| file_in(file_name, files))
+    }
+
+    // Hidden buffers were checked to be unmodified before writing, so they
+    // start over from the new contents.
+    fn reload_hidden_buffers(&mut self, files: &[FileReplacement]) {
+        for buffer in &mut self.buffers {
+            if !buffer_in(buffer, files) {
+                continue;
+            }
+            let Some(Ok(document)) = buffer
+                .document
+                .file_name
+                .as_ref()
+                .map(|file_name| Document::open(file_name))
+            else {
+                continue;
+            };
+            buffer.versions = vec![Version {
+                document: document.clone(),
+                timestamp: chrono::offset::Local::now(),
+                ..Version::default()
+            }];
+            buffer.document = document;
+            buffer.version_index = 0;
+            buffer.saved_version = Some(0);
+            if let Some(journal) = buffer.journal.take() {
+                journal.discard();
+            }
+        }
+    }
+
+    fn open_quickfix_list(&mut self) {
+        if self.quickfix.is_empty() {
+            self.status_message = StatusMessage::from("ERR: Quickfix list is empty".to_string());
+            return;
+        }
+        self.pending_replace.clear();
+        self.timeline.clear();
+        self.output = Some(OutputPane::selectable("quickfix", self.quickfix.lines()));
+        let view_height = self.output_view_height();
+        let index = self.quickfix.index().unwrap_or(0);
+        if let Some(output) = &mut self.output {
+            output.move_selection(index, true, view_height);
+        }
+        self.preview_quickfix(index);
+    }
+
+    fn quickfix_step(&mut self, direction: SearchDirection) {
+        let target = match direction {
+            SearchDirection::Forward => self
+                .quickfix
+                .index()
+                .map_or(Some(0), |index| index.checked_add(1)),
+            SearchDirection::Backward => {
+                self.quickfix.index().and_then(|index| index.checked_sub(1))
+            }
+        };
+        match target.filter(|index| *index < self.quickfix.len()) {
+            Some(index) => self.jump_to_quickfix(index),
+            None if self.quickfix.is_empty() => {
+                self.status_message =
+                    StatusMessage::from("ERR: Quickfix list is empty".to_string());
+            }
+            None => {
+                self.status_message = StatusMessage::from("ERR: No more items".to_string());
+            }
+        }
+    }
+
+    fn jump_to_quickfix(&mut self, index: usize) {
+        let Some(entry) = self.quickfix.get(index) else {
+            return;
+        };
+        if !self.is_current_file(&entry.path) && self.contains_changes() {
+            self.status_message = StatusMessage::from(
+                "WARNING! File has unsaved changes: add ! to override.".to_string(),
+            );
+            return;
+        }
+        self.visit_quickfix(index);
+    }
+
+    fn visit_quickfix(&mut self, index: usize) {
+        let Some(entry) = self.quickfix.get(index).cloned() else {
+            return;
+        };
+        if !self.is_current_file(&entry.path) {
+            self.open_target(&entry.path);
+        }
+        self.quickfix.select(index);
+        self.record_jump();
+        self.cursor_position = Position {
+            x: entry.column.saturating_sub(1),
+            y: entry.line.saturating_sub(1),
+        }
+        .into();
+        self.readjust_cursor();
+        self.scroll();
+        self.status_message = StatusMessage::from(format!(
+            "({} of {}) {}",
+            index.saturating_add(1),
+            self.quickfix.len(),
+            entry.text.trim()
+        ));
+    }
+
+    fn is_current_file(&self, path: &str) -> bool {
+        self.document.file_name.as_ref().map_or(false, |file_name| {
+            fs::canonicalize(file_name).ok() == fs::canonicalize(path).ok()
+        })
+    }
+
+    fn quickfix_do(&mut self, input: &str, per_file: bool) -> Result<()> {
+        if input.is_empty() {
+            return Err(anyhow!("No command entered"));
+        }
+        if self.quickfix.is_empty() {
+            return Err(anyhow!("Quickfix list is empty"));
+        }
+        if self.contains_changes() {
+            return Err(anyhow!("File has unsaved changes"));
+        }
+        let command = excommand::parse(input)?;
+        let targets = if per_file {
+            self.quickfix.file_starts()
+        } else {
+            (0..self.quickfix.len()).collect()
+        };
+        let mut files: usize = 0;
+        let mut previous: Option<String> = None;
+        for index in &targets {
+            let Some(path) = self.quickfix.select(*index).map(|entry| entry.path.clone()) else {
+                continue;
+            };
+            if previous.as_ref() != Some(&path) {
+                files = files.saturating_add(1);
+            }
+            // Changed files stay open as modified buffers for the user to
+            // review and write.
+            self.visit_quickfix(*index);
+            if !self.is_current_file(&path) {
+                return Err(anyhow!("Could not open {}", path));
+            }
+            self.run_ex_command(&command)?;
+            previous = Some(path);
+        }
+        self.status_message = StatusMessage::from(format!(
+            "Ran {} on {} entr{} in {} file{}, changes are not written",
+            input,
+            targets.len(),
+            if targets.len() == 1 { "y" } else { "ies" },
+            files,
+            if files == 1 { "" } else { "s" }
+        ));
+        Ok(())
+    }
+
+    fn show_output(&mut self, title: &str, lines: Vec<String>) {
+        self.pending_replace.clear();
+        self.timeline.clear();
+        self.output = Some(OutputPane::new(title, lines));
+    }
+
+    fn output_view_height(&self) -> usize {
+        self.output_height().saturating_sub(1)
+    }
+
+    fn output_height(&self) -> usize {
+        let max_height = cmp::max(usize::from(self.terminal.size().height) / 2, 2);
+        self.output
+            .as_ref()
+            .map_or(0, |output| output.height(max_height))
+    }
+
+    fn pick<C>(
+        &mut self,
+        title: &str,
+        candidates: Vec<Candidate>,
+        mut preview: C,
+    ) -> Result<Option<Candidate>>
+    where
+        C: FnMut(&mut Self, &Candidate),
+    {
+        if candidates.is_empty() {
+            self.status_message = StatusMessage::from(format!("ERR: No {} to pick from", title));
+            return Ok(None);
+        }
+        let total = candidates.len();
+        let mut picker = Picker::new(candidates);
+        let mut query = String::new();
+        let mut selected: usize = 0;
+        self.pending_replace.clear();
+        let picked = loop {
+            let mut pane = OutputPane::selectable(
+                &format!("{} ({} of {})", title, picker.len(), total),
+                picker.labels(),
+            );
+            pane.set_hint("Enter to pick, Esc to cancel");
+            self.output = Some(pane);
+            let view_height = self.output_view_height();
+            if let Some(output) = &mut self.output {
+                output.move_selection(selected, true, view_height);
+            }
+            if let Some(candidate) = picker.get(selected).cloned() {
+                preview(self, &candidate);
+            }
+            self.status_message = StatusMessage::from(format!("{}> {}", title, picker.query()));
+            self.refresh_screen()?;
+            match self.term_read_key_blocking()? {
+                Key::Esc | Key::Ctrl('c') => break None,
+                Key::Char('\n') => break picker.get(selected).cloned(),
+                Key::Up | Key::Ctrl('p') => selected = selected.saturating_sub(1),
+                Key::Down | Key::Ctrl('n') => {
+                    selected = cmp::min(selected.saturating_add(1), picker.len().saturating_sub(1));
+                }
+                Key::Backspace => {
+                    query.pop();
+                    picker.set_query(&query);
+                    selected = 0;
+                }
+                Key::Char(c) if !c.is_control() => {
+                    query.push(c);
+                    picker.set_query(&query);
+                    selected = 0;
+                }
+                _ => (),
+            }
+        };
+        self.output = None;
+        self.preview = None;
+        self.status_message = StatusMessage::from(String::new());
+        Ok(picked)
+    }
+
+    fn pick_file(&mut self) -> Result<()> {
+        let root = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+        let picked = self.pick(
+            "files",
+            picker::project_files(&root),
+            |editor, candidate| {
+                editor.show_preview(&candidate.value, 0);
+            },
+        )?;
+        if let Some(candidate) = picked {
+            self.perform_command_safely(|editor| editor.open_target(&candidate.value));
+        }
+        Ok(())
+    }
+
+    fn pick_colorscheme(&mut self) -> Result<()> {
+        let mut names = Theme::available();
+        names.sort_by_key(|name| *name != self.theme.name);
+        let candidates = names
+            .into_iter()
+            .map(|name| Candidate::new(name.clone(), name))
+            .collect();
+        let original = self.theme.clone();
+        let picked = self.pick("colorschemes", candidates, |editor, candidate| {
+            if let Ok(theme) = Theme::load(&candidate.value) {
+                editor.theme = theme;
+            }
+        })?;
+        let Some(candidate) = picked else {
+            self.theme = original;
+            return Ok(());
+        };
+        self.set_option(&format!("colorscheme={}", candidate.value))?;
+        self.status_message = match Config::persist("colorscheme", &candidate.value) {
+            Ok(()) => StatusMessage::from(format!("colorscheme={}", candidate.value)),
+            Err(error) => StatusMessage::from(format!(
+                "ERR: Could not save colorscheme to config: {}",
+                error
+            )),
+        };
+        Ok(())
+    }
+
+    fn pick_mark(&mut self) -> Result<()> {
+        let candidates = self
+            .document
+            .marks()
+            .into_iter()
+            .map(|(name, position)| {
+                let text = self
+                    .document
+                    .row(position.y)
+                    .map(Row::contents)
+                    .unwrap_or_default();
+                Candidate::new(
+                    format!(
+                        "{} {:>5}:{:<3} {}",
+                        name,
+                        position.y.saturating_add(1),
+                        position.x.saturating_add(1),
+                        text.trim()
+                    ),
+                    name.to_string(),
+                )
+            })
+            .collect();
+        let origin = self.cursor_position;
+        let origin_offset = self.offset;
+        let picked = self.pick("marks", candidates, |editor, candidate| {
+            if let Some(position) = candidate
+                .value
+                .chars()
+                .next()
+                .and_then(|name| editor.document.mark(name))
+            {
+                editor.cursor_position = position.into();
+                editor.readjust_cursor();
+                editor.scroll();
+            }
+        })?;
+        self.cursor_position = origin;
+        self.offset = origin_offset;
+        if let Some(position) = picked
+            .and_then(|candidate| candidate.value.chars().next())
+            .and_then(|name| self.document.mark(name))
+        {
+            self.record_jump();
+            self.cursor_position = position.into();
+            self.readjust_cursor();
+            self.scroll();
+        }
+        Ok(())
+    }
+
+    fn pick_buffer(&mut self) -> Result<()> {
+        let mut buffers: Vec<&Buffer> = self.buffers.iter().collect();
+        buffers.sort_by(|a, b| b.last_used.cmp(&a.last_used));
+        let candidates = buffers
+            .into_iter()
+            .map(|buffer| {
+                Candidate::new(
+                    format!(
+                        "{:>3} {}{}",
+                        buffer.id,
+                        buffer.name(),
+                        if buffer.is_modified() { " [+]" } else { "" }
+                    ),
+                    buffer.id.to_string(),
+                )
+            })
+            .collect();
+        if let Some(candidate) = self.pick("buffers", candidates, |_, _| ())? {
+            self.buffer_command(&candidate.value);
+        }
+        Ok(())
+    }
+
+    fn pick_register(&mut self) -> Result<()> {
+        let mut candidates = Vec::new();
+        if let Some(clipboard) = &self.clipboard {
+            candidates.push(Candidate::new(
+                format!("\"\" {}", register_preview(&clipboard.text)),
+                String::new(),
+            ));
+        }
+        for register in ['*', '+'] {
+            if let Some(text) = Selection::from_register(register)
+                .and_then(|selection| selection::paste(selection).ok().flatten())
+            {
+                candidates.push(Candidate::new(
+                    format!("\"{} {}", register, register_preview(&text)),
+                    register.to_string(),
+                ));
+            }
+        }
+        if let Some(candidate) = self.pick("registers", candidates, |_, _| ())? {
+            self.run_normal_command(NormalCommand {
+                count: None,
+                register: candidate.value.chars().next(),
+                action: Action::Key(Key::Char('p')),
+            })?;
+        }
+        Ok(())
+    }
+
+    fn pick_symbol(&mut self) -> Result<()> {
+        let candidates = self
+            .document
+            .symbols()
+            .into_iter()
+            .map(|(y, text)| {
+                Candidate::new(
+                    format!("{:>5} {}", y.saturating_add(1), text),
+                    y.to_string(),
+                )
+            })
+            .collect();
+        let origin = self.cursor_position;
+        let origin_offset = self.offset;
+        let picked = self.pick("symbols", candidates, |editor, candidate| {
+            if let Ok(y) = candidate.value.parse::<usize>() {
+                editor.cursor_position = Position { x: 0, y }.into();
+                editor.readjust_cursor();
+                editor.scroll();
+            }
+        })?;
+        self.cursor_position = origin;
+        self.offset = origin_offset;
+        if let Some(y) = picked.and_then(|candidate| candidate.value.parse::<usize>().ok()) {
+            self.record_jump();
+            self.cursor_position = Position { x: 0, y }.into();
+            self.move_cursor(Key::Char('I'));
+            self.scroll();
+        }
+        Ok(())
+    }
+
+    fn profile_command(&mut self, action: Option<&str>, path: Option<&str>) {
+        match action {
+            Some("start") => {
+                self.profiler = Some(Profiler::start());
+                self.status_message = StatusMessage::from("Profiling started".to_string());
+            }
+            Some("stop") => {
+                let Some(profiler) = self.profiler.take() else {
+                    self.status_message =
+                        StatusMessage::from("ERR: Profiling is not running".to_string());
+                    return;
+                };
+                let file_name = self
+                    .document
+                    .file_name
+                    .clone()
+                    .unwrap_or_else(|| "[No Name]".to_string());
+                let report = profiler.report(&file_name, self.document.len());
+                let written = profile::write_report(path, &report);
+                self.show_output("profile", report);
+                self.status_message = match written {
+                    Ok(path) => {
+                        StatusMessage::from(format!("Profile written to {}", path.display()))
+                    }
+                    Err(error) => {
+                        StatusMessage::from(format!("ERR: Could not write profile: {}", error))
+                    }
+                };
+            }
+            _ => {
+                self.status_message =
+                    StatusMessage::from("Usage: profile start|stop [file]".to_string());
+            }
+        }
+    }
+
+    fn show_man_page(&mut self, topic: &str) {
+        if topic.is_empty() {
+            self.status_message = StatusMessage::from("ERR: No topic entered".to_string());
+            return;
+        }
+        let width = usize::from(self.terminal.size().width);
+        match man::render(topic, width) {
+            Ok(lines) => {
+                self.pending_replace.clear();
+                self.output = Some(OutputPane::styled(&format!("man {}", topic), lines));
+            }
+            Err(error) => self.status_message = StatusMessage::from(format!("ERR: {}", error)),
+        }
+    }
+
+    fn filter_lines(&mut self, range: Option<LineRange>, command: &str) -> Result<()> {
+        let command = command.trim();
+        if command.is_empty() {
+            return Err(anyhow!("No command entered"));
+        }
+        if let Some(filter) = &self.filter {
+            if self.tasks.is_running(filter.task) {
+                return Err(anyhow!("{} is still running", filter.command));
+            }
+        }
+        let (start, end) = self.resolve_range(range)?;
+        let mut input: String = (start..=end)
+            .filter_map(|y| self.document.row(y).map(Row::contents))
+            .collect::<Vec<String>>()
+            .join("\n");
+        input.push('\n');
+        let shell_command = command.to_string();
+        let task = self.tasks.spawn(&format!("!{}", command), move |cancel| {
+            Some(TaskResult::Filter(run_filter(
+                &shell_command,
+                input,
+                cancel,
+            )))
+        });
+        self.filter = Some(Filter {
+            task,
+            buffer_id: self.buffer_id,
+            generation: self.document.generation(),
+            start,
+            end,
+            command: command.to_string(),
+        });
+        self.status_message = StatusMessage::from(format!(
+            "Filtering through {}... (:cancel {} to stop)",
+            command, task
+        ));
+        Ok(())
+    }
+
+    fn finish_filter(&mut self, id: usize, output: Result<Vec<String>>) {
+        let Some(filter) = self.filter.take_if(|filter| filter.task == id) else {
+            return;
+        };
+        let lines = match output {
+            Ok(lines) => lines,
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("ERR: {:#}", error));
+                return;
+            }
+        };
+        if filter.buffer_id != self.buffer_id || filter.generation != self.document.generation() {
+            self.status_message = StatusMessage::from(format!(
+                "ERR: The buffer changed while {} ran; its output was dropped",
+                filter.command
+            ));
+            return;
+        }
+        let Filter {
+            start,
+            end,
+            command,
+            ..
+        } = filter;
+        let y = self.cursor_position.y;
+        let cursor_line = self.document.row(y).map(Row::contents);
+        let line_count = lines.len();
+        self.doc_edit(|editor| editor.document.replace_lines(start, end, &lines));
+        self.cursor_position.y = if y < start {
+            y
+        } else if y > end {
+            y.saturating_sub(end.saturating_sub(start).saturating_add(1))
+                .saturating_add(line_count)
+        } else {
+            lines
+                .iter()
+                .enumerate()
+                .filter(|(_, line)| Some(*line) == cursor_line.as_ref())
+                .map(|(index, _)| start.saturating_add(index))
+                .min_by_key(|new_y| new_y.abs_diff(y))
+                .unwrap_or_else(|| cmp::min(y, start.saturating_add(line_count.saturating_sub(1))))
+        };
+        self.readjust_cursor();
+        self.status_message = StatusMessage::from(format!(
+            "{} line{} filtered through {}",
+            end.saturating_sub(start).saturating_add(1),
+            if start == end { "" } else { "s" },
+            command
+        ));
     }
 
-    fn process_keypress(&mut self, pressed_key: Key) -> Result<()> {
-        match pressed_key {
-            Key::Char(c) => match self.mode {
-                Mode::Insert => self.insert_mode(c),
-                Mode::Normal => self.normal_mode(c)?,
-                Mode::Replace => self.replace_mode(c),
-                Mode::Visual => self.visual_mode(c)?,
-            },
-            Key::Esc => self.switch_mode(Mode::Normal),
-            Key::Delete => {
-                self.doc_delete();
-            }
-            Key::Backspace => {
-                if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
-                    self.move_cursor(Key::Left);
-                    if self.mode == Mode::Insert {
-                        let deleted = self.doc_delete();
-                        self.cursor_position.x = self.cursor_position.x.saturating_sub(deleted);
-                        self.cursor_position.max_x = self.cursor_position.x;
-                    }
+    fn run_shell_command(&mut self, command: &str) {
+        let command = command.trim();
+        if command.is_empty() {
+            self.status_message = StatusMessage::from("ERR: No command entered".to_string());
+            return;
+        }
+        match Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .output()
+        {
+            Ok(output) => {
+                let mut lines: Vec<String> = String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .chain(String::from_utf8_lossy(&output.stderr).lines())
+                    .map(|line| line.replace('\t', "    "))
+                    .collect();
+                if !output.status.success() {
+                    lines.push(format!("[{}]", output.status));
                 }
-            }
-            Key::Up
-            | Key::Down
-            | Key::Left
-            | Key::Right
-            | Key::PageUp
-            | Key::PageDown
-            | Key::End
-            | Key::Home => self.move_cursor(pressed_key),
-            Key::Ctrl('r') => {
-                if self.mode == Mode::Normal {
-                    self.redo()?
+                if lines.is_empty() {
+                    self.status_message =
+                        StatusMessage::from(format!("!{} finished with no output", command));
+                } else {
+                    self.show_output(&format!("!{}", command), lines);
                 }
             }
-            _ => (),
+            Err(error) => {
+                self.status_message =
+                    StatusMessage::from(format!("ERR: Could not run {}: {}", command, error));
+            }
         }
-        self.scroll();
-        Ok(())
     }
 
     fn process_mouse_event(&mut self, me: MouseEvent) -> Result<()> {
@@ -882,53 +4996,111 @@ impl Editor {
         use MouseEvent::*;
         match me {
             Press(Left, x, y) => {
-                if y > self.terminal.size().height {
-                    return Ok(());
+                if self.mode == Mode::Visual {
+                    self.switch_mode(Mode::Normal);
                 }
-                let (mut x, mut y): (usize, usize) = (x.into(), y.into());
-                if self.document.row(y).is_some() {
-                    x = x.saturating_sub(5);
+                if let Some(position) = self.mouse_position(x, y) {
+                    self.record_jump();
+                    self.cursor_position = position;
+                    self.readjust_cursor();
                 }
-                x = x.saturating_sub(1).saturating_add(self.offset.x);
-                y = y.saturating_sub(1).saturating_add(self.offset.y);
-                self.cursor_position = CursorPosition {
-                    x,
-                    y,
-                    max_x: self.cursor_position.max_x,
-                };
-                self.readjust_cursor();
             }
-            Press(Right, _, _) | Press(Middle, _, _) => self.switch_mode(Mode::Insert),
-            Press(WheelDown, _, _) => {
-                self.offset.y = std::cmp::min(self.offset.y.saturating_add(1), self.document.len());
+            Press(Middle, x, y) => {
+                if let Some(position) = self.mouse_position(x, y) {
+                    self.cursor_position = position;
+                    self.readjust_cursor();
+                    self.register = Some(Selection::Primary);
+                    self.doc_paste_clipboard(1, false);
+                    self.register = None;
+                }
+            }
+            Press(Right, _, _) => self.switch_mode(Mode::Insert),
+            Press(button @ (WheelDown | WheelUp), _, _) => {
+                self.scroll_view(WHEEL_LINES, button == WheelDown, 0);
+                self.scroll();
+            }
+            Release(_x, _y) => {
+                if self.mode == Mode::Visual {
+                    let cursor: Position = self.cursor_position.into();
+                    let (start, mut end) = if (self.selection_start.y, self.selection_start.x)
+                        <= (cursor.y, cursor.x)
+                    {
+                        (self.selection_start, cursor)
+                    } else {
+                        (cursor, self.selection_start)
+                    };
+                    // The drag covers the character under the pointer too.
+                    end.x = cmp::min(
+                        end.x.saturating_add(1),
+                        self.document.row(end.y).map_or(0, Row::len),
+                    );
+                    let text = self.document.text_range(&start, &end);
+                    if let Err(error) = selection::copy(Selection::Primary, &text) {
+                        self.status_message = StatusMessage::from(format!("ERR: {}", error));
+                    }
+                }
             }
-            Press(WheelUp, _, _) => {
-                self.offset.y = self.offset.y.saturating_sub(1);
+            Hold(x, y) => {
+                if let Some(position) = self.mouse_position(x, y) {
+                    if self.mode != Mode::Visual {
+                        self.switch_mode(Mode::Visual);
+                    }
+                    self.cursor_position = position;
+                    self.readjust_cursor();
+                }
             }
-            Release(_x, _y) => (),
-            Hold(_x, _y) => (),
         }
         Ok(())
     }
 
+    fn mouse_position(&self, x: u16, y: u16) -> Option<CursorPosition> {
+        if y > self.terminal.size().height {
+            return None;
+        }
+        let y = self.document.line_at_display(
+            self.document
+                .display_line(self.offset.y)
+                .saturating_add(usize::from(y).saturating_sub(1)),
+        );
+        let mut x = usize::from(x).saturating_sub(1);
+        if self.document.row(y).is_some() {
+            x = x.saturating_sub(self.gutter_width());
+        }
+        let x = self.document.row(y).map_or(x, |row| {
+            row.index_at_column(x.saturating_add(self.offset.x), self.config.tabstop)
+        });
+        Some(CursorPosition {
+            x,
+            y,
+            max_x: self.cursor_position.max_x,
+        })
+    }
+
     fn scroll(&mut self) {
-        let CursorPosition { x, y, max_x: _ } = self.cursor_position;
-        let width = self.terminal.size().width as usize;
+        self.snap_to_fold();
+        let y = self.document.display_line(self.cursor_position.y);
+        let x = self.cursor_column();
+        let width = self.text_width();
         let height = self.terminal.size().height as usize;
-        let offset = &mut self.offset;
-        let screen_x = x.saturating_sub(offset.x);
-        let screen_y = y.saturating_sub(offset.y);
+        let mut offset_y = self.document.display_line(self.offset.y);
+        let screen_x = x.saturating_sub(self.offset.x);
+        let screen_y = y.saturating_sub(offset_y);
         let width_edge = width / 8;
         let height_edge = height / 5;
-        if y < offset.y {
-            offset.y = y.saturating_sub(height_edge);
+        let pinned = std::mem::take(&mut self.offset_pinned);
+        if pinned {
+            // The view was placed explicitly, so only the horizontal offset follows the cursor.
+        } else if y < offset_y {
+            offset_y = y.saturating_sub(height_edge);
         } else if screen_y < height_edge {
-            offset.y = offset.y.saturating_sub(height_edge - screen_y);
-        } else if y >= offset.y.saturating_add(height) {
-            offset.y = y.saturating_sub(height).saturating_add(height_edge + 1);
+            offset_y = offset_y.saturating_sub(height_edge - screen_y);
+        } else if y >= offset_y.saturating_add(height) {
+            offset_y = y.saturating_sub(height).saturating_add(height_edge + 1);
         } else if screen_y >= height - height_edge {
-            offset.y = offset.y.saturating_add(screen_y - (height - height_edge));
+            offset_y = offset_y.saturating_add(screen_y - (height - height_edge));
         }
+        self.offset.y = self.document.line_at_display(offset_y);
+        let offset = &mut self.offset;
         if x < offset.x {
             offset.x = x.saturating_sub(width_edge);
         } else if screen_x < width_edge {
@@ -940,6 +5112,102 @@ impl Editor {
         }
     }
 
+    fn align_view(&mut self, anchor: ViewAnchor) {
+        let y = self.document.display_line(self.cursor_position.y);
+        let height = self.terminal.size().height as usize;
+        let edge = height / 5;
+        let top = match anchor {
+            ViewAnchor::Top => y.saturating_sub(edge),
+            ViewAnchor::Center => y.saturating_sub(height / 2),
+            ViewAnchor::Bottom => y
+                .saturating_add(edge)
+                .saturating_add(1)
+                .saturating_sub(height),
+        };
+        self.offset.y = self.document.line_at_display(top);
+        self.offset_pinned = true;
+    }
+
+    fn scroll_view(&mut self, lines: usize, down: bool, cursor_lines: usize) {
+        let height = self.terminal.size().height as usize;
+        let edge = height / 5;
+        let last = self
+            .document
+            .display_line(self.document.len().saturating_sub(1));
+        let top = self.document.display_line(self.offset.y);
+        let top = if down {
+            cmp::min(top.saturating_add(lines), last)
+        } else {
+            top.saturating_sub(lines)
+        };
+        let cursor = self.document.display_line(self.cursor_position.y);
+        let cursor = if down {
+            cmp::min(cursor.saturating_add(cursor_lines), last)
+        } else {
+            cursor.saturating_sub(cursor_lines)
+        };
+        let lowest = if top == 0 {
+            0
+        } else {
+            top.saturating_add(edge)
+        };
+        let highest = cmp::min(
+            cmp::max(
+                lowest,
+                top.saturating_add(height)
+                    .saturating_sub(edge)
+                    .saturating_sub(1),
+            ),
+            last,
+        );
+        let cursor = cursor.clamp(cmp::min(lowest, highest), highest);
+        self.offset.y = self.document.line_at_display(top);
+        let max_x = self.cursor_position.max_x;
+        self.restore_cursor(CursorPosition {
+            x: max_x,
+            y: self.document.line_at_display(cursor),
+            max_x,
+        });
+        self.offset_pinned = true;
+    }
+
+    fn screen_line(&self, y: usize) -> usize {
+        self.document
+            .display_line(y)
+            .saturating_sub(self.document.display_line(self.offset.y))
+    }
+
+    #[allow(clippy::integer_division)]
+    fn scroll_horizontally(&mut self, amount: usize, right: bool) {
+        let width = self.text_width();
+        self.offset.x = if right {
+            self.offset.x.saturating_add(amount)
+        } else {
+            self.offset.x.saturating_sub(amount)
+        };
+        let edge = width / 8;
+        let min_column = if self.offset.x == 0 {
+            0
+        } else {
+            self.offset.x.saturating_add(edge)
+        };
+        let max_column = self
+            .offset
+            .x
+            .saturating_add(width)
+            .saturating_sub(edge)
+            .saturating_sub(1);
+        let column = self
+            .cursor_column()
+            .clamp(min_column, cmp::max(min_column, max_column));
+        if let Some(row) = self.document.row(self.cursor_position.y) {
+            let x = row.index_at_column(column, self.config.tabstop);
+            self.cursor_position.x = x;
+            self.cursor_position.max_x = x;
+            self.readjust_cursor();
+        }
+    }
+
     fn readjust_cursor(&mut self) {
         let CursorPosition {
             mut x,
@@ -963,6 +5231,12 @@ impl Editor {
         self.cursor_position = CursorPosition { x, y, max_x: x };
     }
 
+    fn restore_cursor(&mut self, position: CursorPosition) {
+        self.cursor_position = position;
+        self.readjust_cursor();
+        self.cursor_position.max_x = cmp::max(position.max_x, self.cursor_position.x);
+    }
+
     fn move_cursor(&mut self, key: Key) {
         let terminal_height = self.terminal.size().height as usize;
         let CursorPosition {
@@ -971,6 +5245,7 @@ impl Editor {
             mut max_x,
         } = self.cursor_position;
         let height = self.document.len();
+        let from_y = y;
         let mut width = if let Some(row) = self.document.row(y) {
             row.len()
         } else {
@@ -982,6 +5257,9 @@ impl Editor {
         match key {
             Key::Up => y = y.saturating_sub(1),
             Key::Down => {
+                if let Some((_, end)) = self.document.closed_fold(y) {
+                    y = end;
+                }
                 if y < height {
                     y = y.saturating_add(1);
                 }
@@ -1009,18 +5287,21 @@ impl Editor {
                 max_x = x;
             }
             Key::PageUp => {
-                y = if y > terminal_height {
-                    y.saturating_sub(terminal_height)
-                } else {
-                    0
-                }
+                y = self.document.line_at_display(
+                    self.document
+                        .display_line(y)
+                        .saturating_sub(terminal_height),
+                );
             }
             Key::PageDown => {
-                y = if y.saturating_add(terminal_height) < height {
-                    y.saturating_add(terminal_height)
-                } else {
-                    height
-                }
+                y = cmp::min(
+                    self.document.line_at_display(
+                        self.document
+                            .display_line(y)
+                            .saturating_add(terminal_height),
+                    ),
+                    height,
+                );
             }
             Key::Home => {
                 x = 0;
@@ -1036,6 +5317,12 @@ impl Editor {
             }
             _ => (),
         }
+        if let Some((start, _)) = self.document.closed_fold(y) {
+            y = start;
+        }
+        if y != from_y && matches!(key, Key::Up | Key::Down | Key::PageUp | Key::PageDown) {
+            max_x = self.carry_column(from_y, y, max_x);
+        }
         width = if let Some(row) = self.document.row(y) {
             row.len()
         } else {
@@ -1052,6 +5339,23 @@ impl Editor {
         self.cursor_position = CursorPosition { x, y, max_x }
     }
 
+    // Moving between lines keeps the screen column rather than the grapheme
+    // index, which differ once a line holds tabs or wide characters.
+    fn carry_column(&self, from: usize, to: usize, x: usize) -> usize {
+        let tabstop = self.config.tabstop;
+        let Some(column) = self.document.row(from).map(|row| row.column(x, tabstop)) else {
+            return x;
+        };
+        self.document.row(to).map_or(x, |row| {
+            let width = row.column(row.len(), tabstop);
+            if column >= width {
+                row.len().saturating_add(column.saturating_sub(width))
+            } else {
+                row.index_at_column(column, tabstop)
+            }
+        })
+    }
+
     fn draw_welcome_message(&self) {
         let mut welcome_message = format!("Hecto editor -- version {}\r", VERSION);
         let width = self.terminal.size().width as usize;
@@ -1061,52 +5365,284 @@ impl Editor {
         let spaces = " ".repeat(padding.saturating_sub(1));
         welcome_message = format!("~{}{}", spaces, welcome_message);
         welcome_message.truncate(width);
-        println!("{}\r", &welcome_message);
+        Terminal::print_line(&welcome_message);
+    }
+
+    fn is_selected_line(&self, y: usize) -> bool {
+        let start = cmp::min(self.selection_start.y, self.cursor_position.y);
+        let end = cmp::max(self.selection_start.y, self.cursor_position.y);
+        (start..=end).contains(&y)
+    }
+
+    fn cursor_column(&self) -> usize {
+        let x = self.cursor_position.x;
+        self.document
+            .row(self.cursor_position.y)
+            .map_or(x, |row| row.column(x, self.config.tabstop))
+    }
+
+    fn gutter_width(&self) -> usize {
+        if !self.config.number {
+            return 0;
+        }
+        let digits = self.document.len().max(1).to_string().len();
+        cmp::max(digits, MIN_NUMBER_WIDTH).saturating_add(1)
+    }
+
+    fn text_width(&self) -> usize {
+        usize::from(self.terminal.size().width).saturating_sub(self.gutter_width())
     }
 
     pub fn draw_row(&self, row: &Row, num: usize) {
-        let width = self.terminal.size().width as usize;
+        let width = self.text_width();
         let start = self.offset.x;
         let end = self.offset.x.saturating_add(width);
-        let render = row.render(start, end);
+        let row_width = row.column(row.len(), self.config.tabstop);
+        let continues_left = start > 0 && row_width > 0;
+        let continues_right = row_width > end;
+        let render = row.render(
+            if continues_left {
+                start.saturating_add(1)
+            } else {
+                start
+            },
+            if continues_right {
+                end.saturating_sub(1)
+            } else {
+                end
+            },
+            &self.theme,
+            self.config.tabstop,
+            self.config.list,
+            self.document.encoding(),
+        );
+        self.draw_gutter(row, num);
+        if continues_left {
+            Terminal::set_fg_color(self.theme.line_number);
+            Terminal::print("<");
+            Terminal::reset_fg_color();
+        }
+        let selected = self.mode == Mode::Visual && self.is_selected_line(num.saturating_sub(1));
+        if selected {
+            Terminal::set_bg_color(self.theme.selection_bg);
+        }
+        Terminal::print(&render);
+        if selected {
+            Terminal::reset_bg_color();
+        }
+        if continues_right {
+            Terminal::set_fg_color(self.theme.line_number);
+            Terminal::print(">");
+            Terminal::reset_fg_color();
+        }
+        Terminal::end_line();
+    }
+
+    fn draw_fold(&self, row: &Row, num: usize, lines: usize) {
+        self.draw_gutter(row, num);
+        let selected = self.mode == Mode::Visual && self.is_selected_line(num.saturating_sub(1));
+        if selected {
+            Terminal::set_bg_color(self.theme.selection_bg);
+        }
+        Terminal::set_fg_color(self.theme.line_number);
+        let text: String = format!("+-- {} lines folded", lines)
+            .chars()
+            .take(self.text_width())
+            .collect();
+        Terminal::print(&text);
+        Terminal::reset_fg_color();
+        if selected {
+            Terminal::reset_bg_color();
+        }
+        Terminal::end_line();
+    }
+
+    fn draw_gutter(&self, row: &Row, num: usize) {
+        let gutter_width = self.gutter_width();
+        if gutter_width == 0 {
+            return;
+        }
         let cursor_on_row = self.cursor_position.y == num.saturating_sub(1);
         if row.is_dirty() && !cursor_on_row {
-            Terminal::set_fg_color(color::Rgb(128, 0, 0));
+            Terminal::set_fg_color(self.theme.dirty_line_number);
         } else if row.is_dirty() && cursor_on_row {
-            Terminal::set_fg_color(color::Rgb(196, 72, 72));
+            Terminal::set_fg_color(self.theme.dirty_cursor_line_number);
         } else if !cursor_on_row {
-            Terminal::set_fg_color(color::Rgb(85, 85, 85));
+            Terminal::set_fg_color(self.theme.line_number);
+        }
+        Terminal::print(&format!(
+            "{:>width$}",
+            num,
+            width = gutter_width.saturating_sub(1)
+        ));
+        let inconsistent = self
+            .buffer_stats
+            .as_ref()
+            .map_or(false, |stats| stats.is_inconsistent(&row.contents()));
+        if inconsistent {
+            Terminal::set_fg_color(self.theme.indent_warning);
+            Terminal::print("!");
+        } else if row.is_dirty() && self.theme.uses_symbols() {
+            Terminal::print("+");
+        } else {
+            Terminal::print(" ");
         }
-        print!("{:>4} ", num);
         Terminal::reset_fg_color();
-        println!("{}\r", render);
     }
 
     #[allow(clippy::integer_arithmetic, clippy::integer_division)]
     fn draw_rows(&self) {
         let height = self.terminal.size().height;
-        for terminal_row in 0..height {
-            Terminal::clear_current_line();
-            let index = self.offset.y.saturating_add(terminal_row as usize);
+        let output_height = u16::try_from(self.output_height()).unwrap_or(height);
+        let preview_height = u16::try_from(self.preview_height()).unwrap_or(0);
+        let mut index = self
+            .document
+            .closed_fold(self.offset.y)
+            .map_or(self.offset.y, |(start, _)| start);
+        let text_height = height
+            .saturating_sub(output_height)
+            .saturating_sub(preview_height);
+        for terminal_row in 0..text_height {
+            let fold = self.document.closed_fold(index);
             if let Some(row) = self.document.row(index) {
                 let num = index.saturating_add(1);
-                self.draw_row(row, num);
+                match fold {
+                    Some((start, end)) => {
+                        self.draw_fold(row, num, end.saturating_sub(start).saturating_add(1));
+                        index = end;
+                    }
+                    None => self.draw_row(row, num),
+                }
+                index = index.saturating_add(1);
             } else if self.document.is_empty() && terminal_row == height / 3 {
                 self.draw_welcome_message();
             } else {
-                println!("~\r");
+                Terminal::print_line("~");
+            }
+        }
+        self.draw_preview();
+        self.draw_output();
+    }
+
+    // The preview takes the lower half of the space the output pane leaves,
+    // so the file and the list it was picked from stay on screen together.
+    #[allow(clippy::integer_division)]
+    fn preview_height(&self) -> usize {
+        if self.output.is_none() || self.preview.is_none() {
+            return 0;
+        }
+        let available =
+            usize::from(self.terminal.size().height).saturating_sub(self.output_height());
+        let height = available / 2;
+        if height < 3 {
+            0
+        } else {
+            height
+        }
+    }
+
+    fn draw_preview(&self) {
+        let height = self.preview_height();
+        let Some(preview) = self.preview.as_ref().filter(|_| height > 0) else {
+            return;
+        };
+        let width = usize::from(self.terminal.size().width);
+        let document = preview.document();
+        let view_height = height.saturating_sub(1);
+        let top = preview.top(view_height);
+        let mut header: String = format!(
+            " {}:{} [{}]",
+            preview.path(),
+            preview.line().saturating_add(1),
+            document.file_type()
+        )
+        .chars()
+        .take(width)
+        .collect();
+        header.push_str(&" ".repeat(width.saturating_sub(header.chars().count())));
+        Terminal::set_bg_color(self.theme.status_bg);
+        Terminal::set_fg_color(self.theme.status_fg);
+        Terminal::print(&header);
+        Terminal::reset_fg_color();
+        Terminal::reset_bg_color();
+        Terminal::end_line();
+        let number_width = top
+            .saturating_add(view_height)
+            .to_string()
+            .len()
+            .saturating_add(1);
+        for y in top..top.saturating_add(view_height) {
+            let Some(row) = document.row(y) else {
+                Terminal::print_line("~");
+                continue;
+            };
+            Terminal::set_fg_color(self.theme.line_number);
+            Terminal::print(&format!(
+                "{:>width$} ",
+                y.saturating_add(1),
+                width = number_width.saturating_sub(1)
+            ));
+            Terminal::reset_fg_color();
+            let render = row.render(
+                0,
+                width.saturating_sub(number_width),
+                &self.theme,
+                self.config.tabstop,
+                self.config.list,
+                preview.document().encoding(),
+            );
+            if y == preview.line() {
+                Terminal::set_bg_color(self.theme.selection_bg);
+                Terminal::print(&render);
+                Terminal::reset_bg_color();
+            } else {
+                Terminal::print(&render);
+            }
+            Terminal::end_line();
+        }
+    }
+
+    fn draw_output(&self) {
+        let Some(output) = &self.output else {
+            return;
+        };
+        let width = usize::from(self.terminal.size().width);
+        let view_height = self.output_view_height();
+        let mut header: String = output.header(view_height).chars().take(width).collect();
+        header.push_str(&" ".repeat(width.saturating_sub(header.chars().count())));
+        Terminal::set_bg_color(self.theme.status_bg);
+        Terminal::set_fg_color(self.theme.status_fg);
+        Terminal::print(&header);
+        Terminal::reset_fg_color();
+        Terminal::reset_bg_color();
+        Terminal::end_line();
+        for (selected, line, styles) in output.visible_lines(view_height) {
+            if !styles.is_empty() {
+                draw_styled_line(line, styles, width);
+                continue;
+            }
+            let line: String = line.chars().take(width).collect();
+            if selected {
+                Terminal::set_bg_color(self.theme.selection_bg);
+                Terminal::print(&line);
+                Terminal::print(&" ".repeat(width.saturating_sub(line.len())));
+                Terminal::reset_bg_color();
+                Terminal::end_line();
+            } else {
+                Terminal::print_line(&line);
             }
         }
     }
 
     fn draw_status_bar(&self) {
         let mut status;
-        let width = self.terminal.size().width.saturating_add(5) as usize;
+        let width = self.terminal.size().width as usize;
         let modified_indicator = if self.contains_changes() {
             " (modified)"
         } else {
             ""
         };
+        let private_indicator = if self.private { " [private]" } else { "" };
         let mut file_name = "[No Name]".to_string();
         if let Some(name) = &self.document.file_name {
             file_name = name.clone();
@@ -1120,32 +5656,43 @@ impl Editor {
         }
         .to_string();
         status = format!(
-            " {} | {} - {} lines{}",
+            " {} | {}{} - {} lines{}",
             mode,
             file_name,
+            private_indicator,
             self.document.len(),
             modified_indicator
         );
-        let progress = match self.offset.y {
-            n if n == 0 => "top".to_string(),
-            n if n
-                >= self
-                    .document
-                    .len()
-                    .saturating_sub(self.terminal.size().height as usize)
-                && self.cursor_position.y >= n =>
-            {
-                "bot".to_string()
-            }
-            _ => format!(
-                "%{}",
-                ((self.cursor_position.y as f64 / self.document.len() as f64) * 100.0) as usize
-            ),
-        };
+        if let Some((_, current, total)) = self.search_count {
+            status.push_str(&format!(" | match {} of {}", current, total));
+        }
+        if self.mode == Mode::Visual {
+            let start = cmp::min(self.selection_start.y, self.cursor_position.y);
+            let end = cmp::max(self.selection_start.y, self.cursor_position.y);
+            let lines = end.saturating_sub(start).saturating_add(1);
+            let chars: usize = (start..=end)
+                .filter_map(|y| self.document.row(y))
+                .map(Row::len)
+                .sum();
+            status.push_str(&format!(
+                " | {} line{}, {} char{} selected",
+                lines,
+                if lines == 1 { "" } else { "s" },
+                chars,
+                if chars == 1 { "" } else { "s" }
+            ));
+        }
+        let progress = progress_indicator(
+            self.config.progress,
+            self.cursor_position.y,
+            self.document.len(),
+        );
         let line_indicator = format!(
-            "{} {} | {:4}:{:<4} {:4}",
+            "{} {} | {} {} | {:4}:{:<4} {:4}",
             chrono::offset::Local::now().format("%Y-%m-%d %H:%M:%S"),
             self.document.file_type(),
+            self.document.encoding().name(),
+            self.document.line_ending().name(),
             self.cursor_position.y.saturating_add(1),
             self.cursor_position.x.saturating_add(1),
             progress
@@ -1154,33 +5701,94 @@ impl Editor {
         status.push_str(&" ".repeat(width.saturating_sub(len)));
         status = format!("{}{}", status, line_indicator);
         status.truncate(width);
-        Terminal::set_bg_color(STATUS_BG_COLOR);
-        Terminal::set_fg_color(STATUS_FG_COLOR);
-        println!("{}\r", status);
+        Terminal::set_bg_color(self.theme.status_bg);
+        Terminal::set_fg_color(self.theme.status_fg);
+        Terminal::print(&status);
         Terminal::reset_fg_color();
         Terminal::reset_bg_color();
+        Terminal::end_line();
     }
 
     fn draw_message_bar(&self) {
-        Terminal::clear_current_line();
         let message = &self.status_message;
-        if Instant::now() - message.time < Duration::new(5, 0) {
+        if Instant::now() < self.message_expiry() {
             let mut text = message.text.clone();
             text.truncate(self.terminal.size().width as usize);
-            print!("{}", text);
+            Terminal::print(&text);
+        }
+        Terminal::end_line();
+    }
+
+    fn prompt<C>(&mut self, prompt: &str, callback: C) -> Result<Option<String>>
+    where
+        C: FnMut(&mut Self, Key, &String),
+    {
+        self.prompt_with_history(prompt, &[], &[], callback)
+    }
+
+    fn prompt_secret(&mut self, prompt: &str) -> Result<Option<String>> {
+        let mut result = String::new();
+        loop {
+            self.status_message = StatusMessage::from(prompt.to_string());
+            self.refresh_screen()?;
+            match self.term_read_key_blocking()? {
+                Key::Backspace => {
+                    result.pop();
+                }
+                Key::Char('\n') => break,
+                Key::Char(c) if !c.is_control() => result.push(c),
+                Key::Esc | Key::Ctrl('c') => {
+                    result.clear();
+                    break;
+                }
+                _ => (),
+            }
+        }
+        self.status_message = StatusMessage::from(String::new());
+        if result.is_empty() {
+            return Ok(None);
         }
+        Ok(Some(result))
     }
 
-    fn prompt<C>(&mut self, prompt: &str, mut callback: C) -> Result<Option<String>>
+    fn prompt_with_history<C>(
+        &mut self,
+        prompt: &str,
+        history: &[String],
+        completions: &[String],
+        mut callback: C,
+    ) -> Result<Option<String>>
     where
         C: FnMut(&mut Self, Key, &String),
     {
         let mut result = String::new();
+        let mut draft = String::new();
+        let mut history_index: Option<usize> = None;
+        let mut completion: Option<(String, usize)> = None;
         loop {
             self.status_message = StatusMessage::from(format!("{}{}", prompt, result));
             self.refresh_screen()?;
             let key = self.term_read_key_blocking()?;
+            if key != Key::Char('\t') {
+                completion = None;
+            }
             match key {
+                Key::Char('\t') if !result.contains(char::is_whitespace) => {
+                    let prefix = completion
+                        .as_ref()
+                        .map_or_else(|| result.clone(), |(prefix, _)| prefix.clone());
+                    let matches: Vec<&String> = completions
+                        .iter()
+                        .filter(|candidate| candidate.starts_with(&prefix))
+                        .collect();
+                    if !matches.is_empty() {
+                        let index = completion
+                            .as_ref()
+                            .map_or(0, |(_, index)| index.saturating_add(1) % matches.len());
+                        result = matches.get(index).map(|m| (*m).clone()).unwrap_or_default();
+                        completion = Some((prefix, index));
+                    }
+                }
                 Key::Backspace => {
                     let graphemes_cnt = result.graphemes(true).count();
                     if graphemes_cnt == 0 {
@@ -1201,6 +5809,30 @@ impl Editor {
                     result.truncate(0);
                     break;
                 }
+                Key::Up if !history.is_empty() => {
+                    if history_index.is_none() {
+                        draft = result.clone();
+                    }
+                    let index = history_index.map_or(history.len().saturating_sub(1), |index| {
+                        index.saturating_sub(1)
+                    });
+                    history_index = Some(index);
+                    result = history.get(index).cloned().unwrap_or_default();
+                }
+                Key::Down => match history_index {
+                    Some(index) if index.saturating_add(1) < history.len() => {
+                        history_index = Some(index.saturating_add(1));
+                        result = history
+                            .get(index.saturating_add(1))
+                            .cloned()
+                            .unwrap_or_default();
+                    }
+                    Some(_) => {
+                        history_index = None;
+                        result = draft.clone();
+                    }
+                    None => (),
+                },
                 _ => (),
             }
             callback(self, key, &result);
@@ -1212,3 +5844,208 @@ impl Editor {
         Ok(Some(result))
     }
 }
+
+enum TimeOffset {
+    Steps(usize),
+    Duration(chrono::Duration),
+}
+
+fn parse_time_offset(input: &str) -> Option<TimeOffset> {
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let number: i64 = number.parse().ok()?;
+    match unit {
+        "" => Some(TimeOffset::Steps(usize::try_from(number).ok()?)),
+        "s" => chrono::Duration::try_seconds(number).map(TimeOffset::Duration),
+        "m" => chrono::Duration::try_minutes(number).map(TimeOffset::Duration),
+        "h" => chrono::Duration::try_hours(number).map(TimeOffset::Duration),
+        "d" => chrono::Duration::try_days(number).map(TimeOffset::Duration),
+        _ => None,
+    }
+}
+
+fn parse_file_target(target: &str) -> (&str, Option<Position>) {
+    if Path::new(target).exists() {
+        return (target, None);
+    }
+    let mut path = target.trim_end_matches(':');
+    let mut numbers = Vec::new();
+    while numbers.len() < 2 {
+        match path.rsplit_once(':') {
+            Some((head, tail)) if !tail.is_empty() && tail.chars().all(|c| c.is_ascii_digit()) => {
+                numbers.insert(0, tail.parse::<usize>().unwrap_or(1));
+                path = head;
+            }
+            _ => break,
+        }
+    }
+    match numbers.first() {
+        Some(line) => {
+            let column = numbers.get(1).copied().unwrap_or(1);
+            let position = Position {
+                x: column.saturating_sub(1),
+                y: line.saturating_sub(1),
+            };
+            (path, Some(position))
+        }
+        None => (target, None),
+    }
+}
+
+fn point_column(points: &[Position], index: usize, len: impl Fn(usize) -> usize) -> usize {
+    let Some(point) = points.get(index) else {
+        return 0;
+    };
+    points
+        .iter()
+        .take(index)
+        .enumerate()
+        .filter(|(_, earlier)| earlier.y == point.y)
+        .fold(point.x, |x, (earlier, _)| x.saturating_add(len(earlier)))
+}
+
+// Runs on the task pool. The child is polled rather than waited on so that
+// :cancel can kill a filter that hangs.
+fn run_filter(command: &str, input: String, cancel: &CancelToken) -> Result<Vec<String>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(|error| anyhow!("Could not run {}: {}", command, error))?;
+    let writer = child
+        .stdin
+        .take()
+        .map(|mut stdin| thread::spawn(move || io::Write::write_all(&mut stdin, input.as_bytes())));
+    let reader = |pipe: Option<Box<dyn io::Read + Send>>| {
+        thread::spawn(move || {
+            let mut bytes = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut bytes);
+            }
+            bytes
+        })
+    };
+    let stdout = reader(child.stdout.take().map(|pipe| Box::new(pipe) as _));
+    let stderr = reader(child.stderr.take().map(|pipe| Box::new(pipe) as _));
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if cancel.is_cancelled() {
+            // The shell may have left the command running as its own child,
+            // so the whole process group goes.
+            if let Ok(pid) = libc::pid_t::try_from(child.id()) {
+                // SAFETY: kill(2) on the process group the child leads.
+                unsafe {
+                    libc::kill(-pid, libc::SIGKILL);
+                }
+            }
+            let _ = child.wait();
+            return Err(anyhow!("{} was cancelled", command));
+        }
+        thread::sleep(FILTER_POLL_INTERVAL);
+    };
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        return Err(anyhow!(
+            "{} failed ({}){}",
+            command,
+            status,
+            stderr
+                .lines()
+                .last()
+                .map_or(String::new(), |line| format!(": {}", line))
+        ));
+    }
+    Ok(String::from_utf8(stdout)
+        .map_err(|_| anyhow!("{} produced invalid UTF-8", command))?
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+fn file_in(file_name: &str, files: &[FileReplacement]) -> bool {
+    let Ok(current) = fs::canonicalize(file_name) else {
+        return false;
+    };
+    files
+        .iter()
+        .any(|file| fs::canonicalize(&file.path).map_or(false, |path| path == current))
+}
+
+fn buffer_in(buffer: &Buffer, files: &[FileReplacement]) -> bool {
+    buffer
+        .document
+        .file_name
+        .as_ref()
+        .map_or(false, |file_name| file_in(file_name, files))
+}
+
+fn register_preview(text: &str) -> String {
+    let mut lines = text.lines();
+    let first = lines.next().unwrap_or_default();
+    match lines.count() {
+        0 => first.to_string(),
+        more => format!("{} (+{} lines)", first, more),
+    }
+}
+
+fn eol_setting(eol: bool) -> &'static str {
+    if eol {
+        "eol"
+    } else {
+        "noeol"
+    }
+}
+
+fn draw_styled_line(line: &str, styles: &[TextStyle], width: usize) {
+    let mut current = TextStyle::Plain;
+    for (c, style) in line.chars().zip(styles.iter()).take(width) {
+        if *style != current {
+            Terminal::reset_style();
+            match style {
+                TextStyle::Bold => Terminal::set_bold(),
+                TextStyle::Underline => Terminal::set_underline(),
+                TextStyle::BoldUnderline => {
+                    Terminal::set_bold();
+                    Terminal::set_underline();
+                }
+                TextStyle::Plain => (),
+            }
+            current = *style;
+        }
+        Terminal::print(c.encode_utf8(&mut [0; 4]));
+    }
+    Terminal::reset_style();
+    Terminal::end_line();
+}
+
+#[allow(clippy::integer_arithmetic, clippy::integer_division)]
+fn progress_indicator(style: ProgressStyle, y: usize, total: usize) -> String {
+    let line = cmp::min(y.saturating_add(1), cmp::max(total, 1));
+    let total = cmp::max(total, 1);
+    let percent = line.saturating_mul(100) / total;
+    match style {
+        ProgressStyle::Percent => format!("{}%", percent),
+        ProgressStyle::Lines => format!("{}/{}", line, total),
+        ProgressStyle::Bar => {
+            let filled = line.saturating_mul(PROGRESS_BAR_WIDTH) / total;
+            format!(
+                "[{}{}]",
+                "#".repeat(filled),
+                "-".repeat(PROGRESS_BAR_WIDTH.saturating_sub(filled))
+            )
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use anyhow::{anyhow, bail, Result};
+use std::borrow::Cow;
+use std::char::REPLACEMENT_CHARACTER;
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+const UTF16LE_BOM: &[u8] = b"\xff\xfe";
+const UTF16BE_BOM: &[u8] = b"\xfe\xff";
+// In a lossy buffer, bytes that are not valid UTF-8 are carried through
+// editing as characters from the end of the last private use plane, one per
+// byte. Real characters from that range are carried as the markers of their
+// own UTF-8 bytes, so they are written back unchanged.
+const MARKER_BASE: u32 = 0x10_FF00;
+
+#[derive(PartialEq, Clone, Copy, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Utf8Bom,
+    Utf8Lossy,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Self::Utf8),
+            "utf-8-bom" | "utf8-bom" => Some(Self::Utf8Bom),
+            "utf-8-lossy" | "utf8-lossy" => Some(Self::Utf8Lossy),
+            "utf-16le" | "utf16le" => Some(Self::Utf16Le),
+            "utf-16be" | "utf16be" | "utf-16" => Some(Self::Utf16Be),
+            "latin1" | "latin-1" | "iso-8859-1" => Some(Self::Latin1),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Utf8 => "utf-8",
+            Self::Utf8Bom => "utf-8-bom",
+            Self::Utf8Lossy => "utf-8-lossy",
+            Self::Utf16Le => "utf-16le",
+            Self::Utf16Be => "utf-16be",
+            Self::Latin1 => "latin1",
+        }
+    }
+
+    // A byte order mark settles it. Invalid UTF-8 is read as Latin-1 unless
+    // the file also holds proper UTF-8, or bytes that are control codes in
+    // Latin-1 (typically stray Windows-1252 quotes).
+    pub fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(UTF8_BOM) {
+            Self::Utf8Bom
+        } else if bytes.starts_with(UTF16LE_BOM) {
+            Self::Utf16Le
+        } else if bytes.starts_with(UTF16BE_BOM) {
+            Self::Utf16Be
+        } else if std::str::from_utf8(bytes).is_ok() {
+            Self::Utf8
+        } else if bytes.iter().any(|byte| (0x80..=0x9f).contains(byte))
+            || bytes.utf8_chunks().any(|chunk| !chunk.valid().is_ascii())
+        {
+            Self::Utf8Lossy
+        } else {
+            Self::Latin1
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> Result<String> {
+        match self {
+            Self::Utf8 | Self::Utf8Bom => {
+                let bytes = bytes.strip_prefix(UTF8_BOM).unwrap_or(bytes);
+                String::from_utf8(bytes.to_vec()).map_err(|_| anyhow!("File is not valid UTF-8"))
+            }
+            Self::Utf16Le | Self::Utf16Be => {
+                let bom = if self == Self::Utf16Le {
+                    UTF16LE_BOM
+                } else {
+                    UTF16BE_BOM
+                };
+                let bytes = bytes.strip_prefix(bom).unwrap_or(bytes);
+                let pairs = bytes.chunks_exact(2);
+                if !pairs.remainder().is_empty() {
+                    bail!("File is not valid {}: odd number of bytes", self.name());
+                }
+                let units: Vec<u16> = pairs
+                    .filter_map(|pair| match pair {
+                        [a, b] if self == Self::Utf16Le => Some(u16::from_le_bytes([*a, *b])),
+                        [a, b] => Some(u16::from_be_bytes([*a, *b])),
+                        _ => None,
+                    })
+                    .collect();
+                String::from_utf16(&units)
+                    .map_err(|_| anyhow!("File is not valid {}: unpaired surrogate", self.name()))
+            }
+            Self::Utf8Lossy => {
+                let mut text = String::with_capacity(bytes.len());
+                for chunk in bytes.utf8_chunks() {
+                    push_escaped(&mut text, chunk.valid());
+                    text.extend(chunk.invalid().iter().copied().map(byte_marker));
+                }
+                Ok(text)
+            }
+            Self::Latin1 => Ok(bytes.iter().copied().map(char::from).collect()),
+        }
+    }
+
+    pub fn encode(self, text: &str) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(text.len());
+        match self {
+            Self::Utf8 => bytes.extend_from_slice(text.as_bytes()),
+            Self::Utf8Bom => {
+                bytes.extend_from_slice(UTF8_BOM);
+                bytes.extend_from_slice(text.as_bytes());
+            }
+            Self::Utf8Lossy => {
+                for c in text.chars() {
+                    match marker_byte(c) {
+                        Some(byte) => bytes.push(byte),
+                        None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    }
+                }
+            }
+            Self::Utf16Le => {
+                bytes.extend_from_slice(UTF16LE_BOM);
+                for unit in text.encode_utf16() {
+                    bytes.extend_from_slice(&unit.to_le_bytes());
+                }
+            }
+            Self::Utf16Be => {
+                bytes.extend_from_slice(UTF16BE_BOM);
+                for unit in text.encode_utf16() {
+                    bytes.extend_from_slice(&unit.to_be_bytes());
+                }
+            }
+            Self::Latin1 => {
+                for c in text.chars() {
+                    let byte = u8::try_from(u32::from(c))
+                        .ok()
+                        .ok_or_else(|| anyhow!("'{}' cannot be written as {}", c, self.name()))?;
+                    bytes.push(byte);
+                }
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+fn byte_marker(byte: u8) -> char {
+    char::from_u32(MARKER_BASE.saturating_add(u32::from(byte))).unwrap_or(REPLACEMENT_CHARACTER)
+}
+
+pub fn marker_byte(c: char) -> Option<u8> {
+    u32::from(c)
+        .checked_sub(MARKER_BASE)
+        .and_then(|byte| u8::try_from(byte).ok())
+}
+
+fn push_escaped(text: &mut String, valid: &str) {
+    for c in valid.chars() {
+        if marker_byte(c).is_some() {
+            text.extend(c.encode_utf8(&mut [0; 4]).bytes().map(byte_marker));
+        } else {
+            text.push(c);
+        }
+    }
+}
+
+// Markers only stand for bytes in a lossy buffer, so a line moving into or out
+// of one is rewritten to keep meaning the same thing. Outside a lossy buffer a
+// raw byte is read as Latin-1 where that is the target, and is replaced
+// otherwise.
+pub fn convert(text: &str, from: Encoding, to: Encoding) -> Cow<'_, str> {
+    if from == to
+        || (from != Encoding::Utf8Lossy && to != Encoding::Utf8Lossy)
+        || !text.chars().any(|c| marker_byte(c).is_some())
+    {
+        return Cow::Borrowed(text);
+    }
+    if to == Encoding::Utf8Lossy {
+        let mut escaped = String::with_capacity(text.len());
+        push_escaped(&mut escaped, text);
+        return Cow::Owned(escaped);
+    }
+    Cow::Owned(
+        text.chars()
+            .map(|c| match marker_byte(c) {
+                Some(byte) if to == Encoding::Latin1 => char::from(byte),
+                Some(_) => REPLACEMENT_CHARACTER,
+                None => c,
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) -> Option<Vec<u8>> {
+        let encoding = Encoding::detect(bytes);
+        let text = encoding.decode(bytes).ok()?;
+        encoding.encode(&text).ok()
+    }
+
+    #[test]
+    fn byte_order_marks_pick_the_encoding() {
+        assert!(Encoding::detect(b"\xef\xbb\xbfhi") == Encoding::Utf8Bom);
+        assert!(Encoding::detect(b"\xff\xfeh\0i\0") == Encoding::Utf16Le);
+        assert!(Encoding::detect(b"\xfe\xff\0h\0i") == Encoding::Utf16Be);
+        assert_eq!(
+            Encoding::Utf8Bom.decode(b"\xef\xbb\xbfhi").ok().as_deref(),
+            Some("hi")
+        );
+        assert_eq!(
+            Encoding::Utf16Le
+                .decode(b"\xff\xfeh\0\xac\x20")
+                .ok()
+                .as_deref(),
+            Some("h\u{20ac}")
+        );
+        assert_eq!(
+            Encoding::Utf16Be
+                .decode(b"\xfe\xff\0h\x20\xac")
+                .ok()
+                .as_deref(),
+            Some("h\u{20ac}")
+        );
+        for bytes in [&b"\xff\xfeh\0i"[..], b"\xfe\xff\0h\0"] {
+            let encoding = Encoding::detect(bytes);
+            assert!(encoding.decode(bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn utf16_round_trips_with_its_byte_order_mark() {
+        for bytes in [&b"\xff\xfeh\0\xac\x20\n\0"[..], b"\xfe\xff\0h\x20\xac\0\n"] {
+            assert_eq!(round_trip(bytes).as_deref(), Some(bytes));
+        }
+    }
+
+    #[test]
+    fn latin1_is_detected_from_high_bytes_alone() {
+        assert!(Encoding::detect(b"caf\xe9 na\xefve\n") == Encoding::Latin1);
+        assert_eq!(
+            Encoding::Latin1.decode(b"caf\xe9").ok().as_deref(),
+            Some("caf\u{e9}")
+        );
+        assert_eq!(round_trip(b"caf\xe9\n").as_deref(), Some(&b"caf\xe9\n"[..]));
+        assert!(Encoding::Latin1.encode("\u{20ac}").is_err());
+    }
+
+    #[test]
+    fn windows_1252_or_mixed_utf8_is_not_latin1() {
+        assert!(Encoding::detect(b"\x93quoted\x94") == Encoding::Utf8Lossy);
+        assert!(Encoding::detect(b"caf\xc3\xa9 and caf\xe9") == Encoding::Utf8Lossy);
+    }
+
+    #[test]
+    fn valid_utf8_round_trips() {
+        let bytes = "plain ascii, caf\u{e9}, \u{4e2d}\u{6587}\n".as_bytes();
+        assert!(Encoding::detect(bytes) == Encoding::Utf8);
+        assert_eq!(round_trip(bytes).as_deref(), Some(bytes));
+    }
+
+    #[test]
+    fn invalid_bytes_round_trip() {
+        let bytes = b"caf\xe9 \xff\xfe\x80 ok \xc3\xa9\n";
+        assert!(Encoding::detect(bytes) == Encoding::Utf8Lossy);
+        assert_eq!(round_trip(bytes).as_deref(), Some(&bytes[..]));
+    }
+
+    #[test]
+    fn private_use_characters_are_kept() {
+        let valid = "a\u{10ff80}b\u{10ffff}c\u{10ff00}".as_bytes();
+        assert!(Encoding::detect(valid) == Encoding::Utf8);
+        assert_eq!(round_trip(valid).as_deref(), Some(valid));
+
+        let mut mixed = valid.to_vec();
+        mixed.extend_from_slice(b" \xff \xc3\xa9");
+        assert!(Encoding::detect(&mixed) == Encoding::Utf8Lossy);
+        assert_eq!(round_trip(&mixed), Some(mixed));
+    }
+
+    #[test]
+    fn converting_out_of_lossy_replaces_markers() {
+        let text = Encoding::Utf8Lossy
+            .decode(b"x\xe9\xf4\x8f\xbe\x80")
+            .unwrap_or_default();
+        assert_eq!(
+            convert(&text, Encoding::Utf8Lossy, Encoding::Latin1),
+            "x\u{e9}\u{f4}\u{8f}\u{be}\u{80}"
+        );
+        assert_eq!(
+            convert(&text, Encoding::Utf8Lossy, Encoding::Utf8),
+            "x\u{fffd}\u{fffd}\u{fffd}\u{fffd}\u{fffd}"
+        );
+    }
+
+    #[test]
+    fn converting_into_lossy_escapes_private_use_characters() {
+        let text = "a\u{10ff80}b";
+        let converted = convert(text, Encoding::Utf8, Encoding::Utf8Lossy);
+        assert_ne!(converted, text);
+        assert_eq!(
+            Encoding::Utf8Lossy.encode(&converted).ok().as_deref(),
+            Some(text.as_bytes())
+        );
+    }
+}
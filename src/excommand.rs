@@ -0,0 +1,474 @@
+use anyhow::{anyhow, bail, Result};
+
+#[derive(Clone, Copy)]
+pub enum Address {
+    Current,
+    Last,
+    Line(usize),
+    Mark(char),
+}
+
+#[derive(Clone, Copy)]
+pub struct LineSpec {
+    pub address: Address,
+    pub offset: i64,
+}
+
+#[derive(Clone, Copy)]
+pub enum LineRange {
+    Single(LineSpec),
+    Span(LineSpec, LineSpec),
+    Whole,
+}
+
+pub struct ExCommand<'a> {
+    pub range: Option<LineRange>,
+    pub name: &'a str,
+    pub args: &'a str,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Builtin {
+    Write,
+    SaveAs,
+    ShowCwd,
+    WriteQuit,
+    Quit,
+    Edit,
+    Cwd,
+    DeleteFile,
+    RenameFile,
+    Private,
+    Buffers,
+    Buffer,
+    Stat,
+    Stats,
+    FixIndent,
+    CheckPairs,
+    UndoList,
+    Timeline,
+    Earlier,
+    Later,
+    Set,
+    NoHighlight,
+    Grep,
+    Copen,
+    Cnext,
+    Cprevious,
+    Command,
+    DelCommand,
+    Files,
+    Colorscheme,
+    Marks,
+    Registers,
+    Symbols,
+    Profile,
+    Man,
+    Messages,
+    Tasks,
+    Clipboard,
+    Cancel,
+    CheckHealth,
+    Substitute,
+    Cdo,
+    Cfdo,
+    Greplace,
+}
+
+pub struct BuiltinCommand {
+    names: &'static [&'static str],
+    pub builtin: Builtin,
+    bangs: usize,
+    pub range: bool,
+}
+
+impl BuiltinCommand {
+    const fn new(names: &'static [&'static str], builtin: Builtin) -> Self {
+        Self {
+            names,
+            builtin,
+            bangs: 0,
+            range: false,
+        }
+    }
+
+    const fn bangs(self, bangs: usize) -> Self {
+        Self { bangs, ..self }
+    }
+
+    const fn range(self) -> Self {
+        Self {
+            range: true,
+            ..self
+        }
+    }
+}
+
+// Both the dispatcher and completion go through this table. The first name of
+// each command is the one offered for completion.
+const BUILTIN_COMMANDS: &[BuiltinCommand] = &[
+    BuiltinCommand::new(&["w"], Builtin::Write).bangs(2),
+    BuiltinCommand::new(&["saveas"], Builtin::SaveAs).bangs(1),
+    BuiltinCommand::new(&["p"], Builtin::ShowCwd),
+    BuiltinCommand::new(&["wq"], Builtin::WriteQuit).bangs(1),
+    BuiltinCommand::new(&["x"], Builtin::WriteQuit).bangs(1),
+    BuiltinCommand::new(&["q"], Builtin::Quit).bangs(1),
+    BuiltinCommand::new(&["e"], Builtin::Edit).bangs(1),
+    BuiltinCommand::new(&["c"], Builtin::Cwd),
+    BuiltinCommand::new(&["delete-file"], Builtin::DeleteFile),
+    BuiltinCommand::new(&["rename-file"], Builtin::RenameFile),
+    BuiltinCommand::new(&["private"], Builtin::Private),
+    BuiltinCommand::new(&["buffers"], Builtin::Buffers),
+    BuiltinCommand::new(&["ls"], Builtin::Buffers),
+    BuiltinCommand::new(&["buffer", "b"], Builtin::Buffer),
+    BuiltinCommand::new(&["stat"], Builtin::Stat),
+    BuiltinCommand::new(&["stats"], Builtin::Stats),
+    BuiltinCommand::new(&["fixindent"], Builtin::FixIndent),
+    BuiltinCommand::new(&["checkpairs"], Builtin::CheckPairs),
+    BuiltinCommand::new(&["undolist"], Builtin::UndoList),
+    BuiltinCommand::new(&["timeline"], Builtin::Timeline),
+    BuiltinCommand::new(&["earlier"], Builtin::Earlier),
+    BuiltinCommand::new(&["later"], Builtin::Later),
+    BuiltinCommand::new(&["set", "se"], Builtin::Set),
+    BuiltinCommand::new(&["nohlsearch", "noh"], Builtin::NoHighlight),
+    BuiltinCommand::new(&["grep"], Builtin::Grep),
+    BuiltinCommand::new(&["copen"], Builtin::Copen),
+    BuiltinCommand::new(&["cwindow", "cw"], Builtin::Copen),
+    BuiltinCommand::new(&["cnext", "cn"], Builtin::Cnext),
+    BuiltinCommand::new(&["cprevious", "cp", "cN", "cprev"], Builtin::Cprevious),
+    BuiltinCommand::new(&["command", "com"], Builtin::Command).bangs(1),
+    BuiltinCommand::new(&["delcommand", "delc"], Builtin::DelCommand),
+    BuiltinCommand::new(&["files"], Builtin::Files),
+    BuiltinCommand::new(&["colorscheme", "colo"], Builtin::Colorscheme),
+    BuiltinCommand::new(&["marks"], Builtin::Marks),
+    BuiltinCommand::new(&["registers", "reg"], Builtin::Registers),
+    BuiltinCommand::new(&["symbols"], Builtin::Symbols),
+    BuiltinCommand::new(&["profile"], Builtin::Profile),
+    BuiltinCommand::new(&["man"], Builtin::Man),
+    BuiltinCommand::new(&["messages"], Builtin::Messages),
+    BuiltinCommand::new(&["tasks"], Builtin::Tasks),
+    BuiltinCommand::new(&["clipboard"], Builtin::Clipboard),
+    BuiltinCommand::new(&["cancel"], Builtin::Cancel),
+    BuiltinCommand::new(&["checkhealth"], Builtin::CheckHealth),
+    BuiltinCommand::new(&["substitute", "s"], Builtin::Substitute).range(),
+    BuiltinCommand::new(&["cdo"], Builtin::Cdo),
+    BuiltinCommand::new(&["cfdo"], Builtin::Cfdo),
+    BuiltinCommand::new(&["greplace", "gr"], Builtin::Greplace),
+];
+
+// Returns the command along with how many bangs followed its name.
+pub fn builtin(name: &str) -> Option<(&'static BuiltinCommand, usize)> {
+    let base = name.trim_end_matches('!');
+    let bangs = name.len().saturating_sub(base.len());
+    BUILTIN_COMMANDS
+        .iter()
+        .find(|command| command.names.contains(&base))
+        .filter(|command| bangs <= command.bangs)
+        .map(|command| (command, bangs))
+}
+
+pub fn builtin_names() -> impl Iterator<Item = &'static str> {
+    BUILTIN_COMMANDS
+        .iter()
+        .filter_map(|command| command.names.first().copied())
+}
+
+pub struct Substitution {
+    pub pattern: String,
+    pub replacement: String,
+    pub flags: String,
+}
+
+pub fn parse(input: &str) -> Result<ExCommand<'_>> {
+    let input = input.trim();
+    let (range, rest) = parse_range(input)?;
+    let rest = rest.trim_start();
+    let name_len = rest
+        .find(|c: char| !c.is_ascii_alphabetic() && c != '-')
+        .unwrap_or(rest.len());
+    let (name, args) = rest.split_at(name_len);
+    let bang_len = args.find(|c: char| c != '!').unwrap_or(args.len());
+    let name_len = name_len.saturating_add(bang_len);
+    let (name, args) = if bang_len > 0 && !name.is_empty() {
+        rest.split_at(name_len)
+    } else {
+        (name, args)
+    };
+    Ok(ExCommand {
+        range,
+        name,
+        args: args.trim(),
+    })
+}
+
+pub fn parse_substitution(args: &str) -> Result<Substitution> {
+    let mut chars = args.chars();
+    let delimiter = chars
+        .next()
+        .filter(|c| !c.is_alphanumeric() && !c.is_whitespace() && *c != '\\')
+        .ok_or_else(|| anyhow!("Usage: s/pattern/replacement/flags"))?;
+    let mut parts = vec![String::new()];
+    let mut escaped = false;
+    for c in chars {
+        let count = parts.len();
+        let part = parts
+            .last_mut()
+            .ok_or_else(|| anyhow!("Invalid substitution"))?;
+        if escaped {
+            if c != delimiter {
+                part.push('\\');
+            }
+            part.push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == delimiter && count < 3 {
+            parts.push(String::new());
+        } else {
+            part.push(c);
+        }
+    }
+    if escaped {
+        if let Some(part) = parts.last_mut() {
+            part.push('\\');
+        }
+    }
+    let mut parts = parts.into_iter();
+    let pattern = parts.next().unwrap_or_default();
+    if pattern.is_empty() {
+        bail!("Empty search pattern");
+    }
+    Ok(Substitution {
+        pattern,
+        replacement: convert_replacement(&parts.next().unwrap_or_default()),
+        flags: parts.next().unwrap_or_default(),
+    })
+}
+
+fn convert_replacement(replacement: &str) -> String {
+    let mut result = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(digit) if digit.is_ascii_digit() => {
+                    result.push_str(&format!("${{{}}}", digit));
+                }
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some(other) => result.push(other),
+                None => result.push('\\'),
+            },
+            '&' => result.push_str("${0}"),
+            '$' => result.push_str("$$"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn parse_range(input: &str) -> Result<(Option<LineRange>, &str)> {
+    if let Some(rest) = input.strip_prefix('%') {
+        return Ok((Some(LineRange::Whole), rest));
+    }
+    let (start, rest) = match parse_line_spec(input)? {
+        Some(parsed) => parsed,
+        None => return Ok((None, input)),
+    };
+    if let Some(rest) = rest.strip_prefix(',') {
+        let (end, rest) = parse_line_spec(rest)?.unwrap_or((
+            LineSpec {
+                address: Address::Current,
+                offset: 0,
+            },
+            rest,
+        ));
+        return Ok((Some(LineRange::Span(start, end)), rest));
+    }
+    Ok((Some(LineRange::Single(start)), rest))
+}
+
+fn parse_line_spec(input: &str) -> Result<Option<(LineSpec, &str)>> {
+    let mut chars = input.chars();
+    let (address, mut rest) = match chars.next() {
+        Some('.') => (Some(Address::Current), chars.as_str()),
+        Some('$') => (Some(Address::Last), chars.as_str()),
+        Some('\'') => {
+            let mark = chars
+                .next()
+                .ok_or_else(|| anyhow!("Missing mark name after '"))?;
+            (Some(Address::Mark(mark)), chars.as_str())
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let (number, rest) = split_number(input);
+            (Some(Address::Line(number.parse()?)), rest)
+        }
+        _ => (None, input),
+    };
+    let mut offset: i64 = 0;
+    while let Some(sign) = rest.chars().next().filter(|c| *c == '+' || *c == '-') {
+        let (number, remaining) = split_number(&rest[1..]);
+        let amount: i64 = if number.is_empty() {
+            1
+        } else {
+            number.parse()?
+        };
+        offset = if sign == '+' {
+            offset.saturating_add(amount)
+        } else {
+            offset.saturating_sub(amount)
+        };
+        rest = remaining;
+    }
+    if address.is_none() && rest.len() == input.len() {
+        return Ok(None);
+    }
+    Ok(Some((
+        LineSpec {
+            address: address.unwrap_or(Address::Current),
+            offset,
+        },
+        rest,
+    )))
+}
+
+fn split_number(input: &str) -> (&str, &str) {
+    let len = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    input.split_at(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_spec(spec: LineSpec, address: Address, offset: i64) -> bool {
+        let same_address = match (spec.address, address) {
+            (Address::Current, Address::Current) | (Address::Last, Address::Last) => true,
+            (Address::Line(a), Address::Line(b)) => a == b,
+            (Address::Mark(a), Address::Mark(b)) => a == b,
+            _ => false,
+        };
+        same_address && spec.offset == offset
+    }
+
+    #[test]
+    fn ranges_are_split_from_the_command() {
+        let command = parse("3,$-2d").ok();
+        assert!(command.as_ref().map_or(false, |command| {
+            command.name == "d"
+                && matches!(command.range, Some(LineRange::Span(start, end))
+                    if is_spec(start, Address::Line(3), 0) && is_spec(end, Address::Last, -2))
+        }));
+        let command = parse("%s/a/b/g").ok();
+        assert!(command.as_ref().map_or(false, |command| {
+            matches!(command.range, Some(LineRange::Whole))
+                && command.name == "s"
+                && command.args == "/a/b/g"
+        }));
+        let command = parse("'a,'b s/x/y/").ok();
+        assert!(command.as_ref().map_or(false, |command| {
+            command.name == "s"
+                && command.args == "/x/y/"
+                && matches!(command.range, Some(LineRange::Span(start, end))
+                    if is_spec(start, Address::Mark('a'), 0) && is_spec(end, Address::Mark('b'), 0))
+        }));
+    }
+
+    #[test]
+    fn offsets_default_to_the_current_line() {
+        let command = parse(".+3").ok();
+        assert!(command.as_ref().map_or(false, |command| {
+            command.name.is_empty()
+                && matches!(command.range, Some(LineRange::Single(spec))
+                    if is_spec(spec, Address::Current, 3))
+        }));
+        let command = parse("-,+2y").ok();
+        assert!(command.as_ref().map_or(false, |command| {
+            command.name == "y"
+                && matches!(command.range, Some(LineRange::Span(start, end))
+                    if is_spec(start, Address::Current, -1) && is_spec(end, Address::Current, 2))
+        }));
+        let command = parse("1,").ok();
+        assert!(command.as_ref().map_or(false, |command| {
+            matches!(command.range, Some(LineRange::Span(start, end))
+                if is_spec(start, Address::Line(1), 0) && is_spec(end, Address::Current, 0))
+        }));
+    }
+
+    #[test]
+    fn commands_without_a_range_keep_their_bang() {
+        let command = parse("  w! ").ok();
+        assert!(command.as_ref().map_or(false, |command| {
+            command.range.is_none() && command.name == "w!" && command.args.is_empty()
+        }));
+        let command = parse("e ../notes.md").ok();
+        assert!(command.as_ref().map_or(false, |command| {
+            command.name == "e" && command.args == "../notes.md"
+        }));
+        assert!(parse("'").is_err());
+    }
+
+    #[test]
+    fn builtin_names_are_unique() {
+        let mut names: Vec<&str> = BUILTIN_COMMANDS
+            .iter()
+            .flat_map(|command| command.names.iter().copied())
+            .collect();
+        let count = names.len();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), count);
+        assert!(BUILTIN_COMMANDS
+            .iter()
+            .all(|command| !command.names.is_empty()));
+    }
+
+    #[test]
+    fn builtins_are_found_by_any_name_with_their_bangs() {
+        let found = |name| builtin(name).map(|(command, bangs)| (command.builtin, bangs));
+        assert_eq!(found("w"), Some((Builtin::Write, 0)));
+        assert_eq!(found("w!!"), Some((Builtin::Write, 2)));
+        assert_eq!(found("q!"), Some((Builtin::Quit, 1)));
+        assert_eq!(found("cN"), Some((Builtin::Cprevious, 0)));
+        assert_eq!(found("com!"), Some((Builtin::Command, 1)));
+        assert_eq!(found("q!!"), None);
+        assert_eq!(found("ls!"), None);
+        assert_eq!(found("nope"), None);
+    }
+
+    #[test]
+    fn only_substitute_takes_a_range() {
+        let ranged: Vec<Builtin> = BUILTIN_COMMANDS
+            .iter()
+            .filter(|command| command.range)
+            .map(|command| command.builtin)
+            .collect();
+        assert_eq!(ranged, [Builtin::Substitute]);
+    }
+
+    #[test]
+    fn completion_offers_the_first_name() {
+        let names: Vec<&str> = builtin_names().collect();
+        assert!(names.contains(&"substitute"));
+        assert!(names.contains(&"ls") && names.contains(&"buffers"));
+        assert!(!names.contains(&"s") && !names.contains(&"cN"));
+    }
+
+    #[test]
+    fn substitutions_unescape_the_delimiter_and_convert_groups() {
+        let substitution = parse_substitution(r"/a\/b/x&\1\n$/g").ok();
+        assert!(substitution.as_ref().map_or(false, |substitution| {
+            substitution.pattern == "a/b"
+                && substitution.replacement == "x${0}${1}\n$$"
+                && substitution.flags == "g"
+        }));
+        let substitution = parse_substitution(r"#\d+#n#").ok();
+        assert!(substitution.as_ref().map_or(false, |substitution| {
+            substitution.pattern == r"\d+" && substitution.replacement == "n"
+        }));
+        assert!(parse_substitution("//x/").is_err());
+        assert!(parse_substitution("a/b/").is_err());
+    }
+}
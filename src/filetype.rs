@@ -1,9 +1,24 @@
+use crate::Config;
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+static DEFINITIONS: OnceLock<Vec<Definition>> = OnceLock::new();
+const DEFAULT_PAIRS: &str = "()[]{}\"\"''";
+
 #[derive(Clone)]
 pub struct FileType {
     name: String,
     hl_opts: HighlightingOptions,
 }
 
+struct Definition {
+    file_type: FileType,
+    extensions: Vec<String>,
+}
+
 #[derive(Default, Clone)]
 pub struct HighlightingOptions {
     numbers: bool,
@@ -11,6 +26,18 @@ pub struct HighlightingOptions {
     characters: bool,
     comments: bool,
     multiline_comments: bool,
+    raw_strings: bool,
+    triple_quoted_strings: bool,
+    escape_sequences: bool,
+    number_suffixes: bool,
+    code_fences: bool,
+    string_quotes: Vec<char>,
+    line_comment: Vec<char>,
+    block_comment_start: Vec<char>,
+    block_comment_end: Vec<char>,
+    indent_after: Vec<char>,
+    dedent_on: Vec<char>,
+    auto_pairs: Vec<(char, char)>,
     primary_keywords: Vec<String>,
     secondary_keywords: Vec<String>,
 }
@@ -19,7 +46,10 @@ impl Default for FileType {
     fn default() -> Self {
         Self {
             name: String::from("No filetype"),
-            hl_opts: HighlightingOptions::default(),
+            hl_opts: HighlightingOptions {
+                auto_pairs: pairs(DEFAULT_PAIRS),
+                ..HighlightingOptions::default()
+            },
         }
     }
 }
@@ -33,7 +63,67 @@ impl FileType {
         &self.hl_opts
     }
 
+    pub fn load_definitions() -> Vec<String> {
+        let mut definitions = Vec::new();
+        let mut errors = Vec::new();
+        let entries = filetypes_dir().and_then(|dir| fs::read_dir(dir).ok());
+        let mut paths: Vec<PathBuf> = entries
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .map_or(false, |extension| extension == "toml")
+            })
+            .collect();
+        paths.sort();
+        for path in paths {
+            match fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|contents| parse_definition(&path, &contents))
+            {
+                Ok(definition) => definitions.push(definition),
+                Err(error) => errors.push(format!("{}: {}", path.display(), error)),
+            }
+        }
+        let _ = DEFINITIONS.set(definitions);
+        errors
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        let lowercase = name.to_lowercase();
+        if let Some(definition) = definitions().iter().find(|definition| {
+            definition.file_type.name.to_lowercase() == lowercase
+                || definition.extensions.contains(&lowercase)
+        }) {
+            return Some(definition.file_type.clone());
+        }
+        let extension = match lowercase.as_str() {
+            "rust" | "rs" => "rs",
+            "c" | "cpp" | "h" => "c",
+            "python" | "py" => "py",
+            "javascript" | "js" => "js",
+            "toml" => "toml",
+            "json" => "json",
+            "markdown" | "md" => "md",
+            "none" | "text" => return Some(Self::default()),
+            _ => return None,
+        };
+        Some(Self::from(&format!(".{}", extension)))
+    }
+
     pub fn from(file_name: &str) -> Self {
+        let extension = Path::new(file_name)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        if let Some(definition) = extension.and_then(|extension| {
+            definitions()
+                .iter()
+                .find(|definition| definition.extensions.contains(&extension))
+        }) {
+            return definition.file_type.clone();
+        }
         if file_name.ends_with(".rs") {
             return Self {
                 name: String::from("Rust"),
@@ -43,6 +133,18 @@ impl FileType {
                     characters: true,
                     comments: true,
                     multiline_comments: true,
+                    raw_strings: true,
+                    triple_quoted_strings: false,
+                    escape_sequences: true,
+                    number_suffixes: true,
+                    code_fences: false,
+                    string_quotes: vec!['"'],
+                    line_comment: vec!['/', '/'],
+                    block_comment_start: vec!['/', '*'],
+                    block_comment_end: vec!['*', '/'],
+                    indent_after: vec!['{', '[', '('],
+                    dedent_on: vec!['}', ']', ')'],
+                    auto_pairs: pairs("()[]{}\"\""),
                     primary_keywords: vec![
                         "as".to_string(),
                         "break".to_string(),
@@ -126,6 +228,18 @@ impl FileType {
                     characters: true,
                     comments: true,
                     multiline_comments: true,
+                    raw_strings: false,
+                    triple_quoted_strings: false,
+                    escape_sequences: true,
+                    number_suffixes: true,
+                    code_fences: false,
+                    string_quotes: vec!['"'],
+                    line_comment: vec!['/', '/'],
+                    block_comment_start: vec!['/', '*'],
+                    block_comment_end: vec!['*', '/'],
+                    indent_after: vec!['{', '[', '('],
+                    dedent_on: vec!['}', ']', ')'],
+                    auto_pairs: pairs(DEFAULT_PAIRS),
                     primary_keywords: vec![
                         "break".to_string(),
                         "case".to_string(),
@@ -166,9 +280,21 @@ impl FileType {
                 hl_opts: HighlightingOptions {
                     numbers: true,
                     strings: true,
-                    characters: true,
+                    characters: false,
                     comments: true,
-                    multiline_comments: true,
+                    multiline_comments: false,
+                    raw_strings: false,
+                    triple_quoted_strings: true,
+                    escape_sequences: true,
+                    number_suffixes: true,
+                    code_fences: false,
+                    string_quotes: vec!['"', '\''],
+                    line_comment: vec!['#'],
+                    block_comment_start: Vec::new(),
+                    block_comment_end: Vec::new(),
+                    indent_after: vec![':', '[', '(', '{'],
+                    dedent_on: vec![']', ')', '}'],
+                    auto_pairs: pairs(DEFAULT_PAIRS),
                     primary_keywords: vec![
                         "and".to_string(),
                         "as".to_string(),
@@ -207,6 +333,175 @@ impl FileType {
                     secondary_keywords: Vec::new(),
                 },
             };
+        } else if file_name.ends_with(".js")
+            || file_name.ends_with(".mjs")
+            || file_name.ends_with(".cjs")
+            || file_name.ends_with(".jsx")
+        {
+            return Self {
+                name: String::from("JavaScript"),
+                hl_opts: HighlightingOptions {
+                    numbers: true,
+                    strings: true,
+                    characters: false,
+                    comments: true,
+                    multiline_comments: true,
+                    raw_strings: false,
+                    triple_quoted_strings: false,
+                    escape_sequences: true,
+                    number_suffixes: false,
+                    code_fences: false,
+                    string_quotes: vec!['"', '\'', '`'],
+                    line_comment: vec!['/', '/'],
+                    block_comment_start: vec!['/', '*'],
+                    block_comment_end: vec!['*', '/'],
+                    indent_after: vec!['{', '[', '('],
+                    dedent_on: vec!['}', ']', ')'],
+                    auto_pairs: pairs(DEFAULT_PAIRS),
+                    primary_keywords: vec![
+                        "async".to_string(),
+                        "await".to_string(),
+                        "break".to_string(),
+                        "case".to_string(),
+                        "catch".to_string(),
+                        "class".to_string(),
+                        "const".to_string(),
+                        "continue".to_string(),
+                        "debugger".to_string(),
+                        "default".to_string(),
+                        "delete".to_string(),
+                        "do".to_string(),
+                        "else".to_string(),
+                        "export".to_string(),
+                        "extends".to_string(),
+                        "false".to_string(),
+                        "finally".to_string(),
+                        "for".to_string(),
+                        "function".to_string(),
+                        "if".to_string(),
+                        "import".to_string(),
+                        "in".to_string(),
+                        "instanceof".to_string(),
+                        "let".to_string(),
+                        "new".to_string(),
+                        "null".to_string(),
+                        "of".to_string(),
+                        "return".to_string(),
+                        "static".to_string(),
+                        "super".to_string(),
+                        "switch".to_string(),
+                        "this".to_string(),
+                        "throw".to_string(),
+                        "true".to_string(),
+                        "try".to_string(),
+                        "typeof".to_string(),
+                        "undefined".to_string(),
+                        "var".to_string(),
+                        "void".to_string(),
+                        "while".to_string(),
+                        "with".to_string(),
+                        "yield".to_string(),
+                    ],
+                    secondary_keywords: vec![
+                        "Array".to_string(),
+                        "Boolean".to_string(),
+                        "Date".to_string(),
+                        "Error".to_string(),
+                        "JSON".to_string(),
+                        "Map".to_string(),
+                        "Math".to_string(),
+                        "Number".to_string(),
+                        "Object".to_string(),
+                        "Promise".to_string(),
+                        "RegExp".to_string(),
+                        "Set".to_string(),
+                        "String".to_string(),
+                        "Symbol".to_string(),
+                        "console".to_string(),
+                        "document".to_string(),
+                        "window".to_string(),
+                    ],
+                },
+            };
+        } else if file_name.ends_with(".toml") {
+            return Self {
+                name: String::from("TOML"),
+                hl_opts: HighlightingOptions {
+                    numbers: true,
+                    strings: true,
+                    characters: false,
+                    comments: true,
+                    multiline_comments: false,
+                    raw_strings: false,
+                    triple_quoted_strings: true,
+                    escape_sequences: true,
+                    number_suffixes: false,
+                    code_fences: false,
+                    string_quotes: vec!['"', '\''],
+                    line_comment: vec!['#'],
+                    block_comment_start: Vec::new(),
+                    block_comment_end: Vec::new(),
+                    indent_after: vec!['[', '{'],
+                    dedent_on: vec![']', '}'],
+                    auto_pairs: pairs(DEFAULT_PAIRS),
+                    primary_keywords: vec!["true".to_string(), "false".to_string()],
+                    secondary_keywords: Vec::new(),
+                },
+            };
+        } else if file_name.ends_with(".json") {
+            return Self {
+                name: String::from("JSON"),
+                hl_opts: HighlightingOptions {
+                    numbers: true,
+                    strings: true,
+                    characters: false,
+                    comments: false,
+                    multiline_comments: false,
+                    raw_strings: false,
+                    triple_quoted_strings: false,
+                    escape_sequences: true,
+                    number_suffixes: false,
+                    code_fences: false,
+                    string_quotes: vec!['"'],
+                    line_comment: Vec::new(),
+                    block_comment_start: Vec::new(),
+                    block_comment_end: Vec::new(),
+                    indent_after: vec!['{', '['],
+                    dedent_on: vec!['}', ']'],
+                    auto_pairs: pairs(DEFAULT_PAIRS),
+                    primary_keywords: vec![
+                        "true".to_string(),
+                        "false".to_string(),
+                        "null".to_string(),
+                    ],
+                    secondary_keywords: Vec::new(),
+                },
+            };
+        } else if file_name.ends_with(".md") || file_name.ends_with(".markdown") {
+            return Self {
+                name: String::from("Markdown"),
+                hl_opts: HighlightingOptions {
+                    numbers: false,
+                    strings: false,
+                    characters: false,
+                    comments: false,
+                    multiline_comments: true,
+                    raw_strings: false,
+                    triple_quoted_strings: false,
+                    escape_sequences: false,
+                    number_suffixes: false,
+                    code_fences: true,
+                    string_quotes: Vec::new(),
+                    line_comment: Vec::new(),
+                    block_comment_start: vec!['<', '!', '-', '-'],
+                    block_comment_end: vec!['-', '-', '>'],
+                    indent_after: Vec::new(),
+                    dedent_on: Vec::new(),
+                    auto_pairs: pairs(DEFAULT_PAIRS),
+                    primary_keywords: Vec::new(),
+                    secondary_keywords: Vec::new(),
+                },
+            };
         }
         Self::default()
     }
@@ -233,6 +528,57 @@ impl HighlightingOptions {
         self.multiline_comments
     }
 
+    pub fn raw_strings(&self) -> bool {
+        self.raw_strings
+    }
+
+    pub fn triple_quoted_strings(&self) -> bool {
+        self.triple_quoted_strings
+    }
+
+    pub fn escape_sequences(&self) -> bool {
+        self.escape_sequences
+    }
+
+    pub fn number_suffixes(&self) -> bool {
+        self.number_suffixes
+    }
+
+    pub fn code_fences(&self) -> bool {
+        self.code_fences
+    }
+
+    pub fn string_quotes(&self) -> &[char] {
+        &self.string_quotes
+    }
+
+    pub fn line_comment(&self) -> &[char] {
+        &self.line_comment
+    }
+
+    pub fn block_comment_start(&self) -> &[char] {
+        &self.block_comment_start
+    }
+
+    pub fn block_comment_end(&self) -> &[char] {
+        &self.block_comment_end
+    }
+
+    pub fn indent_after(&self) -> &[char] {
+        &self.indent_after
+    }
+
+    pub fn dedent_on(&self) -> &[char] {
+        &self.dedent_on
+    }
+
+    pub fn closing_pair(&self, c: char) -> Option<char> {
+        self.auto_pairs
+            .iter()
+            .find(|(open, _)| *open == c)
+            .map(|(_, close)| *close)
+    }
+
     pub fn primary_keywords(&self) -> &Vec<String> {
         &self.primary_keywords
     }
@@ -241,3 +587,196 @@ impl HighlightingOptions {
         &self.secondary_keywords
     }
 }
+
+fn definitions() -> &'static [Definition] {
+    DEFINITIONS.get().map_or(&[], Vec::as_slice)
+}
+
+fn pairs(spec: &str) -> Vec<(char, char)> {
+    let chars: Vec<char> = spec.chars().collect();
+    chars
+        .chunks(2)
+        .filter_map(|pair| Some((*pair.first()?, *pair.get(1)?)))
+        .collect()
+}
+
+fn filetypes_dir() -> Option<PathBuf> {
+    Some(Config::path()?.parent()?.join("filetypes"))
+}
+
+// Definition files are plain key = value TOML; every key but extensions
+// is optional and falls back to the defaults below.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DefinitionFile {
+    name: Option<String>,
+    extensions: Vec<String>,
+    string_quotes: Option<Vec<String>>,
+    line_comment: Option<String>,
+    block_comment: Option<(String, String)>,
+    indent_after: Option<String>,
+    dedent_on: Option<String>,
+    auto_pairs: Option<String>,
+    #[serde(default)]
+    primary_keywords: Vec<String>,
+    #[serde(default)]
+    secondary_keywords: Vec<String>,
+    #[serde(default)]
+    numbers: bool,
+    #[serde(default)]
+    strings: bool,
+    #[serde(default)]
+    characters: bool,
+    #[serde(default)]
+    raw_strings: bool,
+    #[serde(default)]
+    triple_quoted_strings: bool,
+    #[serde(default)]
+    escape_sequences: bool,
+    #[serde(default)]
+    number_suffixes: bool,
+    #[serde(default)]
+    code_fences: bool,
+}
+
+fn parse_definition(path: &Path, contents: &str) -> Result<Definition> {
+    let file: DefinitionFile = toml::from_str(contents).map_err(|error| {
+        let line = error.span().map_or(1, |span| {
+            contents
+                .get(..span.start)
+                .map_or(0, |before| before.matches('\n').count())
+                .saturating_add(1)
+        });
+        anyhow!("line {}: {}", line, error.message())
+    })?;
+    if file.extensions.is_empty() {
+        bail!("missing extensions");
+    }
+    let name = file.name.unwrap_or_else(|| {
+        path.file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().to_string())
+    });
+    let mut hl_opts = HighlightingOptions {
+        numbers: file.numbers,
+        strings: file.strings,
+        characters: file.characters,
+        raw_strings: file.raw_strings,
+        triple_quoted_strings: file.triple_quoted_strings,
+        escape_sequences: file.escape_sequences,
+        number_suffixes: file.number_suffixes,
+        code_fences: file.code_fences,
+        string_quotes: vec!['"'],
+        auto_pairs: pairs(DEFAULT_PAIRS),
+        indent_after: file.indent_after.unwrap_or_default().chars().collect(),
+        dedent_on: file.dedent_on.unwrap_or_default().chars().collect(),
+        primary_keywords: file.primary_keywords,
+        secondary_keywords: file.secondary_keywords,
+        ..HighlightingOptions::default()
+    };
+    if let Some(quotes) = file.string_quotes {
+        hl_opts.string_quotes = quotes
+            .iter()
+            .filter_map(|quote| quote.chars().next())
+            .collect();
+    }
+    if let Some(line_comment) = file.line_comment {
+        hl_opts.comments = !line_comment.is_empty();
+        hl_opts.line_comment = line_comment.chars().collect();
+    }
+    if let Some((start, end)) = file.block_comment {
+        if start.is_empty() || end.is_empty() {
+            bail!("block_comment must be [\"start\", \"end\"]");
+        }
+        hl_opts.multiline_comments = true;
+        hl_opts.block_comment_start = start.chars().collect();
+        hl_opts.block_comment_end = end.chars().collect();
+    }
+    if let Some(auto_pairs) = file.auto_pairs {
+        if auto_pairs.chars().count() % 2 != 0 {
+            bail!("auto_pairs must list opening and closing characters");
+        }
+        hl_opts.auto_pairs = pairs(&auto_pairs);
+    }
+    Ok(Definition {
+        file_type: FileType { name, hl_opts },
+        extensions: file
+            .extensions
+            .iter()
+            .map(|extension| extension.trim_start_matches('.').to_lowercase())
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<Definition> {
+        parse_definition(Path::new("filetypes/zig.toml"), contents)
+    }
+
+    fn parse_error(contents: &str) -> String {
+        parse(contents)
+            .err()
+            .map(|error| error.to_string())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn parses_a_full_definition() -> Result<()> {
+        let definition = parse(
+            r#"
+            name = "Zig" # trailing comment
+            extensions = [".zig", "ZON"]
+            numbers = true
+            strings = true
+            string_quotes = ["\"", "'"]
+            line_comment = "//"
+            block_comment = ["/*", "*/"]
+            auto_pairs = "()[]"
+            primary_keywords = [
+                "fn",
+                "const", # multi-line arrays may hold comments
+            ]
+            "#,
+        )?;
+        let hl_opts = &definition.file_type.hl_opts;
+        assert_eq!(definition.file_type.name, "Zig");
+        assert_eq!(definition.extensions, ["zig", "zon"]);
+        assert!(hl_opts.numbers && hl_opts.strings && !hl_opts.characters);
+        assert_eq!(hl_opts.string_quotes, ['"', '\'']);
+        assert!(hl_opts.comments && hl_opts.multiline_comments);
+        assert_eq!(hl_opts.block_comment_end, ['*', '/']);
+        assert_eq!(hl_opts.auto_pairs, [('(', ')'), ('[', ']')]);
+        assert_eq!(hl_opts.primary_keywords, ["fn", "const"]);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_keys_fall_back_to_defaults() -> Result<()> {
+        let definition = parse("extensions = ['zig']")?;
+        let hl_opts = &definition.file_type.hl_opts;
+        assert_eq!(definition.file_type.name, "zig");
+        assert_eq!(hl_opts.string_quotes, ['"']);
+        assert_eq!(hl_opts.auto_pairs, pairs(DEFAULT_PAIRS));
+        assert!(!hl_opts.comments && !hl_opts.numbers);
+        Ok(())
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = parse_error("extensions = ['zig']\n\ncolour = true");
+        assert!(error.starts_with("line 3: "), "{}", error);
+        let error = parse_error("extensions = ['zig']\nnumbers = 'yes'");
+        assert!(error.starts_with("line 2: "), "{}", error);
+    }
+
+    #[test]
+    fn rejects_invalid_definitions() {
+        assert!(parse("name = 'zig'").is_err());
+        assert!(parse("extensions = []").is_err());
+        assert!(parse("extensions = ['zig']\nblock_comment = ['/*', '']").is_err());
+        assert!(parse("extensions = ['zig']\nauto_pairs = '()['").is_err());
+        assert!(parse("extensions = ['zig'\nnumbers = true").is_err());
+    }
+}
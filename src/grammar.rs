@@ -0,0 +1,621 @@
+use crate::keymap;
+use termion::event::Key;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operator {
+    Delete,
+    Yank,
+    Change,
+    ShiftRight,
+    ShiftLeft,
+    Format,
+    Lowercase,
+    Uppercase,
+    ToggleCase,
+    Comment,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Motion {
+    Line,
+    Down,
+    Up,
+    WordEnd,
+    LineEnd,
+    LineStart,
+    FirstLine,
+    LastLine,
+    ParagraphEnd,
+    NextMatch,
+    Object { object: char, around: bool },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Action {
+    Key(Key),
+    Operator(Operator, Motion),
+    Replace(char),
+    SetMark(char),
+    JumpToMark { mark: char, linewise: bool },
+    GCommand(char),
+    ZCommand(char),
+    AddSurrounding(Motion, char),
+    DeleteSurrounding(char),
+    ChangeSurrounding(char, char),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct NormalCommand {
+    pub count: Option<usize>,
+    pub register: Option<char>,
+    pub action: Action,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    Pending,
+    Cancelled,
+    Complete(NormalCommand),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum InsertAction {
+    Insert(char),
+    Backspace,
+    Delete,
+    Move(Key),
+    Exit,
+}
+
+enum Parse<T> {
+    Incomplete,
+    Invalid,
+    Done(T),
+}
+
+impl<T> Parse<T> {
+    fn map<U>(self, f: impl FnOnce(T) -> U) -> Parse<U> {
+        match self {
+            Parse::Incomplete => Parse::Incomplete,
+            Parse::Invalid => Parse::Invalid,
+            Parse::Done(value) => Parse::Done(f(value)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct KeyState {
+    count: Option<usize>,
+    register: Option<char>,
+    awaiting_register: bool,
+    keys: Vec<Key>,
+    motion_count: Option<usize>,
+}
+
+impl KeyState {
+    pub fn is_idle(&self) -> bool {
+        self.count.is_none()
+            && self.register.is_none()
+            && !self.awaiting_register
+            && self.keys.is_empty()
+    }
+
+    pub fn awaits_literal(&self) -> bool {
+        self.keys == [Key::Char('r')]
+    }
+
+    pub fn pending_text(&self) -> String {
+        let mut text = String::new();
+        if let Some(register) = self.register {
+            text.push('"');
+            text.push(register);
+        } else if self.awaiting_register {
+            text.push('"');
+        }
+        if let Some(count) = self.count {
+            text.push_str(&count.to_string());
+        }
+        text.push_str(&keymap::format_keys(&self.keys));
+        if let Some(count) = self.motion_count {
+            text.push_str(&count.to_string());
+        }
+        text
+    }
+
+    pub fn feed(&mut self, key: Key) -> Step {
+        if key == Key::Esc && !self.is_idle() {
+            *self = Self::default();
+            return Step::Cancelled;
+        }
+        if self.awaiting_register {
+            self.awaiting_register = false;
+            return match key {
+                Key::Char(register) => {
+                    self.register = Some(register);
+                    Step::Pending
+                }
+                _ => {
+                    *self = Self::default();
+                    Step::Cancelled
+                }
+            };
+        }
+        if self.keys.is_empty() {
+            match key {
+                Key::Char(c @ '0'..='9') if c != '0' || self.count.is_some() => {
+                    self.count = Some(add_digit(self.count, c));
+                    return Step::Pending;
+                }
+                Key::Char('"') if self.register.is_none() => {
+                    self.awaiting_register = true;
+                    return Step::Pending;
+                }
+                _ => (),
+            }
+        } else if awaits_motion(&self.keys) {
+            // A count between operator and motion multiplies the one before,
+            // so 2d3w deletes six words.
+            if let Key::Char(c @ '0'..='9') = key {
+                if c != '0' || self.motion_count.is_some() {
+                    self.motion_count = Some(add_digit(self.motion_count, c));
+                    return Step::Pending;
+                }
+            }
+        }
+        if self.motion_count.is_some() && !awaits_motion(&self.keys) {
+            *self = Self::default();
+            return Step::Cancelled;
+        }
+        self.keys.push(key);
+        match parse(&self.keys) {
+            Parse::Incomplete => Step::Pending,
+            Parse::Invalid => {
+                *self = Self::default();
+                Step::Cancelled
+            }
+            Parse::Done(action) => {
+                let state = std::mem::take(self);
+                let count = match (state.count, state.motion_count) {
+                    (Some(count), Some(motion_count)) => Some(count.saturating_mul(motion_count)),
+                    (count, motion_count) => count.or(motion_count),
+                };
+                Step::Complete(NormalCommand {
+                    count,
+                    register: state.register,
+                    action,
+                })
+            }
+        }
+    }
+}
+
+fn add_digit(count: Option<usize>, c: char) -> usize {
+    let digit = c.to_digit(10).map_or(0, |digit| digit as usize);
+    count
+        .unwrap_or_default()
+        .saturating_mul(10)
+        .saturating_add(digit)
+}
+
+fn awaits_motion(keys: &[Key]) -> bool {
+    matches!(
+        keys,
+        [Key::Char('d' | 'y' | 'c' | '>' | '<')]
+            | [Key::Char('g'), Key::Char('q' | 'u' | 'U' | '~' | 'c')]
+    )
+}
+
+pub fn insert_action(key: Key) -> Option<InsertAction> {
+    match key {
+        Key::Char(c) => Some(InsertAction::Insert(c)),
+        Key::Backspace => Some(InsertAction::Backspace),
+        Key::Delete => Some(InsertAction::Delete),
+        Key::Esc => Some(InsertAction::Exit),
+        Key::Up
+        | Key::Down
+        | Key::Left
+        | Key::Right
+        | Key::PageUp
+        | Key::PageDown
+        | Key::End
+        | Key::Home => Some(InsertAction::Move(key)),
+        _ => None,
+    }
+}
+
+fn parse(keys: &[Key]) -> Parse<Action> {
+    match keys {
+        [] | [Key::Char('g' | 'z' | 'r' | 'm' | '\'' | '`' | 'd' | 'y' | 'c' | '>' | '<')] => {
+            Parse::Incomplete
+        }
+        [Key::Char('r'), Key::Char(c)] => Parse::Done(Action::Replace(*c)),
+        [Key::Char('m'), Key::Char(c)] => Parse::Done(Action::SetMark(*c)),
+        [Key::Char(jump @ ('\'' | '`')), Key::Char(mark)] => Parse::Done(Action::JumpToMark {
+            mark: *mark,
+            linewise: *jump == '\'',
+        }),
+        [Key::Char('g'), Key::Char(c @ ('q' | 'u' | 'U' | '~' | 'c')), motion @ ..] => {
+            let operator = match c {
+                'q' => Operator::Format,
+                'u' => Operator::Lowercase,
+                'U' => Operator::Uppercase,
+                '~' => Operator::ToggleCase,
+                _ => Operator::Comment,
+            };
+            parse_motion(motion, *c).map(|motion| Action::Operator(operator, motion))
+        }
+        [Key::Char('g'), Key::Char(c)] => Parse::Done(Action::GCommand(*c)),
+        [Key::Char('z'), Key::Char(c)] => Parse::Done(Action::ZCommand(*c)),
+        [Key::Char('y'), Key::Char('s'), rest @ ..] => parse_surround_target(rest),
+        [Key::Char('d'), Key::Char('s'), rest @ ..] => match rest {
+            [] => Parse::Incomplete,
+            [Key::Char(target)] => Parse::Done(Action::DeleteSurrounding(*target)),
+            _ => Parse::Invalid,
+        },
+        [Key::Char('c'), Key::Char('s'), rest @ ..] => match rest {
+            [] | [Key::Char(_)] => Parse::Incomplete,
+            [Key::Char(target), Key::Char(replacement)] => {
+                Parse::Done(Action::ChangeSurrounding(*target, *replacement))
+            }
+            _ => Parse::Invalid,
+        },
+        [Key::Char(c @ ('d' | 'y' | 'c' | '>' | '<')), motion @ ..] => {
+            let operator = match c {
+                'd' => Operator::Delete,
+                'y' => Operator::Yank,
+                'c' => Operator::Change,
+                '>' => Operator::ShiftRight,
+                _ => Operator::ShiftLeft,
+            };
+            parse_motion(motion, *c).map(|motion| Action::Operator(operator, motion))
+        }
+        [key] => Parse::Done(Action::Key(*key)),
+        _ => Parse::Invalid,
+    }
+}
+
+// An operator applies to whole lines when its last key is repeated, as in
+// `dd` or `gUU`.
+fn parse_motion(keys: &[Key], line: char) -> Parse<Motion> {
+    match keys {
+        [] | [Key::Char('g' | 'i' | 'a')] => Parse::Incomplete,
+        [Key::Char(c)] if *c == line => Parse::Done(Motion::Line),
+        [Key::Char('j')] => Parse::Done(Motion::Down),
+        [Key::Char('k')] => Parse::Done(Motion::Up),
+        [Key::Char('w' | 'e')] => Parse::Done(Motion::WordEnd),
+        [Key::Char('$')] => Parse::Done(Motion::LineEnd),
+        [Key::Char('0')] => Parse::Done(Motion::LineStart),
+        [Key::Char('G')] => Parse::Done(Motion::LastLine),
+        [Key::Char('}')] => Parse::Done(Motion::ParagraphEnd),
+        [Key::Char('g'), Key::Char('g')] => Parse::Done(Motion::FirstLine),
+        [Key::Char('g'), Key::Char('n')] => Parse::Done(Motion::NextMatch),
+        [Key::Char(c @ ('i' | 'a')), Key::Char(object)] => Parse::Done(Motion::Object {
+            object: *object,
+            around: *c == 'a',
+        }),
+        _ => Parse::Invalid,
+    }
+}
+
+fn parse_surround_target(keys: &[Key]) -> Parse<Action> {
+    let (motion, rest) = match keys {
+        [] | [Key::Char('i' | 'a')] | [Key::Char('i' | 'a'), _] => return Parse::Incomplete,
+        [Key::Char('s'), rest @ ..] => (Motion::Line, rest),
+        [Key::Char('w' | 'e'), rest @ ..] => (Motion::WordEnd, rest),
+        [Key::Char('$'), rest @ ..] => (Motion::LineEnd, rest),
+        [Key::Char(c @ ('i' | 'a')), Key::Char(object), rest @ ..] => (
+            Motion::Object {
+                object: *object,
+                around: *c == 'a',
+            },
+            rest,
+        ),
+        _ => return Parse::Invalid,
+    };
+    match rest {
+        [] => Parse::Incomplete,
+        [Key::Char(target)] => Parse::Done(Action::AddSurrounding(motion, *target)),
+        _ => Parse::Invalid,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_all(state: &mut KeyState, keys: &str) -> Vec<Step> {
+        keys.chars().map(|c| state.feed(Key::Char(c))).collect()
+    }
+
+    fn run(keys: &str) -> Step {
+        let mut state = KeyState::default();
+        feed_all(&mut state, keys).pop().unwrap_or(Step::Pending)
+    }
+
+    fn command(count: Option<usize>, register: Option<char>, action: Action) -> Step {
+        Step::Complete(NormalCommand {
+            count,
+            register,
+            action,
+        })
+    }
+
+    fn key(c: char) -> Action {
+        Action::Key(Key::Char(c))
+    }
+
+    #[test]
+    fn single_keys_complete_immediately() {
+        for c in [
+            'h', 'j', 'x', 'p', 'u', 'n', '/', ':', '.', '~', 'G', '0', '$',
+        ] {
+            assert_eq!(run(&c.to_string()), command(None, None, key(c)), "{}", c);
+        }
+        assert_eq!(
+            KeyState::default().feed(Key::Ctrl('r')),
+            command(None, None, Action::Key(Key::Ctrl('r')))
+        );
+    }
+
+    #[test]
+    fn counts_accumulate_digits() {
+        assert_eq!(run("3j"), command(Some(3), None, key('j')));
+        assert_eq!(run("120x"), command(Some(120), None, key('x')));
+        assert_eq!(
+            run("10dd"),
+            command(
+                Some(10),
+                None,
+                Action::Operator(Operator::Delete, Motion::Line)
+            )
+        );
+        assert_eq!(run("42G"), command(Some(42), None, key('G')));
+        assert_eq!(run("5gg"), command(Some(5), None, Action::GCommand('g')));
+        let mut state = KeyState::default();
+        assert_eq!(feed_all(&mut state, "2"), [Step::Pending]);
+        assert_eq!(
+            state.feed(Key::Ctrl('a')),
+            command(Some(2), None, Action::Key(Key::Ctrl('a')))
+        );
+    }
+
+    #[test]
+    fn huge_counts_saturate() {
+        assert_eq!(
+            run("99999999999999999999999j"),
+            command(Some(usize::MAX), None, key('j'))
+        );
+    }
+
+    #[test]
+    fn operators_wait_for_a_motion() {
+        use Motion::*;
+        use Operator::*;
+        for (keys, operator, motion) in [
+            ("dd", Delete, Line),
+            ("yy", Yank, Line),
+            ("cc", Change, Line),
+            ("dj", Delete, Down),
+            ("dG", Delete, LastLine),
+            (">>", ShiftRight, Line),
+            ("<k", ShiftLeft, Up),
+            ("gqq", Format, Line),
+            ("guu", Lowercase, Line),
+            ("gUj", Uppercase, Down),
+            ("g~~", ToggleCase, Line),
+            ("gcc", Comment, Line),
+            ("cw", Change, WordEnd),
+            ("y$", Yank, LineEnd),
+            ("gu0", Lowercase, LineStart),
+            ("gq}", Format, ParagraphEnd),
+        ] {
+            let mut state = KeyState::default();
+            let steps = feed_all(&mut state, keys);
+            let (last, pending) = steps.split_last().unwrap_or((&Step::Pending, &[]));
+            assert!(
+                pending.iter().all(|step| *step == Step::Pending),
+                "{}",
+                keys
+            );
+            assert_eq!(
+                *last,
+                command(None, None, Action::Operator(operator, motion)),
+                "{}",
+                keys
+            );
+        }
+    }
+
+    #[test]
+    fn text_objects_and_g_motions_take_another_key() {
+        use Motion::*;
+        use Operator::*;
+        let object = |object: char, around: bool| Object { object, around };
+        for (keys, operator, motion) in [
+            ("diw", Delete, object('w', false)),
+            ("ca(", Change, object('(', true)),
+            ("yi\"", Yank, object('"', false)),
+            ("dgn", Delete, NextMatch),
+            ("gqip", Format, object('p', false)),
+            ("gUiw", Uppercase, object('w', false)),
+            ("gugg", Lowercase, FirstLine),
+        ] {
+            assert_eq!(
+                run(keys),
+                command(None, None, Action::Operator(operator, motion)),
+                "{}",
+                keys
+            );
+            assert_eq!(run(&keys[..keys.len() - 1]), Step::Pending, "{}", keys);
+        }
+    }
+
+    #[test]
+    fn counted_case_operators_keep_their_count() {
+        assert_eq!(
+            run("3guw"),
+            command(
+                Some(3),
+                None,
+                Action::Operator(Operator::Lowercase, Motion::WordEnd)
+            )
+        );
+    }
+
+    #[test]
+    fn counts_after_the_operator_multiply() {
+        let word = |operator| Action::Operator(operator, Motion::WordEnd);
+        assert_eq!(
+            run("gu2w"),
+            command(Some(2), None, word(Operator::Lowercase))
+        );
+        assert_eq!(run("d2w"), command(Some(2), None, word(Operator::Delete)));
+        assert_eq!(
+            run("2gU3w"),
+            command(Some(6), None, word(Operator::Uppercase))
+        );
+        assert_eq!(run("d10w"), command(Some(10), None, word(Operator::Delete)));
+        assert_eq!(
+            run("d0"),
+            command(
+                None,
+                None,
+                Action::Operator(Operator::Delete, Motion::LineStart)
+            )
+        );
+    }
+
+    #[test]
+    fn unknown_motions_cancel() {
+        for keys in ["dx", "gqx", "d(", "guz", "ysx", "ys0"] {
+            let mut state = KeyState::default();
+            let steps = feed_all(&mut state, keys);
+            assert_eq!(steps.last(), Some(&Step::Cancelled), "{}", keys);
+            assert!(state.is_idle(), "{}", keys);
+        }
+    }
+
+    #[test]
+    fn surround_commands_take_their_targets() {
+        let object = Motion::Object {
+            object: 'w',
+            around: false,
+        };
+        for (keys, action) in [
+            ("ds(", Action::DeleteSurrounding('(')),
+            ("cs\"'", Action::ChangeSurrounding('"', '\'')),
+            ("ysiw)", Action::AddSurrounding(object, ')')),
+            ("yss]", Action::AddSurrounding(Motion::Line, ']')),
+            ("ysw*", Action::AddSurrounding(Motion::WordEnd, '*')),
+            ("ys$t", Action::AddSurrounding(Motion::LineEnd, 't')),
+        ] {
+            assert_eq!(run(keys), command(None, None, action), "{}", keys);
+        }
+        for keys in ["ds", "cs\"", "ys", "ysi", "ysiw", "yss"] {
+            assert_eq!(run(keys), Step::Pending, "{}", keys);
+        }
+    }
+
+    #[test]
+    fn argument_commands_take_one_key() {
+        for (keys, action) in [
+            ("rx", Action::Replace('x')),
+            ("ma", Action::SetMark('a')),
+            (
+                "'a",
+                Action::JumpToMark {
+                    mark: 'a',
+                    linewise: true,
+                },
+            ),
+            (
+                "`b",
+                Action::JumpToMark {
+                    mark: 'b',
+                    linewise: false,
+                },
+            ),
+            ("gg", Action::GCommand('g')),
+            ("gv", Action::GCommand('v')),
+            ("za", Action::ZCommand('a')),
+            ("zR", Action::ZCommand('R')),
+        ] {
+            assert_eq!(run(keys), command(None, None, action), "{}", keys);
+        }
+        let mut state = KeyState::default();
+        feed_all(&mut state, "r");
+        assert!(state.awaits_literal());
+    }
+
+    #[test]
+    fn registers_prefix_commands() {
+        assert_eq!(run("\"+p"), command(None, Some('+'), key('p')));
+        assert_eq!(
+            run("\"*3yy"),
+            command(
+                Some(3),
+                Some('*'),
+                Action::Operator(Operator::Yank, Motion::Line)
+            )
+        );
+        assert_eq!(run("\"\"P"), command(None, Some('"'), key('P')));
+    }
+
+    #[test]
+    fn escape_cancels_pending_commands() {
+        for keys in ["3", "d", "\"", "\"+", "ys", "2ca"] {
+            let mut state = KeyState::default();
+            feed_all(&mut state, keys);
+            assert!(!state.is_idle(), "{}", keys);
+            assert_eq!(state.feed(Key::Esc), Step::Cancelled, "{}", keys);
+            assert!(state.is_idle(), "{}", keys);
+        }
+        assert_eq!(
+            KeyState::default().feed(Key::Esc),
+            command(None, None, Action::Key(Key::Esc))
+        );
+    }
+
+    #[test]
+    fn pending_text_shows_typed_prefix() {
+        let mut state = KeyState::default();
+        feed_all(&mut state, "\"+12d");
+        assert_eq!(state.pending_text(), "\"+12d");
+        feed_all(&mut state, "d");
+        assert!(state.is_idle());
+        assert_eq!(state.pending_text(), "");
+    }
+
+    #[test]
+    fn state_resets_between_commands() {
+        let mut state = KeyState::default();
+        assert_eq!(
+            feed_all(&mut state, "3jk"),
+            [
+                Step::Pending,
+                command(Some(3), None, key('j')),
+                command(None, None, key('k'))
+            ]
+        );
+    }
+
+    #[test]
+    fn insert_keys_map_to_actions() {
+        assert_eq!(
+            insert_action(Key::Char('a')),
+            Some(InsertAction::Insert('a'))
+        );
+        assert_eq!(
+            insert_action(Key::Char('\n')),
+            Some(InsertAction::Insert('\n'))
+        );
+        assert_eq!(insert_action(Key::Backspace), Some(InsertAction::Backspace));
+        assert_eq!(insert_action(Key::Delete), Some(InsertAction::Delete));
+        assert_eq!(insert_action(Key::Esc), Some(InsertAction::Exit));
+        assert_eq!(
+            insert_action(Key::Left),
+            Some(InsertAction::Move(Key::Left))
+        );
+        assert_eq!(insert_action(Key::Ctrl('r')), None);
+    }
+}
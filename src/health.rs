@@ -0,0 +1,89 @@
+use crate::terminal::ColorDepth;
+use crate::Config;
+use crate::FileType;
+use crate::Terminal;
+use std::env;
+use std::path::Path;
+
+pub fn report(width: u16, height: u16) -> Vec<String> {
+    let mut lines = vec![
+        format!("hecto {}", env!("CARGO_PKG_VERSION")),
+        String::new(),
+        "Terminal".to_string(),
+        format!("  OK: size {}x{}", width, height),
+    ];
+    match env::var("TERM") {
+        Ok(term) => lines.push(format!("  OK: TERM={}", term)),
+        Err(_) => lines.push("  WARN: TERM is not set".to_string()),
+    }
+    match Terminal::color_depth() {
+        ColorDepth::TrueColor => lines.push("  OK: truecolor".to_string()),
+        ColorDepth::Monochrome => lines.push(
+            "  WARN: NO_COLOR is set; using emphasis and symbols instead of color".to_string(),
+        ),
+        depth => lines.push(format!(
+            "  WARN: COLORTERM does not advertise truecolor; approximating with {}",
+            depth.name()
+        )),
+    }
+    let capabilities = Terminal::capabilities();
+    for (supported, feature, fallback) in [
+        (capabilities.italics, "italics", "italic styles are dropped"),
+        (capabilities.undercurl, "undercurl", "drawn as underline"),
+        (
+            capabilities.synchronized_output,
+            "synchronized output",
+            "frames are drawn unsynchronized",
+        ),
+        (capabilities.mouse, "mouse reporting", "mouse disabled"),
+    ] {
+        if supported {
+            lines.push(format!("  OK: {}", feature));
+        } else {
+            lines.push(format!("  WARN: no {} ({})", feature, fallback));
+        }
+    }
+    lines.push(String::new());
+    lines.push("Configuration".to_string());
+    match Config::path() {
+        Some(path) if path.exists() => {
+            lines.push(format!("  OK: {}", path.display()));
+            let (_, errors) = Config::load();
+            if errors.is_empty() {
+                lines.push("  OK: no errors".to_string());
+            }
+            for error in errors {
+                lines.push(format!("  ERROR: {}", error));
+            }
+        }
+        Some(path) => lines.push(format!("  OK: no config file at {}", path.display())),
+        None => lines.push("  WARN: no config directory available".to_string()),
+    }
+    for error in FileType::load_definitions() {
+        lines.push(format!("  ERROR: {}", error));
+    }
+    lines.push(String::new());
+    lines.push("External tools".to_string());
+    for (tool, purpose) in [
+        ("sudo", ":w!!"),
+        ("sh", ":!"),
+        ("gpg", "GPG-encrypted files"),
+        ("age", "age-encrypted files"),
+    ] {
+        if find_executable(tool) {
+            lines.push(format!("  OK: {} found (needed for {})", tool, purpose));
+        } else {
+            lines.push(format!(
+                "  WARN: {} not found ({} unavailable)",
+                tool, purpose
+            ));
+        }
+    }
+    lines
+}
+
+fn find_executable(name: &str) -> bool {
+    env::var_os("PATH").map_or(false, |paths| {
+        env::split_paths(&paths).any(|dir| Path::new(&dir).join(name).is_file())
+    })
+}
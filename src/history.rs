@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Result};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const MAX_ENTRIES: usize = 100;
+
+// Entries from private buffers are offered for the rest of the session but
+// never reach the file, so they are kept off to the side.
+pub struct History {
+    name: &'static str,
+    entries: Vec<String>,
+    session: Vec<String>,
+}
+
+impl History {
+    pub fn load(name: &'static str) -> Self {
+        let entries: Vec<String> = history_path(name)
+            .and_then(|path| fs::read_to_string(path).map_err(anyhow::Error::from))
+            .map(|contents| contents.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        let session = entries.clone();
+        Self {
+            name,
+            entries,
+            session,
+        }
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.session
+    }
+
+    pub fn push(&mut self, entry: &str) -> Result<()> {
+        if entry.is_empty() || entry.contains('\n') {
+            return Ok(());
+        }
+        add(&mut self.entries, entry);
+        add(&mut self.session, entry);
+        self.save()
+    }
+
+    pub fn push_private(&mut self, entry: &str) {
+        add(&mut self.session, entry);
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = history_path(self.name)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut contents = self.entries.join("\n");
+        contents.push('\n');
+        fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+fn add(entries: &mut Vec<String>, entry: &str) {
+    entries.retain(|existing| existing != entry);
+    entries.push(entry.to_string());
+    if entries.len() > MAX_ENTRIES {
+        entries.drain(..entries.len().saturating_sub(MAX_ENTRIES));
+    }
+}
+
+fn history_path(name: &str) -> Result<PathBuf> {
+    let data_home = env::var("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .map_err(|_| anyhow!("No data directory available"))?;
+    Ok(data_home.join("hecto").join(format!("{}_history", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_entries_stay_out_of_the_saved_list() {
+        let mut history = History {
+            name: "test",
+            entries: vec![String::from("public")],
+            session: vec![String::from("public")],
+        };
+        history.push_private("secret");
+        history.push_private("public");
+        assert_eq!(history.entries(), ["secret", "public"]);
+        assert_eq!(history.entries, ["public"]);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::undofile;
+use crate::Document;
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const HEADER: &str = "hecto-journal";
+const FORMAT_VERSION: u32 = 1;
+// Edits are written out in batches, once typing pauses or enough of them
+// have piled up.
+const FLUSH_DELAY: Duration = Duration::from_secs(1);
+const FLUSH_EDITS: usize = 50;
+
+pub struct Splice {
+    pub start: usize,
+    pub removed: usize,
+    pub lines: Vec<String>,
+}
+
+pub struct Recovery {
+    pub base: u64,
+    pub splices: Vec<Splice>,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: fs::File,
+    lines: Vec<String>,
+    seen: (usize, usize),
+    unflushed: usize,
+    dirty_since: Option<Instant>,
+}
+
+impl Journal {
+    pub fn start(file_name: &str, document: &Document, version: usize) -> Result<Self> {
+        Self::start_from(file_name, document.lines(), document, version)
+    }
+
+    // Starts from lines on disk that the document has since moved on from,
+    // as after a write the buffer kept changing during. The difference is
+    // written out straight away.
+    pub fn start_from(
+        file_name: &str,
+        lines: Vec<String>,
+        document: &Document,
+        version: usize,
+    ) -> Result<Self> {
+        let path = journal_path(file_name)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let _ = fs::remove_file(&path);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("{}", path.display()))?;
+        writeln!(
+            file,
+            "{} {} {:016x}",
+            HEADER,
+            FORMAT_VERSION,
+            base_checksum(&lines)
+        )?;
+        file.sync_all()?;
+        let mut journal = Self {
+            path,
+            file,
+            lines,
+            seen: (document.generation(), version),
+            unflushed: 0,
+            dirty_since: Some(Instant::now()),
+        };
+        journal.flush(document)?;
+        Ok(journal)
+    }
+
+    pub fn record(&mut self, document: &Document, version: usize) -> Result<()> {
+        let seen = (document.generation(), version);
+        if seen == self.seen {
+            return Ok(());
+        }
+        self.seen = seen;
+        self.unflushed = self.unflushed.saturating_add(1);
+        self.dirty_since.get_or_insert_with(Instant::now);
+        if self.unflushed >= FLUSH_EDITS {
+            return self.flush(document);
+        }
+        Ok(())
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.dirty_since
+            .map_or(false, |since| since.elapsed() >= FLUSH_DELAY)
+    }
+
+    pub fn flush(&mut self, document: &Document) -> Result<()> {
+        if self.dirty_since.take().is_none() {
+            return Ok(());
+        }
+        self.unflushed = 0;
+        let Some(splice) = self.diff(document) else {
+            return Ok(());
+        };
+        let mut entry = format!(
+            "splice {} {} {} {:016x}\n",
+            splice.start,
+            splice.removed,
+            splice.lines.len(),
+            base_checksum(&splice.lines)
+        );
+        for line in &splice.lines {
+            entry.push_str(line);
+            entry.push('\n');
+        }
+        self.file
+            .write_all(entry.as_bytes())
+            .with_context(|| format!("{}", self.path.display()))?;
+        self.file.sync_data()?;
+        let end = splice.start.saturating_add(splice.removed);
+        self.lines.splice(splice.start..end, splice.lines);
+        Ok(())
+    }
+
+    fn diff(&self, document: &Document) -> Option<Splice> {
+        let same = |old: &String, y: usize| {
+            document
+                .row(y)
+                .map_or(false, |row| row.as_bytes() == old.as_bytes())
+        };
+        let start = self
+            .lines
+            .iter()
+            .enumerate()
+            .take_while(|(y, old)| same(old, *y))
+            .count();
+        if start == self.lines.len() && start == document.len() {
+            return None;
+        }
+        let max_suffix = std::cmp::min(self.lines.len(), document.len()).saturating_sub(start);
+        let suffix = self
+            .lines
+            .iter()
+            .rev()
+            .zip((0..document.len()).rev())
+            .take(max_suffix)
+            .take_while(|(old, y)| same(old, *y))
+            .count();
+        let end = document.len().saturating_sub(suffix);
+        Some(Splice {
+            start,
+            removed: self
+                .lines
+                .len()
+                .saturating_sub(suffix)
+                .saturating_sub(start),
+            lines: (start..end)
+                .filter_map(|y| document.row(y).map(|row| row.contents()))
+                .collect(),
+        })
+    }
+
+    // Follows the file to a new name, keeping the entries already written.
+    pub fn rename(&mut self, file_name: &str) -> Result<()> {
+        let path = journal_path(file_name)?;
+        fs::rename(&self.path, &path).with_context(|| format!("{}", path.display()))?;
+        self.path = path;
+        Ok(())
+    }
+
+    pub fn discard(self) {
+        let _ = fs::remove_file(self.path);
+    }
+}
+
+pub fn read(file_name: &str) -> Result<Option<Recovery>> {
+    let path = journal_path(file_name)?;
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(None),
+    };
+    let mut lines = contents.split('\n');
+    let header: Vec<&str> = lines.next().unwrap_or_default().split(' ').collect();
+    let base = match header[..] {
+        [HEADER, version, base] if version == FORMAT_VERSION.to_string() => {
+            u64::from_str_radix(base, 16)?
+        }
+        _ => return Err(anyhow!("Unrecognized journal {}", path.display())),
+    };
+    let mut splices = Vec::new();
+    // A torn final entry is what a crash mid-write leaves behind, so stop there.
+    while let Some(line) = lines.next() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let (start, removed, count, sum) = match fields[..] {
+            ["splice", start, removed, count, sum] => (start, removed, count, sum),
+            _ => break,
+        };
+        let (Ok(start), Ok(removed), Ok(count), Ok(sum)) = (
+            start.parse(),
+            removed.parse(),
+            count.parse(),
+            u64::from_str_radix(sum, 16),
+        ) else {
+            break;
+        };
+        let entry_lines: Vec<String> = lines.by_ref().take(count).map(str::to_string).collect();
+        if entry_lines.len() != count || base_checksum(&entry_lines) != sum {
+            break;
+        }
+        splices.push(Splice {
+            start,
+            removed,
+            lines: entry_lines,
+        });
+    }
+    Ok(Some(Recovery { base, splices }))
+}
+
+pub fn base_checksum(lines: &[String]) -> u64 {
+    undofile::checksum(lines.join("\n").as_bytes())
+}
+
+fn journal_path(file_name: &str) -> Result<PathBuf> {
+    let cache_home = env::var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map_err(|_| anyhow!("No cache directory available"))?;
+    let absolute = fs::canonicalize(file_name)?;
+    let key = absolute.to_string_lossy().replace('/', "%");
+    Ok(cache_home.join("hecto").join("journal").join(key))
+}
@@ -0,0 +1,63 @@
+use crate::Position;
+
+const MAX_JUMPS: usize = 100;
+
+#[derive(Default, Clone)]
+pub struct JumpList {
+    entries: Vec<Position>,
+    index: usize,
+}
+
+impl JumpList {
+    pub fn push(&mut self, position: Position) {
+        self.entries.truncate(self.index);
+        if let Some(last) = self.entries.last() {
+            if last.y == position.y {
+                self.entries.pop();
+            }
+        }
+        self.entries.push(position);
+        if self.entries.len() > MAX_JUMPS {
+            self.entries.remove(0);
+        }
+        self.index = self.entries.len();
+    }
+
+    pub fn back(&mut self, current: Position) -> Option<Position> {
+        if self.index == 0 {
+            return None;
+        }
+        if self.index == self.entries.len() {
+            self.entries.push(current);
+        }
+        self.index = self.index.saturating_sub(1);
+        self.entries.get(self.index).copied()
+    }
+
+    pub fn forward(&mut self) -> Option<Position> {
+        if self.index.saturating_add(1) >= self.entries.len() {
+            return None;
+        }
+        self.index = self.index.saturating_add(1);
+        self.entries.get(self.index).copied()
+    }
+
+    pub fn shift_down(&mut self, start: usize, count: usize) {
+        for entry in &mut self.entries {
+            if entry.y >= start {
+                entry.y = entry.y.saturating_add(count);
+            }
+        }
+    }
+
+    pub fn remove_lines(&mut self, start: usize, count: usize) {
+        let end = start.saturating_add(count);
+        for entry in &mut self.entries {
+            if entry.y >= end {
+                entry.y = entry.y.saturating_sub(count);
+            } else if entry.y >= start {
+                entry.y = start;
+            }
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use termion::event::Key;
+
+const LANGMAP_PRESETS: [(&str, &str); 3] = [
+    (
+        "russian",
+        "ёйцукенгшщзхъфывапролджэячсмитьбю;`qwertyuiop[]asdfghjkl\\;'zxcvbnm\\,.,\
+         ЁЙЦУКЕНГШЩЗХЪФЫВАПРОЛДЖЭЯЧСМИТЬБЮ;~QWERTYUIOP{}ASDFGHJKL:\"ZXCVBNM<>",
+    ),
+    (
+        "dvorak",
+        "'\\,.pyfgcrl/=aoeuidhtns-\\;qjkxbmwvz[];qwertyuiop[]asdfghjkl\\;'zxcvbnm\\,./-=,\
+         \"<>PYFGCRL?+AOEUIDHTNS_:QJKXBMWVZ{};QWERTYUIOP{}ASDFGHJKL:\"ZXCVBNM<>?_+",
+    ),
+    (
+        "colemak",
+        "fpgjluy\\;rstdneiok;ertyuiopsdfgjkl\\;n,FPGJLUY:RSTDNEIOK;ERTYUIOPSDFGJKL:N",
+    ),
+];
+
+pub struct Mapping {
+    pub file_type: Option<String>,
+    pub lhs: Vec<Key>,
+    pub rhs: Vec<Key>,
+}
+
+pub enum Lookup<'a> {
+    None,
+    Prefix,
+    Exact(&'a Mapping, bool),
+}
+
+impl Mapping {
+    pub fn parse(definition: &str, file_type: Option<&str>, leader: Key) -> Result<Self> {
+        let (lhs, rhs) = definition
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("Usage: map keys expansion"))?;
+        let lhs = parse_keys(lhs, leader)?;
+        if lhs.is_empty() {
+            bail!("Mapping needs at least one key");
+        }
+        Ok(Self {
+            file_type: file_type.map(str::to_lowercase),
+            lhs,
+            rhs: parse_keys(rhs.trim_start(), leader)?,
+        })
+    }
+
+    fn applies_to(&self, file_type: &str) -> bool {
+        self.file_type
+            .as_ref()
+            .map_or(true, |name| name.eq_ignore_ascii_case(file_type))
+    }
+}
+
+pub fn lookup<'a>(mappings: &'a [Mapping], file_type: &str, keys: &[Key]) -> Lookup<'a> {
+    let candidates: Vec<&Mapping> = mappings
+        .iter()
+        .filter(|mapping| mapping.applies_to(file_type) && mapping.lhs.starts_with(keys))
+        .collect();
+    let longer = candidates
+        .iter()
+        .any(|mapping| mapping.lhs.len() > keys.len());
+    let exact = candidates
+        .iter()
+        .filter(|mapping| mapping.lhs == keys)
+        .max_by_key(|mapping| mapping.file_type.is_some());
+    match exact {
+        Some(mapping) => Lookup::Exact(mapping, longer),
+        None if longer => Lookup::Prefix,
+        None => Lookup::None,
+    }
+}
+
+pub fn continuations<'a>(
+    mappings: &'a [Mapping],
+    file_type: &str,
+    keys: &[Key],
+) -> Vec<&'a Mapping> {
+    let mut continuations: Vec<&Mapping> = mappings
+        .iter()
+        .filter(|mapping| {
+            mapping.applies_to(file_type)
+                && mapping.lhs.len() > keys.len()
+                && mapping.lhs.starts_with(keys)
+        })
+        .collect();
+    continuations.sort_by_key(|mapping| format_keys(&mapping.lhs));
+    continuations
+}
+
+pub fn format_keys(keys: &[Key]) -> String {
+    keys.iter()
+        .map(|key| match key {
+            Key::Char('\n') => "<CR>".to_string(),
+            Key::Char('\t') => "<Tab>".to_string(),
+            Key::Char(' ') => "<Space>".to_string(),
+            Key::Char('<') => "<lt>".to_string(),
+            Key::Char(c) => c.to_string(),
+            Key::Ctrl(c) => format!("<C-{}>", c),
+            Key::Esc => "<Esc>".to_string(),
+            Key::Backspace => "<BS>".to_string(),
+            Key::Delete => "<Del>".to_string(),
+            Key::Up => "<Up>".to_string(),
+            Key::Down => "<Down>".to_string(),
+            Key::Left => "<Left>".to_string(),
+            Key::Right => "<Right>".to_string(),
+            Key::Home => "<Home>".to_string(),
+            Key::End => "<End>".to_string(),
+            Key::PageUp => "<PageUp>".to_string(),
+            Key::PageDown => "<PageDown>".to_string(),
+            _ => "<?>".to_string(),
+        })
+        .collect()
+}
+
+pub fn parse_keys(notation: &str, leader: Key) -> Result<Vec<Key>> {
+    let mut keys = Vec::new();
+    let mut rest = notation;
+    while let Some(c) = rest.chars().next() {
+        let special = rest
+            .strip_prefix('<')
+            .and_then(|inner| inner.split_once('>'))
+            .filter(|(name, _)| !name.is_empty() && !name.contains(['<', ' ']));
+        if let Some((name, after)) = special {
+            keys.push(parse_special(name, leader)?);
+            rest = after;
+        } else {
+            keys.push(Key::Char(c));
+            rest = rest.get(c.len_utf8()..).unwrap_or_default();
+        }
+    }
+    Ok(keys)
+}
+
+fn parse_special(name: &str, leader: Key) -> Result<Key> {
+    let lower = name.to_lowercase();
+    if let Some(c) = lower
+        .strip_prefix("c-")
+        .and_then(|rest| rest.chars().next().filter(|_| rest.chars().count() == 1))
+    {
+        return Ok(Key::Ctrl(c));
+    }
+    Ok(match lower.as_str() {
+        "leader" => leader,
+        "cr" | "enter" | "return" => Key::Char('\n'),
+        "esc" => Key::Esc,
+        "tab" => Key::Char('\t'),
+        "space" => Key::Char(' '),
+        "bs" => Key::Backspace,
+        "del" => Key::Delete,
+        "lt" => Key::Char('<'),
+        "bar" => Key::Char('|'),
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" => Key::PageUp,
+        "pagedown" => Key::PageDown,
+        _ => bail!("Unknown key: <{}>", name),
+    })
+}
+
+pub fn parse_langmap(spec: &str) -> Result<HashMap<char, char>> {
+    let spec = LANGMAP_PRESETS
+        .iter()
+        .find(|(name, _)| *name == spec)
+        .map_or(spec, |(_, preset)| preset);
+    let mut langmap = HashMap::new();
+    for part in split_unescaped(spec, ',') {
+        let sides = split_unescaped(&part, ';');
+        let pairs: Vec<(char, char)> = match &sides[..] {
+            [from, to] => {
+                let (from, to) = (unescape(from), unescape(to));
+                if from.chars().count() != to.chars().count() {
+                    bail!("langmap sides differ in length: {}", part);
+                }
+                from.chars().zip(to.chars()).collect()
+            }
+            [pairs] => {
+                let chars: Vec<char> = unescape(pairs).chars().collect();
+                if chars.len() % 2 != 0 {
+                    bail!("langmap pair is missing a character: {}", part);
+                }
+                chars
+                    .chunks(2)
+                    .filter_map(|pair| Some((*pair.first()?, *pair.get(1)?)))
+                    .collect()
+            }
+            _ => bail!("Invalid langmap entry: {}", part),
+        };
+        langmap.extend(pairs);
+    }
+    Ok(langmap)
+}
+
+fn split_unescaped(text: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut escaped = false;
+    for c in text.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(String::new());
+            continue;
+        }
+        if let Some(part) = parts.last_mut() {
+            part.push(c);
+        }
+    }
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut escaped = false;
+    for c in text.chars() {
+        if c == '\\' && !escaped {
+            escaped = true;
+            continue;
+        }
+        escaped = false;
+        result.push(c);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEADER: Key = Key::Char('\\');
+
+    fn mappings(definitions: &[(&str, Option<&str>)]) -> Vec<Mapping> {
+        definitions
+            .iter()
+            .filter_map(|(definition, file_type)| {
+                Mapping::parse(definition, *file_type, LEADER).ok()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn key_notation_is_parsed() {
+        assert_eq!(
+            parse_keys("<C-x><lt>a<Esc><Space><leader>", LEADER).ok(),
+            Some(vec![
+                Key::Ctrl('x'),
+                Key::Char('<'),
+                Key::Char('a'),
+                Key::Esc,
+                Key::Char(' '),
+                LEADER
+            ])
+        );
+        assert_eq!(
+            parse_keys("a<b", LEADER).ok(),
+            Some(vec![Key::Char('a'), Key::Char('<'), Key::Char('b')])
+        );
+        assert!(parse_keys("<nope>", LEADER).is_err());
+    }
+
+    #[test]
+    fn formatting_round_trips_through_parsing() {
+        let keys = vec![Key::Char('<'), Key::Ctrl('w'), Key::Char('\n'), Key::Up];
+        let formatted = format_keys(&keys);
+        assert_eq!(formatted, "<lt><C-w><CR><Up>");
+        assert_eq!(parse_keys(&formatted, LEADER).ok(), Some(keys));
+    }
+
+    #[test]
+    fn definitions_need_keys_and_an_expansion() {
+        let mapping = Mapping::parse("<leader>w  :w<CR>", Some("Rust"), LEADER).ok();
+        assert!(mapping.as_ref().map_or(false, |mapping| {
+            mapping.lhs == [LEADER, Key::Char('w')]
+                && mapping.rhs == [Key::Char(':'), Key::Char('w'), Key::Char('\n')]
+                && mapping.file_type.as_deref() == Some("rust")
+        }));
+        assert!(Mapping::parse("jk", None, LEADER).is_err());
+    }
+
+    #[test]
+    fn lookup_prefers_file_type_mappings_and_reports_prefixes() {
+        let mappings = mappings(&[
+            ("gc :s/^/#/<CR>", None),
+            ("gc :s/^/\\/\\//<CR>", Some("rust")),
+            ("g G", None),
+        ]);
+        let keys = [Key::Char('g'), Key::Char('c')];
+        assert!(matches!(
+            lookup(&mappings, "rust", &keys),
+            Lookup::Exact(mapping, false) if mapping.file_type.is_some()
+        ));
+        assert!(matches!(
+            lookup(&mappings, "python", &keys),
+            Lookup::Exact(mapping, false) if mapping.file_type.is_none()
+        ));
+        assert!(matches!(
+            lookup(&mappings, "python", &[Key::Char('g')]),
+            Lookup::Exact(_, true)
+        ));
+        assert!(matches!(
+            lookup(&mappings, "python", &[Key::Char('x')]),
+            Lookup::None
+        ));
+        assert_eq!(
+            continuations(&mappings, "python", &[Key::Char('g')]).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn langmap_accepts_pairs_sides_and_presets() {
+        let langmap = parse_langmap("ab,xy;XY").unwrap_or_default();
+        assert_eq!(langmap.get(&'a'), Some(&'b'));
+        assert_eq!(langmap.get(&'y'), Some(&'Y'));
+        let escaped = parse_langmap(r"\,\;;ab").unwrap_or_default();
+        assert_eq!(escaped.get(&','), Some(&'a'));
+        assert_eq!(escaped.get(&';'), Some(&'b'));
+        assert!(parse_langmap("abc").is_err());
+        assert!(parse_langmap("ab;x").is_err());
+        let dvorak = parse_langmap("dvorak").unwrap_or_default();
+        assert_eq!(dvorak.get(&'p'), Some(&'r'));
+    }
+}
@@ -7,20 +7,57 @@
     clippy::wildcard_enum_match_arm,
     clippy::else_if_without_else
 )]
+mod bufstats;
+mod config;
+mod crypt;
+mod diff;
 mod document;
 mod editor;
+mod encoding;
+mod excommand;
 mod filetype;
+mod grammar;
+mod health;
 mod highlighting;
+mod history;
+mod journal;
+mod jumplist;
+mod keymap;
+mod man;
+mod output;
+mod pattern;
+mod picker;
+mod preview;
+mod privacy;
+mod profile;
+mod quickfix;
+mod reflow;
+mod replace;
 mod row;
+mod secrets;
+mod selection;
+mod stat;
+mod tasks;
 mod terminal;
+mod theme;
+mod trash;
+mod undofile;
 use anyhow::{Error, Result};
+pub use config::BackupPolicy;
+pub use config::Config;
+pub use config::ProgressStyle;
 pub use document::Document;
+pub use document::LineEnding;
 use editor::Editor;
 pub use editor::Position;
 pub use editor::SearchDirection;
+pub use encoding::Encoding;
 pub use filetype::FileType;
 pub use filetype::HighlightingOptions;
-pub use row::Row;
+pub use jumplist::JumpList;
+pub use pattern::SearchPattern;
+pub use row::{CaseChange, Row};
+use std::process;
 pub use terminal::Terminal;
 
 fn main() {
@@ -36,7 +73,7 @@ fn run() -> Result<()> {
 }
 
 fn die(error: &Error) -> ! {
-    Terminal::clear_screen();
     let _ = Terminal::flush_static();
-    panic!("{}", error);
+    eprintln!("hecto: {:?}", error);
+    process::exit(1);
 }
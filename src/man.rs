@@ -0,0 +1,101 @@
+use crate::output::TextStyle;
+use anyhow::{bail, Context, Result};
+use std::process::{Command, Stdio};
+
+pub fn render(topic: &str, width: usize) -> Result<Vec<Vec<(char, TextStyle)>>> {
+    let output = Command::new("man")
+        .args(topic.split_whitespace())
+        .env("MANWIDTH", width.to_string())
+        .env("MAN_KEEP_FORMATTING", "1")
+        .env("MANPAGER", "cat")
+        .env("PAGER", "cat")
+        .env("GROFF_NO_SGR", "1")
+        .stdin(Stdio::null())
+        .output()
+        .context("Could not run man")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.lines().next() {
+            Some(message) => bail!("{}", message.trim()),
+            None => bail!("No manual entry for {}", topic),
+        }
+    }
+    let lines: Vec<Vec<(char, TextStyle)>> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(parse_overstrike)
+        .collect();
+    if lines.is_empty() {
+        bail!("No manual entry for {}", topic);
+    }
+    Ok(lines)
+}
+
+fn parse_overstrike(line: &str) -> Vec<(char, TextStyle)> {
+    let mut result: Vec<(char, TextStyle)> = Vec::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x08' => {
+                let Some(next) = chars.next() else {
+                    break;
+                };
+                // Striking the same cell again adds to its style, so _\bX\bX
+                // is both underlined and bold.
+                let (previous, previous_style) = result.pop().unwrap_or((' ', TextStyle::Plain));
+                result.push(if previous == next {
+                    (next, previous_style.merge(TextStyle::Bold))
+                } else if previous == '_' {
+                    (next, previous_style.merge(TextStyle::Underline))
+                } else if next == '_' {
+                    (previous, previous_style.merge(TextStyle::Underline))
+                } else {
+                    (next, previous_style)
+                });
+            }
+            '\x1b' => {
+                if chars.next() == Some('[') {
+                    for c in chars.by_ref() {
+                        if c.is_ascii_alphabetic() {
+                            break;
+                        }
+                    }
+                }
+            }
+            '\t' => {
+                let spaces = 8_usize.saturating_sub(result.len() % 8);
+                result.extend((0..spaces).map(|_| (' ', TextStyle::Plain)));
+            }
+            _ => result.push((c, TextStyle::Plain)),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn styles(line: &str) -> Vec<TextStyle> {
+        parse_overstrike(line)
+            .into_iter()
+            .map(|(_, style)| style)
+            .collect()
+    }
+
+    #[test]
+    fn overstrikes_become_styles() {
+        assert!(styles("a\x08a_\x08b") == [TextStyle::Bold, TextStyle::Underline]);
+        assert!(styles("c\x08_") == [TextStyle::Underline]);
+        let text: String = parse_overstrike("x\x08xy")
+            .into_iter()
+            .map(|(c, _)| c)
+            .collect();
+        assert_eq!(text, "xy");
+    }
+
+    #[test]
+    fn bold_and_underline_combine() {
+        assert!(styles("_\x08X\x08X") == [TextStyle::BoldUnderline]);
+        assert!(styles("X\x08X\x08_") == [TextStyle::BoldUnderline]);
+    }
+}
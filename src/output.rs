@@ -0,0 +1,136 @@
+use std::cmp;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TextStyle {
+    Plain,
+    Bold,
+    Underline,
+    BoldUnderline,
+}
+
+impl TextStyle {
+    pub fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::Plain, style) | (style, Self::Plain) => style,
+            (Self::Bold, Self::Bold) => Self::Bold,
+            (Self::Underline, Self::Underline) => Self::Underline,
+            _ => Self::BoldUnderline,
+        }
+    }
+}
+
+pub struct OutputPane {
+    title: String,
+    lines: Vec<String>,
+    styles: Vec<Vec<TextStyle>>,
+    hint: String,
+    scroll: usize,
+    selected: Option<usize>,
+}
+
+impl OutputPane {
+    pub fn new(title: &str, lines: Vec<String>) -> Self {
+        Self {
+            title: title.to_string(),
+            lines,
+            styles: Vec::new(),
+            hint: "j/k to move, q to close".to_string(),
+            scroll: 0,
+            selected: None,
+        }
+    }
+
+    pub fn selectable(title: &str, lines: Vec<String>) -> Self {
+        let mut pane = Self::new(title, lines);
+        pane.selected = Some(0);
+        pane
+    }
+
+    pub fn styled(title: &str, lines: Vec<Vec<(char, TextStyle)>>) -> Self {
+        let mut pane = Self::new(
+            title,
+            lines
+                .iter()
+                .map(|line| line.iter().map(|(c, _)| c).collect())
+                .collect(),
+        );
+        pane.styles = lines
+            .iter()
+            .map(|line| line.iter().map(|(_, style)| *style).collect())
+            .collect();
+        pane
+    }
+
+    pub fn set_hint(&mut self, hint: &str) {
+        self.hint = hint.to_string();
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    pub fn move_selection(&mut self, amount: usize, down: bool, view_height: usize) {
+        let Some(selected) = self.selected else {
+            if down {
+                self.scroll_down(amount, view_height);
+            } else {
+                self.scroll_up(amount);
+            }
+            return;
+        };
+        let selected = if down {
+            cmp::min(
+                selected.saturating_add(amount),
+                self.lines.len().saturating_sub(1),
+            )
+        } else {
+            selected.saturating_sub(amount)
+        };
+        self.selected = Some(selected);
+        if selected < self.scroll {
+            self.scroll = selected;
+        } else if selected >= self.scroll.saturating_add(view_height) {
+            self.scroll = selected.saturating_add(1).saturating_sub(view_height);
+        }
+    }
+
+    pub fn height(&self, max_height: usize) -> usize {
+        cmp::min(self.lines.len().saturating_add(1), max_height)
+    }
+
+    pub fn header(&self, view_height: usize) -> String {
+        let last = cmp::min(self.scroll.saturating_add(view_height), self.lines.len());
+        format!(
+            " {} [{}-{} of {}] {}",
+            self.title,
+            cmp::min(self.scroll.saturating_add(1), last),
+            last,
+            self.lines.len(),
+            self.hint
+        )
+    }
+
+    pub fn visible_lines(
+        &self,
+        view_height: usize,
+    ) -> impl Iterator<Item = (bool, &String, &[TextStyle])> {
+        self.lines
+            .iter()
+            .enumerate()
+            .skip(self.scroll)
+            .take(view_height)
+            .map(|(index, line)| {
+                let styles = self.styles.get(index).map_or(&[][..], Vec::as_slice);
+                (self.selected == Some(index), line, styles)
+            })
+    }
+
+    pub fn scroll_down(&mut self, amount: usize, view_height: usize) {
+        let max_scroll = self.lines.len().saturating_sub(view_height);
+        self.scroll = cmp::min(self.scroll.saturating_add(amount), max_scroll);
+    }
+
+    pub fn scroll_up(&mut self, amount: usize) {
+        self.scroll = self.scroll.saturating_sub(amount);
+    }
+}
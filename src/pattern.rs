@@ -0,0 +1,39 @@
+use anyhow::Result;
+use regex::Regex;
+
+#[derive(Clone)]
+pub enum SearchPattern {
+    Literal(String),
+    Regex(Regex),
+}
+
+impl SearchPattern {
+    pub fn new(query: &str, regex_by_default: bool) -> Result<Self> {
+        if let Some(pattern) = query.strip_prefix("\\v") {
+            return Ok(Self::Regex(Regex::new(pattern)?));
+        }
+        if let Some(literal) = query.strip_prefix("\\V") {
+            return Ok(Self::Literal(literal.to_string()));
+        }
+        if regex_by_default {
+            Ok(Self::Regex(Regex::new(query)?))
+        } else {
+            Ok(Self::Literal(query.to_string()))
+        }
+    }
+
+    pub fn matches(&self, text: &str) -> Vec<(usize, usize)> {
+        match self {
+            Self::Literal(literal) if literal.is_empty() => Vec::new(),
+            Self::Literal(literal) => text
+                .match_indices(literal.as_str())
+                .map(|(start, found)| (start, start.saturating_add(found.len())))
+                .collect(),
+            Self::Regex(regex) => regex
+                .find_iter(text)
+                .filter(|found| !found.is_empty())
+                .map(|found| (found.start(), found.end()))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use ignore::WalkBuilder;
+use std::path::Path;
+
+const MAX_FILES: usize = 50_000;
+
+#[derive(Clone)]
+pub struct Candidate {
+    pub label: String,
+    pub value: String,
+}
+
+impl Candidate {
+    pub fn new(label: String, value: String) -> Self {
+        Self { label, value }
+    }
+}
+
+pub struct Picker {
+    candidates: Vec<Candidate>,
+    query: String,
+    filtered: Vec<usize>,
+}
+
+impl Picker {
+    pub fn new(candidates: Vec<Candidate>) -> Self {
+        let filtered = (0..candidates.len()).collect();
+        Self {
+            candidates,
+            query: String::new(),
+            filtered,
+        }
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    pub fn set_query(&mut self, query: &str) {
+        if query == self.query {
+            return;
+        }
+        self.query = query.to_string();
+        let mut scored: Vec<(i64, usize)> = self
+            .candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(index, candidate)| Some((fuzzy_score(query, &candidate.label)?, index)))
+            .collect();
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        self.filtered = scored.into_iter().map(|(_, index)| index).collect();
+    }
+
+    pub fn len(&self) -> usize {
+        self.filtered.len()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Candidate> {
+        self.candidates.get(*self.filtered.get(index)?)
+    }
+
+    pub fn labels(&self) -> Vec<String> {
+        self.filtered
+            .iter()
+            .filter_map(|index| self.candidates.get(*index))
+            .map(|candidate| candidate.label.clone())
+            .collect()
+    }
+}
+
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i64> {
+    let mut score: i64 = 0;
+    let mut text_chars = text.chars().enumerate().peekable();
+    let mut previous_match: Option<usize> = None;
+    let mut previous_char: Option<char> = None;
+    for query_char in query.chars().filter(|c| !c.is_whitespace()) {
+        loop {
+            let (index, c) = text_chars.next()?;
+            let before = previous_char.replace(c);
+            if !c.to_lowercase().eq(query_char.to_lowercase()) {
+                continue;
+            }
+            score = score.saturating_add(1);
+            if previous_match.map_or(false, |previous| previous.saturating_add(1) == index) {
+                score = score.saturating_add(5);
+            } else if let Some(previous) = previous_match {
+                score = score.saturating_sub(
+                    i64::try_from(index.saturating_sub(previous)).unwrap_or(i64::MAX),
+                );
+            }
+            if before.map_or(true, |before| {
+                !before.is_alphanumeric() || (before.is_lowercase() && c.is_uppercase())
+            }) {
+                score = score.saturating_add(8);
+            }
+            if c == query_char {
+                score = score.saturating_add(1);
+            }
+            previous_match = Some(index);
+            break;
+        }
+    }
+    Some(score)
+}
+
+pub fn project_files(root: &Path) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    for result in WalkBuilder::new(root).build() {
+        let Ok(dir_entry) = result else {
+            continue;
+        };
+        if !dir_entry
+            .file_type()
+            .map_or(false, |file_type| file_type.is_file())
+        {
+            continue;
+        }
+        let path = dir_entry
+            .path()
+            .strip_prefix(root)
+            .unwrap_or(dir_entry.path())
+            .to_string_lossy()
+            .to_string();
+        candidates.push(Candidate::new(path.clone(), path));
+        if candidates.len() >= MAX_FILES {
+            break;
+        }
+    }
+    candidates.sort_by(|a, b| a.label.cmp(&b.label));
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranked(query: &str, labels: &[&str]) -> Vec<String> {
+        let mut picker = Picker::new(
+            labels
+                .iter()
+                .map(|label| Candidate::new(label.to_string(), label.to_string()))
+                .collect(),
+        );
+        picker.set_query(query);
+        picker.labels()
+    }
+
+    #[test]
+    fn query_must_match_in_order() {
+        assert!(fuzzy_score("edt", "src/editor.rs").is_some());
+        assert!(fuzzy_score("EDT", "src/editor.rs").is_some());
+        assert!(fuzzy_score("tde", "src/editor.rs").is_none());
+        assert!(fuzzy_score("editors", "editor").is_none());
+        assert_eq!(fuzzy_score("", "anything"), Some(0));
+    }
+
+    #[test]
+    fn consecutive_matches_rank_higher() {
+        assert_eq!(
+            ranked("row", ["rxoxw.rs", "row.rs"].as_slice()),
+            ["row.rs", "rxoxw.rs"]
+        );
+    }
+
+    #[test]
+    fn word_starts_rank_higher() {
+        assert_eq!(
+            ranked("dr", ["address.rs", "document_row.rs"].as_slice()),
+            ["document_row.rs", "address.rs"]
+        );
+        assert_eq!(
+            ranked("fb", ["fabric", "FooBar"].as_slice()),
+            ["FooBar", "fabric"]
+        );
+    }
+
+    #[test]
+    fn exact_case_breaks_ties() {
+        assert!(fuzzy_score("Row", "Row") > fuzzy_score("Row", "row"));
+    }
+
+    #[test]
+    fn equal_scores_keep_candidate_order() {
+        assert_eq!(ranked("a", ["b/a", "c/a"].as_slice()), ["b/a", "c/a"]);
+        assert_eq!(ranked("", ["z", "a"].as_slice()), ["z", "a"]);
+    }
+
+    #[test]
+    fn non_matches_are_filtered_out() {
+        assert_eq!(
+            ranked("xyz", ["main.rs", "x/y/z.rs"].as_slice()),
+            ["x/y/z.rs"]
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use crate::crypt;
+use crate::Document;
+use anyhow::{bail, Result};
+use std::cmp;
+use std::fs;
+
+const MAX_BYTES: u64 = 1 << 20;
+// Highlighting runs this far past the previewed line, which covers any
+// terminal it is drawn in.
+const CONTEXT: usize = 200;
+
+pub struct Preview {
+    path: String,
+    line: usize,
+    document: Document,
+    highlighted: usize,
+}
+
+impl Preview {
+    pub fn open(path: &str, line: usize) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() || metadata.len() > MAX_BYTES {
+            bail!("{} is too large to preview", path);
+        }
+        if crypt::detect(path).is_some() {
+            bail!("{} is encrypted", path);
+        }
+        let mut preview = Self {
+            path: path.to_string(),
+            line: 0,
+            document: Document::open(path)?,
+            highlighted: 0,
+        };
+        preview.set_line(line);
+        Ok(preview)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn document(&self) -> &Document {
+        &self.document
+    }
+
+    pub fn set_line(&mut self, line: usize) {
+        self.line = cmp::min(line, self.document.len().saturating_sub(1));
+        let until = self.line.saturating_add(CONTEXT);
+        if until > self.highlighted {
+            self.document
+                .highlight_window(&None, self.highlighted, until);
+            self.highlighted = until;
+        }
+    }
+
+    // The first line shown when the previewed line sits in the middle of a
+    // window of the given height.
+    #[allow(clippy::integer_division)]
+    pub fn top(&self, height: usize) -> usize {
+        let last_top = self.document.len().saturating_sub(height);
+        cmp::min(self.line.saturating_sub(height / 2), last_top)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn the_line_is_centred_and_clamped() {
+        let path = env::temp_dir().join(format!("hecto-preview-{}.rs", process::id()));
+        let path = path.to_string_lossy().to_string();
+        let contents: String = (1..=20).map(|n| format!("let x{} = {};\n", n, n)).collect();
+        assert!(fs::write(&path, contents).is_ok());
+        let preview = Preview::open(&path, 10);
+        fs::remove_file(&path).ok();
+        assert!(preview.is_ok());
+        if let Ok(mut preview) = preview {
+            assert_eq!(preview.top(6), 7);
+            preview.set_line(0);
+            assert_eq!(preview.top(6), 0);
+            preview.set_line(100);
+            assert_eq!(preview.line(), 19);
+            assert_eq!(preview.top(6), 14);
+        }
+    }
+
+    #[test]
+    fn directories_are_not_previewed() {
+        assert!(Preview::open(&env::temp_dir().to_string_lossy(), 0).is_err());
+    }
+}
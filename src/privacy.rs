@@ -0,0 +1,89 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+pub const DEFAULT_PATTERNS: [&str; 5] = [
+    "~/.ssh/*",
+    "~/.gnupg/*",
+    "~/.aws/*",
+    "~/.password-store/*",
+    "/dev/shm/*",
+];
+
+pub fn is_private(file_name: &str, patterns: &[String]) -> bool {
+    let path = fs::canonicalize(file_name).unwrap_or_else(|_| Path::new(file_name).to_path_buf());
+    let path = path.to_string_lossy();
+    patterns
+        .iter()
+        .any(|pattern| glob_match(&expand_home(pattern), &path))
+}
+
+fn expand_home(pattern: &str) -> String {
+    match (pattern.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home.trim_end_matches('/'), rest),
+        _ => pattern.to_string(),
+    }
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p = p.saturating_add(1);
+            }
+            Some(c) if *c == '?' || Some(c) == text.get(t) => {
+                p = p.saturating_add(1);
+                t = t.saturating_add(1);
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star.saturating_add(1);
+                    t = matched.saturating_add(1);
+                    backtrack = Some((star, t));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern
+        .get(p..)
+        .map_or(true, |rest| rest.iter().all(|c| *c == '*'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stars_and_question_marks_match() {
+        assert!(glob_match("/dev/shm/*", "/dev/shm/secret"));
+        assert!(glob_match("/dev/shm/*", "/dev/shm/nested/secret"));
+        assert!(glob_match("*.key", "/etc/ssl/server.key"));
+        assert!(glob_match("/tmp/?.txt", "/tmp/a.txt"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("/tmp/?.txt", "/tmp/ab.txt"));
+        assert!(!glob_match("*.key", "/etc/ssl/server.key.pub"));
+        assert!(!glob_match("/dev/shm/*", "/dev/shmem"));
+    }
+
+    #[test]
+    fn home_is_expanded_in_patterns() {
+        if let Ok(home) = env::var("HOME") {
+            let expanded = expand_home("~/.ssh/*");
+            assert_eq!(expanded, format!("{}/.ssh/*", home.trim_end_matches('/')));
+        }
+        assert_eq!(expand_home("/dev/shm/*"), "/dev/shm/*");
+    }
+
+    #[test]
+    fn missing_files_are_matched_by_name() {
+        let patterns = [String::from("/dev/shm/*")];
+        assert!(is_private("/dev/shm/hecto-missing-file", &patterns));
+        assert!(!is_private("/no/such/dir/file", &patterns));
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::{anyhow, Result};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy)]
+pub enum Phase {
+    Highlight,
+    Render,
+    Input,
+}
+
+#[derive(Default)]
+struct Samples {
+    count: usize,
+    total: Duration,
+    max: Duration,
+}
+
+impl Samples {
+    fn record(&mut self, duration: Duration) {
+        self.count = self.count.saturating_add(1);
+        self.total = self.total.saturating_add(duration);
+        self.max = self.max.max(duration);
+    }
+
+    fn describe(&self, name: &str, elapsed: Duration) -> String {
+        let average = u32::try_from(self.count)
+            .ok()
+            .filter(|count| *count > 0)
+            .map_or(Duration::ZERO, |count| self.total / count);
+        let share = if elapsed.is_zero() {
+            0.0
+        } else {
+            self.total.as_secs_f64() / elapsed.as_secs_f64() * 100.0
+        };
+        format!(
+            "{:<10} {:>8} samples  total {:>10.3}ms  avg {:>8.3}ms  max {:>8.3}ms  {:>5.1}%",
+            name,
+            self.count,
+            self.total.as_secs_f64() * 1000.0,
+            average.as_secs_f64() * 1000.0,
+            self.max.as_secs_f64() * 1000.0,
+            share
+        )
+    }
+}
+
+pub struct Profiler {
+    started: Instant,
+    frames: usize,
+    highlight: Samples,
+    render: Samples,
+    input: Samples,
+}
+
+impl Profiler {
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            frames: 0,
+            highlight: Samples::default(),
+            render: Samples::default(),
+            input: Samples::default(),
+        }
+    }
+
+    pub fn frame(&mut self) {
+        self.frames = self.frames.saturating_add(1);
+    }
+
+    pub fn record(&mut self, phase: Phase, duration: Duration) {
+        match phase {
+            Phase::Highlight => self.highlight.record(duration),
+            Phase::Render => self.render.record(duration),
+            Phase::Input => self.input.record(duration),
+        }
+    }
+
+    pub fn report(&self, file_name: &str, lines: usize) -> Vec<String> {
+        let elapsed = self.started.elapsed();
+        vec![
+            format!(
+                "{} ({} lines), {:.3}s, {} frames",
+                file_name,
+                lines,
+                elapsed.as_secs_f64(),
+                self.frames
+            ),
+            self.highlight.describe("highlight", elapsed),
+            self.render.describe("render", elapsed),
+            self.input.describe("input", elapsed),
+        ]
+    }
+}
+
+// Reports go to the cache directory unless a path is given, and are only
+// readable by the user since they name the files being edited.
+pub fn write_report(path: Option<&str>, report: &[String]) -> Result<PathBuf> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => env::var("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .map_err(|_| anyhow!("No cache directory available"))?
+            .join("hecto")
+            .join("profile.txt"),
+    };
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    for line in report {
+        writeln!(file, "{}", line)?;
+    }
+    Ok(path)
+}
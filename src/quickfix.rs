@@ -0,0 +1,122 @@
+use crate::tasks::CancelToken;
+use crate::SearchPattern;
+use ignore::WalkBuilder;
+use std::fs;
+use std::path::Path;
+
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Clone)]
+pub struct QuickfixEntry {
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+}
+
+#[derive(Default)]
+pub struct QuickfixList {
+    entries: Vec<QuickfixEntry>,
+    index: Option<usize>,
+}
+
+impl QuickfixList {
+    pub fn new(entries: Vec<QuickfixEntry>) -> Self {
+        Self {
+            entries,
+            index: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // None until an entry has been visited, so that stepping forward from a
+    // fresh list lands on the first entry.
+    pub fn index(&self) -> Option<usize> {
+        self.index
+    }
+
+    pub fn get(&self, index: usize) -> Option<&QuickfixEntry> {
+        self.entries.get(index)
+    }
+
+    pub fn select(&mut self, index: usize) -> Option<&QuickfixEntry> {
+        let entry = self.entries.get(index)?;
+        self.index = Some(index);
+        Some(entry)
+    }
+
+    pub fn file_starts(&self) -> Vec<usize> {
+        let mut seen: Vec<&str> = Vec::new();
+        let mut starts = Vec::new();
+        for (index, entry) in self.entries.iter().enumerate() {
+            if !seen.contains(&entry.path.as_str()) {
+                seen.push(&entry.path);
+                starts.push(index);
+            }
+        }
+        starts
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{}:{}:{}: {}",
+                    entry.path,
+                    entry.line,
+                    entry.column,
+                    entry.text.trim()
+                )
+            })
+            .collect()
+    }
+}
+
+pub fn grep(root: &Path, pattern: &SearchPattern, cancel: &CancelToken) -> Vec<QuickfixEntry> {
+    let mut entries = Vec::new();
+    for result in WalkBuilder::new(root).build() {
+        if cancel.is_cancelled() {
+            break;
+        }
+        let Ok(dir_entry) = result else {
+            continue;
+        };
+        if !dir_entry
+            .file_type()
+            .map_or(false, |file_type| file_type.is_file())
+        {
+            continue;
+        }
+        let Ok(contents) = fs::read_to_string(dir_entry.path()) else {
+            continue;
+        };
+        let path = dir_entry
+            .path()
+            .strip_prefix(root)
+            .unwrap_or(dir_entry.path())
+            .to_string_lossy()
+            .to_string();
+        for (index, line) in contents.lines().enumerate() {
+            if let Some((start, _)) = pattern.matches(line).first() {
+                entries.push(QuickfixEntry {
+                    path: path.clone(),
+                    line: index.saturating_add(1),
+                    column: line[..*start].chars().count().saturating_add(1),
+                    text: line.to_string(),
+                });
+                if entries.len() >= MAX_ENTRIES {
+                    return entries;
+                }
+            }
+        }
+    }
+    entries
+}
@@ -0,0 +1,259 @@
+use crate::HighlightingOptions;
+use std::cmp;
+use unicode_segmentation::UnicodeSegmentation;
+
+const FENCES: [&str; 2] = ["```", "~~~"];
+
+struct Line<'a> {
+    prefix: String,
+    comment: bool,
+    bullet: Option<&'a str>,
+    body: &'a str,
+    body_start: usize,
+}
+
+pub fn reflow(lines: &[String], width: usize, opts: &HighlightingOptions) -> Vec<String> {
+    let mut reflowed = Vec::new();
+    let mut paragraph: Option<(String, String, Vec<&str>)> = None;
+    let mut in_fence = false;
+    for line in lines {
+        let verbatim = opts.code_fences() && {
+            let trimmed = line.trim_start();
+            let fence = FENCES.iter().any(|fence| trimmed.starts_with(fence));
+            in_fence ^= fence;
+            fence || in_fence || trimmed.starts_with('#')
+        };
+        let parsed = parse_line(line, opts);
+        let delimiter = [opts.block_comment_start(), opts.block_comment_end()]
+            .iter()
+            .any(|delimiter| {
+                let delimiter: String = delimiter.iter().collect();
+                !delimiter.is_empty() && parsed.body.starts_with(&delimiter)
+            });
+        if verbatim || delimiter || parsed.body.is_empty() {
+            if let Some((first, rest, words)) = paragraph.take() {
+                fill(&mut reflowed, &first, &rest, &words, width);
+            }
+            reflowed.push(line.trim_end().to_string());
+            continue;
+        }
+        let continues = paragraph.as_ref().map_or(false, |(_, rest, _)| {
+            rest.trim_end() == parsed.prefix.trim_end()
+        });
+        if parsed.bullet.is_some() || !continues {
+            if let Some((first, rest, words)) = paragraph.take() {
+                fill(&mut reflowed, &first, &rest, &words, width);
+            }
+            let (first, rest) = match parsed.bullet {
+                Some(bullet) => (
+                    format!("{}{} ", parsed.prefix, bullet),
+                    format!(
+                        "{}{}",
+                        parsed.prefix,
+                        " ".repeat(bullet.chars().count().saturating_add(1))
+                    ),
+                ),
+                None => (parsed.prefix.clone(), parsed.prefix.clone()),
+            };
+            paragraph = Some((first, rest, Vec::new()));
+        }
+        if let Some((_, _, words)) = paragraph.as_mut() {
+            words.extend(parsed.body.split_whitespace());
+        }
+    }
+    if let Some((first, rest, words)) = paragraph.take() {
+        fill(&mut reflowed, &first, &rest, &words, width);
+    }
+    reflowed
+}
+
+pub fn is_comment(line: &str, opts: &HighlightingOptions) -> bool {
+    parse_line(line, opts).comment
+}
+
+pub fn hard_wrap(
+    line: &str,
+    width: usize,
+    tabstop: usize,
+    opts: &HighlightingOptions,
+) -> Option<(String, String, usize)> {
+    let parsed = parse_line(line, opts);
+    let tabstop = cmp::max(tabstop, 1);
+    let mut column: usize = 0;
+    let mut fitting = None;
+    let mut first = None;
+    let mut previous_blank = true;
+    for (index, (offset, grapheme)) in line.grapheme_indices(true).enumerate() {
+        let blank = grapheme.chars().all(char::is_whitespace);
+        if blank && !previous_blank && offset >= parsed.body_start {
+            first = first.or(Some((index, offset)));
+            if column <= width {
+                fitting = Some((index, offset));
+            }
+        }
+        previous_blank = blank;
+        column = if grapheme == "\t" {
+            column.saturating_add(tabstop.saturating_sub(column % tabstop))
+        } else {
+            column.saturating_add(1)
+        };
+    }
+    if column <= width {
+        return None;
+    }
+    let (index, offset) = fitting.or(first)?;
+    let head = line.get(..offset)?.trim_end().to_string();
+    let skipped = line
+        .get(offset..)?
+        .graphemes(true)
+        .take_while(|grapheme| grapheme.chars().all(char::is_whitespace))
+        .count();
+    let prefix = match parsed.bullet {
+        Some(bullet) => format!(
+            "{}{}",
+            parsed.prefix,
+            " ".repeat(bullet.chars().count().saturating_add(1))
+        ),
+        None => parsed.prefix,
+    };
+    Some((head, prefix, index.saturating_add(skipped)))
+}
+
+fn fill(reflowed: &mut Vec<String>, first: &str, rest: &str, words: &[&str], width: usize) {
+    let mut current = first.to_string();
+    let mut empty = true;
+    for word in words {
+        let len = current.chars().count().saturating_add(word.chars().count());
+        if !empty && len.saturating_add(1) > width {
+            reflowed.push(current);
+            current = rest.to_string();
+            empty = true;
+        }
+        if !empty {
+            current.push(' ');
+        }
+        current.push_str(word);
+        empty = false;
+    }
+    reflowed.push(current);
+}
+
+fn parse_line<'a>(line: &'a str, opts: &HighlightingOptions) -> Line<'a> {
+    let indent_len = line
+        .find(|c: char| c != ' ' && c != '\t')
+        .unwrap_or(line.len());
+    let (indent, mut rest) = line.split_at(indent_len);
+    let mut prefix = indent.to_string();
+    let comment: String = opts.line_comment().iter().collect();
+    let leader_len = if !comment.is_empty() && rest.starts_with(&comment) {
+        let run = comment.chars().last().unwrap_or_default();
+        rest.find(|c: char| c != run && c != '!')
+            .unwrap_or(rest.len())
+    } else if (opts.code_fences() && rest.starts_with('>'))
+        || (opts.block_comment_start().last() == Some(&'*')
+            && (rest == "*" || rest.starts_with("* ")))
+    {
+        1
+    } else {
+        0
+    };
+    let comment = leader_len > 0 && !rest.starts_with('>');
+    if leader_len > 0 {
+        let (leader, body) = rest.split_at(leader_len);
+        let spaces = body.len().saturating_sub(body.trim_start().len());
+        prefix.push_str(leader);
+        prefix.push_str(&" ".repeat(cmp::max(spaces, 1)));
+        rest = body.trim_start();
+    }
+    let bullet = list_bullet(rest);
+    let body = bullet.map_or(rest, |bullet| {
+        rest.get(bullet.len()..).unwrap_or_default().trim_start()
+    });
+    Line {
+        prefix,
+        comment,
+        bullet,
+        body: body.trim_end(),
+        body_start: line.len().saturating_sub(body.len()),
+    }
+}
+
+fn list_bullet(text: &str) -> Option<&str> {
+    let (marker, _) = text.split_at(text.find(' ')?);
+    let is_numbered = marker
+        .strip_suffix('.')
+        .or_else(|| marker.strip_suffix(')'))
+        .map_or(false, |digits| {
+            !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+        });
+    (matches!(marker, "-" | "*" | "+") || is_numbered).then_some(marker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileType;
+
+    fn opts(file_name: &str) -> HighlightingOptions {
+        FileType::from(file_name).highlighting_options().clone()
+    }
+
+    fn lines(text: &[&str]) -> Vec<String> {
+        text.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn comments_keep_their_leader() {
+        let rust = opts("main.rs");
+        assert_eq!(
+            reflow(&lines(&["// aaa bbb", "//   ccc ddd eee"]), 14, &rust),
+            ["// aaa bbb ccc", "// ddd eee"]
+        );
+        assert!(is_comment("    // note", &rust));
+        assert!(!is_comment("let x = 1; // note", &rust));
+    }
+
+    #[test]
+    fn bullets_start_paragraphs_and_indent_their_continuation() {
+        let markdown = opts("notes.md");
+        assert_eq!(
+            reflow(&lines(&["- one two three four", "- five"]), 12, &markdown),
+            ["- one two", "  three four", "- five"]
+        );
+        assert_eq!(
+            reflow(&lines(&["1. one two three"]), 9, &markdown),
+            ["1. one", "   two", "   three"]
+        );
+    }
+
+    #[test]
+    fn blank_lines_and_fences_are_left_alone() {
+        let markdown = opts("notes.md");
+        assert_eq!(
+            reflow(
+                &lines(&["a", "b", "", "```", "c    d", "```", "# Title", "e"]),
+                80,
+                &markdown
+            ),
+            ["a b", "", "```", "c    d", "```", "# Title", "e"]
+        );
+    }
+
+    #[test]
+    fn hard_wrap_breaks_at_the_last_fitting_blank() {
+        let rust = opts("main.rs");
+        assert!(hard_wrap("aaa bbb", 8, 4, &rust).is_none());
+        assert_eq!(
+            hard_wrap("aaa bbb ccc", 8, 4, &rust),
+            Some((String::from("aaa bbb"), String::new(), 8))
+        );
+        assert_eq!(
+            hard_wrap("// aaa bbb ccc", 11, 4, &rust),
+            Some((String::from("// aaa bbb"), String::from("// "), 11))
+        );
+        assert_eq!(
+            hard_wrap("\taa bb", 8, 8, &rust),
+            Some((String::from("\taa"), String::from("\t"), 4))
+        );
+    }
+}
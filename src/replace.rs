@@ -0,0 +1,131 @@
+use anyhow::{bail, Context, Result};
+use ignore::WalkBuilder;
+use regex::Regex;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub struct LineChange {
+    pub line: usize,
+    pub before: String,
+    pub after: String,
+}
+
+pub struct FileReplacement {
+    pub path: PathBuf,
+    pub name: String,
+    original: String,
+    contents: String,
+    pub changes: Vec<LineChange>,
+    pub replacements: usize,
+}
+
+pub fn plan(root: &Path, regex: &Regex, replacement: &str, global: bool) -> Vec<FileReplacement> {
+    let mut files = Vec::new();
+    for result in WalkBuilder::new(root).build() {
+        let Ok(dir_entry) = result else {
+            continue;
+        };
+        if !dir_entry
+            .file_type()
+            .map_or(false, |file_type| file_type.is_file())
+        {
+            continue;
+        }
+        let Ok(original) = fs::read_to_string(dir_entry.path()) else {
+            continue;
+        };
+        if !regex.is_match(&original) {
+            continue;
+        }
+        let mut contents = String::with_capacity(original.len());
+        let mut changes = Vec::new();
+        let mut replacements: usize = 0;
+        for (index, line) in original.split_inclusive('\n').enumerate() {
+            let body = line.trim_end_matches(&['\r', '\n'][..]);
+            let ending = &line[body.len()..];
+            let count = regex.find_iter(body).count();
+            if count == 0 {
+                contents.push_str(line);
+                continue;
+            }
+            let replaced = if global {
+                regex.replace_all(body, replacement)
+            } else {
+                regex.replace(body, replacement)
+            };
+            replacements = replacements.saturating_add(if global { count } else { 1 });
+            contents.push_str(&replaced);
+            contents.push_str(ending);
+            changes.push(LineChange {
+                line: index.saturating_add(1),
+                before: body.to_string(),
+                after: replaced.to_string(),
+            });
+        }
+        if changes.is_empty() {
+            continue;
+        }
+        let name = dir_entry
+            .path()
+            .strip_prefix(root)
+            .unwrap_or(dir_entry.path())
+            .to_string_lossy()
+            .to_string();
+        files.push(FileReplacement {
+            path: dir_entry.path().to_path_buf(),
+            name,
+            original,
+            contents,
+            changes,
+            replacements,
+        });
+    }
+    files
+}
+
+pub fn preview(files: &[FileReplacement]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for file in files {
+        lines.push(format!(
+            "{} ({} replacement{})",
+            file.name,
+            file.replacements,
+            if file.replacements == 1 { "" } else { "s" }
+        ));
+        for change in &file.changes {
+            lines.push(format!("{:>6} - {}", change.line, change.before));
+            lines.push(format!("{:>6} + {}", change.line, change.after));
+        }
+    }
+    lines
+}
+
+pub fn apply(files: &[FileReplacement]) -> Result<usize> {
+    for file in files {
+        if fs::read_to_string(&file.path)? != file.original {
+            bail!("{} changed since the preview", file.name);
+        }
+    }
+    for file in files {
+        write_atomically(&file.path, &file.contents)?;
+    }
+    Ok(files.len())
+}
+
+// Each file is written beside itself and renamed into place, so an
+// interrupted replace never leaves one half written.
+fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut temp_path = path.clone().into_os_string();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let permissions = fs::metadata(&path)?.permissions();
+    fs::write(&temp_path, contents).with_context(|| format!("{}", temp_path.display()))?;
+    let renamed =
+        fs::set_permissions(&temp_path, permissions).and_then(|()| fs::rename(&temp_path, &path));
+    if let Err(error) = renamed {
+        let _ = fs::remove_file(&temp_path);
+        return Err(error).with_context(|| format!("{}", path.display()));
+    }
+    Ok(())
+}
@@ -1,17 +1,49 @@
+use crate::encoding::{self, Encoding};
 use crate::highlighting;
+use crate::terminal::Attribute;
+use crate::theme::Theme;
 use crate::HighlightingOptions;
 use crate::SearchDirection;
+use crate::SearchPattern;
+use crate::Terminal;
+use regex::Regex;
+use std::cell::RefCell;
 use std::cmp;
+use std::mem;
+use std::sync::OnceLock;
 use termion::color;
 use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+const MAX_CHAR_ESCAPE_LEN: usize = 11;
+const TAB_GLYPH: char = '»';
+const TRAIL_GLYPH: char = '·';
+const NBSP_GLYPH: char = '␣';
+const INVALID_BYTE_GLYPH: char = '\u{fffd}';
+
+static NUMBER: OnceLock<Option<Regex>> = OnceLock::new();
+static TAG: OnceLock<Option<Regex>> = OnceLock::new();
+
+#[derive(Clone, PartialEq)]
+struct RenderKey {
+    start: usize,
+    end: usize,
+    tabstop: usize,
+    list: bool,
+    encoding: Encoding,
+    theme: usize,
+}
 
 #[derive(Default, Clone)]
 pub struct Row {
     string: String,
     highlighting: Vec<highlighting::Type>,
     pub is_highlighted: bool,
+    start_state: highlighting::State,
+    end_state: highlighting::State,
     len: usize,
     dirty: bool,
+    rendered: RefCell<Option<(RenderKey, String)>>,
 }
 
 impl From<&str> for Row {
@@ -20,48 +52,157 @@ impl From<&str> for Row {
             string: String::from(slice),
             highlighting: Vec::new(),
             is_highlighted: false,
+            start_state: highlighting::State::Normal,
+            end_state: highlighting::State::Normal,
             len: slice.graphemes(true).count(),
             dirty: false,
+            rendered: RefCell::new(None),
         }
     }
 }
 
 impl Row {
-    pub fn render(&self, start: usize, end: usize) -> String {
-        let end = cmp::min(end, self.string.len());
-        let start = cmp::min(start, end);
+    pub fn render(
+        &self,
+        start: usize,
+        end: usize,
+        theme: &Theme,
+        tabstop: usize,
+        list: bool,
+        encoding: Encoding,
+    ) -> String {
+        let key = RenderKey {
+            start,
+            end,
+            tabstop,
+            list,
+            encoding,
+            theme: theme.id,
+        };
+        if let Some((cached_key, rendered)) = &*self.rendered.borrow() {
+            if *cached_key == key {
+                return rendered.clone();
+            }
+        }
+        let rendered = self.render_uncached(start, end, theme, tabstop, list, encoding);
+        *self.rendered.borrow_mut() = Some((key, rendered.clone()));
+        rendered
+    }
+
+    // Anything that changes the text or its highlighting drops the cached
+    // rendering, so render only has to compare the viewport.
+    fn invalidate_render(&mut self) {
+        *self.rendered.get_mut() = None;
+    }
+
+    fn render_uncached(
+        &self,
+        start: usize,
+        end: usize,
+        theme: &Theme,
+        tabstop: usize,
+        list: bool,
+        encoding: Encoding,
+    ) -> String {
         let mut result = String::new();
         let mut current_highlighting = &highlighting::Type::None;
-        #[allow(clippy::integer_arithmetic)]
-        for (index, grapheme) in self.string[..]
-            .graphemes(true)
-            .enumerate()
-            .skip(start)
-            .take(end.saturating_sub(start))
-        {
-            if let Some(c) = grapheme.chars().next() {
-                let highlighting_type = self
-                    .highlighting
-                    .get(index)
-                    .unwrap_or(&highlighting::Type::None);
-                if highlighting_type != current_highlighting {
-                    current_highlighting = highlighting_type;
-                    let start_highlight =
-                        format!("{}", termion::color::Fg(highlighting_type.to_color()));
-                    result.push_str(&start_highlight[..]);
-                }
-                if c == '\t' {
-                    result.push_str(" ");
-                } else {
-                    result.push(c);
+        let mut current_style = Attribute::Plain;
+        let mut column: usize = 0;
+        let trailing_start = self.len.saturating_sub(
+            self.string[..]
+                .graphemes(true)
+                .rev()
+                .take_while(|grapheme| *grapheme == " ")
+                .count(),
+        );
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            if column >= end {
+                break;
+            }
+            let next_column = column.saturating_add(grapheme_width(grapheme, column, tabstop));
+            if next_column > start {
+                if let Some(c) = grapheme.chars().next() {
+                    let glyph = if list {
+                        match c {
+                            '\t' => Some(TAB_GLYPH),
+                            ' ' if index >= trailing_start => Some(TRAIL_GLYPH),
+                            '\u{a0}' => Some(NBSP_GLYPH),
+                            _ => None,
+                        }
+                    } else {
+                        None
+                    }
+                    // Markers only stand for raw bytes in a lossy buffer.
+                    .or_else(|| {
+                        encoding::marker_byte(c)
+                            .filter(|_| encoding == Encoding::Utf8Lossy)
+                            .map(|_| INVALID_BYTE_GLYPH)
+                    });
+                    let highlighting_type = if glyph.is_some() {
+                        &highlighting::Type::Whitespace
+                    } else {
+                        self.highlighting
+                            .get(index)
+                            .unwrap_or(&highlighting::Type::None)
+                    };
+                    if highlighting_type != current_highlighting {
+                        current_highlighting = highlighting_type;
+                        let start_highlight =
+                            Terminal::fg_sequence(highlighting_type.to_color(theme));
+                        result.push_str(&start_highlight[..]);
+                        let style = theme.style(*highlighting_type);
+                        if style != current_style {
+                            result.push_str(Terminal::attribute_end_sequence(current_style));
+                            result.push_str(&Terminal::attribute_sequence(style));
+                            current_style = style;
+                        }
+                    }
+                    let visible =
+                        cmp::min(next_column, end).saturating_sub(cmp::max(column, start));
+                    if c == '\t' {
+                        if let Some(glyph) = glyph.filter(|_| column >= start && visible > 0) {
+                            result.push(glyph);
+                            result.push_str(&" ".repeat(visible.saturating_sub(1)));
+                        } else {
+                            result.push_str(&" ".repeat(visible));
+                        }
+                    } else if column < start || next_column > end {
+                        // Half of a wide character is all that fits at the edge.
+                        result.push_str(&" ".repeat(visible));
+                    } else if let Some(glyph) = glyph {
+                        result.push(glyph);
+                    } else {
+                        result.push_str(grapheme);
+                    }
                 }
             }
+            column = next_column;
         }
         let end_highlight = format!("{}", termion::color::Fg(color::Reset));
         result.push_str(&end_highlight[..]);
+        result.push_str(Terminal::attribute_end_sequence(current_style));
         result
     }
 
+    pub fn column(&self, at: usize, tabstop: usize) -> usize {
+        let mut column: usize = 0;
+        for grapheme in self.string[..].graphemes(true).take(at) {
+            column = column.saturating_add(grapheme_width(grapheme, column, tabstop));
+        }
+        column.saturating_add(at.saturating_sub(self.len))
+    }
+
+    pub fn index_at_column(&self, target: usize, tabstop: usize) -> usize {
+        let mut column: usize = 0;
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            column = column.saturating_add(grapheme_width(grapheme, column, tabstop));
+            if column > target {
+                return index;
+            }
+        }
+        self.len
+    }
+
     pub fn len(&self) -> usize {
         self.len
     }
@@ -70,6 +211,25 @@ impl Row {
         self.dirty = false;
     }
 
+    // A copy of the text alone, for highlighting on another thread.
+    pub fn text_copy(&self) -> Self {
+        Self {
+            string: self.string.clone(),
+            len: self.len,
+            ..Self::default()
+        }
+    }
+
+    pub fn take_highlighting(&mut self, other: Self) {
+        if self.highlighting != other.highlighting {
+            self.invalidate_render();
+        }
+        self.highlighting = other.highlighting;
+        self.is_highlighted = other.is_highlighted;
+        self.start_state = other.start_state;
+        self.end_state = other.end_state;
+    }
+
     pub fn is_dirty(&self) -> bool {
         self.dirty
     }
@@ -80,7 +240,7 @@ impl Row {
 
     pub fn left_space(&self) -> usize {
         for (i, c) in self.string.chars().enumerate() {
-            if c != ' ' {
+            if c != ' ' && c != '\t' {
                 return i;
             }
         }
@@ -91,6 +251,7 @@ impl Row {
         if at >= self.len() {
             self.string.push(c);
             self.len = self.len.saturating_add(1);
+            self.invalidate_render();
             return;
         }
         let mut result: String = String::new();
@@ -103,6 +264,7 @@ impl Row {
         self.len = self.len.saturating_add(1);
         self.string = result;
         self.dirty = true;
+        self.invalidate_render();
     }
 
     pub fn delete(&mut self, at: usize) -> usize {
@@ -131,15 +293,17 @@ impl Row {
         self.len -= deleted;
         self.string = result;
         self.dirty = true;
+        self.invalidate_render();
         deleted.saturating_sub(1)
     }
 
     pub fn append(&mut self, new: &Self) {
         self.string = format!("{}{}", self.string, new.string);
         self.len = self.len.saturating_add(new.len);
+        self.invalidate_render();
     }
 
-    pub fn split(&mut self, at: usize) -> Self {
+    pub fn split(&mut self, at: usize, tabstop: usize, shiftwidth: usize) -> Self {
         let mut row: String = String::new();
         let mut length: usize = 0;
         let mut splitted_row: String = String::new();
@@ -152,38 +316,68 @@ impl Row {
             }
         }
 
-        let mut spaces = self.indentation();
+        let mut spaces = self.indentation(tabstop, shiftwidth);
+        let indent: Vec<&str> = self.string[..].graphemes(true).take(spaces).collect();
         let mut splitted_length = self.len.saturating_sub(length).saturating_add(spaces);
         if at < spaces {
-            row.insert_str(0, &" ".repeat(spaces.saturating_sub(at))[..]);
+            row.insert_str(0, &indent.get(at..).unwrap_or_default().concat());
             let indent_offset = spaces.saturating_sub(at);
             length = length.saturating_add(indent_offset);
             splitted_length = self.len.saturating_sub(length).saturating_add(spaces);
             spaces = spaces.saturating_sub(indent_offset);
         }
-        splitted_row.insert_str(0, &" ".repeat(spaces)[..]);
+        splitted_row.insert_str(0, &indent.get(..spaces).unwrap_or_default().concat());
         self.string = row;
         self.len = length;
         self.is_highlighted = false;
         self.dirty = true;
+        self.invalidate_render();
         Self {
             string: splitted_row,
             len: splitted_length,
             is_highlighted: false,
+            start_state: highlighting::State::Normal,
+            end_state: highlighting::State::Normal,
             highlighting: Vec::new(),
             dirty: true,
+            rendered: RefCell::new(None),
         }
     }
 
-    pub fn indentation(&self) -> usize {
-        let mut spaces: usize = 0;
-        for grapheme in self.string[..].graphemes(true) {
-            if grapheme != &" "[..] {
-                break;
-            }
-            spaces = spaces.saturating_add(1);
-        }
-        spaces.saturating_sub(spaces % 4)
+    pub fn is_blank(&self) -> bool {
+        self.string.trim().is_empty()
+    }
+
+    pub fn indent_len(&self) -> usize {
+        self.string[..]
+            .graphemes(true)
+            .take_while(|grapheme| *grapheme == " " || *grapheme == "\t")
+            .count()
+    }
+
+    // How much of the leading whitespace a new line inherits: the indent
+    // rounded down to a multiple of shiftwidth, with tabs reaching the next
+    // tabstop.
+    pub fn indentation(&self, tabstop: usize, shiftwidth: usize) -> usize {
+        let (tabstop, shiftwidth) = (cmp::max(tabstop, 1), cmp::max(shiftwidth, 1));
+        let mut column: usize = 0;
+        let columns: Vec<usize> = self.string[..]
+            .graphemes(true)
+            .take_while(|grapheme| *grapheme == " " || *grapheme == "\t")
+            .map(|grapheme| {
+                column = if grapheme == "\t" {
+                    column.saturating_add(tabstop.saturating_sub(column % tabstop))
+                } else {
+                    column.saturating_add(1)
+                };
+                column
+            })
+            .collect();
+        let limit = column.saturating_sub(column % shiftwidth);
+        columns
+            .iter()
+            .take_while(|column| **column <= limit)
+            .count()
     }
 
     pub fn as_bytes(&self) -> &[u8] {
@@ -194,60 +388,315 @@ impl Row {
         self.string.clone()
     }
 
-    pub fn find(&self, query: &str, at: usize, direction: SearchDirection) -> Option<usize> {
-        if at > self.len || query.is_empty() {
-            return None;
-        }
-        let start = if direction == SearchDirection::Forward {
-            at
-        } else {
-            0
-        };
-        let end = if direction == SearchDirection::Forward {
-            self.len
-        } else {
-            at
-        };
-        #[allow(clippy::integer_arithmetic)]
-        let substring: String = self.string[..]
+    pub fn grapheme(&self, at: usize) -> Option<&str> {
+        self.string[..].graphemes(true).nth(at)
+    }
+
+    pub fn substring(&self, start: usize, end: usize) -> String {
+        self.string[..]
             .graphemes(true)
             .skip(start)
             .take(end.saturating_sub(start))
+            .collect()
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    pub fn delete_range(&mut self, start: usize, end: usize) {
+        let end = cmp::min(end, self.len);
+        if start >= end {
+            return;
+        }
+        let result: String = self.string[..]
+            .graphemes(true)
+            .enumerate()
+            .filter(|(index, _)| *index < start || *index >= end)
+            .map(|(_, grapheme)| grapheme)
             .collect();
-        let matching_byte_index = if direction == SearchDirection::Forward {
-            substring.find(query)
+        self.len = self.len.saturating_sub(end.saturating_sub(start));
+        self.string = result;
+        self.is_highlighted = false;
+        self.dirty = true;
+        self.invalidate_render();
+    }
+
+    pub fn change_case(&mut self, start: usize, end: usize, case: CaseChange) -> bool {
+        let result: String = self.string[..]
+            .graphemes(true)
+            .enumerate()
+            .map(|(index, grapheme)| {
+                if index >= start && index < end {
+                    grapheme.chars().map(|c| case.apply(c)).collect()
+                } else {
+                    grapheme.to_string()
+                }
+            })
+            .collect();
+        if result == self.string {
+            return false;
+        }
+        self.len = result.graphemes(true).count();
+        self.string = result;
+        self.is_highlighted = false;
+        self.dirty = true;
+        self.invalidate_render();
+        true
+    }
+
+    pub fn has_number_after(&self, at: usize) -> bool {
+        self.number_bytes(at).is_some()
+    }
+
+    pub fn add_to_number(&mut self, at: usize, delta: i64) -> Option<usize> {
+        let (start, end) = self.number_bytes(at)?;
+        let number = self.string.get(start..end)?;
+        let replacement = match number
+            .strip_prefix("0x")
+            .or_else(|| number.strip_prefix("0X"))
+        {
+            Some(digits) => {
+                let value = u64::from_str_radix(digits, 16)
+                    .ok()?
+                    .wrapping_add_signed(delta);
+                let width = digits.len();
+                let prefix = number.get(..2).unwrap_or_default();
+                if digits.chars().any(|c| c.is_ascii_uppercase()) {
+                    format!("{}{:0width$X}", prefix, value)
+                } else {
+                    format!("{}{:0width$x}", prefix, value)
+                }
+            }
+            None => {
+                let value = number
+                    .parse::<i128>()
+                    .ok()?
+                    .saturating_add(i128::from(delta));
+                let digits = number.trim_start_matches('-');
+                // Zero padded numbers keep their width, so 007 becomes 008.
+                let width = if digits.len() > 1 && digits.starts_with('0') {
+                    digits.len()
+                } else {
+                    0
+                };
+                let sign = if value < 0 { "-" } else { "" };
+                format!("{}{:0width$}", sign, value.unsigned_abs())
+            }
+        };
+        let first = self.string.get(..start)?.graphemes(true).count();
+        let last = first.saturating_add(replacement.len()).saturating_sub(1);
+        self.string.replace_range(start..end, &replacement);
+        self.len = self.string[..].graphemes(true).count();
+        self.is_highlighted = false;
+        self.dirty = true;
+        self.invalidate_render();
+        Some(last)
+    }
+
+    fn number_bytes(&self, at: usize) -> Option<(usize, usize)> {
+        let cursor = self
+            .string
+            .grapheme_indices(true)
+            .nth(at)
+            .map_or(self.string.len(), |(offset, _)| offset);
+        let regex = NUMBER
+            .get_or_init(|| Regex::new("(-?)(0[xX][0-9a-fA-F]+|[0-9]+)").ok())
+            .as_ref()?;
+        let captures = regex
+            .captures_iter(&self.string)
+            .find(|captures| captures.get(0).map_or(false, |found| found.end() > cursor))?;
+        let digits = captures.get(2)?;
+        let hex = digits.as_str().starts_with("0x") || digits.as_str().starts_with("0X");
+        let signed = captures.get(1).map_or(false, |sign| {
+            !sign.as_str().is_empty()
+                && !hex
+                && !self.string[..sign.start()]
+                    .chars()
+                    .last()
+                    .map_or(false, char::is_alphanumeric)
+        });
+        let start = if signed {
+            digits.start().saturating_sub(1)
         } else {
-            substring.rfind(query)
+            digits.start()
         };
-        if let Some(matching_byte_index) = matching_byte_index {
-            for (grapheme_index, (byte_index, _)) in
-                substring[..].grapheme_indices(true).enumerate()
-            {
-                if matching_byte_index == byte_index {
-                    #[allow(clippy::integer_arithmetic)]
-                    return Some(start.saturating_add(grapheme_index));
+        Some((start, digits.end()))
+    }
+
+    pub fn path_at(&self, at: usize) -> Option<String> {
+        let graphemes: Vec<&str> = self.string[..].graphemes(true).collect();
+        let is_path = |index: usize| graphemes.get(index).map_or(false, |g| is_path_grapheme(g));
+        if !is_path(at) {
+            return None;
+        }
+        let mut start = at;
+        while start > 0 && is_path(start.saturating_sub(1)) {
+            start = start.saturating_sub(1);
+        }
+        let mut end = at;
+        while is_path(end) {
+            end = end.saturating_add(1);
+        }
+        Some(self.substring(start, end))
+    }
+
+    pub fn word_bounds(&self, at: usize, around: bool) -> Option<(usize, usize)> {
+        let graphemes: Vec<&str> = self.string[..].graphemes(true).collect();
+        let class = CharClass::from(*graphemes.get(at)?);
+        let class_at = |index: usize| graphemes.get(index).map(|g| CharClass::from(*g));
+        let mut start = at;
+        while start > 0 && class_at(start.saturating_sub(1)) == Some(class) {
+            start = start.saturating_sub(1);
+        }
+        let mut end = at.saturating_add(1);
+        while class_at(end) == Some(class) {
+            end = end.saturating_add(1);
+        }
+        if around {
+            if let Some(next_class) = class_at(end) {
+                if class == CharClass::Whitespace || next_class == CharClass::Whitespace {
+                    while class_at(end) == Some(next_class) {
+                        end = end.saturating_add(1);
+                    }
+                    return Some((start, end));
+                }
+            }
+            if class != CharClass::Whitespace {
+                while start > 0 && class_at(start.saturating_sub(1)) == Some(CharClass::Whitespace)
+                {
+                    start = start.saturating_sub(1);
                 }
             }
         }
-        None
+        Some((start, end))
     }
 
-    fn highlight_match(&mut self, word: &Option<String>) {
-        if let Some(word) = word {
-            if word.is_empty() {
-                return;
+    pub fn quote_bounds(&self, at: usize, quote: char, around: bool) -> Option<(usize, usize)> {
+        let mut quote_str = [0; 4];
+        let quote_str = quote.encode_utf8(&mut quote_str);
+        let graphemes: Vec<&str> = self.string[..].graphemes(true).collect();
+        let mut quotes = Vec::new();
+        for (index, grapheme) in graphemes.iter().enumerate() {
+            let escaped = index > 0 && graphemes.get(index.saturating_sub(1)) == Some(&"\\");
+            if *grapheme == quote_str && !escaped {
+                quotes.push(index);
             }
-            let mut index = 0;
-            while let Some(search_match) = self.find(word, index, SearchDirection::Forward) {
-                if let Some(next_index) = search_match.checked_add(word[..].graphemes(true).count())
-                {
-                    #[allow(clippy::indexing_slicing)]
-                    for i in search_match..next_index {
-                        self.highlighting[i] = highlighting::Type::Match;
-                    }
-                    index = next_index;
-                } else {
-                    break;
+        }
+        let (start, end) = quotes
+            .chunks_exact(2)
+            .filter_map(|pair| Some((*pair.first()?, *pair.get(1)?)))
+            .find(|(_, end)| at <= *end)?;
+        if around {
+            Some((start, end.saturating_add(1)))
+        } else {
+            Some((start.saturating_add(1), end))
+        }
+    }
+
+    pub fn tag_bounds(&self, at: usize) -> Option<((usize, usize), (usize, usize))> {
+        let cursor = self
+            .string
+            .grapheme_indices(true)
+            .nth(at)
+            .map_or(self.string.len(), |(offset, _)| offset);
+        let regex = TAG
+            .get_or_init(|| Regex::new(r"<(/?)([A-Za-z][\w:.-]*)[^<>]*?(/?)>").ok())
+            .as_ref()?;
+        let mut open: Vec<(&str, usize, usize)> = Vec::new();
+        let mut innermost = None;
+        for captures in regex.captures_iter(&self.string) {
+            let (Some(tag), Some(name)) = (captures.get(0), captures.get(2)) else {
+                continue;
+            };
+            let closing = captures
+                .get(1)
+                .map_or(false, |slash| !slash.as_str().is_empty());
+            let self_closing = captures
+                .get(3)
+                .map_or(false, |slash| !slash.as_str().is_empty());
+            if self_closing {
+                continue;
+            }
+            if !closing {
+                open.push((name.as_str(), tag.start(), tag.end()));
+                continue;
+            }
+            let Some(index) = open
+                .iter()
+                .rposition(|(opened, _, _)| *opened == name.as_str())
+            else {
+                continue;
+            };
+            let (_, start, end) = open.get(index).copied()?;
+            open.truncate(index);
+            if start <= cursor
+                && cursor < tag.end()
+                && innermost.map_or(true, |((inner, _), _)| start > inner)
+            {
+                innermost = Some(((start, end), (tag.start(), tag.end())));
+            }
+        }
+        let index = |byte: usize| {
+            self.string
+                .get(..byte)
+                .unwrap_or_default()
+                .graphemes(true)
+                .count()
+        };
+        innermost.map(|((open_start, open_end), (close_start, close_end))| {
+            (
+                (index(open_start), index(open_end)),
+                (index(close_start), index(close_end)),
+            )
+        })
+    }
+
+    pub fn find(
+        &self,
+        pattern: &SearchPattern,
+        at: usize,
+        direction: SearchDirection,
+    ) -> Option<usize> {
+        if at > self.len {
+            return None;
+        }
+        let starts = self
+            .match_ranges(pattern)
+            .into_iter()
+            .map(|(start, _)| start);
+        if direction == SearchDirection::Forward {
+            starts.filter(|start| *start >= at).min()
+        } else {
+            starts.filter(|start| *start < at).max()
+        }
+    }
+
+    pub fn match_at(&self, pattern: &SearchPattern, at: usize) -> Option<(usize, usize)> {
+        self.match_ranges(pattern)
+            .into_iter()
+            .find(|(start, end)| *start <= at && at < *end)
+    }
+
+    pub fn match_ranges(&self, pattern: &SearchPattern) -> Vec<(usize, usize)> {
+        pattern
+            .matches(&self.string)
+            .into_iter()
+            .map(|(start, end)| (self.grapheme_index(start), self.grapheme_index(end)))
+            .collect()
+    }
+
+    fn grapheme_index(&self, byte_index: usize) -> usize {
+        self.string
+            .get(..byte_index)
+            .map_or(self.len, |prefix| prefix.graphemes(true).count())
+    }
+
+    fn highlight_match(&mut self, word: &Option<SearchPattern>) {
+        if let Some(word) = word {
+            for (start, end) in self.match_ranges(word) {
+                for highlighting in self.highlighting.iter_mut().take(end).skip(start) {
+                    *highlighting = highlighting::Type::Match;
                 }
             }
         }
@@ -346,7 +795,11 @@ impl Row {
     ) -> bool {
         if opts.characters() && c == '\'' {
             if let Some(next_char) = chars.get(index.saturating_add(1)) {
-                let closing_index = if *next_char == '\\' {
+                let closing_index = if *next_char == '\\' && opts.escape_sequences() {
+                    find_sequence(chars, index.saturating_add(3), &['\''])
+                        .filter(|closing| closing.saturating_sub(*index) <= MAX_CHAR_ESCAPE_LEN)
+                        .unwrap_or_else(|| index.saturating_add(3))
+                } else if *next_char == '\\' {
                     index.saturating_add(3)
                 } else {
                     index.saturating_add(2)
@@ -372,16 +825,18 @@ impl Row {
         c: char,
         chars: &[char],
     ) -> bool {
-        if opts.comments() && c == '/' && *index < chars.len() {
-            if let Some(next_char) = chars.get(index.saturating_add(1)) {
-                if *next_char == '/' {
-                    for _ in *index..chars.len() {
-                        self.highlighting.push(highlighting::Type::Comment);
-                        *index = index.saturating_add(1);
-                    }
-                    return true;
-                }
+        let delimiter = opts.line_comment();
+        if opts.comments()
+            && delimiter.first() == Some(&c)
+            && chars
+                .get(*index..)
+                .map_or(false, |rest| rest.starts_with(delimiter))
+        {
+            for _ in *index..chars.len() {
+                self.highlighting.push(highlighting::Type::Comment);
+                *index = index.saturating_add(1);
             }
+            return true;
         }
         false
     }
@@ -394,23 +849,22 @@ impl Row {
         c: char,
         chars: &[char],
     ) -> bool {
-        if opts.multiline_comments() && c == '/' && *index < chars.len() {
-            if let Some(next_char) = chars.get(index.saturating_add(1)) {
-                if *next_char == '*' {
-                    let closing_index = if let Some(closing_index) =
-                        self.string[index.saturating_add(2)..].find("*/")
-                    {
-                        index.saturating_add(closing_index).saturating_add(4)
-                    } else {
-                        chars.len()
-                    };
-                    for _ in *index..closing_index {
-                        self.highlighting.push(highlighting::Type::MultilineComment);
-                        *index = index.saturating_add(1);
-                    }
-                    return true;
-                }
+        let start = opts.block_comment_start();
+        let end = opts.block_comment_end();
+        if opts.multiline_comments()
+            && !end.is_empty()
+            && start.first() == Some(&c)
+            && chars
+                .get(*index..)
+                .map_or(false, |rest| rest.starts_with(start))
+        {
+            let closing_index = find_sequence(chars, index.saturating_add(start.len()), end)
+                .map_or(chars.len(), |closing| closing.saturating_add(end.len()));
+            for _ in *index..closing_index {
+                self.highlighting.push(highlighting::Type::MultilineComment);
+                *index = index.saturating_add(1);
             }
+            return true;
         }
         false
     }
@@ -421,31 +875,100 @@ impl Row {
         opts: &HighlightingOptions,
         c: char,
         chars: &[char],
+        end_state: &mut highlighting::State,
     ) -> bool {
-        if opts.strings() && c == '"' {
-            loop {
-                self.highlighting.push(highlighting::Type::String);
-                *index = index.saturating_add(1);
-                if let Some(next_char) = chars.get(*index) {
-                    if *next_char == '"' {
-                        if let Some(prev_char) = chars.get(index.saturating_sub(1)) {
-                            if *prev_char != '\\' {
-                                break;
-                            } else {
-                                continue;
-                            }
-                        }
-                        break;
-                    }
-                } else {
-                    break;
+        if !opts.strings() {
+            return false;
+        }
+        if opts.raw_strings() && self.highlight_raw_string(index, chars, end_state) {
+            return true;
+        }
+        if opts.triple_quoted_strings()
+            && (c == '"' || c == '\'')
+            && chars.get(index.saturating_add(1)) == Some(&c)
+            && chars.get(index.saturating_add(2)) == Some(&c)
+        {
+            let closing = vec![c, c, c];
+            let end = match find_sequence(chars, index.saturating_add(3), &closing) {
+                Some(found) => found.saturating_add(3),
+                None => {
+                    *end_state = highlighting::State::MultilineString(closing);
+                    chars.len()
                 }
+            };
+            self.push_highlighting(index, end, highlighting::Type::String);
+            return true;
+        }
+        if !opts.string_quotes().contains(&c) {
+            return false;
+        }
+        self.push_highlighting(index, index.saturating_add(1), highlighting::Type::String);
+        while let Some(next_char) = chars.get(*index) {
+            if *next_char == '\\' {
+                let hl_type = if opts.escape_sequences() {
+                    highlighting::Type::Escape
+                } else {
+                    highlighting::Type::String
+                };
+                let end = cmp::min(index.saturating_add(2), chars.len());
+                self.push_highlighting(index, end, hl_type);
+                continue;
             }
-            self.highlighting.push(highlighting::Type::String);
+            self.push_highlighting(index, index.saturating_add(1), highlighting::Type::String);
+            if *next_char == c {
+                break;
+            }
+        }
+        true
+    }
+
+    fn highlight_raw_string(
+        &mut self,
+        index: &mut usize,
+        chars: &[char],
+        end_state: &mut highlighting::State,
+    ) -> bool {
+        if let Some(prev_char) = index.checked_sub(1).and_then(|prev| chars.get(prev)) {
+            if !is_separator(*prev_char) {
+                return false;
+            }
+        }
+        let mut start = *index;
+        if chars.get(start) == Some(&'b') {
+            start = start.saturating_add(1);
+        }
+        if chars.get(start) != Some(&'r') {
+            return false;
+        }
+        let mut quote = start.saturating_add(1);
+        while chars.get(quote) == Some(&'#') {
+            quote = quote.saturating_add(1);
+        }
+        if chars.get(quote) != Some(&'"') {
+            return false;
+        }
+        let mut closing = vec!['"'];
+        closing.extend(
+            chars
+                .get(start.saturating_add(1)..quote)
+                .unwrap_or_default(),
+        );
+        let end = match find_sequence(chars, quote.saturating_add(1), &closing) {
+            Some(found) => found.saturating_add(closing.len()),
+            None => {
+                *end_state = highlighting::State::MultilineString(closing);
+                chars.len()
+            }
+        };
+        self.push_highlighting(index, end, highlighting::Type::String);
+        true
+    }
+
+    fn push_highlighting(&mut self, index: &mut usize, end: usize, hl_type: highlighting::Type) {
+        while *index < end {
+            self.highlighting.push(hl_type);
             *index = index.saturating_add(1);
-            return true;
         }
-        false
     }
 
     fn highlight_number(
@@ -467,7 +990,17 @@ impl Row {
                 self.highlighting.push(highlighting::Type::Number);
                 *index = index.saturating_add(1);
                 if let Some(next_char) = chars.get(*index) {
-                    if *next_char != '.' && !next_char.is_ascii_digit() {
+                    let continues = if opts.number_suffixes() {
+                        next_char.is_ascii_alphanumeric()
+                            || *next_char == '_'
+                            || (*next_char == '.'
+                                && chars
+                                    .get(index.saturating_add(1))
+                                    .map_or(false, char::is_ascii_digit))
+                    } else {
+                        *next_char == '.' || next_char.is_ascii_digit()
+                    };
+                    if !continues {
                         break;
                     }
                 } else {
@@ -479,30 +1012,127 @@ impl Row {
         false
     }
 
+    pub fn fence_language(&self) -> Option<&str> {
+        let trimmed = self.string.trim_start_matches(' ');
+        if self.string.len().saturating_sub(trimmed.len()) > 3 {
+            return None;
+        }
+        let info = trimmed
+            .strip_prefix("```")
+            .or_else(|| trimmed.strip_prefix("~~~"))?;
+        let info = info.trim_start_matches(&['`', '~'][..]).trim();
+        Some(info.split_whitespace().next().unwrap_or_default())
+    }
+
+    pub fn end_state(&self) -> &highlighting::State {
+        &self.end_state
+    }
+
+    pub fn code_chars(&self) -> impl Iterator<Item = (usize, char)> + '_ {
+        self.string
+            .graphemes(true)
+            .enumerate()
+            .filter(|(index, _)| {
+                !matches!(
+                    self.highlighting.get(*index),
+                    Some(
+                        highlighting::Type::String
+                            | highlighting::Type::Character
+                            | highlighting::Type::Escape
+                            | highlighting::Type::Comment
+                            | highlighting::Type::MultilineComment
+                    )
+                )
+            })
+            .filter_map(|(index, grapheme)| Some((index, grapheme.chars().next()?)))
+    }
+
+    pub fn unterminated_string(&self) -> Option<usize> {
+        if *self.end_state.inner() != highlighting::State::Normal {
+            return None;
+        }
+        let graphemes: Vec<&str> = self.string.graphemes(true).collect();
+        let last = graphemes.len().checked_sub(1)?;
+        if !matches!(
+            self.highlighting.get(last),
+            Some(highlighting::Type::String | highlighting::Type::Escape)
+        ) {
+            return None;
+        }
+        let start = self
+            .highlighting
+            .iter()
+            .take(last)
+            .rposition(|hl_type| {
+                *hl_type != highlighting::Type::String && *hl_type != highlighting::Type::Escape
+            })
+            .map_or(0, |index| index.saturating_add(1));
+        if start == 0 && *self.start_state.inner() != highlighting::State::Normal {
+            return None;
+        }
+        let quote = graphemes.get(start)?;
+        if *quote != "\"" && *quote != "'" {
+            return None;
+        }
+        let closed = last > start
+            && graphemes.get(last) == Some(quote)
+            && self.highlighting.get(last) == Some(&highlighting::Type::String);
+        if closed {
+            None
+        } else {
+            Some(start)
+        }
+    }
+
+    pub fn highlight_range(&mut self, start: usize, end: usize, hl_type: highlighting::Type) {
+        for highlighting in self.highlighting.iter_mut().take(end).skip(start) {
+            *highlighting = hl_type;
+        }
+        self.is_highlighted = false;
+        self.invalidate_render();
+    }
+
     #[allow(clippy::indexing_slicing, clippy::integer_arithmetic)]
     pub fn highlight(
         &mut self,
         opts: &HighlightingOptions,
-        word: &Option<String>,
-        start_with_comment: bool,
-    ) -> bool {
-        let chars: Vec<char> = self.string.chars().collect();
-        if self.is_highlighted && word.is_none() {
-            return false;
+        word: &Option<SearchPattern>,
+        start_state: highlighting::State,
+    ) -> highlighting::State {
+        if self.is_highlighted && word.is_none() && self.start_state == start_state {
+            return self.end_state.clone();
         }
-        self.highlighting = Vec::new();
+        let chars: Vec<char> = self.string.chars().collect();
+        let previous = mem::take(&mut self.highlighting);
         let mut index = 0;
-        let mut in_ml_comment = start_with_comment;
-        if in_ml_comment {
-            let closing_index = if let Some(closing_index) = self.string.find("*/") {
-                closing_index.saturating_add(2)
-            } else {
-                chars.len()
-            };
-            for _ in 0..closing_index {
-                self.highlighting.push(highlighting::Type::MultilineComment);
+        let mut end_state = highlighting::State::Normal;
+        let mut in_ml_comment = false;
+        match start_state.inner() {
+            highlighting::State::MultilineComment => {
+                let end = opts.block_comment_end();
+                let closing_index = find_sequence(&chars, 0, end)
+                    .map_or(chars.len(), |closing| closing.saturating_add(end.len()));
+                self.push_highlighting(
+                    &mut index,
+                    closing_index,
+                    highlighting::Type::MultilineComment,
+                );
+                in_ml_comment = true;
+            }
+            highlighting::State::MultilineString(closing) => {
+                match find_sequence(&chars, 0, closing) {
+                    Some(found) => self.push_highlighting(
+                        &mut index,
+                        found.saturating_add(closing.len()),
+                        highlighting::Type::String,
+                    ),
+                    None => {
+                        self.push_highlighting(&mut index, chars.len(), highlighting::Type::String);
+                        end_state = start_state.inner().clone();
+                    }
+                }
             }
-            index = closing_index;
+            highlighting::State::Normal | highlighting::State::Fence(_, _) => (),
         }
         while let Some(c) = chars.get(index) {
             if self.highlight_multiline_comment(&mut index, opts, *c, &chars) {
@@ -514,11 +1144,11 @@ impl Row {
                 break;
             }
             let prev_index = index;
-            if self.highlight_char(&mut index, opts, *c, &chars)
+            if self.highlight_string(&mut index, opts, *c, &chars, &mut end_state)
+                || self.highlight_char(&mut index, opts, *c, &chars)
                 || self.highlight_comment(&mut index, opts, *c, &chars)
                 || self.highlight_primary_keywords(&mut index, opts, &chars)
                 || self.highlight_secondary_keywords(&mut index, opts, &chars)
-                || self.highlight_string(&mut index, opts, *c, &chars)
                 || self.highlight_number(&mut index, opts, *c, &chars)
             {
                 if index != prev_index {
@@ -529,14 +1159,212 @@ impl Row {
             index = index.saturating_add(1);
         }
         self.highlight_match(word);
-        if in_ml_comment && &self.string[self.string.len().saturating_sub(2)..] != "*/" {
-            return true;
+        if in_ml_comment && !chars.ends_with(opts.block_comment_end()) {
+            end_state = highlighting::State::MultilineComment;
+        }
+        if let highlighting::State::Fence(language, _) = &start_state {
+            end_state = highlighting::State::Fence(language.clone(), Box::new(end_state));
+        }
+        if self.highlighting != previous {
+            self.invalidate_render();
         }
         self.is_highlighted = true;
-        false
+        self.start_state = start_state;
+        self.end_state = end_state.clone();
+        end_state
     }
 }
 
+#[derive(PartialEq, Clone, Copy)]
+pub enum CaseChange {
+    Lower,
+    Upper,
+    Toggle,
+}
+
+impl CaseChange {
+    fn apply(self, c: char) -> String {
+        match self {
+            Self::Lower => c.to_lowercase().collect(),
+            Self::Upper => c.to_uppercase().collect(),
+            Self::Toggle if c.is_lowercase() => c.to_uppercase().collect(),
+            Self::Toggle => c.to_lowercase().collect(),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum CharClass {
+    Whitespace,
+    Word,
+    Punctuation,
+}
+
+impl From<&str> for CharClass {
+    fn from(grapheme: &str) -> Self {
+        match grapheme.chars().next() {
+            Some(c) if c.is_whitespace() => CharClass::Whitespace,
+            Some(c) if c.is_alphanumeric() || c == '_' => CharClass::Word,
+            _ => CharClass::Punctuation,
+        }
+    }
+}
+
+fn is_path_grapheme(grapheme: &str) -> bool {
+    grapheme
+        .chars()
+        .all(|c| c.is_alphanumeric() || "/._-~:+@".contains(c))
+}
+
+fn find_sequence(chars: &[char], from: usize, sequence: &[char]) -> Option<usize> {
+    if sequence.is_empty() {
+        return None;
+    }
+    chars
+        .get(from..)?
+        .windows(sequence.len())
+        .position(|window| window == sequence)
+        .map(|position| position.saturating_add(from))
+}
+
 fn is_separator(c: char) -> bool {
     c.is_ascii_punctuation() || c.is_ascii_whitespace()
 }
+
+fn grapheme_width(grapheme: &str, column: usize, tabstop: usize) -> usize {
+    if grapheme == "\t" {
+        let tabstop = cmp::max(tabstop, 1);
+        tabstop.saturating_sub(column % tabstop)
+    } else {
+        grapheme.width()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cjk_graphemes_take_two_columns() {
+        for grapheme in ["\u{4e2d}", "\u{3042}", "\u{d55c}", "\u{ff21}"] {
+            assert_eq!(grapheme_width(grapheme, 0, 4), 2, "{}", grapheme);
+        }
+    }
+
+    #[test]
+    fn emoji_sequences_take_two_columns() {
+        for grapheme in [
+            "\u{1f600}",
+            "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}",
+            "\u{1f44d}\u{1f3fd}",
+            "\u{2764}\u{fe0f}",
+            "\u{1f1ef}\u{1f1f5}",
+        ] {
+            assert_eq!(grapheme_width(grapheme, 0, 4), 2, "{:?}", grapheme);
+        }
+    }
+
+    #[test]
+    fn combining_marks_add_no_width() {
+        for grapheme in ["e\u{301}", "a\u{308}\u{323}", "\u{5d0}\u{5b8}"] {
+            assert_eq!(grapheme_width(grapheme, 0, 4), 1, "{:?}", grapheme);
+        }
+    }
+
+    #[test]
+    fn tabs_reach_the_next_tabstop() {
+        assert_eq!(grapheme_width("\t", 0, 4), 4);
+        assert_eq!(grapheme_width("\t", 5, 4), 3);
+        assert_eq!(grapheme_width("\t", 3, 0), 1);
+    }
+
+    #[test]
+    fn indentation_rounds_down_to_shiftwidth() {
+        assert_eq!(Row::from("foo").indentation(8, 4), 0);
+        assert_eq!(Row::from("    foo").indentation(8, 4), 4);
+        assert_eq!(Row::from("      foo").indentation(8, 4), 4);
+        assert_eq!(Row::from("   foo").indentation(8, 4), 0);
+        assert_eq!(Row::from("      foo").indentation(8, 1), 6);
+    }
+
+    #[test]
+    fn indentation_counts_tabs_to_the_next_tabstop() {
+        assert_eq!(Row::from("\tfoo").indentation(8, 4), 1);
+        assert_eq!(Row::from("\t  foo").indentation(8, 4), 1);
+        assert_eq!(Row::from("  \tfoo").indentation(4, 4), 3);
+        assert_eq!(Row::from("\t\tfoo").indentation(4, 8), 2);
+        assert_eq!(Row::from("\t \t").indentation(4, 8), 3);
+    }
+
+    #[test]
+    fn split_copies_the_rounded_indentation() {
+        let mut row = Row::from("      foo bar");
+        let rest = row.split(10, 8, 4);
+        assert_eq!(row.contents(), "      foo ");
+        assert_eq!(rest.contents(), "    bar");
+        let mut row = Row::from("\t foo");
+        assert_eq!(row.split(5, 4, 4).contents(), "\t");
+    }
+
+    fn add(text: &str, at: usize, delta: i64) -> String {
+        let mut row = Row::from(text);
+        row.add_to_number(at, delta);
+        row.contents()
+    }
+
+    #[test]
+    fn add_to_number_keeps_zero_padding() {
+        assert_eq!(add("x = 007", 0, 1), "x = 008");
+        assert_eq!(add("x = 009", 0, 1), "x = 010");
+        assert_eq!(add("x = 010", 0, 5), "x = 015");
+        assert_eq!(add("x = 099", 0, 1), "x = 100");
+        assert_eq!(add("x = 999", 0, 1), "x = 1000");
+        assert_eq!(add("x = 0", 0, -1), "x = -1");
+        assert_eq!(add("x = -007", 0, 10), "x = 003");
+        assert_eq!(add("x = 0x0f", 0, 1), "x = 0x10");
+    }
+
+    #[test]
+    fn add_to_number_finds_the_number_at_or_after_the_cursor() {
+        assert_eq!(add("a1 b2", 0, 1), "a2 b2");
+        assert_eq!(add("a1 b2", 2, 1), "a1 b3");
+        assert_eq!(add("x-1", 0, 1), "x-2");
+        assert_eq!(add("x -1", 0, 2), "x 1");
+    }
+
+    #[test]
+    fn markers_stand_for_invalid_bytes_only_in_lossy_buffers() {
+        let theme = Theme::default();
+        let row = Row::from("a\u{10ffe9}b");
+        assert!(row
+            .render(0, 10, &theme, 4, false, Encoding::Utf8Lossy)
+            .contains(INVALID_BYTE_GLYPH));
+        let render = row.render(0, 10, &theme, 4, false, Encoding::Utf8);
+        assert!(!render.contains(INVALID_BYTE_GLYPH));
+        assert!(render.contains('\u{10ffe9}'));
+    }
+
+    #[test]
+    fn edits_drop_the_cached_rendering() {
+        let theme = Theme::default();
+        let mut row = Row::from("abc");
+        let plain = row.render(0, 10, &theme, 4, false, Encoding::Utf8);
+        assert_eq!(row.render(0, 10, &theme, 4, false, Encoding::Utf8), plain);
+        row.insert(3, 'd');
+        assert!(row
+            .render(0, 10, &theme, 4, false, Encoding::Utf8)
+            .contains("abcd"));
+        row.highlight(
+            &HighlightingOptions::default(),
+            &None,
+            highlighting::State::Normal,
+        );
+        let edited = row.render(0, 10, &theme, 4, false, Encoding::Utf8);
+        row.highlight_range(0, 2, highlighting::Type::Match);
+        assert_ne!(row.render(0, 10, &theme, 4, false, Encoding::Utf8), edited);
+        row.delete_range(0, 4);
+        assert!(!row
+            .render(0, 10, &theme, 4, false, Encoding::Utf8)
+            .contains('a'));
+    }
+}
@@ -0,0 +1,54 @@
+use anyhow::Result;
+use regex::Regex;
+
+const BUILTIN_PATTERNS: [(&str, &str); 5] = [
+    ("AWS access key", r"\b(AKIA|ASIA)[0-9A-Z]{16}\b"),
+    (
+        "AWS secret key",
+        r#"(?i)aws_secret_access_key\s*[=:]\s*["']?[A-Za-z0-9/+=]{40}\b"#,
+    ),
+    (
+        "private key",
+        r"-----BEGIN ([A-Z0-9]+ )*PRIVATE KEY( BLOCK)?-----",
+    ),
+    ("GitHub token", r"\bgh[pousr]_[A-Za-z0-9]{36,}\b"),
+    ("Slack token", r"\bxox[abprs]-[A-Za-z0-9-]{10,}"),
+];
+
+pub struct SecretPattern {
+    name: String,
+    regex: Regex,
+}
+
+impl SecretPattern {
+    pub fn new(name: &str, pattern: &str) -> Result<Self> {
+        Ok(Self {
+            name: name.to_string(),
+            regex: Regex::new(pattern)?,
+        })
+    }
+}
+
+pub struct SecretMatch {
+    pub name: String,
+    pub line: usize,
+}
+
+pub fn builtin() -> Vec<SecretPattern> {
+    BUILTIN_PATTERNS
+        .iter()
+        .filter_map(|(name, pattern)| SecretPattern::new(name, pattern).ok())
+        .collect()
+}
+
+pub fn scan(lines: &[String], patterns: &[SecretPattern]) -> Option<SecretMatch> {
+    lines.iter().enumerate().find_map(|(index, line)| {
+        patterns
+            .iter()
+            .find(|pattern| pattern.regex.is_match(line))
+            .map(|pattern| SecretMatch {
+                name: pattern.name.clone(),
+                line: index.saturating_add(1),
+            })
+    })
+}
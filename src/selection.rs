@@ -0,0 +1,286 @@
+use crate::Terminal;
+use anyhow::{anyhow, bail, Context, Result};
+use std::env;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Selection {
+    Primary,
+    Clipboard,
+}
+
+impl Selection {
+    pub fn from_register(register: char) -> Option<Self> {
+        match register {
+            '*' => Some(Self::Primary),
+            '+' => Some(Self::Clipboard),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Primary => "primary selection",
+            Self::Clipboard => "clipboard",
+        }
+    }
+}
+
+pub trait ClipboardProvider {
+    fn name(&self) -> &'static str;
+    fn is_available(&self) -> bool;
+    fn supports(&self, _selection: Selection) -> bool {
+        true
+    }
+    fn can_paste(&self) -> bool {
+        true
+    }
+    fn copy(&self, selection: Selection, text: &str) -> Result<()>;
+    // None leaves the editor's own register in charge.
+    fn paste(&self, selection: Selection) -> Result<Option<String>>;
+}
+
+struct WlClipboard;
+
+impl ClipboardProvider for WlClipboard {
+    fn name(&self) -> &'static str {
+        "wl-clipboard"
+    }
+
+    fn is_available(&self) -> bool {
+        env::var_os("WAYLAND_DISPLAY").is_some() && in_path("wl-copy")
+    }
+
+    fn copy(&self, selection: Selection, text: &str) -> Result<()> {
+        let args: &[&str] = match selection {
+            Selection::Primary => &["--primary"],
+            Selection::Clipboard => &[],
+        };
+        run_copy("wl-copy", args, text)
+    }
+
+    fn paste(&self, selection: Selection) -> Result<Option<String>> {
+        let args: &[&str] = match selection {
+            Selection::Primary => &["--no-newline", "--primary"],
+            Selection::Clipboard => &["--no-newline"],
+        };
+        run_paste("wl-paste", args, selection).map(Some)
+    }
+}
+
+struct Xclip;
+
+impl ClipboardProvider for Xclip {
+    fn name(&self) -> &'static str {
+        "xclip"
+    }
+
+    fn is_available(&self) -> bool {
+        env::var_os("DISPLAY").is_some() && in_path("xclip")
+    }
+
+    fn copy(&self, selection: Selection, text: &str) -> Result<()> {
+        run_copy("xclip", &["-selection", x11_target(selection)], text)
+    }
+
+    fn paste(&self, selection: Selection) -> Result<Option<String>> {
+        run_paste(
+            "xclip",
+            &["-selection", x11_target(selection), "-out"],
+            selection,
+        )
+        .map(Some)
+    }
+}
+
+struct Xsel;
+
+impl ClipboardProvider for Xsel {
+    fn name(&self) -> &'static str {
+        "xsel"
+    }
+
+    fn is_available(&self) -> bool {
+        env::var_os("DISPLAY").is_some() && in_path("xsel")
+    }
+
+    fn copy(&self, selection: Selection, text: &str) -> Result<()> {
+        run_copy("xsel", &[xsel_flag(selection), "--input"], text)
+    }
+
+    fn paste(&self, selection: Selection) -> Result<Option<String>> {
+        run_paste("xsel", &[xsel_flag(selection), "--output"], selection).map(Some)
+    }
+}
+
+struct Pasteboard;
+
+impl ClipboardProvider for Pasteboard {
+    fn name(&self) -> &'static str {
+        "pbcopy"
+    }
+
+    fn is_available(&self) -> bool {
+        cfg!(target_os = "macos") && in_path("pbcopy")
+    }
+
+    fn supports(&self, selection: Selection) -> bool {
+        selection == Selection::Clipboard
+    }
+
+    fn copy(&self, _selection: Selection, text: &str) -> Result<()> {
+        run_copy("pbcopy", &[], text)
+    }
+
+    fn paste(&self, selection: Selection) -> Result<Option<String>> {
+        run_paste("pbpaste", &[], selection).map(Some)
+    }
+}
+
+struct Osc52;
+
+impl ClipboardProvider for Osc52 {
+    fn name(&self) -> &'static str {
+        "osc52"
+    }
+
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    fn can_paste(&self) -> bool {
+        false
+    }
+
+    fn copy(&self, selection: Selection, text: &str) -> Result<()> {
+        Terminal::copy_to_clipboard(selection == Selection::Primary, text);
+        Ok(())
+    }
+
+    fn paste(&self, selection: Selection) -> Result<Option<String>> {
+        bail!("OSC 52 cannot read the {}", selection.name())
+    }
+}
+
+struct Internal;
+
+impl ClipboardProvider for Internal {
+    fn name(&self) -> &'static str {
+        "internal"
+    }
+
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    fn copy(&self, _selection: Selection, _text: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn paste(&self, _selection: Selection) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+fn x11_target(selection: Selection) -> &'static str {
+    match selection {
+        Selection::Primary => "primary",
+        Selection::Clipboard => "clipboard",
+    }
+}
+
+fn xsel_flag(selection: Selection) -> &'static str {
+    match selection {
+        Selection::Primary => "--primary",
+        Selection::Clipboard => "--clipboard",
+    }
+}
+
+pub fn providers() -> Vec<Box<dyn ClipboardProvider>> {
+    vec![
+        Box::new(WlClipboard),
+        Box::new(Xclip),
+        Box::new(Xsel),
+        Box::new(Pasteboard),
+        Box::new(Osc52),
+        Box::new(Internal),
+    ]
+}
+
+fn chain(selection: Selection, paste: bool) -> impl Iterator<Item = Box<dyn ClipboardProvider>> {
+    providers().into_iter().filter(move |provider| {
+        provider.is_available() && provider.supports(selection) && (!paste || provider.can_paste())
+    })
+}
+
+pub fn active(selection: Selection, paste: bool) -> &'static str {
+    chain(selection, paste)
+        .next()
+        .map_or(Internal.name(), |provider| provider.name())
+}
+
+pub fn copy(selection: Selection, text: &str) -> Result<&'static str> {
+    let mut last_error = None;
+    for provider in chain(selection, false) {
+        match provider.copy(selection, text) {
+            Ok(()) => return Ok(provider.name()),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("No provider can set the {}", selection.name())))
+}
+
+pub fn paste(selection: Selection) -> Result<Option<String>> {
+    let mut last_error = None;
+    for provider in chain(selection, true) {
+        match provider.paste(selection) {
+            Ok(text) => return Ok(text),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("No provider can read the {}", selection.name())))
+}
+
+fn in_path(program: &str) -> bool {
+    env::var_os("PATH").map_or(false, |paths| {
+        env::split_paths(&paths).any(|dir| Path::new(&dir).join(program).is_file())
+    })
+}
+
+fn run_copy(program: &str, args: &[&str], text: &str) -> Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("{} failed", program))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("{} failed ({})", program, status);
+    }
+    Ok(())
+}
+
+fn run_paste(program: &str, args: &[&str], selection: Selection) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("{} failed", program))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "{} failed: {}",
+            program,
+            stderr.lines().last().unwrap_or_default()
+        );
+    }
+    String::from_utf8(output.stdout)
+        .map_err(|_| anyhow!("The {} is not valid UTF-8", selection.name()))
+}
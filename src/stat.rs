@@ -0,0 +1,150 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use std::ffi::CStr;
+use std::fs;
+use std::mem;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::ptr;
+
+const MAX_ENTRY_BUFFER: usize = 1 << 20;
+
+pub fn describe(path: &Path) -> Result<String> {
+    let link_metadata = fs::symlink_metadata(path)?;
+    // A dangling link has nothing to follow, so it describes itself.
+    let metadata = fs::metadata(path).unwrap_or_else(|_| link_metadata.clone());
+    let modified: DateTime<Local> = metadata.modified()?.into();
+    let mut description = format!(
+        "{} {} {}:{} {} bytes, modified {}",
+        path.display(),
+        mode_string(&metadata),
+        user_name(metadata.uid()),
+        group_name(metadata.gid()),
+        metadata.len(),
+        modified.format("%Y-%m-%d %H:%M:%S")
+    );
+    if link_metadata.file_type().is_symlink() {
+        let target = fs::read_link(path)?;
+        description.push_str(&format!(" -> {}", target.display()));
+    }
+    Ok(description)
+}
+
+fn mode_string(metadata: &fs::Metadata) -> String {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_dir() {
+        'd'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_block_device() {
+        'b'
+    } else if file_type.is_fifo() {
+        'p'
+    } else if file_type.is_socket() {
+        's'
+    } else {
+        '-'
+    };
+    let mode = metadata.permissions().mode();
+    let mut result = kind.to_string();
+    for (shift, symbols) in [(6, "rwx"), (3, "rwx"), (0, "rwx")] {
+        for (bit, symbol) in symbols.chars().enumerate() {
+            if mode >> shift & (0b100 >> bit) != 0 {
+                result.push(symbol);
+            } else {
+                result.push('-');
+            }
+        }
+    }
+    result
+}
+
+fn user_name(uid: u32) -> String {
+    lookup_name(uid, |buffer| {
+        // SAFETY: a zeroed passwd is only an out-parameter for getpwuid_r.
+        let mut entry: libc::passwd = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        // SAFETY: every pointer outlives the call and the length is the buffer's own.
+        let status = unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        (status, (!result.is_null()).then_some(entry.pw_name))
+    })
+}
+
+fn group_name(gid: u32) -> String {
+    lookup_name(gid, |buffer| {
+        // SAFETY: a zeroed group is only an out-parameter for getgrgid_r.
+        let mut entry: libc::group = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        // SAFETY: every pointer outlives the call and the length is the buffer's own.
+        let status = unsafe {
+            libc::getgrgid_r(
+                gid,
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        (status, (!result.is_null()).then_some(entry.gr_name))
+    })
+}
+
+// Goes through NSS, so directory users are named too. An entry too big for
+// the buffer is retried with a larger one; an id without a name is shown as
+// the number.
+fn lookup_name<F>(id: u32, mut lookup: F) -> String
+where
+    F: FnMut(&mut [libc::c_char]) -> (libc::c_int, Option<*mut libc::c_char>),
+{
+    let mut size: usize = 1024;
+    while size <= MAX_ENTRY_BUFFER {
+        let mut buffer = vec![0; size];
+        match lookup(&mut buffer) {
+            (libc::ERANGE, _) => size = size.saturating_mul(2),
+            (0, Some(name)) if !name.is_null() => {
+                // SAFETY: the name points into buffer, still alive here, and
+                // the lookup wrote it NUL-terminated.
+                return unsafe { CStr::from_ptr(name) }
+                    .to_string_lossy()
+                    .into_owned();
+            }
+            _ => break,
+        }
+    }
+    id.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    #[test]
+    fn dangling_links_show_their_target() {
+        let link = env::temp_dir().join(format!("hecto-stat-{}", process::id()));
+        assert!(symlink("/no/such/target", &link).is_ok());
+        let description = describe(&link);
+        fs::remove_file(&link).ok();
+        let description = description.unwrap_or_default();
+        assert!(description.contains(" lrwxrwxrwx "));
+        assert!(description.ends_with(" -> /no/such/target"));
+    }
+
+    #[test]
+    fn ids_are_named_through_the_system_databases() {
+        assert_eq!(user_name(0), "root");
+        assert_eq!(user_name(4_000_000_000), "4000000000");
+    }
+}
@@ -0,0 +1,223 @@
+use crate::bufstats::BufferStats;
+use crate::document::HighlightJob;
+use crate::quickfix::QuickfixEntry;
+use crate::terminal::Waker;
+use anyhow::{anyhow, Result};
+use std::cmp;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_WORKERS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub enum TaskResult {
+    Grep {
+        pattern: String,
+        entries: Vec<QuickfixEntry>,
+        buffer_id: usize,
+    },
+    BufferStats(BufferStats),
+    Save(Result<usize>),
+    Filter(Result<Vec<String>>),
+    Highlight(HighlightJob),
+}
+
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+struct Task {
+    id: usize,
+    name: String,
+    queued: Instant,
+    started: Arc<Mutex<Option<Instant>>>,
+    cancel: CancelToken,
+    cancellable: bool,
+}
+
+pub struct TaskStatus {
+    pub id: usize,
+    pub name: String,
+    pub running: Option<Duration>,
+    pub waiting: Duration,
+    pub cancelled: bool,
+}
+
+pub struct Tasks {
+    next_id: usize,
+    tasks: Vec<Task>,
+    jobs: Option<Sender<Job>>,
+    sender: Sender<(usize, Option<TaskResult>)>,
+    receiver: Receiver<(usize, Option<TaskResult>)>,
+    waker: Option<Waker>,
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            next_id: 1,
+            tasks: Vec::new(),
+            jobs: None,
+            sender,
+            receiver,
+            waker: None,
+        }
+    }
+}
+
+impl Tasks {
+    pub fn set_waker(&mut self, waker: Waker) {
+        self.waker = Some(waker);
+    }
+
+    pub fn spawn<F>(&mut self, name: &str, work: F) -> usize
+    where
+        F: FnOnce(&CancelToken) -> Option<TaskResult> + Send + 'static,
+    {
+        self.spawn_task(name, true, work)
+    }
+
+    // For work whose result has to be delivered once it has started, such as
+    // a write that may already have replaced the file.
+    pub fn spawn_uncancellable<F>(&mut self, name: &str, work: F) -> usize
+    where
+        F: FnOnce() -> TaskResult + Send + 'static,
+    {
+        self.spawn_task(name, false, move |_| Some(work()))
+    }
+
+    fn spawn_task<F>(&mut self, name: &str, cancellable: bool, work: F) -> usize
+    where
+        F: FnOnce(&CancelToken) -> Option<TaskResult> + Send + 'static,
+    {
+        let id = self.next_id;
+        self.next_id = self.next_id.saturating_add(1);
+        let cancel = CancelToken::default();
+        let started = Arc::new(Mutex::new(None));
+        self.tasks.push(Task {
+            id,
+            name: name.to_string(),
+            queued: Instant::now(),
+            started: Arc::clone(&started),
+            cancel: cancel.clone(),
+            cancellable,
+        });
+        let sender = self.sender.clone();
+        let waker = self.waker.clone();
+        let job: Job = Box::new(move || {
+            let result = if cancel.is_cancelled() {
+                None
+            } else {
+                if let Ok(mut started) = started.lock() {
+                    *started = Some(Instant::now());
+                }
+                work(&cancel).filter(|_| !cancel.is_cancelled())
+            };
+            let _ = sender.send((id, result));
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        });
+        if let Err(mpsc::SendError(job)) = self.workers().send(job) {
+            thread::spawn(job);
+        }
+        id
+    }
+
+    fn workers(&mut self) -> &Sender<Job> {
+        self.jobs.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel::<Job>();
+            let receiver = Arc::new(Mutex::new(receiver));
+            let count = thread::available_parallelism().map_or(1, |count| count.get());
+            for _ in 0..cmp::min(count, MAX_WORKERS) {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let job = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                });
+            }
+            sender
+        })
+    }
+
+    pub fn poll(&mut self) -> Vec<(usize, TaskResult)> {
+        let mut results = Vec::new();
+        loop {
+            match self.receiver.try_recv() {
+                Ok((id, result)) => {
+                    self.tasks.retain(|task| task.id != id);
+                    if let Some(result) = result {
+                        results.push((id, result));
+                    }
+                }
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+            }
+        }
+        results
+    }
+
+    pub fn is_running(&self, id: usize) -> bool {
+        self.tasks.iter().any(|task| task.id == id)
+    }
+
+    pub fn cancel(&mut self, id: usize) -> Result<()> {
+        let task = self
+            .tasks
+            .iter()
+            .find(|task| task.id == id)
+            .ok_or_else(|| anyhow!("No running task {}", id))?;
+        if !task.cancellable {
+            return Err(anyhow!("Task {} ({}) cannot be cancelled", id, task.name));
+        }
+        task.cancel.cancel();
+        Ok(())
+    }
+
+    pub fn cancel_all(&mut self) -> usize {
+        let cancellable = self.tasks.iter().filter(|task| task.cancellable);
+        let mut count: usize = 0;
+        for task in cancellable {
+            task.cancel.cancel();
+            count = count.saturating_add(1);
+        }
+        count
+    }
+
+    pub fn status(&self) -> Vec<TaskStatus> {
+        self.tasks
+            .iter()
+            .map(|task| {
+                let started = task.started.lock().ok().and_then(|started| *started);
+                TaskStatus {
+                    id: task.id,
+                    name: task.name.clone(),
+                    running: started.map(|started| started.elapsed()),
+                    waiting: started
+                        .unwrap_or_else(Instant::now)
+                        .duration_since(task.queued),
+                    cancelled: task.cancel.is_cancelled(),
+                }
+            })
+            .collect()
+    }
+}
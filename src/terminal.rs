@@ -1,36 +1,332 @@
 use crate::Position;
 use anyhow::Result;
-use std::io::{stdout, Stdout, Write};
+use std::cell::RefCell;
+use std::env;
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, stdout, Read, Stdout, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
 use termion::cursor::{Goto, Hide, Show};
 use termion::event::Event;
-use termion::input::{Events, MouseTerminal, TermRead};
+use termion::input::{MouseTerminal, TermRead};
 use termion::raw::{IntoRawMode, RawTerminal};
 use termion::screen::{AlternateScreen, IntoAlternateScreen};
-use termion::{async_stdin, color, AsyncReader};
+use termion::{color, style};
 
 pub struct Size {
     pub width: u16,
     pub height: u16,
 }
+const ENABLE_FOCUS_REPORTING: &str = "\x1b[?1004h";
+const DISABLE_FOCUS_REPORTING: &str = "\x1b[?1004l";
+const FOCUS_GAINED: &[u8] = b"\x1b[I";
+const FOCUS_LOST: &[u8] = b"\x1b[O";
+const BEGIN_SYNCHRONIZED_UPDATE: &str = "\x1b[?2026h";
+const END_SYNCHRONIZED_UPDATE: &str = "\x1b[?2026l";
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const DISABLE_MOUSE: &str = "\x1b[?1006l\x1b[?1015l\x1b[?1002l\x1b[?1000l";
+const MODERN_TERMINALS: [&str; 7] = [
+    "kitty",
+    "wezterm",
+    "alacritty",
+    "foot",
+    "ghostty",
+    "contour",
+    "iterm",
+];
+// XTGETTCAP queries for italics, undercurl and true colour (the capability
+// names are hex encoded), DECRQM for synchronized output, and DA1 last: every
+// terminal answers that one, so its reply ends the wait.
+const CAPABILITY_QUERY: &str = concat!(
+    "\x1bP+q7369746d\x1b\\",
+    "\x1bP+q536d756c78\x1b\\",
+    "\x1bP+q524742\x1b\\",
+    "\x1b[?2026$p",
+    "\x1b[c",
+);
+const SITM: &str = "7369746d";
+const SMULX: &str = "536d756c78";
+const RGB: &str = "524742";
+const QUERY_TIMEOUT: Duration = Duration::from_millis(100);
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+const ANSI_COLORS: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+static CAPABILITIES: OnceLock<Capabilities> = OnceLock::new();
+static RESIZE_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+thread_local! {
+    static FRAME: RefCell<Frame> = RefCell::new(Frame::default());
+}
+
+#[derive(Default)]
+struct Frame {
+    lines: Vec<String>,
+    line: String,
+    output: String,
+}
+
+pub enum TerminalEvent {
+    Input(Result<Event>),
+    Resize,
+    Wake,
+}
+
+enum Message {
+    Input(io::Result<Event>),
+    Resize,
+    Wake,
+}
+
+#[derive(Clone)]
+pub struct Waker(Sender<Message>);
+
+impl Waker {
+    pub fn wake(&self) {
+        let _ = self.0.send(Message::Wake);
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum ColorDepth {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+    Monochrome,
+}
+
+impl ColorDepth {
+    pub fn detect() -> Self {
+        if env::var_os("NO_COLOR").map_or(false, |value| !value.is_empty()) {
+            return Self::Monochrome;
+        }
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            return Self::TrueColor;
+        }
+        let term = env::var("TERM").unwrap_or_default();
+        if term.ends_with("-direct") {
+            Self::TrueColor
+        } else if term.contains("256color") {
+            Self::Ansi256
+        } else {
+            Self::Ansi16
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::TrueColor => "truecolor",
+            Self::Ansi256 => "256 colors",
+            Self::Ansi16 => "16 colors",
+            Self::Monochrome => "no color",
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Attribute {
+    Plain,
+    Bold,
+    Italic,
+    Underline,
+    Undercurl,
+    Reverse,
+}
+
+#[derive(Clone, Copy)]
+pub struct Capabilities {
+    pub color_depth: ColorDepth,
+    pub italics: bool,
+    pub undercurl: bool,
+    pub synchronized_output: bool,
+    pub mouse: bool,
+}
+
+impl Capabilities {
+    pub fn detect() -> Self {
+        let term = env::var("TERM").unwrap_or_default();
+        let program = env::var("TERM_PROGRAM").unwrap_or_default().to_lowercase();
+        let vte_version: u32 = env::var("VTE_VERSION")
+            .ok()
+            .and_then(|version| version.parse().ok())
+            .unwrap_or(0);
+        let basic = is_basic(&term);
+        let modern = MODERN_TERMINALS
+            .iter()
+            .any(|name| term.contains(name) || program.contains(name));
+        Self {
+            color_depth: ColorDepth::detect(),
+            italics: !basic && !term.starts_with("screen"),
+            undercurl: modern || vte_version >= 5200,
+            synchronized_output: modern,
+            mouse: !basic,
+        }
+    }
+
+    // Starts from the guess above and lets whatever the terminal answers
+    // override it. The replies arrive as input, so this has to run before
+    // anything else reads the tty.
+    fn query(tty: &mut File) -> Self {
+        let mut capabilities = Self::detect();
+        if is_basic(&env::var("TERM").unwrap_or_default())
+            || tty.write_all(CAPABILITY_QUERY.as_bytes()).is_err()
+            || tty.flush().is_err()
+        {
+            return capabilities;
+        }
+        let replies = read_replies(tty);
+        if let Some(italics) = termcap_reply(&replies, SITM) {
+            capabilities.italics = italics;
+        }
+        if let Some(undercurl) = termcap_reply(&replies, SMULX) {
+            capabilities.undercurl = undercurl;
+        }
+        // Plenty of true colour terminals lack the RGB capability, so only a
+        // yes counts.
+        if termcap_reply(&replies, RGB) == Some(true)
+            && capabilities.color_depth != ColorDepth::Monochrome
+        {
+            capabilities.color_depth = ColorDepth::TrueColor;
+        }
+        if let Some(synchronized_output) = mode_reply(&replies, 2026) {
+            capabilities.synchronized_output = synchronized_output;
+        }
+        capabilities
+    }
+}
+
+fn is_basic(term: &str) -> bool {
+    term.is_empty()
+        || term == "dumb"
+        || term == "linux"
+        || term == "cons25"
+        || term.starts_with("vt")
+}
+
+fn read_replies(tty: &mut File) -> String {
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    let mut poll_fd = libc::pollfd {
+        fd: tty.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let mut replies = String::new();
+    let mut buffer = [0; 256];
+    while !has_device_attributes(&replies) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let timeout = libc::c_int::try_from(remaining.as_millis()).unwrap_or(0);
+        // SAFETY: poll_fd is a single pollfd for the open tty.
+        if timeout == 0 || unsafe { libc::poll(&mut poll_fd, 1, timeout) } <= 0 {
+            break;
+        }
+        match tty.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => replies.push_str(&String::from_utf8_lossy(
+                buffer.get(..read).unwrap_or_default(),
+            )),
+        }
+    }
+    replies
+}
+
+// A DA1 reply is CSI ? followed by parameters and a final c.
+fn has_device_attributes(replies: &str) -> bool {
+    replies.split("\x1b[?").skip(1).any(|reply| {
+        reply
+            .trim_start_matches(|c: char| c.is_ascii_digit() || c == ';')
+            .starts_with('c')
+    })
+}
+
+// A terminal that answers XTGETTCAP at all says 1+r for the capabilities it
+// has and 0+r for the rest, though not always with the name.
+fn termcap_reply(replies: &str, name: &str) -> Option<bool> {
+    if replies.contains(&format!("\x1bP1+r{}", name)) {
+        Some(true)
+    } else if replies.contains("\x1bP0+r") || replies.contains("\x1bP1+r") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+// DECRPM reports 1 to 3 for a mode the terminal has, and 0 or 4 for one it
+// does not know or cannot turn on.
+fn mode_reply(replies: &str, mode: u16) -> Option<bool> {
+    let (_, rest) = replies.split_once(&format!("\x1b[?{};", mode))?;
+    match rest.chars().next()? {
+        '1' | '2' | '3' => Some(true),
+        '0' | '4' => Some(false),
+        _ => None,
+    }
+}
+
 pub struct Terminal {
     size: Size,
-    stdin: Events<AsyncReader>,
+    events: Receiver<Message>,
+    sender: Sender<Message>,
+    shadow: Vec<String>,
     stdout: RawTerminal<AlternateScreen<MouseTerminal<Stdout>>>,
 }
 
 impl Terminal {
     pub fn default() -> Result<Self> {
         let size = termion::terminal_size()?;
-        Ok(Self {
+        let mut tty = termion::get_tty()?;
+        let stdout = MouseTerminal::from(stdout())
+            .into_alternate_screen()?
+            .into_raw_mode()?;
+        let _ = CAPABILITIES.set(Capabilities::query(&mut tty));
+        let (sender, events) = mpsc::channel();
+        let input = sender.clone();
+        thread::spawn(move || {
+            for event in tty.events() {
+                if input.send(Message::Input(event)).is_err() {
+                    return;
+                }
+            }
+        });
+        watch_resize(sender.clone());
+        let mut terminal = Self {
             size: Size {
-                width: size.0.saturating_sub(5),
+                width: size.0,
                 height: size.1.saturating_sub(2),
             },
-            stdin: async_stdin().events(),
-            stdout: MouseTerminal::from(stdout())
-                .into_alternate_screen()?
-                .into_raw_mode()?,
-        })
+            events,
+            sender,
+            shadow: Vec::new(),
+            stdout,
+        };
+        write!(terminal.stdout, "{}", ENABLE_FOCUS_REPORTING)?;
+        if !Self::capabilities().mouse {
+            write!(terminal.stdout, "{}", DISABLE_MOUSE)?;
+        }
+        Ok(terminal)
     }
 
     pub fn size(&self) -> &Size {
@@ -39,72 +335,465 @@ impl Terminal {
 
     pub fn update_size(&mut self) -> Result<()> {
         let size = termion::terminal_size()?;
-        self.size = Size {
-            width: size.0.saturating_sub(5),
+        let size = Size {
+            width: size.0,
             height: size.1.saturating_sub(2),
         };
+        if size.width != self.size.width || size.height != self.size.height {
+            self.invalidate();
+        }
+        self.size = size;
         Ok(())
     }
 
-    pub fn clear_screen() {
-        print!("{}", termion::clear::All);
+    // Forget what is on screen so the next frame repaints every row.
+    pub fn invalidate(&mut self) {
+        self.shadow.clear();
     }
 
+    pub fn print(text: &str) {
+        FRAME.with(|frame| frame.borrow_mut().line.push_str(text));
+    }
+
+    pub fn end_line() {
+        FRAME.with(|frame| {
+            let mut frame = frame.borrow_mut();
+            let line = mem::take(&mut frame.line);
+            frame.lines.push(line);
+        });
+    }
+
+    pub fn print_line(text: &str) {
+        Self::print(text);
+        Self::end_line();
+    }
+
+    // Rows drawn since the last frame are compared against the shadow copy of
+    // the screen, and only those that differ are sent to the terminal.
     #[allow(clippy::cast_possible_truncation)]
-    pub fn cursor_position(position: &Position, is_row: bool) {
-        let Position { mut x, mut y } = position;
-        if is_row {
-            x = x.saturating_add(6);
+    pub fn present(&mut self, dim: bool) {
+        let mut lines = FRAME.with(|frame| {
+            let mut frame = frame.borrow_mut();
+            if !frame.line.is_empty() {
+                let line = mem::take(&mut frame.line);
+                frame.lines.push(line);
+            }
+            mem::take(&mut frame.lines)
+        });
+        let height = usize::from(self.size.height).saturating_add(2);
+        lines.resize(height, String::new());
+        self.shadow.resize(height, String::from("\0"));
+        let prefix = if dim {
+            format!("{}", style::Faint)
         } else {
-            x = x.saturating_add(1);
+            String::new()
+        };
+        for (y, (line, shown)) in lines.into_iter().zip(self.shadow.iter_mut()).enumerate() {
+            let line = format!("{}{}", prefix, line);
+            if line == *shown {
+                continue;
+            }
+            emit(format_args!(
+                "{}{}{}{}",
+                Goto(1, u16::try_from(y.saturating_add(1)).unwrap_or(u16::MAX)),
+                style::Reset,
+                termion::clear::CurrentLine,
+                line
+            ));
+            *shown = line;
         }
+        emit(format_args!("{}", style::Reset));
+    }
+
+    pub fn clear_screen() {
+        emit(format_args!("{}", termion::clear::All));
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn cursor_position(position: &Position, x_offset: usize) {
+        let Position { mut x, mut y } = position;
+        x = x.saturating_add(x_offset).saturating_add(1);
         y = y.saturating_add(1);
-        print!(
+        emit(format_args!(
             "{}",
             Goto(
                 x.try_into().unwrap_or_default(),
                 y.try_into().unwrap_or_default()
             )
-        );
+        ));
     }
 
     pub fn flush_static() -> Result<()> {
         stdout().flush().map_err(anyhow::Error::from)
     }
 
+    // Everything queued for the frame goes out in a single write, so a slow
+    // terminal never sees it half drawn.
     pub fn flush(&mut self) -> Result<()> {
+        let output = FRAME.with(|frame| mem::take(&mut frame.borrow_mut().output));
+        self.stdout.write_all(output.as_bytes())?;
         self.stdout.flush().map_err(anyhow::Error::from)
     }
 
-    pub fn read_event(&mut self) -> Option<Result<Event>> {
-        self.stdin.next().map(|op| op.map_err(anyhow::Error::from))
+    pub fn suspend_raw_mode(&self) -> Result<()> {
+        self.stdout.suspend_raw_mode().map_err(anyhow::Error::from)
+    }
+
+    pub fn activate_raw_mode(&self) -> Result<()> {
+        self.stdout.activate_raw_mode().map_err(anyhow::Error::from)
+    }
+
+    pub fn waker(&self) -> Waker {
+        Waker(self.sender.clone())
+    }
+
+    // Blocks until input, a resize or a wake-up arrives; None once the timeout
+    // passes, so timers are just deadlines handed in by the caller.
+    pub fn read_event(&mut self, timeout: Option<Duration>) -> Option<TerminalEvent> {
+        let message = match timeout {
+            Some(timeout) => match self.events.recv_timeout(timeout) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return None,
+            },
+            None => self.events.recv().ok()?,
+        };
+        Some(match message {
+            Message::Input(event) => TerminalEvent::Input(event.map_err(anyhow::Error::from)),
+            Message::Resize => TerminalEvent::Resize,
+            Message::Wake => TerminalEvent::Wake,
+        })
+    }
+
+    pub fn focus_change(bytes: &[u8]) -> Option<bool> {
+        match bytes {
+            FOCUS_GAINED => Some(true),
+            FOCUS_LOST => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn copy_to_clipboard(primary: bool, text: &str) {
+        let target = if primary { 'p' } else { 'c' };
+        emit(format_args!(
+            "\x1b]52;{};{}\x07",
+            target,
+            base64(text.as_bytes())
+        ));
     }
 
     pub fn cursor_hide() {
-        print!("{Hide}");
+        emit(format_args!("{Hide}"));
     }
 
     pub fn cursor_show() {
-        print!("{Show}");
+        emit(format_args!("{Show}"));
     }
 
-    pub fn clear_current_line() {
-        print!("{}", termion::clear::CurrentLine);
+    pub fn begin_synchronized_update() {
+        if Self::capabilities().synchronized_output {
+            emit(format_args!("{}", BEGIN_SYNCHRONIZED_UPDATE));
+        }
+    }
+
+    pub fn end_synchronized_update() {
+        if Self::capabilities().synchronized_output {
+            emit(format_args!("{}", END_SYNCHRONIZED_UPDATE));
+        }
+    }
+
+    pub fn capabilities() -> Capabilities {
+        *CAPABILITIES.get_or_init(Capabilities::detect)
+    }
+
+    pub fn color_depth() -> ColorDepth {
+        Self::capabilities().color_depth
+    }
+
+    pub fn attribute_sequence(attribute: Attribute) -> String {
+        let capabilities = Self::capabilities();
+        match attribute {
+            Attribute::Plain => String::new(),
+            Attribute::Bold => format!("{}", style::Bold),
+            Attribute::Italic if capabilities.italics => format!("{}", style::Italic),
+            Attribute::Italic => String::new(),
+            Attribute::Undercurl if capabilities.undercurl => "\x1b[4:3m".to_string(),
+            Attribute::Underline | Attribute::Undercurl => format!("{}", style::Underline),
+            Attribute::Reverse => format!("{}", style::Invert),
+        }
+    }
+
+    pub fn attribute_end_sequence(attribute: Attribute) -> &'static str {
+        match attribute {
+            Attribute::Plain => "",
+            Attribute::Bold => "\x1b[22m",
+            Attribute::Italic => "\x1b[23m",
+            Attribute::Underline | Attribute::Undercurl => "\x1b[24m",
+            Attribute::Reverse => "\x1b[27m",
+        }
+    }
+
+    pub fn fg_sequence(color: color::Rgb) -> String {
+        match Self::color_depth() {
+            ColorDepth::Monochrome => String::new(),
+            ColorDepth::TrueColor => format!("{}", color::Fg(color)),
+            ColorDepth::Ansi256 => format!("{}", color::Fg(color::AnsiValue(to_ansi256(color)))),
+            ColorDepth::Ansi16 => match to_ansi16(color) {
+                index @ 0..=7 => format!("\x1b[{}m", 30_u8.saturating_add(index)),
+                index => format!("\x1b[{}m", 82_u8.saturating_add(index)),
+            },
+        }
+    }
+
+    pub fn bg_sequence(color: color::Rgb) -> String {
+        match Self::color_depth() {
+            ColorDepth::Monochrome => format!("{}", style::Invert),
+            ColorDepth::TrueColor => format!("{}", color::Bg(color)),
+            ColorDepth::Ansi256 => format!("{}", color::Bg(color::AnsiValue(to_ansi256(color)))),
+            ColorDepth::Ansi16 => match to_ansi16(color) {
+                index @ 0..=7 => format!("\x1b[{}m", 40_u8.saturating_add(index)),
+                index => format!("\x1b[{}m", 92_u8.saturating_add(index)),
+            },
+        }
     }
 
     pub fn set_bg_color(color: color::Rgb) {
-        print!("{}", color::Bg(color));
+        Self::print(&Self::bg_sequence(color));
     }
 
     pub fn reset_bg_color() {
-        print!("{}", color::Bg(color::Reset));
+        if Self::color_depth() == ColorDepth::Monochrome {
+            Self::print(Self::attribute_end_sequence(Attribute::Reverse));
+        } else {
+            Self::print(&color::Bg(color::Reset).to_string());
+        }
+    }
+
+    pub fn set_bold() {
+        Self::print(&style::Bold.to_string());
+    }
+
+    pub fn set_underline() {
+        Self::print(&style::Underline.to_string());
+    }
+
+    pub fn reset_style() {
+        Self::print(&style::Reset.to_string());
     }
 
     pub fn set_fg_color(color: color::Rgb) {
-        print!("{}", color::Fg(color));
+        Self::print(&Self::fg_sequence(color));
     }
 
     pub fn reset_fg_color() {
-        print!("{}", color::Fg(color::Reset));
+        Self::print(&color::Fg(color::Reset).to_string());
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = write!(self.stdout, "{}", DISABLE_FOCUS_REPORTING);
+        let _ = self.stdout.flush();
+    }
+}
+
+fn emit(args: fmt::Arguments) {
+    FRAME.with(|frame| {
+        let _ = frame.borrow_mut().output.write_fmt(args);
+    });
+}
+
+extern "C" fn on_resize(_signal: libc::c_int) {
+    let fd = RESIZE_PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // SAFETY: write(2) is async-signal-safe and the buffer outlives the call.
+        unsafe {
+            libc::write(fd, b"r".as_ptr().cast(), 1);
+        }
+    }
+}
+
+fn set_nonblocking(fd: libc::c_int) -> bool {
+    // SAFETY: fcntl(2) only reads and sets the flags of an open descriptor.
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        flags >= 0 && libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) == 0
+    }
+}
+
+// SIGWINCH only gets as far as a self-pipe; a thread turns it into an event.
+// Both ends are non-blocking, so a full pipe drops the signal instead of
+// stalling whatever the handler interrupted.
+fn watch_resize(sender: Sender<Message>) {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for the two descriptors pipe(2) fills in.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return;
+    }
+    // SAFETY: the read end was just created and nothing else owns it.
+    let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+    if !set_nonblocking(fds[0]) || !set_nonblocking(fds[1]) {
+        // SAFETY: the write end was never shared.
+        unsafe {
+            libc::close(fds[1]);
+        }
+        return;
+    }
+    RESIZE_PIPE.store(fds[1], Ordering::Relaxed);
+    // SAFETY: the handler only touches an atomic and calls write(2), and the
+    // zeroed sigaction is filled in before use.
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_resize as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGWINCH, &action, ptr::null_mut());
+    }
+    thread::spawn(move || {
+        let mut poll_fd = libc::pollfd {
+            fd: fds[0],
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let mut buffer = [0; 64];
+        loop {
+            // SAFETY: poll_fd is a single pollfd for the open read end.
+            if unsafe { libc::poll(&mut poll_fd, 1, -1) } < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return;
+            }
+            // A burst of signals is drained into a single event.
+            let mut resized = false;
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) => return,
+                    Ok(_) => resized = true,
+                    Err(error) if error.kind() == io::ErrorKind::Interrupted => (),
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(_) => return,
+                }
+            }
+            if resized && sender.send(Message::Resize).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let channel = |x: u8, y: u8| u32::from(x.abs_diff(y)).saturating_pow(2);
+    channel(a.0, b.0)
+        .saturating_add(channel(a.1, b.1))
+        .saturating_add(channel(a.2, b.2))
+}
+
+#[allow(clippy::integer_division)]
+fn to_ansi256(color: color::Rgb) -> u8 {
+    let color::Rgb(r, g, b) = color;
+    let level = |value: u8| {
+        CUBE_LEVELS
+            .iter()
+            .copied()
+            .enumerate()
+            .min_by_key(|(_, level)| level.abs_diff(value))
+            .unwrap_or((0, 0))
+    };
+    let ((cube_r, level_r), (cube_g, level_g), (cube_b, level_b)) = (level(r), level(g), level(b));
+    let cube = (level_r, level_g, level_b);
+    let cube_index = cube_r
+        .saturating_mul(36)
+        .saturating_add(cube_g.saturating_mul(6))
+        .saturating_add(cube_b)
+        .saturating_add(16);
+    let average = u16::from(r)
+        .saturating_add(u16::from(g))
+        .saturating_add(u16::from(b))
+        / 3;
+    let gray_step = u8::try_from(average.saturating_sub(3) / 10)
+        .unwrap_or(23)
+        .min(23);
+    let gray_value = gray_step.saturating_mul(10).saturating_add(8);
+    let gray = (gray_value, gray_value, gray_value);
+    if distance(gray, (r, g, b)) < distance(cube, (r, g, b)) {
+        gray_step.saturating_add(232)
+    } else {
+        u8::try_from(cube_index).unwrap_or(231)
+    }
+}
+
+fn to_ansi16(color: color::Rgb) -> u8 {
+    let color::Rgb(r, g, b) = color;
+    ANSI_COLORS
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, ansi)| distance(**ansi, (r, g, b)))
+        .and_then(|(index, _)| u8::try_from(index).ok())
+        .unwrap_or(7)
+}
+
+// The shifts are by constants well inside a u32.
+#[allow(clippy::integer_arithmetic)]
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3).saturating_mul(4));
+    for chunk in bytes.chunks(3) {
+        let b = |index: usize| u32::from(chunk.get(index).copied().unwrap_or(0));
+        let triple = (b(0) << 16) | (b(1) << 8) | b(2);
+        for (index, shift) in [18, 12, 6, 0].into_iter().enumerate() {
+            let sextet = usize::try_from((triple >> shift) & 0x3f).unwrap_or(0);
+            match BASE64_ALPHABET.get(sextet) {
+                Some(c) if index <= chunk.len() => encoded.push(char::from(*c)),
+                _ => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_matches_rfc_4648() {
+        for (input, expected) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64(input.as_bytes()), expected);
+        }
+    }
+
+    #[test]
+    fn colors_map_to_the_nearest_palette_entry() {
+        assert_eq!(to_ansi256(color::Rgb(0, 0, 0)), 16);
+        assert_eq!(to_ansi256(color::Rgb(255, 0, 0)), 196);
+        assert_eq!(to_ansi256(color::Rgb(255, 255, 255)), 231);
+        assert_eq!(to_ansi256(color::Rgb(128, 128, 128)), 244);
+        assert_eq!(to_ansi16(color::Rgb(0, 0, 0)), 0);
+    }
+
+    #[test]
+    fn capability_replies_are_read() {
+        let replies = "\x1bP1+r536d756c78=1b5b343a33\x1b\\\x1bP0+r\x1b\\\x1b[?2026;2$y\x1b[?62;22c";
+        assert!(has_device_attributes(replies));
+        assert_eq!(termcap_reply(replies, SMULX), Some(true));
+        assert_eq!(termcap_reply(replies, SITM), Some(false));
+        assert_eq!(mode_reply(replies, 2026), Some(true));
+        assert_eq!(mode_reply("\x1b[?2026;0$y", 2026), Some(false));
+    }
+
+    #[test]
+    fn unanswered_queries_keep_the_guess() {
+        let replies = "\x1b[?1;2c";
+        assert!(has_device_attributes(replies));
+        assert!(!has_device_attributes("\x1b[?2026;2$y"));
+        assert_eq!(termcap_reply(replies, SITM), None);
+        assert_eq!(mode_reply(replies, 2026), None);
     }
 }
@@ -0,0 +1,359 @@
+use crate::highlighting::Type;
+use crate::terminal::{Attribute, ColorDepth};
+use crate::Config;
+use crate::Terminal;
+use anyhow::{anyhow, bail, Result};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use termion::color::Rgb;
+
+pub const DEFAULT_THEME: &str = "default";
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Clone)]
+pub struct Theme {
+    pub name: String,
+    text: Rgb,
+    number: Rgb,
+    matched: Rgb,
+    current_match: Rgb,
+    string: Rgb,
+    character: Rgb,
+    escape: Rgb,
+    comment: Rgb,
+    primary_keywords: Rgb,
+    secondary_keywords: Rgb,
+    whitespace: Rgb,
+    pub status_fg: Rgb,
+    pub status_bg: Rgb,
+    pub selection_bg: Rgb,
+    pub line_number: Rgb,
+    pub dirty_line_number: Rgb,
+    pub dirty_cursor_line_number: Rgb,
+    pub indent_warning: Rgb,
+    styles: Vec<(Type, Attribute)>,
+    emphasis: bool,
+    pub id: usize,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_THEME.to_string(),
+            text: Rgb(255, 255, 255),
+            number: Rgb(220, 163, 163),
+            matched: Rgb(38, 139, 210),
+            current_match: Rgb(203, 75, 22),
+            string: Rgb(211, 54, 130),
+            character: Rgb(108, 113, 196),
+            escape: Rgb(220, 50, 47),
+            comment: Rgb(133, 153, 0),
+            primary_keywords: Rgb(181, 137, 0),
+            secondary_keywords: Rgb(42, 161, 152),
+            whitespace: Rgb(88, 88, 88),
+            status_fg: Rgb(63, 63, 63),
+            status_bg: Rgb(239, 239, 239),
+            selection_bg: Rgb(68, 68, 68),
+            line_number: Rgb(85, 85, 85),
+            dirty_line_number: Rgb(128, 0, 0),
+            dirty_cursor_line_number: Rgb(196, 72, 72),
+            indent_warning: Rgb(203, 75, 22),
+            styles: Vec::new(),
+            emphasis: false,
+            id: 0,
+        }
+    }
+}
+
+impl Theme {
+    pub fn builtin() -> Vec<Self> {
+        let default = Self::default();
+        vec![
+            default.clone(),
+            Self {
+                name: "gruvbox".to_string(),
+                text: Rgb(235, 219, 178),
+                number: Rgb(211, 134, 155),
+                matched: Rgb(131, 165, 152),
+                current_match: Rgb(254, 128, 25),
+                string: Rgb(184, 187, 38),
+                character: Rgb(211, 134, 155),
+                escape: Rgb(254, 128, 25),
+                comment: Rgb(146, 131, 116),
+                primary_keywords: Rgb(251, 73, 52),
+                secondary_keywords: Rgb(250, 189, 47),
+                whitespace: Rgb(102, 92, 84),
+                status_fg: Rgb(235, 219, 178),
+                status_bg: Rgb(80, 73, 69),
+                selection_bg: Rgb(60, 56, 54),
+                line_number: Rgb(124, 111, 100),
+                ..default.clone()
+            },
+            Self {
+                name: "monokai".to_string(),
+                text: Rgb(248, 248, 242),
+                number: Rgb(174, 129, 255),
+                matched: Rgb(102, 217, 239),
+                current_match: Rgb(253, 151, 31),
+                string: Rgb(230, 219, 116),
+                character: Rgb(174, 129, 255),
+                escape: Rgb(174, 129, 255),
+                comment: Rgb(117, 113, 94),
+                primary_keywords: Rgb(249, 38, 114),
+                secondary_keywords: Rgb(102, 217, 239),
+                whitespace: Rgb(117, 113, 94),
+                status_fg: Rgb(248, 248, 242),
+                status_bg: Rgb(73, 72, 62),
+                selection_bg: Rgb(73, 72, 62),
+                line_number: Rgb(144, 144, 138),
+                ..default.clone()
+            },
+            Self {
+                name: "light".to_string(),
+                text: Rgb(40, 40, 40),
+                number: Rgb(152, 104, 1),
+                matched: Rgb(7, 102, 120),
+                current_match: Rgb(175, 58, 3),
+                string: Rgb(121, 116, 14),
+                character: Rgb(143, 63, 113),
+                escape: Rgb(157, 0, 6),
+                comment: Rgb(124, 111, 100),
+                primary_keywords: Rgb(157, 0, 6),
+                secondary_keywords: Rgb(66, 123, 88),
+                whitespace: Rgb(189, 174, 147),
+                status_fg: Rgb(251, 241, 199),
+                status_bg: Rgb(80, 73, 69),
+                selection_bg: Rgb(213, 196, 161),
+                line_number: Rgb(168, 153, 132),
+                dirty_line_number: Rgb(204, 36, 29),
+                dirty_cursor_line_number: Rgb(157, 0, 6),
+                indent_warning: Rgb(175, 58, 3),
+                ..default.clone()
+            },
+            Self {
+                name: "high-contrast".to_string(),
+                text: Rgb(255, 255, 255),
+                number: Rgb(255, 215, 0),
+                matched: Rgb(0, 255, 255),
+                current_match: Rgb(255, 0, 255),
+                string: Rgb(0, 255, 0),
+                character: Rgb(0, 255, 0),
+                escape: Rgb(255, 135, 0),
+                comment: Rgb(192, 192, 192),
+                primary_keywords: Rgb(255, 255, 0),
+                secondary_keywords: Rgb(0, 215, 255),
+                whitespace: Rgb(128, 128, 128),
+                status_fg: Rgb(0, 0, 0),
+                status_bg: Rgb(255, 255, 255),
+                selection_bg: Rgb(0, 0, 175),
+                line_number: Rgb(192, 192, 192),
+                dirty_line_number: Rgb(255, 95, 95),
+                dirty_cursor_line_number: Rgb(255, 0, 0),
+                indent_warning: Rgb(255, 135, 0),
+                emphasis: true,
+                ..default
+            },
+        ]
+    }
+
+    pub fn available() -> Vec<String> {
+        let mut names: Vec<String> = Self::builtin()
+            .into_iter()
+            .map(|theme| theme.name)
+            .collect();
+        if let Some(entries) = themes_dir().and_then(|dir| fs::read_dir(dir).ok()) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    pub fn load(name: &str) -> Result<Self> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.contains("..") {
+            bail!("Invalid colorscheme name: {}", name);
+        }
+        if let Some(path) = themes_dir().map(|dir| dir.join(name)) {
+            if path.is_file() {
+                let mut theme = Self::default();
+                theme.name = name.to_string();
+                let contents = fs::read_to_string(&path)?;
+                let mut lines: Vec<(usize, &str)> = contents
+                    .lines()
+                    .map(str::trim)
+                    .enumerate()
+                    .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+                    .collect();
+                // A base replaces every color, so it is applied before the
+                // keys that override it wherever it appears in the file.
+                lines.sort_by_key(|(_, line)| {
+                    line.split_once('=')
+                        .map_or(true, |(key, _)| key.trim() != "base")
+                });
+                for (index, line) in lines {
+                    theme
+                        .apply(line)
+                        .map_err(|error| anyhow!("{}:{}: {}", path.display(), index + 1, error))?;
+                }
+                theme.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                return Ok(theme);
+            }
+        }
+        let mut theme = Self::builtin()
+            .into_iter()
+            .find(|theme| theme.name == name)
+            .ok_or_else(|| anyhow!("Unknown colorscheme: {}", name))?;
+        theme.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        Ok(theme)
+    }
+
+    fn apply(&mut self, line: &str) -> Result<()> {
+        let (key, value) = line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .ok_or_else(|| anyhow!("Expected key = color"))?;
+        if key == "base" {
+            let name = self.name.clone();
+            *self = Self::builtin()
+                .into_iter()
+                .find(|theme| theme.name == value)
+                .ok_or_else(|| anyhow!("Unknown base colorscheme: {}", value))?;
+            self.name = name;
+            return Ok(());
+        }
+        if key == "emphasis" {
+            self.emphasis = value
+                .parse()
+                .map_err(|_| anyhow!("Expected true or false: {}", value))?;
+            return Ok(());
+        }
+        if let Some(name) = key.strip_suffix("_style") {
+            let attribute = parse_attribute(value)?;
+            let types =
+                highlight_types(name).ok_or_else(|| anyhow!("Unknown theme style: {}", key))?;
+            self.styles.retain(|(hl_type, _)| !types.contains(hl_type));
+            self.styles
+                .extend(types.iter().map(|hl_type| (*hl_type, attribute)));
+            return Ok(());
+        }
+        let color = parse_color(value)?;
+        let field = match key {
+            "text" => &mut self.text,
+            "number" => &mut self.number,
+            "match" => &mut self.matched,
+            "current_match" => &mut self.current_match,
+            "string" => &mut self.string,
+            "character" => &mut self.character,
+            "escape" => &mut self.escape,
+            "comment" => &mut self.comment,
+            "primary_keywords" => &mut self.primary_keywords,
+            "secondary_keywords" => &mut self.secondary_keywords,
+            "whitespace" => &mut self.whitespace,
+            "status_fg" => &mut self.status_fg,
+            "status_bg" => &mut self.status_bg,
+            "selection_bg" => &mut self.selection_bg,
+            "line_number" => &mut self.line_number,
+            "dirty_line_number" => &mut self.dirty_line_number,
+            "dirty_cursor_line_number" => &mut self.dirty_cursor_line_number,
+            "indent_warning" => &mut self.indent_warning,
+            _ => bail!("Unknown theme color: {}", key),
+        };
+        *field = color;
+        Ok(())
+    }
+
+    pub fn color(&self, hl_type: Type) -> Rgb {
+        match hl_type {
+            Type::Number => self.number,
+            Type::Match => self.matched,
+            Type::CurrentMatch => self.current_match,
+            Type::String => self.string,
+            Type::Character => self.character,
+            Type::Escape => self.escape,
+            Type::Comment | Type::MultilineComment => self.comment,
+            Type::PrimaryKeywords => self.primary_keywords,
+            Type::SecondaryKeywords => self.secondary_keywords,
+            Type::Whitespace => self.whitespace,
+            Type::None => self.text,
+        }
+    }
+
+    pub fn style(&self, hl_type: Type) -> Attribute {
+        if let Some((_, attribute)) = self.styles.iter().find(|(styled, _)| *styled == hl_type) {
+            return *attribute;
+        }
+        if !self.uses_symbols() {
+            return Attribute::Plain;
+        }
+        match hl_type {
+            Type::Match => Attribute::Underline,
+            Type::CurrentMatch => Attribute::Reverse,
+            Type::PrimaryKeywords | Type::SecondaryKeywords => Attribute::Bold,
+            Type::Comment | Type::MultilineComment => Attribute::Italic,
+            _ => Attribute::Plain,
+        }
+    }
+
+    pub fn uses_symbols(&self) -> bool {
+        self.emphasis || Terminal::color_depth() == ColorDepth::Monochrome
+    }
+}
+
+fn highlight_types(name: &str) -> Option<&'static [Type]> {
+    Some(match name {
+        "text" => &[Type::None],
+        "number" => &[Type::Number],
+        "match" => &[Type::Match],
+        "current_match" => &[Type::CurrentMatch],
+        "string" => &[Type::String],
+        "character" => &[Type::Character],
+        "escape" => &[Type::Escape],
+        "comment" => &[Type::Comment, Type::MultilineComment],
+        "primary_keywords" => &[Type::PrimaryKeywords],
+        "secondary_keywords" => &[Type::SecondaryKeywords],
+        "whitespace" => &[Type::Whitespace],
+        _ => return None,
+    })
+}
+
+fn parse_attribute(value: &str) -> Result<Attribute> {
+    Ok(match value {
+        "plain" | "none" => Attribute::Plain,
+        "bold" => Attribute::Bold,
+        "italic" => Attribute::Italic,
+        "underline" => Attribute::Underline,
+        "undercurl" => Attribute::Undercurl,
+        "reverse" => Attribute::Reverse,
+        _ => bail!("Invalid style: {}", value),
+    })
+}
+
+fn themes_dir() -> Option<PathBuf> {
+    Some(Config::path()?.parent()?.join("themes"))
+}
+
+fn parse_color(value: &str) -> Result<Rgb> {
+    if let Some(hex) = value.strip_prefix('#') {
+        if hex.len() == 6 {
+            let channel = |range| u8::from_str_radix(hex.get(range).unwrap_or_default(), 16);
+            if let (Ok(r), Ok(g), Ok(b)) = (channel(0..2), channel(2..4), channel(4..6)) {
+                return Ok(Rgb(r, g, b));
+            }
+        }
+        bail!("Invalid color: {}", value);
+    }
+    let channels: Vec<u8> = value
+        .split(',')
+        .map(|channel| channel.trim().parse::<u8>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| anyhow!("Invalid color: {}", value))?;
+    match channels[..] {
+        [r, g, b] => Ok(Rgb(r, g, b)),
+        _ => bail!("Invalid color: {}", value),
+    }
+}
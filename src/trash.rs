@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use std::env;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{self, Path, PathBuf};
+
+// A symlink is trashed as itself, so the path is made absolute without
+// resolving links.
+pub fn remove(path: &Path) -> Result<PathBuf> {
+    let path = path::absolute(path)?;
+    fs::symlink_metadata(&path)?;
+    if let Ok(graveyard) = env::var("HECTO_GRAVEYARD") {
+        let graveyard = PathBuf::from(graveyard);
+        fs::create_dir_all(&graveyard)?;
+        let destination = unique_destination(&graveyard, &path)?;
+        move_file(&path, &destination)?;
+        return Ok(destination);
+    }
+    let trash = trash_dir()?;
+    let files = trash.join("files");
+    let info = trash.join("info");
+    fs::create_dir_all(&files)?;
+    fs::create_dir_all(&info)?;
+    let destination = unique_destination(&files, &path)?;
+    let file_name = destination
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid trash destination"))?
+        .to_string_lossy()
+        .to_string();
+    let trash_info = format!(
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        percent_encode(&path),
+        Local::now().format("%Y-%m-%dT%H:%M:%S")
+    );
+    let info_path = info.join(format!("{}.trashinfo", file_name));
+    fs::write(&info_path, trash_info)?;
+    if let Err(error) = move_file(&path, &destination) {
+        let _ = fs::remove_file(&info_path);
+        return Err(error);
+    }
+    Ok(destination)
+}
+
+fn trash_dir() -> Result<PathBuf> {
+    if let Ok(data_home) = env::var("XDG_DATA_HOME") {
+        return Ok(PathBuf::from(data_home).join("Trash"));
+    }
+    let home = env::var("HOME").map_err(|_| anyhow!("HOME is not set"))?;
+    Ok(PathBuf::from(home).join(".local/share/Trash"))
+}
+
+fn unique_destination(dir: &Path, path: &Path) -> Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Path has no file name: {}", path.display()))?
+        .to_string_lossy();
+    let mut destination = dir.join(&*file_name);
+    let mut count: usize = 1;
+    while fs::symlink_metadata(&destination).is_ok() {
+        destination = dir.join(format!("{}.{}", file_name, count));
+        count = count.saturating_add(1);
+    }
+    Ok(destination)
+}
+
+pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).is_err() {
+        if fs::symlink_metadata(from)?.file_type().is_symlink() {
+            symlink(fs::read_link(from)?, to)?;
+        } else {
+            fs::copy(from, to)?;
+        }
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+fn percent_encode(path: &Path) -> String {
+    let mut encoded = String::new();
+    for byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(char::from(*byte));
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
@@ -0,0 +1,167 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+const HEADER: &str = "hecto-undo";
+const FORMAT_VERSION: u32 = 2;
+
+pub struct UndoRecord {
+    pub parent: Option<usize>,
+    pub redo_child: Option<usize>,
+    pub timestamp: DateTime<Local>,
+    pub cursor: (usize, usize, usize),
+    pub lines: Vec<String>,
+}
+
+pub fn write(file_name: &str, current: usize, records: &[UndoRecord]) -> Result<()> {
+    let path = undo_path(file_name)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut body = format!("current {}\n", current);
+    for record in records {
+        body.push_str(&format!(
+            "version {} {} {} {} {} {} {}\n",
+            format_index(record.parent),
+            format_index(record.redo_child),
+            record.timestamp.to_rfc3339(),
+            record.cursor.0,
+            record.cursor.1,
+            record.cursor.2,
+            record.lines.len()
+        ));
+        for line in &record.lines {
+            body.push_str(line);
+            body.push('\n');
+        }
+    }
+    let mut temp_path = path.clone().into_os_string();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let _ = fs::remove_file(&temp_path);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .with_context(|| format!("{}", temp_path.display()))?;
+    writeln!(file, "{} {}", HEADER, FORMAT_VERSION)?;
+    writeln!(file, "checksum {:016x}", checksum(body.as_bytes()))?;
+    file.write_all(body.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, &path).with_context(|| format!("{}", path.display()))?;
+    Ok(())
+}
+
+pub fn read(file_name: &str) -> Result<Option<(usize, Vec<UndoRecord>)>> {
+    let path = undo_path(file_name)?;
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(None),
+    };
+    let mut sections = contents.splitn(3, '\n');
+    let version = sections
+        .next()
+        .and_then(|line| line.strip_prefix(HEADER))
+        .ok_or_else(|| anyhow!("Unrecognized undo file {}", path.display()))?
+        .trim();
+    if version != FORMAT_VERSION.to_string() {
+        return Err(anyhow!(
+            "Undo file {} has format version {}, expected {}",
+            path.display(),
+            if version.is_empty() { "1" } else { version },
+            FORMAT_VERSION
+        ));
+    }
+    let expected = sections
+        .next()
+        .and_then(|line| line.strip_prefix("checksum "))
+        .and_then(|sum| u64::from_str_radix(sum, 16).ok())
+        .ok_or_else(|| anyhow!("Missing checksum in {}", path.display()))?;
+    let body = sections.next().unwrap_or_default();
+    if checksum(body.as_bytes()) != expected {
+        return Err(anyhow!("Undo file {} is corrupted", path.display()));
+    }
+    let mut lines = body.strip_suffix('\n').unwrap_or(body).split('\n');
+    let current = lines
+        .next()
+        .and_then(|line| line.strip_prefix("current "))
+        .and_then(|index| index.parse().ok())
+        .ok_or_else(|| anyhow!("Missing current version in {}", path.display()))?;
+    let mut records = Vec::new();
+    while let Some(line) = lines.next() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let field = |index: usize| {
+            fields
+                .get(index)
+                .copied()
+                .ok_or_else(|| anyhow!("Malformed undo entry in {}", path.display()))
+        };
+        if field(0)? != "version" {
+            return Err(anyhow!("Malformed undo entry in {}", path.display()));
+        }
+        let number = |index: usize| -> Result<usize> { Ok(field(index)?.parse()?) };
+        let line_count = number(7)?;
+        let mut record_lines = Vec::with_capacity(line_count);
+        for _ in 0..line_count {
+            let line = lines
+                .next()
+                .ok_or_else(|| anyhow!("Truncated undo file {}", path.display()))?;
+            record_lines.push(line.to_string());
+        }
+        records.push(UndoRecord {
+            parent: parse_index(field(1)?)?,
+            redo_child: parse_index(field(2)?)?,
+            timestamp: DateTime::parse_from_rfc3339(field(3)?)?.with_timezone(&Local),
+            cursor: (number(4)?, number(5)?, number(6)?),
+            lines: record_lines,
+        });
+    }
+    let in_bounds = |index: &Option<usize>| index.map_or(true, |index| index < records.len());
+    if current >= records.len()
+        || !records
+            .iter()
+            .all(|record| in_bounds(&record.parent) && in_bounds(&record.redo_child))
+    {
+        return Err(anyhow!("Inconsistent undo tree in {}", path.display()));
+    }
+    Ok(Some((current, records)))
+}
+
+pub fn remove(file_name: &str) {
+    if let Ok(path) = undo_path(file_name) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+fn undo_path(file_name: &str) -> Result<PathBuf> {
+    let cache_home = env::var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map_err(|_| anyhow!("No cache directory available"))?;
+    let absolute = fs::canonicalize(file_name)?;
+    let key = absolute.to_string_lossy().replace('/', "%");
+    Ok(cache_home.join("hecto").join("undo").join(key))
+}
+
+fn format_index(index: Option<usize>) -> String {
+    index.map_or_else(|| "-".to_string(), |index| index.to_string())
+}
+
+fn parse_index(field: &str) -> Result<Option<usize>> {
+    if field == "-" {
+        return Ok(None);
+    }
+    Ok(Some(field.parse()?))
+}
+
+pub fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}